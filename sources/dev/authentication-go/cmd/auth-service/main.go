@@ -6,17 +6,22 @@ package main
 import (
 	"context"
 	"fmt"
+	"net"
 	"os"
+	"os/signal"
 	"sort"
 	"strings"
+	"syscall"
 
 	"github.com/zhaochy1990/x/logger"
 
+	"github.com/zhaochy1990/auth-service/internal/audit"
 	"github.com/zhaochy1990/auth-service/internal/auth"
 	"github.com/zhaochy1990/auth-service/internal/config"
 	"github.com/zhaochy1990/auth-service/internal/repository"
 	"github.com/zhaochy1990/auth-service/internal/repository/aztables"
 	mysqlrepo "github.com/zhaochy1990/auth-service/internal/repository/mysql"
+	"github.com/zhaochy1990/auth-service/internal/securitylog"
 	"github.com/zhaochy1990/auth-service/internal/seed"
 	"github.com/zhaochy1990/auth-service/internal/server"
 	"github.com/zhaochy1990/auth-service/internal/storage"
@@ -40,6 +45,10 @@ func main() {
 	if err != nil {
 		log.Fatalw("failed to load configuration", "error", err)
 	}
+	audit.Configure(log, cfg.AuditWebhookURL)
+	if err := securitylog.Configure(cfg.SecurityLogTarget); err != nil {
+		log.Fatalw("failed to open security log", "target", cfg.SecurityLogTarget, "error", err)
+	}
 
 	log.Infow("opening storage backend", "backend", cfg.StorageBackend)
 	repo, err := storage.Open(ctx, cfg)
@@ -49,7 +58,11 @@ func main() {
 	log.Infow("storage ready", "backend", cfg.StorageBackend)
 
 	if len(args) > 1 && args[1] == "seed" {
-		runSeed(ctx, repo, args)
+		runSeed(ctx, repo, cfg.Roles, args)
+		return
+	}
+	if len(args) > 1 && args[1] == "create-app" {
+		runCreateApp(ctx, repo, args)
 		return
 	}
 	if len(args) > 1 && args[1] == "migrate" {
@@ -60,6 +73,29 @@ func main() {
 	if err != nil {
 		log.Fatalw("failed to initialize JWT manager", "error", err)
 	}
+	// SIGHUP re-reads the signing keypair, for rotation without a restart.
+	hup := make(chan os.Signal, 1)
+	signal.Notify(hup, syscall.SIGHUP)
+	go func() {
+		for range hup {
+			info, err := jwt.ReloadKeys()
+			if err != nil {
+				log.Errorw("failed to reload JWT keys; keeping the current ones", "error", err)
+				continue
+			}
+			log.Infow("JWT keys loaded", "kid", info.Kid)
+		}
+	}()
+
+	// The server itself only speaks plain HTTP; TLS belongs to a proxy.
+	if cfg.RequireHTTPS {
+		log.Infow("REQUIRE_HTTPS set: rejecting requests not forwarded over TLS")
+	} else if !isLoopback(cfg.ServerHost) {
+		log.Warnw("serving plain HTTP on a non-loopback address without REQUIRE_HTTPS; credentials and tokens may cross the network unencrypted", "addr", cfg.Addr())
+	}
+	if len(cfg.TrustedProxies) == 0 {
+		log.Warnw("TRUSTED_PROXIES is empty: X-Forwarded-For is ignored, so behind a reverse proxy every caller shares the proxy's address for rate limits and lockouts")
+	}
 
 	r := server.NewRouter(repo, jwt, cfg)
 	log.Infow("starting server", "addr", cfg.Addr())
@@ -68,41 +104,80 @@ func main() {
 	}
 }
 
-func runSeed(ctx context.Context, repo repository.Repository, args []string) {
+func runSeed(ctx context.Context, repo repository.Repository, roles config.Roles, args []string) {
+	opts := seed.Options{
+		CheckOnly:     hasArg(args[2:], "--check"),
+		AppName:       flagValue(args[2:], "--app-name"),
+		RedirectURIs:  splitList(flagValue(args[2:], "--redirect-uris")),
+		AllowedScopes: splitList(flagValue(args[2:], "--scopes")),
+		Role:          flagValue(args[2:], "--role"),
+	}
+	appName := opts.AppName
+	if appName == "" {
+		appName = seed.DefaultAppName
+	}
+	role := opts.Role
+	if role == "" {
+		role = "admin"
+	}
+	if !roles.Valid(role) {
+		fmt.Printf("unknown role %q; allowed roles: %s\n", role, strings.Join(roles.Allowed, ", "))
+		os.Exit(2)
+	}
+	positional := withoutFlags(args[2:])
 	email := "admin@example.com"
-	if len(args) > 2 {
-		email = args[2]
+	if len(positional) > 0 {
+		email = positional[0]
 	}
 	var password *string
-	if len(args) > 3 {
-		password = &args[3]
+	if len(positional) > 1 {
+		password = &positional[1]
 	}
 
 	fmt.Println("=== Auth Service Bootstrap ===")
 	fmt.Println()
 
-	result, err := seed.Bootstrap(ctx, repo, email, password)
+	result, err := seed.Bootstrap(ctx, repo, email, password, opts)
 	if err != nil {
 		fmt.Println("bootstrap failed:", err)
 		os.Exit(1)
 	}
 
+	if result.CheckOnly {
+		fmt.Println("Check only; nothing was written.")
+		fmt.Println()
+		if result.AppAction == "created" {
+			fmt.Printf("  Would create the %s application.\n", appName)
+		} else {
+			fmt.Printf("  %s application exists (Client ID: %s).\n", appName, result.AppClientID)
+		}
+		switch result.UserAction {
+		case "created":
+			fmt.Printf("  Would create %s user: %s\n", role, email)
+		case "promoted":
+			fmt.Printf("  Would promote %s to %s role.\n", email, role)
+		case "already_admin":
+			fmt.Printf("  User %s already has the %s role.\n", email, role)
+		}
+		return
+	}
+
 	fmt.Printf("  Client ID: %s\n", result.AppClientID)
 	if result.AppClientSecret != nil {
 		fmt.Printf("  Client Secret: %s\n", *result.AppClientSecret)
 		fmt.Println("  (Save this secret — it won't be shown again!)")
 	} else {
-		fmt.Println("  Admin Dashboard application already exists.")
+		fmt.Printf("  %s application already exists.\n", appName)
 	}
 	fmt.Println()
 
 	switch result.UserAction {
 	case "created":
-		fmt.Printf("Created admin user: %s\n", email)
+		fmt.Printf("Created %s user: %s\n", role, email)
 	case "promoted":
-		fmt.Printf("Promoted %s to admin role.\n", email)
+		fmt.Printf("Promoted %s to %s role.\n", email, role)
 	case "already_admin":
-		fmt.Printf("User %s is already an admin.\n", email)
+		fmt.Printf("User %s already has the %s role.\n", email, role)
 	}
 
 	fmt.Println()
@@ -112,6 +187,29 @@ func runSeed(ctx context.Context, repo repository.Repository, args []string) {
 	fmt.Printf("  VITE_API_CLIENT_ID=%s\n", result.AppClientID)
 }
 
+func runCreateApp(ctx context.Context, repo repository.Repository, args []string) {
+	if len(args) < 3 {
+		fmt.Println("usage: auth-service create-app <name> [redirect_uri,...] [scope,...]")
+		os.Exit(2)
+	}
+	var redirectURIs, scopes []string
+	if len(args) > 3 {
+		redirectURIs = splitList(args[3])
+	}
+	if len(args) > 4 {
+		scopes = splitList(args[4])
+	}
+	app, secret, err := seed.CreateApplication(ctx, repo, args[2], redirectURIs, scopes)
+	if err != nil {
+		fmt.Println("create-app failed:", err)
+		os.Exit(1)
+	}
+	fmt.Printf("Created application %q (id %s)\n", app.Name, app.ID)
+	fmt.Printf("  Client ID: %s\n", app.ClientID)
+	fmt.Printf("  Client Secret: %s\n", secret)
+	fmt.Println("  (Save this secret — it won't be shown again!)")
+}
+
 func runMigrate(ctx context.Context, repo repository.Repository) {
 	azRepo, ok := repo.(*aztables.Repository)
 	if !ok {
@@ -254,6 +352,36 @@ func hasArg(args []string, want string) bool {
 	return false
 }
 
+// flagValue returns the value of a `--name=value` argument, or "".
+func flagValue(args []string, name string) string {
+	for _, arg := range args {
+		if v, ok := strings.CutPrefix(arg, name+"="); ok {
+			return v
+		}
+	}
+	return ""
+}
+
+func splitList(s string) []string {
+	var out []string
+	for _, part := range strings.Split(s, ",") {
+		if part = strings.TrimSpace(part); part != "" {
+			out = append(out, part)
+		}
+	}
+	return out
+}
+
+func withoutFlags(args []string) []string {
+	out := make([]string, 0, len(args))
+	for _, arg := range args {
+		if !strings.HasPrefix(arg, "--") {
+			out = append(out, arg)
+		}
+	}
+	return out
+}
+
 func printCounts(label string, counts map[string]int) {
 	keys := make([]string, 0, len(counts))
 	for key := range counts {
@@ -268,3 +396,12 @@ func printCounts(label string, counts map[string]int) {
 		fmt.Printf("  %s %-18s %d\n", prefix, key+":", counts[key])
 	}
 }
+
+// isLoopback reports whether host only accepts local connections.
+func isLoopback(host string) bool {
+	if host == "localhost" {
+		return true
+	}
+	ip := net.ParseIP(host)
+	return ip != nil && ip.IsLoopback()
+}
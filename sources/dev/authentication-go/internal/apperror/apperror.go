@@ -1,7 +1,8 @@
 // Package apperror defines the application's typed error model and its mapping
 // to HTTP responses. Each variant carries an HTTP status, a stable
 // machine-readable `error` type string, and a human-readable message. The JSON
-// body shape is always {"error","message"}.
+// body shape is always {"error","message"}, plus "details" for the few errors
+// a client has to act on.
 package apperror
 
 import (
@@ -15,10 +16,35 @@ type Error struct {
 	Status  int
 	Type    string // stable machine-readable code, e.g. "invalid_credentials"
 	Message string
+	// Key names the message for translation when Type alone is too coarse
+	// (several "bad_request" messages, say). Empty means Type.
+	Key string
+	// Details is sent as "details" next to error and message when set.
+	Details map[string]any
 }
 
 func (e *Error) Error() string { return e.Message }
 
+// WithKey sets the translation key and returns e.
+func (e *Error) WithKey(key string) *Error {
+	e.Key = key
+	return e
+}
+
+// WithDetails sets the details and returns e.
+func (e *Error) WithDetails(details map[string]any) *Error {
+	e.Details = details
+	return e
+}
+
+// MessageKey is the key Message is translated under.
+func (e *Error) MessageKey() string {
+	if e.Key != "" {
+		return e.Key
+	}
+	return e.Type
+}
+
 // New builds an Error.
 func New(status int, errType, message string) *Error {
 	return &Error{Status: status, Type: errType, Message: message}
@@ -57,6 +83,15 @@ func ProviderNotSupported(id string) *Error {
 func ProviderNotConfigured() *Error {
 	return New(http.StatusBadRequest, "provider_not_configured", "Provider not configured for this application")
 }
+func ProviderAuthFailed(msg string) *Error {
+	return New(http.StatusUnauthorized, "provider_auth_failed", msg)
+}
+func ProviderUnavailable() *Error {
+	return New(http.StatusBadGateway, "provider_unavailable", "External provider unavailable")
+}
+func ProviderRateLimited() *Error {
+	return New(http.StatusTooManyRequests, "provider_rate_limited", "External provider rate limit reached, retry later")
+}
 func InvalidAuthorizationCode() *Error {
 	return New(http.StatusBadRequest, "invalid_authorization_code", "Invalid authorization code")
 }
@@ -93,9 +128,27 @@ func Forbidden() *Error {
 func UserDisabled() *Error {
 	return New(http.StatusForbidden, "user_disabled", "User account is disabled")
 }
+func AccountLocked() *Error {
+	return New(http.StatusTooManyRequests, "account_locked", "Too many failed login attempts; try again later")
+}
+func PasswordChangeRequired() *Error {
+	return New(http.StatusForbidden, "password_change_required", "This account's password must be reset before it can sign in")
+}
+func LoginThrottled() *Error {
+	return New(http.StatusTooManyRequests, "login_throttled", "Too many failed login attempts from this address; try again later")
+}
+func ReauthenticationRequired() *Error {
+	return New(http.StatusForbidden, "reauthentication_required", "Sign in again to confirm this action")
+}
+func EmailRequired() *Error {
+	return New(http.StatusUnprocessableEntity, "email_required", "An email address is required to finish signing up")
+}
 func AccountAlreadyLinked() *Error {
 	return New(http.StatusConflict, "account_already_linked", "Account already linked")
 }
+func ProviderAccountInUse() *Error {
+	return New(http.StatusConflict, "provider_account_in_use", "This provider account is already linked to another user")
+}
 func CannotUnlinkLastAccount() *Error {
 	return New(http.StatusBadRequest, "cannot_unlink_last_account", "Cannot unlink last account")
 }
@@ -123,9 +176,30 @@ func TeamTransferTargetNotMember() *Error {
 func UserOwnsTeams(n int) *Error {
 	return New(http.StatusConflict, "user_owns_teams", "User still owns "+strconv.Itoa(n)+" team(s)")
 }
+func IdempotencyKeyInUse() *Error {
+	return New(http.StatusConflict, "idempotency_key_in_use", "A request with this Idempotency-Key is still in progress")
+}
+func IdempotencyKeyReused() *Error {
+	return New(http.StatusUnprocessableEntity, "idempotency_key_reused", "Idempotency-Key was already used with a different request")
+}
+func RateLimited() *Error {
+	return New(http.StatusTooManyRequests, "rate_limited", "Too many requests. Please try again later.")
+}
+func AdminIPBlocked() *Error {
+	return New(http.StatusForbidden, "ip_blocked", "Too many failed admin authentication attempts; try again later")
+}
+func HTTPSRequired() *Error {
+	return New(http.StatusForbidden, "https_required", "HTTPS is required")
+}
+func EmailUnavailable() *Error {
+	return New(http.StatusServiceUnavailable, "email_unavailable", "Email could not be sent")
+}
 func BadRequest(msg string) *Error {
 	return New(http.StatusBadRequest, "bad_request", msg)
 }
+func RequestTimeout() *Error {
+	return New(http.StatusGatewayTimeout, "request_timeout", "Request took too long to process")
+}
 
 // Internal returns a generic 500 with a non-leaky message.
 func Internal() *Error {
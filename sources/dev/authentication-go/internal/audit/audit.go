@@ -0,0 +1,155 @@
+// Package audit records security-relevant events. Every event goes to the
+// service logger and, once a store is set, into the audit table behind
+// GET /admin/users/:id/audit; when a webhook URL is configured the event is
+// also POSTed there as JSON so operators get an actionable notification.
+package audit
+
+import (
+	"bytes"
+	"context"
+	"encoding/json"
+	"net/http"
+	"sync"
+	"time"
+
+	"github.com/google/uuid"
+
+	"github.com/zhaochy1990/auth-service/internal/domain"
+)
+
+// Event types.
+const (
+	RefreshTokenReuse    = "refresh_token_reuse"
+	RefreshTokensRevoked = "refresh_tokens_revoked"
+	AccountLocked        = "account_locked"
+	AccountUnlocked      = "account_unlocked"
+	EmailChanged         = "email_changed"
+	AdminAuthAbuse       = "admin_auth_abuse"
+	ConsentRevoked       = "consent_revoked"
+	UserCreated          = "user_created"
+	UserUpdated          = "user_updated"
+	UserDeleted          = "user_deleted"
+	PasswordReset        = "password_reset"
+	AccountUnlinked      = "account_unlinked"
+)
+
+// Types lists every event type Record is called with.
+var Types = []string{
+	RefreshTokenReuse, RefreshTokensRevoked, AccountLocked, AccountUnlocked,
+	EmailChanged, AdminAuthAbuse, ConsentRevoked, UserCreated, UserUpdated,
+	UserDeleted, PasswordReset, AccountUnlinked,
+}
+
+// Event is a single audit record. UserID is the user who acted, or the one an
+// automatic event (a lockout, a reuse) concerns; TargetID is the user an admin
+// acted on.
+type Event struct {
+	Type     string         `json:"type"`
+	UserID   string         `json:"user_id,omitempty"`
+	TargetID string         `json:"target_id,omitempty"`
+	AppID    string         `json:"app_id,omitempty"`
+	IP       string         `json:"ip,omitempty"`
+	Details  map[string]any `json:"details,omitempty"`
+	At       time.Time      `json:"at"`
+}
+
+// Store persists events; repository.AuditEventRepository satisfies it.
+type Store interface {
+	Insert(ctx context.Context, e *domain.AuditEvent) error
+}
+
+// Logger is the subset of the service's sugared logger used for audit output.
+type Logger interface {
+	Warnw(msg string, keysAndValues ...interface{})
+}
+
+var (
+	mu         sync.RWMutex
+	logger     Logger
+	webhookURL string
+	store      Store
+	client     = &http.Client{Timeout: 5 * time.Second}
+)
+
+// Configure installs the logger and optional webhook URL. Called once from
+// main; until then events are dropped.
+func Configure(l Logger, webhook string) {
+	mu.Lock()
+	defer mu.Unlock()
+	logger = l
+	webhookURL = webhook
+}
+
+// SetStore installs the store events are persisted to. NewRouter sets it to
+// the repository it serves.
+func SetStore(s Store) {
+	mu.Lock()
+	defer mu.Unlock()
+	store = s
+}
+
+// Warn logs a problem the request cannot report, such as a best-effort step
+// that failed after the response was decided or a misconfigured proxy.
+func Warn(msg string, keysAndValues ...interface{}) {
+	mu.RLock()
+	l := logger
+	mu.RUnlock()
+	if l != nil {
+		l.Warnw(msg, keysAndValues...)
+	}
+}
+
+// Record logs the event, persists it when a store is set and, if configured,
+// delivers it to the webhook in the background. Store and delivery failures
+// are logged and never block the request.
+func Record(e Event) {
+	if e.At.IsZero() {
+		e.At = time.Now().UTC()
+	}
+	mu.RLock()
+	l, url, s := logger, webhookURL, store
+	mu.RUnlock()
+
+	if l != nil {
+		l.Warnw("audit event", "type", e.Type, "user_id", e.UserID, "target_id", e.TargetID, "app_id", e.AppID, "ip", e.IP, "details", e.Details)
+	}
+	if s != nil {
+		if err := persist(s, e); err != nil && l != nil {
+			l.Warnw("audit event not stored", "type", e.Type, "error", err)
+		}
+	}
+	if url == "" {
+		return
+	}
+	go func() {
+		body, err := json.Marshal(e)
+		if err != nil {
+			return
+		}
+		resp, err := client.Post(url, "application/json", bytes.NewReader(body))
+		if err != nil {
+			if l != nil {
+				l.Warnw("audit webhook delivery failed", "type", e.Type, "error", err)
+			}
+			return
+		}
+		_ = resp.Body.Close()
+	}()
+}
+
+func persist(s Store, e Event) error {
+	details := "{}"
+	if len(e.Details) > 0 {
+		b, err := json.Marshal(e.Details)
+		if err != nil {
+			return err
+		}
+		details = string(b)
+	}
+	ctx, cancel := context.WithTimeout(context.Background(), 5*time.Second)
+	defer cancel()
+	return s.Insert(ctx, &domain.AuditEvent{
+		ID: uuid.NewString(), Type: e.Type, UserID: e.UserID, TargetID: e.TargetID,
+		AppID: e.AppID, IP: e.IP, Details: details, CreatedAt: e.At,
+	})
+}
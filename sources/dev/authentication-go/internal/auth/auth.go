@@ -5,15 +5,24 @@ package auth
 
 import (
 	"context"
+	"crypto/aes"
+	"crypto/cipher"
 	"crypto/rand"
 	"crypto/rsa"
 	"crypto/sha256"
-	"crypto/subtle"
+	"crypto/x509"
 	"encoding/base64"
 	"encoding/hex"
 	"encoding/json"
+	"encoding/pem"
+	"errors"
+	"math/big"
 	"os"
+	"slices"
+	"strconv"
 	"strings"
+	"sync"
+	"sync/atomic"
 	"time"
 	"unicode"
 
@@ -22,6 +31,7 @@ import (
 	"github.com/google/uuid"
 
 	"github.com/zhaochy1990/auth-service/internal/apperror"
+	"github.com/zhaochy1990/auth-service/internal/auth/providers"
 	"github.com/zhaochy1990/auth-service/internal/config"
 	"github.com/zhaochy1990/auth-service/internal/domain"
 	"github.com/zhaochy1990/auth-service/internal/repository"
@@ -30,18 +40,26 @@ import (
 // ─── JWT ─────────────────────────────────────────────────────────────────────
 
 // AccessClaims is the access-token payload. Field names and shape preserve the
-// public JWT contract: aud is a single string and membership is snake_case.
+// public JWT contract: aud is a single string unless the app configured extra
+// audiences, and membership is snake_case.
 type AccessClaims struct {
 	Sub        string   `json:"sub"`
-	Aud        string   `json:"aud"`
+	Aud        Audience `json:"aud"`
 	Iss        string   `json:"iss"`
 	Exp        int64    `json:"exp"`
 	Iat        int64    `json:"iat"`
+	AuthTime   int64    `json:"auth_time,omitempty"`
 	Scopes     []string `json:"scopes"`
 	Role       string   `json:"role"`
 	Membership string   `json:"membership"`
 	UserType   string   `json:"user_type"`
 	Name       *string  `json:"name,omitempty"`
+	Email      *string  `json:"email,omitempty"`
+	// Ext carries the issuing application's custom claims and Attributes the
+	// user's token-visible custom attributes. Nesting them keeps either from
+	// shadowing sub/aud/exp or any other top-level claim.
+	Ext        map[string]any `json:"ext,omitempty"`
+	Attributes map[string]any `json:"attributes,omitempty"`
 }
 
 func (c AccessClaims) GetExpirationTime() (*jwt.NumericDate, error) {
@@ -53,7 +71,41 @@ func (c AccessClaims) GetIssuedAt() (*jwt.NumericDate, error) {
 func (c AccessClaims) GetNotBefore() (*jwt.NumericDate, error) { return nil, nil }
 func (c AccessClaims) GetIssuer() (string, error)              { return c.Iss, nil }
 func (c AccessClaims) GetSubject() (string, error)             { return c.Sub, nil }
-func (c AccessClaims) GetAudience() (jwt.ClaimStrings, error)  { return jwt.ClaimStrings{c.Aud}, nil }
+func (c AccessClaims) GetAudience() (jwt.ClaimStrings, error)  { return jwt.ClaimStrings(c.Aud), nil }
+
+// Audience is the aud claim: the client_id first, then any extra audiences of
+// the app. It is written as a plain string when it holds only the client_id,
+// as tokens always were, and as an array otherwise; either form is read.
+type Audience []string
+
+// ClientID is the client the token was issued to.
+func (a Audience) ClientID() string {
+	if len(a) == 0 {
+		return ""
+	}
+	return a[0]
+}
+
+func (a Audience) MarshalJSON() ([]byte, error) {
+	if len(a) == 1 {
+		return json.Marshal(a[0])
+	}
+	return json.Marshal([]string(a))
+}
+
+func (a *Audience) UnmarshalJSON(b []byte) error {
+	var single string
+	if json.Unmarshal(b, &single) == nil {
+		*a = Audience{single}
+		return nil
+	}
+	var list []string
+	if err := json.Unmarshal(b, &list); err != nil {
+		return err
+	}
+	*a = list
+	return nil
+}
 
 // Tier returns the effective membership tier from the claim, treating an
 // absent/unknown value as Regular.
@@ -69,11 +121,12 @@ func (c AccessClaims) Type() domain.UserType {
 
 // AppClaims is the client-credentials token payload.
 type AppClaims struct {
-	Sub       string `json:"sub"`
-	Iss       string `json:"iss"`
-	Exp       int64  `json:"exp"`
-	Iat       int64  `json:"iat"`
-	GrantType string `json:"grant_type"`
+	Sub       string         `json:"sub"`
+	Iss       string         `json:"iss"`
+	Exp       int64          `json:"exp"`
+	Iat       int64          `json:"iat"`
+	GrantType string         `json:"grant_type"`
+	Ext       map[string]any `json:"ext,omitempty"`
 }
 
 func (c AppClaims) GetExpirationTime() (*jwt.NumericDate, error) {
@@ -87,21 +140,112 @@ func (c AppClaims) GetIssuer() (string, error)              { return c.Iss, nil
 func (c AppClaims) GetSubject() (string, error)             { return c.Sub, nil }
 func (c AppClaims) GetAudience() (jwt.ClaimStrings, error)  { return nil, nil }
 
-// JWTManager issues and verifies RS256 tokens.
+// registrationTokenType is the typ of a registration token, and
+// RegistrationTokenTTL how long one stays usable.
+const (
+	registrationTokenType = "registration"
+	RegistrationTokenTTL  = 10 * time.Minute
+)
+
+// RegistrationClaims is the payload of a registration token: a provider
+// sign-in that could not create its user yet, held until the client supplies
+// the missing fields. It has no sub, so it never passes as an access token.
+// Tokens are the provider tokens, present only when sealed.
+type RegistrationClaims struct {
+	Iss               string  `json:"iss"`
+	Aud               string  `json:"aud"`
+	Exp               int64   `json:"exp"`
+	Iat               int64   `json:"iat"`
+	Typ               string  `json:"typ"`
+	ProviderID        string  `json:"provider_id"`
+	ProviderAccountID string  `json:"provider_account_id"`
+	Name              *string `json:"name,omitempty"`
+	AvatarURL         *string `json:"avatar_url,omitempty"`
+	Metadata          string  `json:"metadata,omitempty"`
+	Tokens            *string `json:"tokens,omitempty"`
+}
+
+func (c RegistrationClaims) GetExpirationTime() (*jwt.NumericDate, error) {
+	return jwt.NewNumericDate(time.Unix(c.Exp, 0)), nil
+}
+func (c RegistrationClaims) GetIssuedAt() (*jwt.NumericDate, error) {
+	return jwt.NewNumericDate(time.Unix(c.Iat, 0)), nil
+}
+func (c RegistrationClaims) GetNotBefore() (*jwt.NumericDate, error) { return nil, nil }
+func (c RegistrationClaims) GetIssuer() (string, error)              { return c.Iss, nil }
+func (c RegistrationClaims) GetSubject() (string, error)             { return "", nil }
+func (c RegistrationClaims) GetAudience() (jwt.ClaimStrings, error)  { return jwt.ClaimStrings{c.Aud}, nil }
+
+// JWTManager issues and verifies RS256 tokens, or opaque access tokens once
+// UseOpaqueTokens has been called. The keypair can be replaced while running
+// with ReloadKeys.
 type JWTManager struct {
-	priv             *rsa.PrivateKey
-	pub              *rsa.PublicKey
+	keys             atomic.Pointer[keySet]
+	reloadMu         sync.Mutex
+	privPath         string
+	pubPath          string
 	issuer           string
 	accessExpirySecs int64
+	minIssuedAt      int64
+	opaque           repository.AccessTokenRepository
+	apps             repository.ApplicationRepository
+}
+
+// keySet is the signing keypair plus the public keys earlier reloads retired.
+// It is never modified once stored; ReloadKeys swaps in a new one.
+type keySet struct {
+	priv    *rsa.PrivateKey
+	pub     *rsa.PublicKey
+	info    KeyInfo
+	retired []retiredKey
+}
+
+// retiredKey verifies tokens signed before a reload until they have all
+// expired.
+type retiredKey struct {
+	kid   string
+	pub   *rsa.PublicKey
+	until time.Time
+}
+
+// opaqueTokenPrefix marks opaque access tokens so they are never mistaken for
+// a JWT.
+const opaqueTokenPrefix = "at_"
+
+// KeyInfo describes the verification key for operators checking tokens by
+// hand. Computed whenever the keypair is loaded.
+type KeyInfo struct {
+	Issuer    string `json:"issuer"`
+	Algorithm string `json:"algorithm"`
+	Kid       string `json:"kid"`
+	PEM       string `json:"pem"`
 }
 
 // NewJWTManager loads the RSA keypair from disk.
 func NewJWTManager(cfg *config.Config) (*JWTManager, error) {
-	privBytes, err := os.ReadFile(cfg.JWTPrivateKeyPath)
+	m := &JWTManager{
+		privPath:         cfg.JWTPrivateKeyPath,
+		pubPath:          cfg.JWTPublicKeyPath,
+		issuer:           cfg.JWTIssuer,
+		accessExpirySecs: cfg.JWTAccessTokenExpirySecs,
+		minIssuedAt:      cfg.JWTMinIssuedAt,
+	}
+	keys, err := m.loadKeys()
 	if err != nil {
 		return nil, err
 	}
-	pubBytes, err := os.ReadFile(cfg.JWTPublicKeyPath)
+	m.keys.Store(keys)
+	return m, nil
+}
+
+// loadKeys reads and parses the keypair, refusing a private key that doesn't
+// match the public one (say, a rotation caught halfway through copying).
+func (m *JWTManager) loadKeys() (*keySet, error) {
+	privBytes, err := os.ReadFile(m.privPath)
+	if err != nil {
+		return nil, err
+	}
+	pubBytes, err := os.ReadFile(m.pubPath)
 	if err != nil {
 		return nil, err
 	}
@@ -113,84 +257,309 @@ func NewJWTManager(cfg *config.Config) (*JWTManager, error) {
 	if err != nil {
 		return nil, err
 	}
-	return &JWTManager{priv: priv, pub: pub, issuer: cfg.JWTIssuer, accessExpirySecs: cfg.JWTAccessTokenExpirySecs}, nil
+	if !priv.PublicKey.Equal(pub) {
+		return nil, errors.New("JWT private key does not match the public key")
+	}
+	info, err := newKeyInfo(pub, m.issuer)
+	if err != nil {
+		return nil, err
+	}
+	return &keySet{priv: priv, pub: pub, info: info}, nil
+}
+
+// ReloadKeys re-reads the keypair and, if it changed, signs new tokens with it
+// from now on. The replaced public key keeps verifying for one access-token
+// lifetime, so tokens issued before the swap stay valid until they expire. On
+// error the current keys stay in use.
+func (m *JWTManager) ReloadKeys() (KeyInfo, error) {
+	m.reloadMu.Lock()
+	defer m.reloadMu.Unlock()
+	next, err := m.loadKeys()
+	if err != nil {
+		return KeyInfo{}, err
+	}
+	cur := m.keys.Load()
+	if next.info.Kid == cur.info.Kid {
+		return cur.info, nil
+	}
+	now := time.Now()
+	next.retired = []retiredKey{{kid: cur.info.Kid, pub: cur.pub, until: now.Add(time.Duration(m.accessExpirySecs) * time.Second)}}
+	for _, k := range cur.retired {
+		if k.until.After(now) && k.kid != next.info.Kid {
+			next.retired = append(next.retired, k)
+		}
+	}
+	m.keys.Store(next)
+	return next.info, nil
+}
+
+// newKeyInfo re-encodes the public key as canonical PKIX PEM and derives its
+// kid as the RFC 7638 JWK thumbprint.
+func newKeyInfo(pub *rsa.PublicKey, issuer string) (KeyInfo, error) {
+	der, err := x509.MarshalPKIXPublicKey(pub)
+	if err != nil {
+		return KeyInfo{}, err
+	}
+	enc := base64.RawURLEncoding
+	thumbprintInput := `{"e":"` + enc.EncodeToString(big.NewInt(int64(pub.E)).Bytes()) +
+		`","kty":"RSA","n":"` + enc.EncodeToString(pub.N.Bytes()) + `"}`
+	sum := sha256.Sum256([]byte(thumbprintInput))
+	return KeyInfo{
+		Issuer:    issuer,
+		Algorithm: "RS256",
+		Kid:       enc.EncodeToString(sum[:]),
+		PEM:       string(pem.EncodeToMemory(&pem.Block{Type: "PUBLIC KEY", Bytes: der})),
+	}, nil
+}
+
+// UseOpaqueTokens makes the manager issue opaque access tokens whose claims are
+// kept in store (ACCESS_TOKEN_FORMAT=opaque). Deleting a row revokes its token
+// immediately. JWTs issued before the switch keep verifying until they expire.
+func (m *JWTManager) UseOpaqueTokens(store repository.AccessTokenRepository) { m.opaque = store }
+
+// UseAppIssuers lets user access tokens carry their app's issuer override
+// (TokenExtras.Issuer): a JWT whose iss is not JWT_ISSUER then verifies if it
+// is the issuer of the app named by its client_id.
+func (m *JWTManager) UseAppIssuers(apps repository.ApplicationRepository) { m.apps = apps }
+
+// TokenExtras are the optional nested claims of a user access token.
+type TokenExtras struct {
+	// App is the issuing application's custom claims ("ext").
+	App map[string]any
+	// Attributes are the user's custom attributes exposed in tokens.
+	Attributes map[string]any
+	// AuthTime is when the user last presented credentials; zero (tokens
+	// minted from a refresh token) omits the auth_time claim.
+	AuthTime time.Time
+	// Email becomes the email claim when the scopes include "email".
+	Email *string
+	// Audiences are appended to aud after the client_id.
+	Audiences []string
+	// Issuer replaces the default iss (the app's issuer override).
+	Issuer string
 }
 
 // IssueAccessToken mints a user access token.
-func (m *JWTManager) IssueAccessToken(userID, clientID string, scopes []string, role string, membership domain.MembershipTier, userType domain.UserType, name *string) (string, error) {
+func (m *JWTManager) IssueAccessToken(ctx context.Context, userID, clientID string, scopes []string, role string, membership domain.MembershipTier, userType domain.UserType, name *string, extras TokenExtras) (string, error) {
+	claims := m.AccessClaims(userID, clientID, scopes, role, membership, userType, name, extras)
+	return m.issue(ctx, userID, claims.Exp, claims)
+}
+
+// AccessClaims builds the payload IssueAccessToken would sign now, without
+// issuing anything.
+func (m *JWTManager) AccessClaims(userID, clientID string, scopes []string, role string, membership domain.MembershipTier, userType domain.UserType, name *string, extras TokenExtras) AccessClaims {
 	if scopes == nil {
 		scopes = []string{}
 	}
 	now := time.Now().Unix()
 	claims := AccessClaims{
-		Sub: userID, Aud: clientID, Iss: m.issuer,
+		Sub: userID, Aud: append(Audience{clientID}, extras.Audiences...), Iss: m.issuer,
 		Exp: now + m.accessExpirySecs, Iat: now,
 		Scopes: scopes, Role: role, Membership: string(membership), UserType: string(domain.UserTypeFromString(string(userType))), Name: name,
+		Ext: extras.App, Attributes: extras.Attributes,
 	}
-	tok := jwt.NewWithClaims(jwt.SigningMethodRS256, claims)
-	s, err := tok.SignedString(m.priv)
-	if err != nil {
-		return "", apperror.Internal()
+	if extras.Issuer != "" {
+		claims.Iss = extras.Issuer
+	}
+	if !extras.AuthTime.IsZero() {
+		claims.AuthTime = extras.AuthTime.Unix()
 	}
-	return s, nil
+	if slices.Contains(scopes, "email") {
+		claims.Email = extras.Email
+	}
+	return claims
 }
 
 // IssueAppToken mints a client-credentials token.
-func (m *JWTManager) IssueAppToken(appID string) (string, error) {
+func (m *JWTManager) IssueAppToken(ctx context.Context, appID string, ext map[string]any) (string, error) {
 	now := time.Now().Unix()
-	claims := AppClaims{Sub: appID, Iss: m.issuer, Exp: now + m.accessExpirySecs, Iat: now, GrantType: "client_credentials"}
-	tok := jwt.NewWithClaims(jwt.SigningMethodRS256, claims)
-	s, err := tok.SignedString(m.priv)
+	claims := AppClaims{Sub: appID, Iss: m.issuer, Exp: now + m.accessExpirySecs, Iat: now, GrantType: "client_credentials", Ext: ext}
+	return m.issue(ctx, appID, claims.Exp, claims)
+}
+
+// IssueRegistrationToken mints a registration token for clientID from the
+// filled-in claims, setting the issuer, type and lifetime.
+func (m *JWTManager) IssueRegistrationToken(ctx context.Context, clientID string, claims RegistrationClaims) (string, error) {
+	now := time.Now()
+	claims.Iss, claims.Aud, claims.Typ = m.issuer, clientID, registrationTokenType
+	claims.Iat, claims.Exp = now.Unix(), now.Add(RegistrationTokenTTL).Unix()
+	return m.issue(ctx, claims.ProviderAccountID, claims.Exp, claims)
+}
+
+// issue signs claims as a JWT, or stores them behind a random opaque token.
+func (m *JWTManager) issue(ctx context.Context, subject string, exp int64, claims jwt.Claims) (string, error) {
+	if m.opaque == nil {
+		keys := m.keys.Load()
+		token := jwt.NewWithClaims(jwt.SigningMethodRS256, claims)
+		token.Header["kid"] = keys.info.Kid
+		s, err := token.SignedString(keys.priv)
+		if err != nil {
+			return "", apperror.Internal()
+		}
+		return s, nil
+	}
+	payload, err := json.Marshal(claims)
 	if err != nil {
 		return "", apperror.Internal()
 	}
-	return s, nil
+	token := opaqueTokenPrefix + RandomHex(32)
+	err = m.opaque.Insert(ctx, &domain.AccessToken{
+		TokenHash: HashToken(token),
+		Subject:   subject,
+		Claims:    string(payload),
+		ExpiresAt: time.Unix(exp, 0).UTC(),
+		CreatedAt: time.Now().UTC(),
+	})
+	if err != nil {
+		return "", err
+	}
+	return token, nil
+}
+
+// parse fills claims from a stored opaque token or a signed JWT, enforcing
+// expiry either way (and the issuer for JWTs, see trustedIssuer).
+func (m *JWTManager) parse(ctx context.Context, token string, claims jwt.Claims) error {
+	if m.opaque != nil && strings.HasPrefix(token, opaqueTokenPrefix) {
+		var stored *domain.AccessToken
+		for _, hash := range TokenHashCandidates(token) {
+			var err error
+			if stored, err = m.opaque.FindByTokenHash(ctx, hash); err != nil {
+				return err
+			}
+			if stored != nil {
+				break
+			}
+		}
+		if stored == nil || !time.Now().Before(stored.ExpiresAt) {
+			return apperror.InvalidToken()
+		}
+		if json.Unmarshal([]byte(stored.Claims), claims) != nil {
+			return apperror.InvalidToken()
+		}
+		return nil
+	}
+	_, err := jwt.ParseWithClaims(token, claims, m.keyfunc,
+		jwt.WithValidMethods([]string{"RS256"}),
+		jwt.WithExpirationRequired(),
+	)
+	if err != nil || !m.trustedIssuer(ctx, claims) {
+		return apperror.InvalidToken()
+	}
+	return nil
+}
+
+// trustedIssuer reports whether a JWT's iss is JWT_ISSUER or, for a user
+// access token, the issuer override of the app it was issued to.
+func (m *JWTManager) trustedIssuer(ctx context.Context, claims jwt.Claims) bool {
+	iss, _ := claims.GetIssuer()
+	if iss == m.issuer {
+		return true
+	}
+	access, ok := claims.(*AccessClaims)
+	if !ok || m.apps == nil || iss == "" {
+		return false
+	}
+	app, err := m.apps.FindByClientID(ctx, access.Aud.ClientID())
+	return err == nil && app != nil && app.Issuer != nil && *app.Issuer == iss
+}
+
+// RevokeAccessToken deletes an opaque access token. Self-contained JWTs can't
+// be revoked, so for them this is a no-op.
+func (m *JWTManager) RevokeAccessToken(ctx context.Context, token string) error {
+	if m.opaque == nil || !strings.HasPrefix(token, opaqueTokenPrefix) {
+		return nil
+	}
+	for _, hash := range TokenHashCandidates(token) {
+		if err := m.opaque.DeleteByTokenHash(ctx, hash); err != nil {
+			return err
+		}
+	}
+	return nil
 }
 
+// KeyInfo returns the current verification-key metadata.
+func (m *JWTManager) KeyInfo() KeyInfo { return m.keys.Load().info }
+
 // AccessTokenExpirySecs exposes the configured access-token TTL.
 func (m *JWTManager) AccessTokenExpirySecs() int64 { return m.accessExpirySecs }
 
 // VerifyAccessToken validates and parses a user access token. It enforces the
-// issuer and the required claims (sub, aud, exp, iat). The audience value itself
-// is not validated (no expected audience is configured).
-func (m *JWTManager) VerifyAccessToken(token string) (*AccessClaims, error) {
+// issuer, the required claims (sub, aud, exp, iat) and the JWT_MIN_IAT cutoff.
+// The audience value itself is not validated (no expected audience is
+// configured).
+func (m *JWTManager) VerifyAccessToken(ctx context.Context, token string) (*AccessClaims, error) {
 	claims := &AccessClaims{}
-	_, err := jwt.ParseWithClaims(token, claims, m.keyfunc,
-		jwt.WithValidMethods([]string{"RS256"}),
-		jwt.WithIssuer(m.issuer),
-		jwt.WithExpirationRequired(),
-	)
-	if err != nil {
+	if err := m.parse(ctx, token, claims); err != nil {
+		return nil, err
+	}
+	// Reject a valid token missing any required claim (sub/aud/iat); exp is
+	// already enforced by parse.
+	if claims.Sub == "" || claims.Aud.ClientID() == "" || claims.Iat == 0 {
 		return nil, apperror.InvalidToken()
 	}
-	// Reject a validly-signed token missing any required claim (sub/aud/iat);
-	// exp is already enforced by WithExpirationRequired.
-	if claims.Sub == "" || claims.Aud == "" || claims.Iat == 0 {
+	if claims.Iat < m.minIssuedAt {
 		return nil, apperror.InvalidToken()
 	}
 	return claims, nil
 }
 
-// VerifyAppToken validates and parses a client-credentials token.
-func (m *JWTManager) VerifyAppToken(token string) (*AppClaims, error) {
+// VerifyAppToken validates and parses a client-credentials token, applying the
+// same JWT_MIN_IAT cutoff as user tokens.
+func (m *JWTManager) VerifyAppToken(ctx context.Context, token string) (*AppClaims, error) {
 	claims := &AppClaims{}
-	_, err := jwt.ParseWithClaims(token, claims, m.keyfunc,
-		jwt.WithValidMethods([]string{"RS256"}),
-		jwt.WithIssuer(m.issuer),
-		jwt.WithExpirationRequired(),
-	)
-	if err != nil {
-		return nil, apperror.InvalidToken()
+	if err := m.parse(ctx, token, claims); err != nil {
+		return nil, err
 	}
 	if claims.Sub == "" || claims.Iat == 0 || claims.GrantType != "client_credentials" {
 		return nil, apperror.InvalidToken()
 	}
+	if claims.Iat < m.minIssuedAt {
+		return nil, apperror.InvalidToken()
+	}
+	return claims, nil
+}
+
+// VerifyRegistrationToken validates a registration token issued to clientID.
+func (m *JWTManager) VerifyRegistrationToken(ctx context.Context, token, clientID string) (*RegistrationClaims, error) {
+	claims := &RegistrationClaims{}
+	if err := m.parse(ctx, token, claims); err != nil {
+		return nil, err
+	}
+	if claims.Typ != registrationTokenType || claims.Aud != clientID || claims.ProviderID == "" || claims.ProviderAccountID == "" {
+		return nil, apperror.InvalidToken()
+	}
+	if claims.Iat < m.minIssuedAt {
+		return nil, apperror.InvalidToken()
+	}
 	return claims, nil
 }
 
-func (m *JWTManager) keyfunc(_ *jwt.Token) (interface{}, error) { return m.pub, nil }
+// keyfunc picks the verification key by the token's kid. A token without one
+// predates kid headers and may match the current key or any retired one.
+func (m *JWTManager) keyfunc(t *jwt.Token) (interface{}, error) {
+	keys := m.keys.Load()
+	kid, _ := t.Header["kid"].(string)
+	if kid == keys.info.Kid {
+		return keys.pub, nil
+	}
+	set := jwt.VerificationKeySet{Keys: []jwt.VerificationKey{keys.pub}}
+	now := time.Now()
+	for _, k := range keys.retired {
+		if !k.until.After(now) {
+			continue
+		}
+		if kid == k.kid {
+			return k.pub, nil
+		}
+		set.Keys = append(set.Keys, k.pub)
+	}
+	if kid != "" {
+		return nil, apperror.InvalidToken()
+	}
+	return set, nil
+}
 
-// ─── Password & client secrets ───────────────────────────────────────────────
+// ─── Passwords ───────────────────────────────────────────────────────────────
 
 // HashPassword hashes a password with Argon2id (PHC string output).
 func HashPassword(password string) (string, error) {
@@ -211,35 +580,163 @@ func VerifyPassword(password, hash string) (bool, error) {
 	return match, nil
 }
 
-// HashClientSecret hashes a high-entropy client secret with SHA-256. Argon2's
-// brute-force resistance is unnecessary here and its cost would bottleneck
-// every OAuth2 request.
-func HashClientSecret(secret string) string {
-	sum := sha256.Sum256([]byte(secret))
-	return "sha256:" + hex.EncodeToString(sum[:])
+// ─── Data encryption ─────────────────────────────────────────────────────────
+
+var (
+	keysMu       sync.RWMutex
+	dataKeys     config.DataKeys
+	tokenHashKey []byte
+)
+
+// Configure installs the keys auth takes from the loaded config, which
+// FromEnv has already validated. NewRouter calls it before serving; until
+// then nothing is sealed and token hashes are plain SHA-256.
+func Configure(cfg *config.Config) {
+	keysMu.Lock()
+	defer keysMu.Unlock()
+	dataKeys = cfg.DataKeys
+	tokenHashKey = nil
+	if cfg.TokenHashKey != "" {
+		tokenHashKey = []byte(cfg.TokenHashKey)
+	}
 }
 
-// VerifyClientSecret verifies a client secret. Supports SHA-256 (new) and
-// Argon2 (legacy) hashes.
-func VerifyClientSecret(secret, hash string) (bool, error) {
-	if hexHash, ok := strings.CutPrefix(hash, "sha256:"); ok {
-		sum := sha256.Sum256([]byte(secret))
-		computed := hex.EncodeToString(sum[:])
-		if len(computed) != len(hexHash) {
-			return false, nil
-		}
-		return subtle.ConstantTimeCompare([]byte(computed), []byte(hexHash)) == 1, nil
+func currentDataKeys() config.DataKeys {
+	keysMu.RLock()
+	defer keysMu.RUnlock()
+	return dataKeys
+}
+
+// DataEncryptionEnabled reports whether a DATA_ENCRYPTION_KEY is configured.
+func DataEncryptionEnabled() bool {
+	return currentDataKeys().Version != 0
+}
+
+// IsSealed reports whether s looks like a SealSecret output ("v<N>:...").
+func IsSealed(s string) bool {
+	_, _, ok := splitSealed(s)
+	return ok
+}
+
+func splitSealed(s string) (int, string, bool) {
+	v, rest, ok := strings.Cut(s, ":")
+	if !ok || len(v) < 2 || v[0] != 'v' {
+		return 0, "", false
+	}
+	version, err := strconv.Atoi(v[1:])
+	if err != nil || version < 1 {
+		return 0, "", false
 	}
-	return VerifyPassword(secret, hash)
+	return version, rest, true
+}
+
+// SealSecret encrypts plaintext with AES-256-GCM under the current key as
+// "v<version>:<base64(nonce|ct)>". The version names the key, so values can be
+// re-sealed under a new key while old ones stay readable.
+func SealSecret(plaintext []byte) (string, error) {
+	keys := currentDataKeys()
+	if keys.Version == 0 {
+		return "", apperror.Internal()
+	}
+	gcm, err := newGCM(keys.Keys[keys.Version])
+	if err != nil {
+		return "", err
+	}
+	nonce := make([]byte, gcm.NonceSize())
+	if _, err := rand.Read(nonce); err != nil {
+		return "", apperror.Internal()
+	}
+	sealed := gcm.Seal(nonce, nonce, plaintext, nil)
+	return "v" + strconv.Itoa(keys.Version) + ":" + base64.StdEncoding.EncodeToString(sealed), nil
+}
+
+// OpenSecret decrypts a value produced by SealSecret with the key its version
+// prefix names.
+func OpenSecret(sealed string) ([]byte, error) {
+	version, encoded, ok := splitSealed(sealed)
+	if !ok {
+		return nil, apperror.Internal()
+	}
+	key := currentDataKeys().Keys[version]
+	if key == nil {
+		return nil, apperror.Internal()
+	}
+	raw, err := base64.StdEncoding.DecodeString(encoded)
+	if err != nil {
+		return nil, apperror.Internal()
+	}
+	gcm, err := newGCM(key)
+	if err != nil {
+		return nil, err
+	}
+	if len(raw) < gcm.NonceSize() {
+		return nil, apperror.Internal()
+	}
+	plain, err := gcm.Open(nil, raw[:gcm.NonceSize()], raw[gcm.NonceSize():], nil)
+	if err != nil {
+		return nil, apperror.Internal()
+	}
+	return plain, nil
+}
+
+func newGCM(key []byte) (cipher.AEAD, error) {
+	block, err := aes.NewCipher(key)
+	if err != nil {
+		return nil, apperror.Internal()
+	}
+	gcm, err := cipher.NewGCM(block)
+	if err != nil {
+		return nil, apperror.Internal()
+	}
+	return gcm, nil
+}
+
+// SealProviderTokens seals provider tokens for Account.ProviderTokens. It
+// returns nil (store nothing) when there are no tokens or no encryption key,
+// so provider tokens are never written in plaintext.
+func SealProviderTokens(tokens *providers.Tokens) (*string, error) {
+	if tokens == nil || !DataEncryptionEnabled() {
+		return nil, nil
+	}
+	b, err := json.Marshal(tokens)
+	if err != nil {
+		return nil, apperror.Internal()
+	}
+	sealed, err := SealSecret(b)
+	if err != nil {
+		return nil, err
+	}
+	return &sealed, nil
+}
+
+// ProviderTokens returns the stored provider tokens of a user's linked
+// account, or nil when none are stored. Internal use only.
+func ProviderTokens(ctx context.Context, repo repository.Repository, userID, providerID string) (*providers.Tokens, error) {
+	account, err := repo.Accounts().FindByUserAndProvider(ctx, userID, providerID)
+	if err != nil {
+		return nil, err
+	}
+	if account == nil || account.ProviderTokens == nil {
+		return nil, nil
+	}
+	plain, err := OpenSecret(*account.ProviderTokens)
+	if err != nil {
+		return nil, err
+	}
+	var tokens providers.Tokens
+	if err := json.Unmarshal(plain, &tokens); err != nil {
+		return nil, apperror.Internal()
+	}
+	return &tokens, nil
 }
 
 // ValidatePassword enforces password complexity.
 func ValidatePassword(password string) error {
 	if len(password) < 8 {
-		return apperror.BadRequest("Password must be at least 8 characters")
+		return apperror.BadRequest("Password must be at least 8 characters").WithKey("password_too_short")
 	}
 	if len(password) > 128 {
-		return apperror.BadRequest("Password must not exceed 128 characters")
+		return apperror.BadRequest("Password must not exceed 128 characters").WithKey("password_too_long")
 	}
 	var hasUpper, hasLower, hasDigit, hasSpecial bool
 	for _, r := range password {
@@ -257,16 +754,36 @@ func ValidatePassword(password string) error {
 		}
 	}
 	if !hasUpper {
-		return apperror.BadRequest("Password must contain at least one uppercase letter")
+		return apperror.BadRequest("Password must contain at least one uppercase letter").WithKey("password_missing_uppercase")
 	}
 	if !hasLower {
-		return apperror.BadRequest("Password must contain at least one lowercase letter")
+		return apperror.BadRequest("Password must contain at least one lowercase letter").WithKey("password_missing_lowercase")
 	}
 	if !hasDigit {
-		return apperror.BadRequest("Password must contain at least one digit")
+		return apperror.BadRequest("Password must contain at least one digit").WithKey("password_missing_digit")
 	}
 	if !hasSpecial {
-		return apperror.BadRequest("Password must contain at least one special character")
+		return apperror.BadRequest("Password must contain at least one special character").WithKey("password_missing_special")
+	}
+	return nil
+}
+
+// leetReplacer undoes the usual digit/symbol-for-letter substitutions.
+var leetReplacer = strings.NewReplacer("0", "o", "1", "i", "3", "e", "4", "a", "5", "s", "7", "t", "@", "a", "$", "s")
+
+// CheckPasswordDenylist rejects a password found in denylist (lowercase
+// entries), ignoring case, leading/trailing digits and symbols, and leetspeak,
+// so "P@ssw0rd1!" matches "password". An empty denylist allows everything.
+func CheckPasswordDenylist(password string, denylist map[string]bool) error {
+	if len(denylist) == 0 {
+		return nil
+	}
+	lower := strings.ToLower(password)
+	trimmed := strings.TrimFunc(lower, func(r rune) bool { return !unicode.IsLetter(r) })
+	for _, v := range []string{lower, trimmed, leetReplacer.Replace(lower), leetReplacer.Replace(trimmed)} {
+		if v != "" && denylist[v] {
+			return apperror.BadRequest("Password is too common").WithKey("password_too_common")
+		}
 	}
 	return nil
 }
@@ -287,17 +804,55 @@ func GenerateAuthCode() string { return RandomHex(64) }
 // GenerateRefreshToken returns a cryptographically random refresh token.
 func GenerateRefreshToken() string { return RandomHex(32) }
 
-// GenerateClientID returns an OAuth2 client_id of the form "app_<24 chars>".
-func GenerateClientID() string {
-	return "app_" + strings.ReplaceAll(uuid.NewString(), "-", "")[:24]
+// tokenHashPrefix marks a token hash keyed with TOKEN_HASH_KEY; the number
+// is the scheme version. Unprefixed hashes are plain SHA-256 digests written
+// before a key was set.
+const tokenHashPrefix = "hmac1:"
+
+// currentTokenHashKey returns the HMAC key for stored token hashes, or nil
+// when TOKEN_HASH_KEY is unset.
+func currentTokenHashKey() []byte {
+	keysMu.RLock()
+	defer keysMu.RUnlock()
+	return tokenHashKey
 }
 
-// HashToken hashes a token with SHA-256 for storage.
+// HashToken hashes a token for storage: HMAC-SHA256 under TOKEN_HASH_KEY
+// ("hmac1:" prefix) when it is set, plain SHA-256 otherwise.
 func HashToken(token string) string {
+	if key := currentTokenHashKey(); key != nil {
+		return tokenHashPrefix + pepperedSecretHex(token, key)
+	}
+	return plainTokenHash(token)
+}
+
+func plainTokenHash(token string) string {
 	sum := sha256.Sum256([]byte(token))
 	return hex.EncodeToString(sum[:])
 }
 
+// TokenHashCandidates lists the hashes a stored token may be filed under,
+// current scheme first. With TOKEN_HASH_KEY set it includes the plain SHA-256
+// hash, so tokens stored before the key was introduced keep working until
+// they expire or rotate.
+func TokenHashCandidates(token string) []string {
+	current := HashToken(token)
+	if plain := plainTokenHash(token); plain != current {
+		return []string{current, plain}
+	}
+	return []string{current}
+}
+
+// VerifyTokenHash reports whether token matches a stored hash of either
+// scheme. A keyed hash never verifies while TOKEN_HASH_KEY is unset.
+func VerifyTokenHash(token, hash string) bool {
+	if hexHash, ok := strings.CutPrefix(hash, tokenHashPrefix); ok {
+		key := currentTokenHashKey()
+		return key != nil && constantTimeHexEqual(pepperedSecretHex(token, key), hexHash)
+	}
+	return constantTimeHexEqual(plainTokenHash(token), hash)
+}
+
 // VerifyPKCE verifies a PKCE code_verifier against a code_challenge.
 func VerifyPKCE(verifier, challenge, method string) bool {
 	switch method {
@@ -330,11 +885,21 @@ func DecodeStringArray(s string) []string {
 	return out
 }
 
-// StoreAuthCode persists an authorization code (10-minute TTL).
+// DecodeObject decodes a JSON object stored as a string, returning nil for an
+// empty or malformed value.
+func DecodeObject(s string) map[string]any {
+	var out map[string]any
+	if s == "" || json.Unmarshal([]byte(s), &out) != nil || len(out) == 0 {
+		return nil
+	}
+	return out
+}
+
+// StoreAuthCode persists a hashed authorization code (10-minute TTL).
 func StoreAuthCode(ctx context.Context, repo repository.Repository, code, appID, userID, redirectURI string, scopes []string, challenge, method *string) error {
 	now := time.Now().UTC()
 	ac := &domain.AuthorizationCode{
-		Code:                code,
+		Code:                HashToken(code),
 		AppID:               appID,
 		UserID:              userID,
 		RedirectURI:         redirectURI,
@@ -351,7 +916,7 @@ func StoreAuthCode(ctx context.Context, repo repository.Repository, code, appID,
 // ExchangeAuthCode validates an authorization code, enforces PKCE, marks it
 // used, and returns the user id and granted scopes.
 func ExchangeAuthCode(ctx context.Context, repo repository.Repository, code, appID, redirectURI string, verifier *string) (string, []string, error) {
-	ac, err := repo.AuthCodes().FindByCode(ctx, code)
+	ac, err := findAuthCode(ctx, repo, code)
 	if err != nil {
 		return "", nil, err
 	}
@@ -379,62 +944,147 @@ func ExchangeAuthCode(ctx context.Context, repo repository.Repository, code, app
 			return "", nil, apperror.InvalidCodeVerifier()
 		}
 	}
-	if err := repo.AuthCodes().MarkUsed(ctx, code); err != nil {
+	if err := repo.AuthCodes().MarkUsed(ctx, ac.Code); err != nil {
 		return "", nil, err
 	}
 	return ac.UserID, DecodeStringArray(ac.Scopes), nil
 }
 
+// findAuthCode looks a code up by each of its candidate hashes, then by its
+// raw value as codes were stored before they were hashed.
+func findAuthCode(ctx context.Context, repo repository.Repository, code string) (*domain.AuthorizationCode, error) {
+	for _, key := range append(TokenHashCandidates(code), code) {
+		ac, err := repo.AuthCodes().FindByCode(ctx, key)
+		if err != nil || ac != nil {
+			return ac, err
+		}
+	}
+	return nil, nil
+}
+
+// findRefreshToken looks a refresh token up by each of its candidate hashes.
+func findRefreshToken(ctx context.Context, repo repository.Repository, token string) (*domain.RefreshToken, error) {
+	for _, hash := range TokenHashCandidates(token) {
+		stored, err := repo.RefreshTokens().FindByTokenHash(ctx, hash)
+		if err != nil || stored != nil {
+			return stored, err
+		}
+	}
+	return nil, nil
+}
+
+// RefreshableScopes returns scopes without those in nonRefreshable (config
+// NON_REFRESHABLE_SCOPES), the set a refresh token may carry.
+func RefreshableScopes(scopes, nonRefreshable []string) []string {
+	out := make([]string, 0, len(scopes))
+	for _, s := range scopes {
+		if !slices.Contains(nonRefreshable, s) {
+			out = append(out, s)
+		}
+	}
+	return out
+}
+
 // StoreRefreshToken persists a hashed refresh token.
 func StoreRefreshToken(ctx context.Context, repo repository.Repository, userID, appID, token string, scopes []string, deviceID *string, expiryDays int64) error {
 	now := time.Now().UTC()
 	rt := &domain.RefreshToken{
-		ID:        uuid.NewString(),
-		UserID:    userID,
-		AppID:     appID,
-		TokenHash: HashToken(token),
-		Scopes:    encodeScopes(scopes),
-		DeviceID:  deviceID,
-		ExpiresAt: now.AddDate(0, 0, int(expiryDays)),
-		Revoked:   false,
-		CreatedAt: now,
+		ID:         uuid.NewString(),
+		UserID:     userID,
+		AppID:      appID,
+		TokenHash:  HashToken(token),
+		Scopes:     encodeScopes(scopes),
+		DeviceID:   deviceID,
+		ExpiresAt:  now.AddDate(0, 0, int(expiryDays)),
+		ExpiryDays: expiryDays,
+		Revoked:    false,
+		CreatedAt:  now,
 	}
 	return repo.RefreshTokens().Insert(ctx, rt)
 }
 
+// RotatedRefreshToken is the outcome of a refresh: the token the client should
+// keep from now on and the lifetime (in days) it was given.
+type RotatedRefreshToken struct {
+	UserID     string
+	Token      string
+	Scopes     []string
+	ExpiryDays int64
+}
+
 // RotateRefreshToken validates a refresh token and issues a replacement,
-// revoking the old one. Returns the user id, new token, and scopes.
-func RotateRefreshToken(ctx context.Context, repo repository.Repository, token, appID string, expiryDays int64) (string, string, []string, error) {
-	stored, err := repo.RefreshTokens().FindByTokenHash(ctx, HashToken(token))
+// revoking the old one. With rotate false (apps that opted out of rotation)
+// the same token is kept and its expiry pushed out instead. When a revoked
+// token is presented again (reuse), the TokenRevoked error comes back with a
+// result carrying the owning user id so callers can react to the incident.
+//
+// A non-nil narrow restricts the result to those scopes; it must be a subset
+// of the stored token's scopes (InvalidScope otherwise, before anything is
+// revoked). The replacement token keeps the narrowed set, but a token that is
+// not rotated keeps its original scopes. Scopes in nonRefreshable are dropped
+// from the result either way, for tokens stored before they were listed.
+func RotateRefreshToken(ctx context.Context, repo repository.Repository, token, appID string, expiryDays int64, rotate bool, narrow, nonRefreshable []string) (*RotatedRefreshToken, error) {
+	stored, err := findRefreshToken(ctx, repo, token)
 	if err != nil {
-		return "", "", nil, err
+		return nil, err
 	}
 	if stored == nil {
-		return "", "", nil, apperror.InvalidToken()
+		return nil, apperror.InvalidToken()
 	}
 	if stored.Revoked {
-		return "", "", nil, apperror.TokenRevoked()
+		return &RotatedRefreshToken{UserID: stored.UserID}, apperror.TokenRevoked()
 	}
 	if stored.AppID != appID {
-		return "", "", nil, apperror.InvalidToken()
+		return nil, apperror.InvalidToken()
 	}
-	if stored.ExpiresAt.Before(time.Now().UTC()) {
-		return "", "", nil, apperror.RefreshTokenExpired()
+	now := time.Now().UTC()
+	if stored.ExpiresAt.Before(now) {
+		return nil, apperror.RefreshTokenExpired()
 	}
-	if err := repo.RefreshTokens().Revoke(ctx, stored.ID); err != nil {
-		return "", "", nil, err
+	// The session keeps the lifetime it was granted at login, so neither a
+	// "remember me" nor a short session falls back to the default on refresh.
+	// expiryDays only applies to tokens stored before the lifetime was
+	// recorded; for those a shorter one is still read off the token itself.
+	if stored.ExpiryDays > 0 {
+		expiryDays = stored.ExpiryDays
+	} else if days := int64(stored.ExpiresAt.Sub(stored.CreatedAt).Round(24*time.Hour) / (24 * time.Hour)); days > 0 && days < expiryDays {
+		expiryDays = days
 	}
-	newToken := GenerateRefreshToken()
 	scopes := DecodeStringArray(stored.Scopes)
+	if narrow != nil {
+		for _, s := range narrow {
+			if !slices.Contains(scopes, s) {
+				return nil, apperror.InvalidScope()
+			}
+		}
+		scopes = narrow
+	}
+	scopes = RefreshableScopes(scopes, nonRefreshable)
+	if !rotate {
+		if err := repo.RefreshTokens().Extend(ctx, stored.ID, now.AddDate(0, 0, int(expiryDays))); err != nil {
+			return nil, err
+		}
+		return &RotatedRefreshToken{UserID: stored.UserID, Token: token, Scopes: scopes, ExpiryDays: expiryDays}, nil
+	}
+	// Revoking is the compare-and-set: of two concurrent rotations only one
+	// flips the flag, and the other is treated as reuse.
+	revoked, err := repo.RefreshTokens().RevokeIfActive(ctx, stored.ID)
+	if err != nil {
+		return nil, err
+	}
+	if !revoked {
+		return &RotatedRefreshToken{UserID: stored.UserID}, apperror.TokenRevoked()
+	}
+	newToken := GenerateRefreshToken()
 	if err := StoreRefreshToken(ctx, repo, stored.UserID, appID, newToken, scopes, stored.DeviceID, expiryDays); err != nil {
-		return "", "", nil, err
+		return nil, err
 	}
-	return stored.UserID, newToken, scopes, nil
+	return &RotatedRefreshToken{UserID: stored.UserID, Token: newToken, Scopes: scopes, ExpiryDays: expiryDays}, nil
 }
 
 // RevokeRefreshToken revokes a refresh token by its raw value.
 func RevokeRefreshToken(ctx context.Context, repo repository.Repository, token string) error {
-	stored, err := repo.RefreshTokens().FindByTokenHash(ctx, HashToken(token))
+	stored, err := findRefreshToken(ctx, repo, token)
 	if err != nil {
 		return err
 	}
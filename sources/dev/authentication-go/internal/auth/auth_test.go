@@ -1,16 +1,22 @@
 package auth
 
 import (
+	"context"
 	"crypto/rand"
 	"crypto/rsa"
 	"crypto/sha256"
+	"crypto/x509"
 	"encoding/base64"
+	"encoding/pem"
+	"os"
+	"path/filepath"
 	"strings"
 	"testing"
 	"time"
 
 	"github.com/golang-jwt/jwt/v5"
 
+	"github.com/zhaochy1990/auth-service/internal/config"
 	"github.com/zhaochy1990/auth-service/internal/domain"
 )
 
@@ -37,6 +43,23 @@ func TestValidatePassword(t *testing.T) {
 	}
 }
 
+func TestCheckPasswordDenylist(t *testing.T) {
+	if err := CheckPasswordDenylist("Password1!", nil); err != nil {
+		t.Fatalf("empty denylist rejected: %v", err)
+	}
+	denylist := map[string]bool{"password": true, "letmein": true}
+	for _, pw := range []string{"Password1!", "PASSWORD", "P@ssw0rd99#", "2024LetMeIn!"} {
+		if err := CheckPasswordDenylist(pw, denylist); err == nil {
+			t.Errorf("CheckPasswordDenylist(%q) = nil, want rejection", pw)
+		}
+	}
+	for _, pw := range []string{"Passwords1!", "Correct-Horse-9"} {
+		if err := CheckPasswordDenylist(pw, denylist); err != nil {
+			t.Errorf("CheckPasswordDenylist(%q) = %v, want nil", pw, err)
+		}
+	}
+}
+
 func TestClientSecretSHA256(t *testing.T) {
 	secret := "test_secret_value_12345"
 	hash := HashClientSecret(secret)
@@ -51,6 +74,127 @@ func TestClientSecretSHA256(t *testing.T) {
 	}
 }
 
+func TestGenerateClientSecret(t *testing.T) {
+	secret, hash := GenerateClientSecret()
+	if len(secret) != 64 || !strings.HasPrefix(hash, "sha256:") {
+		t.Fatalf("unexpected secret/hash: %q %q", secret, hash)
+	}
+	if ok, _ := VerifyClientSecret(secret, hash); !ok {
+		t.Fatal("expected generated secret to verify")
+	}
+	if other, _ := GenerateClientSecret(); other == secret {
+		t.Fatal("expected distinct secrets")
+	}
+}
+
+func TestClientSecretPepper(t *testing.T) {
+	secret := "test_secret_value_12345"
+	plain := HashClientSecret(secret)
+
+	t.Setenv("CLIENT_SECRET_PEPPER", "pepper-one")
+	peppered := HashClientSecret(secret)
+	if !strings.HasPrefix(peppered, "sha256p:") {
+		t.Fatalf("hash missing sha256p prefix: %s", peppered)
+	}
+	if ok, _ := VerifyClientSecret(secret, peppered); !ok {
+		t.Fatal("expected peppered secret to verify")
+	}
+	if ok, _ := VerifyClientSecret("wrong", peppered); ok {
+		t.Fatal("expected wrong secret to fail")
+	}
+	if ok, _ := VerifyClientSecret(secret, plain); !ok {
+		t.Fatal("expected pepper-less hash to keep verifying")
+	}
+
+	t.Setenv("CLIENT_SECRET_PEPPER", "pepper-two")
+	if ok, _ := VerifyClientSecret(secret, peppered); ok {
+		t.Fatal("expected hash to fail under a different pepper")
+	}
+	t.Setenv("CLIENT_SECRET_PEPPER", "")
+	if ok, _ := VerifyClientSecret(secret, peppered); ok {
+		t.Fatal("expected peppered hash to fail without a pepper")
+	}
+}
+
+func TestTokenHashKey(t *testing.T) {
+	token := GenerateRefreshToken()
+	useKeys(t, &config.Config{})
+	plain := HashToken(token)
+	if strings.HasPrefix(plain, tokenHashPrefix) || len(plain) != 64 {
+		t.Fatalf("unkeyed hash = %q", plain)
+	}
+	if c := TokenHashCandidates(token); len(c) != 1 || c[0] != plain {
+		t.Fatalf("unkeyed candidates = %v", c)
+	}
+
+	Configure(&config.Config{TokenHashKey: "key-one"})
+	keyed := HashToken(token)
+	if !strings.HasPrefix(keyed, tokenHashPrefix) {
+		t.Fatalf("keyed hash = %q", keyed)
+	}
+	if c := TokenHashCandidates(token); len(c) != 2 || c[0] != keyed || c[1] != plain {
+		t.Fatalf("keyed candidates = %v", c)
+	}
+	if !VerifyTokenHash(token, keyed) || !VerifyTokenHash(token, plain) {
+		t.Fatal("expected both schemes to verify while the key is set")
+	}
+	if VerifyTokenHash("other", keyed) {
+		t.Fatal("expected a different token to fail")
+	}
+
+	Configure(&config.Config{TokenHashKey: "key-two"})
+	if VerifyTokenHash(token, keyed) {
+		t.Fatal("expected hash to fail under a different key")
+	}
+	Configure(&config.Config{})
+	if VerifyTokenHash(token, keyed) {
+		t.Fatal("expected keyed hash to fail without a key")
+	}
+}
+
+// useKeys configures auth with cfg for the rest of the test.
+func useKeys(t *testing.T, cfg *config.Config) {
+	t.Helper()
+	Configure(cfg)
+	t.Cleanup(func() { Configure(&config.Config{}) })
+}
+
+func TestSealSecret(t *testing.T) {
+	useKeys(t, &config.Config{})
+	if _, err := SealSecret([]byte("x")); err == nil {
+		t.Fatal("expected sealing to fail without a key")
+	}
+
+	zero, other := make([]byte, 32), []byte(strings.Repeat("k", 32))
+	useKeys(t, &config.Config{DataKeys: config.DataKeys{Version: 1, Keys: map[int][]byte{1: zero}}})
+	sealed, err := SealSecret([]byte("provider-refresh-token"))
+	if err != nil {
+		t.Fatalf("seal: %v", err)
+	}
+	if !strings.HasPrefix(sealed, "v1:") || strings.Contains(sealed, "provider-refresh-token") {
+		t.Fatalf("unexpected sealed value: %s", sealed)
+	}
+	plain, err := OpenSecret(sealed)
+	if err != nil || string(plain) != "provider-refresh-token" {
+		t.Fatalf("open = %q, %v", plain, err)
+	}
+
+	useKeys(t, &config.Config{DataKeys: config.DataKeys{Version: 1, Keys: map[int][]byte{1: other}}})
+	if _, err := OpenSecret(sealed); err == nil {
+		t.Fatal("expected open to fail under a different key")
+	}
+
+	// Rotation: the new key gets version 2; the retired key stays readable.
+	useKeys(t, &config.Config{DataKeys: config.DataKeys{Version: 2, Keys: map[int][]byte{1: zero, 2: other}}})
+	if plain, err := OpenSecret(sealed); err != nil || string(plain) != "provider-refresh-token" {
+		t.Fatalf("open v1 after rotation = %q, %v", plain, err)
+	}
+	resealed, err := SealSecret([]byte("provider-refresh-token"))
+	if err != nil || !strings.HasPrefix(resealed, "v2:") {
+		t.Fatalf("reseal = %q, %v", resealed, err)
+	}
+}
+
 func TestClientSecretLegacyArgon2(t *testing.T) {
 	secret := "test_secret"
 	h, err := HashPassword(secret)
@@ -81,19 +225,31 @@ func TestPasswordRoundtrip(t *testing.T) {
 	}
 }
 
+// testJWTManager builds a manager around priv without key files.
+func testJWTManager(t *testing.T, priv *rsa.PrivateKey) *JWTManager {
+	t.Helper()
+	m := &JWTManager{issuer: "auth-service", accessExpirySecs: 3600}
+	info, err := newKeyInfo(&priv.PublicKey, m.issuer)
+	if err != nil {
+		t.Fatal(err)
+	}
+	m.keys.Store(&keySet{priv: priv, pub: &priv.PublicKey, info: info})
+	return m
+}
+
 func TestVerifyAccessTokenRequiredClaims(t *testing.T) {
 	priv, err := rsa.GenerateKey(rand.Reader, 2048)
 	if err != nil {
 		t.Fatal(err)
 	}
-	m := &JWTManager{priv: priv, pub: &priv.PublicKey, issuer: "auth-service", accessExpirySecs: 3600}
+	m := testJWTManager(t, priv)
 
 	// A fully-formed token verifies.
-	good, err := m.IssueAccessToken("user-1", "client-1", []string{"openid"}, "user", domain.MembershipRegular, domain.UserTypeRegular, nil)
+	good, err := m.IssueAccessToken(context.Background(), "user-1", "client-1", []string{"openid"}, "user", domain.MembershipRegular, domain.UserTypeRegular, nil, TokenExtras{})
 	if err != nil {
 		t.Fatal(err)
 	}
-	if _, err := m.VerifyAccessToken(good); err != nil {
+	if _, err := m.VerifyAccessToken(context.Background(), good); err != nil {
 		t.Fatalf("valid token rejected: %v", err)
 	}
 
@@ -114,24 +270,52 @@ func TestVerifyAccessTokenRequiredClaims(t *testing.T) {
 		if err != nil {
 			t.Fatal(err)
 		}
-		if _, err := m.VerifyAccessToken(signed); err == nil {
+		if _, err := m.VerifyAccessToken(context.Background(), signed); err == nil {
 			t.Errorf("token missing %q was accepted", missing)
 		}
 	}
 }
 
+func TestVerifyAccessTokenMinIssuedAt(t *testing.T) {
+	priv, err := rsa.GenerateKey(rand.Reader, 2048)
+	if err != nil {
+		t.Fatal(err)
+	}
+	m := testJWTManager(t, priv)
+	tok, err := m.IssueAccessToken(context.Background(), "user-1", "client-1", nil, "user", domain.MembershipRegular, domain.UserTypeRegular, nil, TokenExtras{})
+	if err != nil {
+		t.Fatal(err)
+	}
+	app, err := m.IssueAppToken(context.Background(), "app-1", nil)
+	if err != nil {
+		t.Fatal(err)
+	}
+
+	m.minIssuedAt = time.Now().Add(time.Minute).Unix()
+	if _, err := m.VerifyAccessToken(context.Background(), tok); err == nil {
+		t.Fatal("token issued before JWT_MIN_IAT was accepted")
+	}
+	if _, err := m.VerifyAppToken(context.Background(), app); err == nil {
+		t.Fatal("app token issued before JWT_MIN_IAT was accepted")
+	}
+	m.minIssuedAt = time.Now().Add(-time.Minute).Unix()
+	if _, err := m.VerifyAccessToken(context.Background(), tok); err != nil {
+		t.Fatalf("token issued after the cutoff rejected: %v", err)
+	}
+}
+
 func TestVerifyAppTokenRequiredClaims(t *testing.T) {
 	priv, err := rsa.GenerateKey(rand.Reader, 2048)
 	if err != nil {
 		t.Fatal(err)
 	}
-	m := &JWTManager{priv: priv, pub: &priv.PublicKey, issuer: "auth-service", accessExpirySecs: 3600}
+	m := testJWTManager(t, priv)
 
-	good, err := m.IssueAppToken("app-1")
+	good, err := m.IssueAppToken(context.Background(), "app-1", nil)
 	if err != nil {
 		t.Fatal(err)
 	}
-	if _, err := m.VerifyAppToken(good); err != nil {
+	if _, err := m.VerifyAppToken(context.Background(), good); err != nil {
 		t.Fatalf("valid app token rejected: %v", err)
 	}
 
@@ -150,12 +334,113 @@ func TestVerifyAppTokenRequiredClaims(t *testing.T) {
 		if err != nil {
 			t.Fatal(err)
 		}
-		if _, err := m.VerifyAppToken(signed); err == nil {
+		if _, err := m.VerifyAppToken(context.Background(), signed); err == nil {
 			t.Errorf("app token missing %q was accepted", missing)
 		}
 	}
 }
 
+func TestReloadKeys(t *testing.T) {
+	dir := t.TempDir()
+	cfg := &config.Config{
+		JWTPrivateKeyPath:        filepath.Join(dir, "private.pem"),
+		JWTPublicKeyPath:         filepath.Join(dir, "public.pem"),
+		JWTIssuer:                "auth-service",
+		JWTAccessTokenExpirySecs: 3600,
+	}
+	writeKeys := func(priv *rsa.PrivateKey) {
+		t.Helper()
+		pub, err := x509.MarshalPKIXPublicKey(&priv.PublicKey)
+		if err != nil {
+			t.Fatal(err)
+		}
+		if err := os.WriteFile(cfg.JWTPrivateKeyPath, pem.EncodeToMemory(&pem.Block{Type: "RSA PRIVATE KEY", Bytes: x509.MarshalPKCS1PrivateKey(priv)}), 0o600); err != nil {
+			t.Fatal(err)
+		}
+		if err := os.WriteFile(cfg.JWTPublicKeyPath, pem.EncodeToMemory(&pem.Block{Type: "PUBLIC KEY", Bytes: pub}), 0o600); err != nil {
+			t.Fatal(err)
+		}
+	}
+	newKey := func() *rsa.PrivateKey {
+		t.Helper()
+		priv, err := rsa.GenerateKey(rand.Reader, 2048)
+		if err != nil {
+			t.Fatal(err)
+		}
+		return priv
+	}
+	ctx := context.Background()
+
+	first := newKey()
+	writeKeys(first)
+	m, err := NewJWTManager(cfg)
+	if err != nil {
+		t.Fatal(err)
+	}
+	oldKid := m.KeyInfo().Kid
+	oldTok, err := m.IssueAccessToken(ctx, "user-1", "client-1", nil, "user", domain.MembershipRegular, domain.UserTypeRegular, nil, TokenExtras{})
+	if err != nil {
+		t.Fatal(err)
+	}
+	// Tokens signed before kid headers existed still verify.
+	legacy, err := jwt.NewWithClaims(jwt.SigningMethodRS256, jwt.MapClaims{
+		"sub": "user-1", "aud": "client-1", "iss": "auth-service",
+		"exp": time.Now().Add(time.Hour).Unix(), "iat": time.Now().Unix(),
+	}).SignedString(first)
+	if err != nil {
+		t.Fatal(err)
+	}
+
+	// An unchanged keypair is a no-op.
+	if info, err := m.ReloadKeys(); err != nil || info.Kid != oldKid {
+		t.Fatalf("reload of unchanged keys = %+v, %v", info, err)
+	}
+
+	writeKeys(newKey())
+	info, err := m.ReloadKeys()
+	if err != nil {
+		t.Fatal(err)
+	}
+	if info.Kid == oldKid || m.KeyInfo().Kid != info.Kid {
+		t.Fatalf("kid after reload = %q (KeyInfo %q), was %q", info.Kid, m.KeyInfo().Kid, oldKid)
+	}
+	newTok, err := m.IssueAccessToken(ctx, "user-1", "client-1", nil, "user", domain.MembershipRegular, domain.UserTypeRegular, nil, TokenExtras{})
+	if err != nil {
+		t.Fatal(err)
+	}
+	for name, tok := range map[string]string{"old": oldTok, "legacy": legacy, "new": newTok} {
+		if _, err := m.VerifyAccessToken(ctx, tok); err != nil {
+			t.Errorf("%s token rejected after reload: %v", name, err)
+		}
+	}
+	parsed, _, err := jwt.NewParser().ParseUnverified(newTok, jwt.MapClaims{})
+	if err != nil {
+		t.Fatal(err)
+	}
+	if parsed.Header["kid"] != info.Kid {
+		t.Fatalf("new token kid = %v, want %q", parsed.Header["kid"], info.Kid)
+	}
+
+	// A mismatched pair is refused and the current keys stay in use.
+	mismatched := newKey()
+	if err := os.WriteFile(cfg.JWTPrivateKeyPath, pem.EncodeToMemory(&pem.Block{Type: "RSA PRIVATE KEY", Bytes: x509.MarshalPKCS1PrivateKey(mismatched)}), 0o600); err != nil {
+		t.Fatal(err)
+	}
+	if _, err := m.ReloadKeys(); err == nil {
+		t.Fatal("mismatched keypair was loaded")
+	}
+	if m.KeyInfo().Kid != info.Kid {
+		t.Fatal("failed reload replaced the keys")
+	}
+
+	// Once retired keys have outlived every token they signed, they stop
+	// verifying.
+	m.keys.Load().retired[0].until = time.Now().Add(-time.Second)
+	if _, err := m.VerifyAccessToken(ctx, oldTok); err == nil {
+		t.Fatal("token signed by an expired retired key was accepted")
+	}
+}
+
 func TestVerifyPKCE(t *testing.T) {
 	verifier := "dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk"
 	sum := sha256.Sum256([]byte(verifier))
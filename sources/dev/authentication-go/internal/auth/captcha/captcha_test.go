@@ -0,0 +1,43 @@
+package captcha
+
+import (
+	"context"
+	"net/http"
+	"net/http/httptest"
+	"testing"
+
+	"github.com/zhaochy1990/auth-service/internal/apperror"
+)
+
+func TestSiteVerify(t *testing.T) {
+	srv := httptest.NewServer(http.HandlerFunc(func(w http.ResponseWriter, r *http.Request) {
+		if err := r.ParseForm(); err != nil || r.PostForm.Get("secret") != "s3cret" || r.PostForm.Get("remoteip") != "203.0.113.7" {
+			w.WriteHeader(http.StatusBadRequest)
+			return
+		}
+		if r.PostForm.Get("response") == "good" {
+			_, _ = w.Write([]byte(`{"success":true}`))
+			return
+		}
+		_, _ = w.Write([]byte(`{"success":false,"error-codes":["invalid-input-response"]}`))
+	}))
+	defer srv.Close()
+	v := NewSiteVerify(srv.URL, "s3cret", srv.Client())
+	ctx := context.Background()
+
+	if err := v.Verify(ctx, "good", "203.0.113.7"); err != nil {
+		t.Fatalf("valid token rejected: %v", err)
+	}
+	for _, token := range []string{"bad", ""} {
+		err := v.Verify(ctx, token, "203.0.113.7")
+		if ae, ok := apperror.As(err); !ok || ae.Status != http.StatusBadRequest {
+			t.Errorf("Verify(%q) = %v, want 400", token, err)
+		}
+	}
+
+	srv.Close()
+	err := v.Verify(ctx, "good", "203.0.113.7")
+	if ae, ok := apperror.As(err); !ok || ae.Type != "provider_unavailable" {
+		t.Fatalf("unreachable siteverify = %v, want provider_unavailable", err)
+	}
+}
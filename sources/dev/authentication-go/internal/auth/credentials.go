@@ -0,0 +1,145 @@
+package auth
+
+import (
+	"crypto"
+	"crypto/hmac"
+	"crypto/sha256"
+	"crypto/subtle"
+	"encoding/hex"
+	"errors"
+	"os"
+	"slices"
+	"strings"
+	"time"
+
+	"github.com/golang-jwt/jwt/v5"
+	"github.com/google/uuid"
+
+	"github.com/zhaochy1990/auth-service/internal/apperror"
+)
+
+// ─── Client credentials ──────────────────────────────────────────────────────
+
+// GenerateClientID returns an OAuth2 client_id of the form "app_<24 chars>".
+func GenerateClientID() string {
+	return "app_" + strings.ReplaceAll(uuid.NewString(), "-", "")[:24]
+}
+
+// GenerateClientSecret returns a fresh client secret and the hash to store for
+// it. Every code path that issues a secret (admin create/rotate, seed,
+// create-app) goes through here so they all hash the same way.
+func GenerateClientSecret() (secret, hash string) {
+	secret = RandomHex(32)
+	return secret, HashClientSecret(secret)
+}
+
+// clientSecretPepper returns the server-side HMAC key mixed into client-secret
+// hashes, or nil when CLIENT_SECRET_PEPPER is unset. Read per call (like the
+// invite-code gate) so the key never has to be threaded through callers.
+func clientSecretPepper() []byte {
+	if v := os.Getenv("CLIENT_SECRET_PEPPER"); v != "" {
+		return []byte(v)
+	}
+	return nil
+}
+
+func pepperedSecretHex(secret string, pepper []byte) string {
+	mac := hmac.New(sha256.New, pepper)
+	mac.Write([]byte(secret))
+	return hex.EncodeToString(mac.Sum(nil))
+}
+
+// HashClientSecret hashes a high-entropy client secret with SHA-256. Argon2's
+// brute-force resistance is unnecessary here and its cost would bottleneck
+// every OAuth2 request. With CLIENT_SECRET_PEPPER set the digest becomes an
+// HMAC keyed by the pepper ("sha256p:" prefix), so a leaked table alone can't
+// confirm guessed secrets.
+func HashClientSecret(secret string) string {
+	if pepper := clientSecretPepper(); pepper != nil {
+		return "sha256p:" + pepperedSecretHex(secret, pepper)
+	}
+	sum := sha256.Sum256([]byte(secret))
+	return "sha256:" + hex.EncodeToString(sum[:])
+}
+
+// VerifyClientSecret verifies a client secret. Supports peppered SHA-256,
+// plain SHA-256, and Argon2 (legacy) hashes. A peppered hash never verifies
+// while the pepper is unset.
+func VerifyClientSecret(secret, hash string) (bool, error) {
+	if hexHash, ok := strings.CutPrefix(hash, "sha256p:"); ok {
+		pepper := clientSecretPepper()
+		if pepper == nil {
+			return false, nil
+		}
+		return constantTimeHexEqual(pepperedSecretHex(secret, pepper), hexHash), nil
+	}
+	if hexHash, ok := strings.CutPrefix(hash, "sha256:"); ok {
+		sum := sha256.Sum256([]byte(secret))
+		return constantTimeHexEqual(hex.EncodeToString(sum[:]), hexHash), nil
+	}
+	return VerifyPassword(secret, hash)
+}
+
+func constantTimeHexEqual(computed, stored string) bool {
+	if len(computed) != len(stored) {
+		return false
+	}
+	return subtle.ConstantTimeCompare([]byte(computed), []byte(stored)) == 1
+}
+
+// ─── private_key_jwt ─────────────────────────────────────────────────────────
+
+// ClientAssertionType is the client_assertion_type a private_key_jwt client
+// sends with its assertion (RFC 7523).
+const ClientAssertionType = "urn:ietf:params:oauth:client-assertion-type:jwt-bearer"
+
+// maxAssertionLifetime caps how far ahead an assertion's exp may be, which
+// also bounds how long its jti has to be remembered.
+const maxAssertionLifetime = 5 * time.Minute
+
+// ParseClientPublicKey parses the PEM RSA or EC public key an application
+// registers for private_key_jwt.
+func ParseClientPublicKey(pemKey string) (crypto.PublicKey, error) {
+	if key, err := jwt.ParseRSAPublicKeyFromPEM([]byte(pemKey)); err == nil {
+		return key, nil
+	}
+	if key, err := jwt.ParseECPublicKeyFromPEM([]byte(pemKey)); err == nil {
+		return key, nil
+	}
+	return nil, errors.New("not a PEM RSA or EC public key")
+}
+
+// ClientAssertionSubject returns the unverified sub of an assertion, which
+// names the client when the request carries no client_id.
+func ClientAssertionSubject(assertion string) string {
+	var claims jwt.RegisteredClaims
+	if _, _, err := jwt.NewParser().ParseUnverified(assertion, &claims); err != nil {
+		return ""
+	}
+	return claims.Subject
+}
+
+// VerifyClientAssertion checks a private_key_jwt assertion: signed by
+// publicKeyPEM, iss and sub equal to clientID, aud naming one of audiences,
+// and a jti with a short-lived exp. It returns the jti and expiry so the
+// caller can refuse a replay.
+func VerifyClientAssertion(assertion, clientID, publicKeyPEM string, audiences []string) (string, time.Time, error) {
+	key, err := ParseClientPublicKey(publicKeyPEM)
+	if err != nil {
+		return "", time.Time{}, apperror.InvalidCredentials()
+	}
+	var claims jwt.RegisteredClaims
+	_, err = jwt.ParseWithClaims(assertion, &claims, func(*jwt.Token) (interface{}, error) { return key, nil },
+		jwt.WithValidMethods([]string{"RS256", "PS256", "ES256"}),
+		jwt.WithIssuer(clientID),
+		jwt.WithSubject(clientID),
+		jwt.WithExpirationRequired(),
+	)
+	if err != nil || claims.ID == "" || claims.ExpiresAt.After(time.Now().Add(maxAssertionLifetime)) {
+		return "", time.Time{}, apperror.InvalidCredentials()
+	}
+	if !slices.ContainsFunc(claims.Audience, func(aud string) bool { return slices.Contains(audiences, aud) }) {
+		return "", time.Time{}, apperror.InvalidCredentials()
+	}
+	return claims.ID, claims.ExpiresAt.Time, nil
+}
@@ -6,6 +6,7 @@ import (
 	"context"
 	"encoding/json"
 	"fmt"
+	"net"
 	"net/http"
 	"net/url"
 	"time"
@@ -20,6 +21,16 @@ type UserInfo struct {
 	Name              *string
 	AvatarURL         *string
 	Metadata          json.RawMessage
+	// Tokens are provider-issued OAuth tokens, when the provider returns any.
+	Tokens *Tokens
+}
+
+// Tokens are kept so the service can call the provider on the user's behalf.
+// They are stored encrypted and must never appear in an API response.
+type Tokens struct {
+	AccessToken  string     `json:"access_token,omitempty"`
+	RefreshToken string     `json:"refresh_token,omitempty"`
+	ExpiresAt    *time.Time `json:"expires_at,omitempty"`
 }
 
 // Provider authenticates a credential and returns the resolved identity.
@@ -28,15 +39,44 @@ type Provider interface {
 	Authenticate(ctx context.Context, credential json.RawMessage) (*UserInfo, error)
 }
 
-// Create builds a provider by id. allowTest enables the "test" provider, which
-// is otherwise rejected.
-func Create(providerID string, config json.RawMessage, allowTest bool) (Provider, error) {
+// NewHTTPClient builds the client shared by all providers for outbound API
+// calls: one connection pool, a fixed User-Agent, connect bounding dialing and
+// the TLS handshake, and request bounding the whole exchange.
+func NewHTTPClient(connect, request time.Duration, userAgent string) *http.Client {
+	transport := http.DefaultTransport.(*http.Transport).Clone()
+	transport.DialContext = (&net.Dialer{Timeout: connect, KeepAlive: 30 * time.Second}).DialContext
+	transport.TLSHandshakeTimeout = connect
+	transport.MaxIdleConnsPerHost = 16
+	return &http.Client{Timeout: request, Transport: userAgentTransport{base: transport, userAgent: userAgent}}
+}
+
+type userAgentTransport struct {
+	base      http.RoundTripper
+	userAgent string
+}
+
+func (t userAgentTransport) RoundTrip(req *http.Request) (*http.Response, error) {
+	if req.Header.Get("User-Agent") == "" {
+		req = req.Clone(req.Context())
+		req.Header.Set("User-Agent", t.userAgent)
+	}
+	return t.base.RoundTrip(req)
+}
+
+// ExternalIDs lists the provider ids Create knows, i.e. every provider an
+// account can be linked through besides "password".
+var ExternalIDs = []string{"wechat", "test"}
+
+// Create builds a provider by id. client is the shared outbound HTTP client
+// (see NewHTTPClient). allowTest enables the "test" provider, which is
+// otherwise rejected.
+func Create(providerID string, config json.RawMessage, client *http.Client, allowTest bool) (Provider, error) {
 	switch providerID {
 	case "wechat":
-		return newWeChat(config)
+		return newWeChat(config, client)
 	case "test":
 		if allowTest {
-			return &testProvider{}, nil
+			return newTest(config)
 		}
 		return nil, apperror.ProviderNotSupported(providerID)
 	default:
@@ -44,11 +84,6 @@ func Create(providerID string, config json.RawMessage, allowTest bool) (Provider
 	}
 }
 
-// providerError is the 502 used when an external provider call fails.
-func providerError() *apperror.Error {
-	return apperror.New(http.StatusBadGateway, "provider_error", "External provider error")
-}
-
 // --- WeChat ---
 
 type weChatProvider struct {
@@ -74,7 +109,7 @@ type jsCode2SessionResponse struct {
 	ErrMsg     *string `json:"errmsg"`
 }
 
-func newWeChat(config json.RawMessage) (Provider, error) {
+func newWeChat(config json.RawMessage, client *http.Client) (Provider, error) {
 	var cfg weChatConfig
 	if err := json.Unmarshal(config, &cfg); err != nil {
 		return nil, apperror.BadRequest(fmt.Sprintf("Invalid WeChat config: %v", err))
@@ -82,7 +117,7 @@ func newWeChat(config json.RawMessage) (Provider, error) {
 	return &weChatProvider{
 		appID:  cfg.AppID,
 		secret: cfg.Secret,
-		client: &http.Client{Timeout: 10 * time.Second},
+		client: client,
 	}, nil
 }
 
@@ -107,13 +142,19 @@ func (p *weChatProvider) Authenticate(ctx context.Context, credential json.RawMe
 	}
 	resp, err := p.client.Do(req)
 	if err != nil {
-		return nil, providerError()
+		return nil, apperror.ProviderUnavailable()
 	}
 	defer resp.Body.Close()
+	switch {
+	case resp.StatusCode == http.StatusTooManyRequests:
+		return nil, apperror.ProviderRateLimited()
+	case resp.StatusCode >= 300:
+		return nil, apperror.ProviderUnavailable()
+	}
 
 	var body jsCode2SessionResponse
 	if err := json.NewDecoder(resp.Body).Decode(&body); err != nil {
-		return nil, providerError()
+		return nil, apperror.ProviderUnavailable()
 	}
 
 	if body.ErrCode != nil && *body.ErrCode != 0 {
@@ -121,10 +162,10 @@ func (p *weChatProvider) Authenticate(ctx context.Context, credential json.RawMe
 		if body.ErrMsg != nil {
 			msg = *body.ErrMsg
 		}
-		return nil, apperror.BadRequest(fmt.Sprintf("WeChat API error %d: %s", *body.ErrCode, msg))
+		return nil, weChatError(*body.ErrCode, msg)
 	}
 	if body.OpenID == nil {
-		return nil, apperror.BadRequest("WeChat API did not return openid")
+		return nil, apperror.ProviderAuthFailed("WeChat API did not return openid")
 	}
 
 	// Do NOT persist session_key — it is a sensitive server-side secret.
@@ -138,28 +179,96 @@ func (p *weChatProvider) Authenticate(ctx context.Context, credential json.RawMe
 	}, nil
 }
 
+// weChatError classifies a jscode2session errcode: -1 (system busy) is an
+// outage, 45011 is WeChat's per-user rate limit, and everything else (invalid
+// or reused code, blocked user) is an authentication failure.
+func weChatError(code int64, msg string) *apperror.Error {
+	switch code {
+	case -1:
+		return apperror.ProviderUnavailable()
+	case 45011:
+		return apperror.ProviderRateLimited()
+	default:
+		return apperror.ProviderAuthFailed(fmt.Sprintf("WeChat API error %d: %s", code, msg))
+	}
+}
+
 // --- Test provider (gated) ---
 
-type testProvider struct{}
+// Test provider failure modes, set as the "fail" config key.
+const (
+	TestFailAuth        = "auth_failed"
+	TestFailUnavailable = "unavailable"
+	TestFailRateLimited = "rate_limited"
+)
+
+// testProvider echoes the identity in the credential. Its app config can pin
+// a canned identity ("user") or make every login fail ("fail") so integration
+// tests can drive linking, sign-up and error paths deterministically.
+type testProvider struct {
+	user *testCredential
+	fail string
+}
+
+type testConfig struct {
+	User *testCredential `json:"user"`
+	Fail string          `json:"fail"`
+}
 
 type testCredential struct {
-	AccountID string  `json:"account_id"`
-	Email     *string `json:"email"`
-	Name      *string `json:"name"`
+	AccountID    string  `json:"account_id"`
+	Email        *string `json:"email"`
+	Name         *string `json:"name"`
+	AvatarURL    *string `json:"avatar_url"`
+	RefreshToken string  `json:"refresh_token"`
+}
+
+func newTest(config json.RawMessage) (Provider, error) {
+	var cfg testConfig
+	if len(config) > 0 {
+		if err := json.Unmarshal(config, &cfg); err != nil {
+			return nil, apperror.BadRequest(fmt.Sprintf("Invalid test provider config: %v", err))
+		}
+	}
+	switch cfg.Fail {
+	case "", TestFailAuth, TestFailUnavailable, TestFailRateLimited:
+	default:
+		return nil, apperror.BadRequest("Invalid test provider config: unknown fail mode " + cfg.Fail)
+	}
+	if cfg.User != nil && cfg.User.AccountID == "" {
+		return nil, apperror.BadRequest("Invalid test provider config: user.account_id is required")
+	}
+	return &testProvider{user: cfg.User, fail: cfg.Fail}, nil
 }
 
 func (p *testProvider) ID() string { return "test" }
 
 func (p *testProvider) Authenticate(_ context.Context, credential json.RawMessage) (*UserInfo, error) {
-	var cred testCredential
-	if err := json.Unmarshal(credential, &cred); err != nil || cred.AccountID == "" {
-		return nil, apperror.BadRequest("Invalid test credential")
+	switch p.fail {
+	case TestFailAuth:
+		return nil, apperror.ProviderAuthFailed("Test provider rejected the credential")
+	case TestFailUnavailable:
+		return nil, apperror.ProviderUnavailable()
+	case TestFailRateLimited:
+		return nil, apperror.ProviderRateLimited()
+	}
+	cred := p.user
+	if cred == nil {
+		cred = &testCredential{}
+		if err := json.Unmarshal(credential, cred); err != nil || cred.AccountID == "" {
+			return nil, apperror.BadRequest("Invalid test credential")
+		}
 	}
 	meta, _ := json.Marshal(map[string]any{"provider": "test"})
-	return &UserInfo{
+	info := &UserInfo{
 		ProviderAccountID: cred.AccountID,
 		Email:             cred.Email,
 		Name:              cred.Name,
+		AvatarURL:         cred.AvatarURL,
 		Metadata:          meta,
-	}, nil
+	}
+	if cred.RefreshToken != "" {
+		info.Tokens = &Tokens{RefreshToken: cred.RefreshToken}
+	}
+	return info, nil
 }
@@ -0,0 +1,105 @@
+package providers
+
+import (
+	"context"
+	"encoding/json"
+	"net/http"
+	"net/http/httptest"
+	"testing"
+	"time"
+
+	"github.com/zhaochy1990/auth-service/internal/apperror"
+)
+
+func TestWeChatErrorClassification(t *testing.T) {
+	cases := []struct {
+		code       int64
+		wantType   string
+		wantStatus int
+	}{
+		{-1, "provider_unavailable", http.StatusBadGateway},
+		{45011, "provider_rate_limited", http.StatusTooManyRequests},
+		{40029, "provider_auth_failed", http.StatusUnauthorized},
+		{40163, "provider_auth_failed", http.StatusUnauthorized},
+	}
+	for _, c := range cases {
+		err := weChatError(c.code, "msg")
+		if err.Type != c.wantType || err.Status != c.wantStatus {
+			t.Errorf("weChatError(%d) = %s/%d, want %s/%d", c.code, err.Type, err.Status, c.wantType, c.wantStatus)
+		}
+	}
+}
+
+func TestWeChatUsesSharedClient(t *testing.T) {
+	client := NewHTTPClient(time.Second, 3*time.Second, "auth-service/test")
+	a, err := Create("wechat", json.RawMessage(`{"appid":"a","secret":"s"}`), client, false)
+	if err != nil {
+		t.Fatal(err)
+	}
+	b, _ := Create("wechat", json.RawMessage(`{"appid":"b","secret":"s"}`), client, false)
+	if a.(*weChatProvider).client != client || b.(*weChatProvider).client != client {
+		t.Fatal("providers should use the shared HTTP client")
+	}
+	if client.Timeout != 3*time.Second {
+		t.Fatalf("timeout = %v", client.Timeout)
+	}
+}
+
+func TestHTTPClientSetsUserAgent(t *testing.T) {
+	var got string
+	srv := httptest.NewServer(http.HandlerFunc(func(w http.ResponseWriter, r *http.Request) {
+		got = r.Header.Get("User-Agent")
+	}))
+	defer srv.Close()
+	resp, err := NewHTTPClient(time.Second, time.Second, "auth-service/test").Get(srv.URL)
+	if err != nil {
+		t.Fatal(err)
+	}
+	resp.Body.Close()
+	if got != "auth-service/test" {
+		t.Fatalf("User-Agent = %q", got)
+	}
+}
+
+func TestTestProviderConfig(t *testing.T) {
+	ctx := context.Background()
+	if _, err := Create("test", nil, nil, false); err == nil {
+		t.Fatal("test provider must be gated")
+	}
+
+	echo, err := Create("test", json.RawMessage(`{}`), nil, true)
+	if err != nil {
+		t.Fatal(err)
+	}
+	info, err := echo.Authenticate(ctx, json.RawMessage(`{"account_id":"a-1","email":"a@example.com"}`))
+	if err != nil || info.ProviderAccountID != "a-1" || info.Email == nil || *info.Email != "a@example.com" {
+		t.Fatalf("echo = %+v, %v", info, err)
+	}
+
+	canned, err := Create("test", json.RawMessage(`{"user":{"account_id":"fixed","name":"Fixed"}}`), nil, true)
+	if err != nil {
+		t.Fatal(err)
+	}
+	info, err = canned.Authenticate(ctx, json.RawMessage(`{"account_id":"ignored"}`))
+	if err != nil || info.ProviderAccountID != "fixed" || info.Name == nil || *info.Name != "Fixed" {
+		t.Fatalf("canned = %+v, %v", info, err)
+	}
+
+	for mode, want := range map[string]int{
+		TestFailAuth:        http.StatusUnauthorized,
+		TestFailUnavailable: http.StatusBadGateway,
+		TestFailRateLimited: http.StatusTooManyRequests,
+	} {
+		p, err := Create("test", json.RawMessage(`{"fail":"`+mode+`"}`), nil, true)
+		if err != nil {
+			t.Fatal(err)
+		}
+		_, err = p.Authenticate(ctx, json.RawMessage(`{"account_id":"a-1"}`))
+		if ae, ok := apperror.As(err); !ok || ae.Status != want {
+			t.Errorf("fail=%s: err = %v, want status %d", mode, err, want)
+		}
+	}
+	if _, err := Create("test", json.RawMessage(`{"fail":"sometimes"}`), nil, true); err == nil {
+		t.Fatal("unknown fail mode should be rejected")
+	}
+}
@@ -2,9 +2,19 @@
 package config
 
 import (
+	"encoding/base64"
+	"encoding/json"
 	"fmt"
+	"net"
+	"net/url"
 	"os"
 	"strconv"
+	"strings"
+	"time"
+
+	"github.com/zhaochy1990/auth-service/internal/auth/captcha"
+	"github.com/zhaochy1990/auth-service/internal/domain"
+	"github.com/zhaochy1990/auth-service/internal/i18n"
 )
 
 // Config holds all runtime configuration.
@@ -24,6 +34,235 @@ type Config struct {
 	CORSAllowedOrigins           string
 	// EnableTestProviders gates the "test" auth provider. Off in production.
 	EnableTestProviders bool
+	// Refresh TTLs selected by the login "remember me" flag. Logins that omit
+	// the flag keep JWTRefreshTokenExpiryDays.
+	JWTRefreshTokenShortExpiryDays      int64
+	JWTRefreshTokenRememberMeExpiryDays int64
+	// JWTKeysRequireAdmin puts GET /oauth/keys/pem behind admin auth.
+	JWTKeysRequireAdmin bool
+	// JWTMinIssuedAt (JWT_MIN_IAT, unix seconds, 0 when unset) rejects user,
+	// app and registration tokens issued before it.
+	JWTMinIssuedAt int64
+	// AuditWebhookURL receives a JSON POST for every audit event when set.
+	AuditWebhookURL string
+	// RefreshReuseForcePasswordChange makes refresh-token reuse also revoke all
+	// of the user's sessions and flag the account for a password change.
+	RefreshReuseForcePasswordChange bool
+	// Roles is the role allowlist and which roles carry admin privileges.
+	Roles Roles
+	// AccessTokenFormat is "jwt" (self-contained, the default) or "opaque"
+	// (a random handle whose claims are stored and looked up server-side).
+	AccessTokenFormat string
+	// RequestTimeoutSecs bounds every request; ProviderRequestTimeoutSecs
+	// replaces it on the routes that call out to external providers. 0
+	// disables the deadline.
+	RequestTimeoutSecs         int64
+	ProviderRequestTimeoutSecs int64
+	// Outbound timeouts for provider API calls (e.g. WeChat).
+	ProviderHTTPConnectTimeoutSecs int64
+	ProviderHTTPTimeoutSecs        int64
+	// LoginMaxFailedAttempts locks password login for LoginLockoutSecs after
+	// that many consecutive wrong passwords. 0 disables lockout.
+	LoginMaxFailedAttempts int64
+	LoginLockoutSecs       int64
+	// LoginPairMaxFailures failed logins for one email or username from one
+	// IP within LoginPairFailureWindowSecs refuse that pair for
+	// LoginPairBlockSecs, before any attempt counts toward the account
+	// lockout. 0 disables it; otherwise LoginMaxFailedAttempts, if set, must
+	// be higher.
+	LoginPairMaxFailures       int64
+	LoginPairFailureWindowSecs int64
+	LoginPairBlockSecs         int64
+	// CaptchaEnabled requires a verified captcha_token on registration.
+	// CaptchaProvider is "turnstile" or "hcaptcha".
+	CaptchaEnabled  bool
+	CaptchaProvider string
+	CaptchaSecret   string
+	// CustomAttributesAdminOnly lists custom attribute keys users cannot set
+	// via PATCH /api/users/me; CustomAttributesTokenClaims lists the keys
+	// copied into the access token's "attributes" claim. Every token claim
+	// key must be admin-only, so users cannot sign their own claims.
+	CustomAttributesAdminOnly   []string
+	CustomAttributesTokenClaims []string
+	// HSTSMaxAgeSecs enables Strict-Transport-Security when > 0.
+	HSTSMaxAgeSecs int64
+	// RequireHTTPS rejects requests that didn't arrive over TLS (directly or
+	// per X-Forwarded-Proto) and marks any cookie the service sets Secure.
+	RequireHTTPS bool
+	// IdempotencyTTLSecs is how long a response to a request carrying an
+	// Idempotency-Key is kept for replay. 0 ignores the header.
+	IdempotencyTTLSecs int64
+	// LinkReauthMaxAgeSecs requires the access token used to link a provider
+	// to come from a credential check at most this old (auth_time). 0 skips
+	// the check.
+	LinkReauthMaxAgeSecs int64
+	// ErrorFormat is "json" ({"error","message"}, the default) or
+	// "problemjson" (RFC 7807 application/problem+json).
+	ErrorFormat string
+	// ScopeRegistry maps each scope to its consent-screen text.
+	// ScopeRegistryStrict rejects app allowed_scopes missing from it.
+	ScopeRegistry       map[string]ScopeInfo
+	ScopeRegistryStrict bool
+	// NonRefreshableScopes are kept out of stored refresh tokens, so a
+	// refresh never renews them and the user has to sign in again.
+	NonRefreshableScopes []string
+	// SMTP relay for transactional email; SMTPHost "" disables sending.
+	SMTPHost     string
+	SMTPPort     int
+	SMTPUsername string
+	SMTPPassword string
+	SMTPFrom     string
+	// EmailConfirmURL is the page email-change links point at; the token is
+	// appended as the "token" query parameter. EmailChangeTokenTTLSecs is how
+	// long such a link stays valid.
+	EmailConfirmURL         string
+	EmailChangeTokenTTLSecs int64
+	// AdminAuthMaxFailures failed admin authentications from one IP within
+	// AdminAuthFailureWindowSecs block that IP from the admin API for
+	// AdminAuthBlockSecs and raise an audit alert. 0 disables the guard.
+	AdminAuthMaxFailures       int64
+	AdminAuthFailureWindowSecs int64
+	AdminAuthBlockSecs         int64
+	// DefaultScopes is granted when a client asks for no scopes and its app
+	// sets no default_scopes, limited to the app's allowed scopes. Empty
+	// grants every allowed scope.
+	DefaultScopes []string
+	// Locales translates user-facing error messages by Accept-Language: the
+	// bundled locales plus any <tag>.json files in LOCALES_DIR.
+	Locales i18n.Catalog
+	// RateLimitExemptCIDRs (IPs or CIDRs) skip the per-IP rate limiters.
+	// RateLimitTrustedClientIDs get their own bucket, RateLimitTrustedFactor
+	// times the normal limit.
+	RateLimitExemptCIDRs      []string
+	RateLimitTrustedClientIDs []string
+	RateLimitTrustedFactor    int64
+	// TrustedProxies (IPs or CIDRs) are the peers whose X-Forwarded-For and
+	// X-Real-IP are believed for rate limiting and abuse guards; from any
+	// other peer the connection's own address counts.
+	TrustedProxies []string
+	// PublicBaseURL is the scheme and host clients reach the service at, e.g.
+	// https://auth.example.com. Client assertions may name its token endpoint
+	// as aud; unset, only the issuer is accepted.
+	PublicBaseURL string
+	// DataKeys seal provider secrets, metadata and tokens at rest.
+	DataKeys DataKeys
+	// TokenHashKey (TOKEN_HASH_KEY) keys the HMAC stored refresh-token,
+	// access-token, code and email-change hashes use; empty keeps plain
+	// SHA-256.
+	TokenHashKey string
+	// PasswordDenylist holds the lowercased entries of PASSWORD_DENYLIST_PATH;
+	// nil (the default) disables the check.
+	PasswordDenylist map[string]bool
+	// BootstrapAppName is the app whose client_id GET /api/bootstrap-info
+	// publishes to the frontend; empty means the seed's default app.
+	BootstrapAppName string
+	// RevealDisabledStatus makes password login answer user_disabled for a
+	// disabled account; by default it gets invalid_credentials like a wrong
+	// password, so login can't be used to find disabled accounts.
+	RevealDisabledStatus bool
+	// AdminAPIKey is a static key accepted in X-Admin-Key on /admin routes,
+	// for automation and CI. Empty disables it.
+	AdminAPIKey string
+	// SecurityLogTarget is where structured authentication-outcome lines
+	// go: "stdout", "stderr" or a file path. Empty disables them.
+	SecurityLogTarget string
+	// StepUpMaxAgeSecs requires the access token behind a sensitive account
+	// change (email change, unlinking a provider, deleting the account) to
+	// come from a credential check at most this old. 0 skips the check.
+	StepUpMaxAgeSecs int64
+	// MaxPageSize caps per_page on every paginated endpoint.
+	MaxPageSize uint64
+}
+
+// ScopeInfo is the user-facing description of a scope.
+type ScopeInfo struct {
+	DisplayName string `json:"display_name"`
+	Description string `json:"description"`
+}
+
+// Roles configures the user roles the service accepts and what they may do in
+// the admin API. Admin roles are superusers holding every permission;
+// read-only admin roles hold every ":read" permission; Permissions grants
+// individual permissions per role on top of that.
+type Roles struct {
+	Default       string
+	Allowed       []string
+	Admin         []string
+	ReadOnlyAdmin []string
+	Permissions   map[string][]string
+}
+
+// DefaultRoles is the built-in role set: "user" by default, "admin" as the
+// full admin, and "support" with read-only admin access.
+func DefaultRoles() Roles {
+	return Roles{
+		Default:       domain.RoleUser,
+		Allowed:       []string{domain.RoleUser, domain.RoleAdmin, domain.RoleSupport},
+		Admin:         []string{domain.RoleAdmin},
+		ReadOnlyAdmin: []string{domain.RoleSupport},
+		Permissions:   map[string][]string{},
+	}
+}
+
+// Valid reports whether role is on the allowlist.
+func (r Roles) Valid(role string) bool { return contains(r.Allowed, role) }
+
+// IsPrivileged reports whether role grants any admin API access.
+func (r Roles) IsPrivileged(role string) bool {
+	return contains(r.Admin, role) || contains(r.ReadOnlyAdmin, role) || len(r.Permissions[role]) > 0
+}
+
+// HasPermission reports whether role grants the admin API permission perm.
+func (r Roles) HasPermission(role, perm string) bool {
+	if contains(r.Admin, role) {
+		return true
+	}
+	if contains(r.ReadOnlyAdmin, role) && strings.HasSuffix(perm, ":read") {
+		return true
+	}
+	return contains(r.Permissions[role], perm)
+}
+
+// CanAssign reports whether a caller holding role assigner may give a user
+// role: every admin API permission role grants, assigner must hold too.
+func (r Roles) CanAssign(assigner, role string) bool {
+	for _, perm := range domain.AdminPermissions {
+		if r.HasPermission(role, perm) && !r.HasPermission(assigner, perm) {
+			return false
+		}
+	}
+	return true
+}
+
+func (r Roles) validate() error {
+	if !r.Valid(r.Default) {
+		return fmt.Errorf("ROLES_DEFAULT %q is not in ROLES_ALLOWED", r.Default)
+	}
+	for _, role := range append(append([]string{}, r.Admin...), r.ReadOnlyAdmin...) {
+		if !r.Valid(role) {
+			return fmt.Errorf("admin role %q is not in ROLES_ALLOWED", role)
+		}
+	}
+	for role, perms := range r.Permissions {
+		if !r.Valid(role) {
+			return fmt.Errorf("ROLE_PERMISSIONS role %q is not in ROLES_ALLOWED", role)
+		}
+		for _, perm := range perms {
+			if !contains(domain.AdminPermissions, perm) {
+				return fmt.Errorf("ROLE_PERMISSIONS: unknown permission %q for role %q", perm, role)
+			}
+		}
+	}
+	return nil
+}
+
+func contains(list []string, s string) bool {
+	for _, v := range list {
+		if v == s {
+			return true
+		}
+	}
+	return false
 }
 
 const (
@@ -31,6 +270,16 @@ const (
 	StorageBackendMySQL      = "mysql"
 )
 
+const (
+	AccessTokenFormatJWT    = "jwt"
+	AccessTokenFormatOpaque = "opaque"
+)
+
+const (
+	ErrorFormatJSON        = "json"
+	ErrorFormatProblemJSON = "problemjson"
+)
+
 // FromEnv builds a Config from environment variables. Storage defaults to
 // MySQL when MYSQL_DSN is present, otherwise Azure Tables for rollback
 // compatibility during the migration window.
@@ -59,24 +308,256 @@ func FromEnv() (*Config, error) {
 	default:
 		return nil, fmt.Errorf("unsupported STORAGE_BACKEND %q", backend)
 	}
+	refreshDays := envInt64("JWT_REFRESH_TOKEN_EXPIRY_DAYS", 30)
+	defaults := DefaultRoles()
+	roles := Roles{
+		Default:       EnvOr("ROLES_DEFAULT", defaults.Default),
+		Allowed:       envList("ROLES_ALLOWED", defaults.Allowed),
+		Admin:         envList("ROLES_ADMIN", defaults.Admin),
+		ReadOnlyAdmin: envList("ROLES_ADMIN_READONLY", defaults.ReadOnlyAdmin),
+		Permissions:   envRolePermissions("ROLE_PERMISSIONS"),
+	}
+	if err := roles.validate(); err != nil {
+		return nil, err
+	}
+	var minIssuedAt int64
+	if v := strings.TrimSpace(os.Getenv("JWT_MIN_IAT")); v != "" {
+		n, err := strconv.ParseInt(v, 10, 64)
+		if err != nil {
+			t, err := time.Parse(time.RFC3339, v)
+			if err != nil {
+				return nil, fmt.Errorf("JWT_MIN_IAT must be unix seconds or an RFC 3339 timestamp, got %q", v)
+			}
+			n = t.Unix()
+		}
+		minIssuedAt = n
+	}
+	captchaEnabled := envBool("CAPTCHA_ENABLED", false)
+	captchaProvider := EnvOr("CAPTCHA_PROVIDER", captcha.ProviderTurnstile)
+	if captchaEnabled {
+		if captchaProvider != captcha.ProviderTurnstile && captchaProvider != captcha.ProviderHCaptcha {
+			return nil, fmt.Errorf("unsupported CAPTCHA_PROVIDER %q (want turnstile or hcaptcha)", captchaProvider)
+		}
+		if os.Getenv("CAPTCHA_SECRET") == "" {
+			return nil, fmt.Errorf("CAPTCHA_SECRET is required when CAPTCHA_ENABLED=true")
+		}
+	}
+	tokenFormat := EnvOr("ACCESS_TOKEN_FORMAT", AccessTokenFormatJWT)
+	if tokenFormat != AccessTokenFormatJWT && tokenFormat != AccessTokenFormatOpaque {
+		return nil, fmt.Errorf("unsupported ACCESS_TOKEN_FORMAT %q (want jwt or opaque)", tokenFormat)
+	}
+	scopeRegistry := map[string]ScopeInfo{}
+	if v := strings.TrimSpace(os.Getenv("SCOPE_REGISTRY")); v != "" {
+		if err := json.Unmarshal([]byte(v), &scopeRegistry); err != nil {
+			return nil, fmt.Errorf("SCOPE_REGISTRY must be a JSON object of scope to {display_name, description}: %v", err)
+		}
+	}
+	scopeRegistryStrict := envBool("SCOPE_REGISTRY_STRICT", false)
+	if scopeRegistryStrict && len(scopeRegistry) == 0 {
+		return nil, fmt.Errorf("SCOPE_REGISTRY is required when SCOPE_REGISTRY_STRICT=true")
+	}
+	if os.Getenv("SMTP_HOST") != "" && (os.Getenv("SMTP_FROM") == "" || os.Getenv("EMAIL_CONFIRM_URL") == "") {
+		return nil, fmt.Errorf("SMTP_FROM and EMAIL_CONFIRM_URL are required when SMTP_HOST is set")
+	}
+	locales, err := i18n.Load(os.Getenv("LOCALES_DIR"))
+	if err != nil {
+		return nil, fmt.Errorf("LOCALES_DIR: %v", err)
+	}
+	dataKeys, err := envDataKeys()
+	if err != nil {
+		return nil, err
+	}
+	passwordDenylist, err := loadPasswordDenylist(os.Getenv("PASSWORD_DENYLIST_PATH"))
+	if err != nil {
+		return nil, fmt.Errorf("PASSWORD_DENYLIST_PATH: %v", err)
+	}
+	exemptCIDRs := envList("RATE_LIMIT_EXEMPT_CIDRS", []string{})
+	for _, entry := range exemptCIDRs {
+		if _, _, err := net.ParseCIDR(entry); err != nil && net.ParseIP(entry) == nil {
+			return nil, fmt.Errorf("RATE_LIMIT_EXEMPT_CIDRS: %q is not an IP or CIDR", entry)
+		}
+	}
+	trustedProxies := envList("TRUSTED_PROXIES", []string{})
+	for _, entry := range trustedProxies {
+		if _, _, err := net.ParseCIDR(entry); err != nil && net.ParseIP(entry) == nil {
+			return nil, fmt.Errorf("TRUSTED_PROXIES: %q is not an IP or CIDR", entry)
+		}
+	}
+	publicBaseURL := strings.TrimSuffix(os.Getenv("PUBLIC_BASE_URL"), "/")
+	if publicBaseURL != "" {
+		u, err := url.Parse(publicBaseURL)
+		if err != nil || (u.Scheme != "https" && u.Scheme != "http") || u.Host == "" || u.Path != "" {
+			return nil, fmt.Errorf("PUBLIC_BASE_URL must be an http(s) scheme and host, got %q", publicBaseURL)
+		}
+	}
+	trustedFactor := envInt64("RATE_LIMIT_TRUSTED_FACTOR", 10)
+	if trustedFactor < 1 {
+		return nil, fmt.Errorf("RATE_LIMIT_TRUSTED_FACTOR must be at least 1")
+	}
+	maxPageSize := envInt64("MAX_PAGE_SIZE", 100)
+	if maxPageSize < 1 {
+		return nil, fmt.Errorf("MAX_PAGE_SIZE must be at least 1")
+	}
+	loginMaxFailed := envInt64("LOGIN_MAX_FAILED_ATTEMPTS", 0)
+	loginPairMax := envInt64("LOGIN_PAIR_MAX_FAILURES", 0)
+	if loginPairMax > 0 && loginMaxFailed > 0 && loginMaxFailed <= loginPairMax {
+		return nil, fmt.Errorf("LOGIN_MAX_FAILED_ATTEMPTS must be higher than LOGIN_PAIR_MAX_FAILURES")
+	}
+	attrsAdminOnly := envList("CUSTOM_ATTRIBUTES_ADMIN_ONLY", []string{})
+	attrsTokenClaims := envList("CUSTOM_ATTRIBUTES_TOKEN_CLAIMS", []string{})
+	for _, key := range attrsTokenClaims {
+		if !contains(attrsAdminOnly, key) {
+			return nil, fmt.Errorf("CUSTOM_ATTRIBUTES_TOKEN_CLAIMS: %q must also be listed in CUSTOM_ATTRIBUTES_ADMIN_ONLY", key)
+		}
+	}
+	errorFormat := EnvOr("ERROR_FORMAT", ErrorFormatJSON)
+	if errorFormat != ErrorFormatJSON && errorFormat != ErrorFormatProblemJSON {
+		return nil, fmt.Errorf("unsupported ERROR_FORMAT %q (want json or problemjson)", errorFormat)
+	}
 	return &Config{
-		StorageBackend:               backend,
-		AzureStorageConnectionString: conn,
-		MySQLDSN:                     mysqlDSN,
-		MySQLTLSCAPEM:                mysqlTLSCAPEM,
-		MySQLTLSCAPath:               mysqlTLSCAPath,
-		JWTPrivateKeyPath:            EnvOr("JWT_PRIVATE_KEY_PATH", "keys/private.pem"),
-		JWTPublicKeyPath:             EnvOr("JWT_PUBLIC_KEY_PATH", "keys/public.pem"),
-		JWTIssuer:                    EnvOr("JWT_ISSUER", "auth-service"),
-		JWTAccessTokenExpirySecs:     envInt64("JWT_ACCESS_TOKEN_EXPIRY_SECS", 3600),
-		JWTRefreshTokenExpiryDays:    envInt64("JWT_REFRESH_TOKEN_EXPIRY_DAYS", 30),
-		ServerHost:                   EnvOr("SERVER_HOST", "127.0.0.1"),
-		ServerPort:                   int(envInt64("SERVER_PORT", 3000)),
-		CORSAllowedOrigins:           EnvOr("CORS_ALLOWED_ORIGINS", "http://localhost:5173,http://localhost:3000"),
-		EnableTestProviders:          envBool("AUTH_ENABLE_TEST_PROVIDERS", false),
+		StorageBackend:                      backend,
+		AzureStorageConnectionString:        conn,
+		MySQLDSN:                            mysqlDSN,
+		MySQLTLSCAPEM:                       mysqlTLSCAPEM,
+		MySQLTLSCAPath:                      mysqlTLSCAPath,
+		JWTPrivateKeyPath:                   EnvOr("JWT_PRIVATE_KEY_PATH", "keys/private.pem"),
+		JWTPublicKeyPath:                    EnvOr("JWT_PUBLIC_KEY_PATH", "keys/public.pem"),
+		JWTIssuer:                           EnvOr("JWT_ISSUER", "auth-service"),
+		JWTAccessTokenExpirySecs:            envInt64("JWT_ACCESS_TOKEN_EXPIRY_SECS", 3600),
+		JWTRefreshTokenExpiryDays:           refreshDays,
+		ServerHost:                          EnvOr("SERVER_HOST", "127.0.0.1"),
+		ServerPort:                          int(envInt64("SERVER_PORT", 3000)),
+		CORSAllowedOrigins:                  EnvOr("CORS_ALLOWED_ORIGINS", "http://localhost:5173,http://localhost:3000"),
+		EnableTestProviders:                 envBool("AUTH_ENABLE_TEST_PROVIDERS", false),
+		JWTRefreshTokenShortExpiryDays:      envInt64("JWT_REFRESH_TOKEN_SHORT_EXPIRY_DAYS", 1),
+		JWTRefreshTokenRememberMeExpiryDays: envInt64("JWT_REFRESH_TOKEN_REMEMBER_ME_EXPIRY_DAYS", refreshDays),
+		JWTKeysRequireAdmin:                 envBool("JWT_KEYS_REQUIRE_ADMIN", false),
+		JWTMinIssuedAt:                      minIssuedAt,
+		AuditWebhookURL:                     os.Getenv("AUDIT_WEBHOOK_URL"),
+		RefreshReuseForcePasswordChange:     envBool("REFRESH_REUSE_FORCE_PASSWORD_CHANGE", false),
+		Roles:                               roles,
+		AccessTokenFormat:                   tokenFormat,
+		RequestTimeoutSecs:                  envInt64("REQUEST_TIMEOUT_SECS", 30),
+		ProviderRequestTimeoutSecs:          envInt64("PROVIDER_REQUEST_TIMEOUT_SECS", 60),
+		ProviderHTTPConnectTimeoutSecs:      envInt64("PROVIDER_HTTP_CONNECT_TIMEOUT_SECS", 5),
+		ProviderHTTPTimeoutSecs:             envInt64("PROVIDER_HTTP_TIMEOUT_SECS", 10),
+		LoginMaxFailedAttempts:              loginMaxFailed,
+		LoginLockoutSecs:                    envInt64("LOGIN_LOCKOUT_SECS", 900),
+		LoginPairMaxFailures:                loginPairMax,
+		LoginPairFailureWindowSecs:          envInt64("LOGIN_PAIR_FAILURE_WINDOW_SECS", 900),
+		LoginPairBlockSecs:                  envInt64("LOGIN_PAIR_BLOCK_SECS", 900),
+		CaptchaEnabled:                      captchaEnabled,
+		CaptchaProvider:                     captchaProvider,
+		CaptchaSecret:                       os.Getenv("CAPTCHA_SECRET"),
+		CustomAttributesAdminOnly:           attrsAdminOnly,
+		CustomAttributesTokenClaims:         attrsTokenClaims,
+		HSTSMaxAgeSecs:                      envInt64("HSTS_MAX_AGE_SECS", 0),
+		RequireHTTPS:                        envBool("REQUIRE_HTTPS", false),
+		IdempotencyTTLSecs:                  envInt64("IDEMPOTENCY_TTL_SECS", 600),
+		LinkReauthMaxAgeSecs:                envInt64("LINK_REAUTH_MAX_AGE_SECS", 0),
+		ErrorFormat:                         errorFormat,
+		ScopeRegistry:                       scopeRegistry,
+		ScopeRegistryStrict:                 scopeRegistryStrict,
+		NonRefreshableScopes:                envList("NON_REFRESHABLE_SCOPES", []string{}),
+		SMTPHost:                            os.Getenv("SMTP_HOST"),
+		SMTPPort:                            int(envInt64("SMTP_PORT", 587)),
+		SMTPUsername:                        os.Getenv("SMTP_USERNAME"),
+		SMTPPassword:                        os.Getenv("SMTP_PASSWORD"),
+		SMTPFrom:                            os.Getenv("SMTP_FROM"),
+		EmailConfirmURL:                     os.Getenv("EMAIL_CONFIRM_URL"),
+		EmailChangeTokenTTLSecs:             envInt64("EMAIL_CHANGE_TOKEN_TTL_SECS", 86400),
+		AdminAuthMaxFailures:                envInt64("ADMIN_AUTH_MAX_FAILURES", 20),
+		AdminAuthFailureWindowSecs:          envInt64("ADMIN_AUTH_FAILURE_WINDOW_SECS", 300),
+		AdminAuthBlockSecs:                  envInt64("ADMIN_AUTH_BLOCK_SECS", 3600),
+		DefaultScopes:                       envList("DEFAULT_SCOPES", []string{}),
+		Locales:                             locales,
+		RateLimitExemptCIDRs:                exemptCIDRs,
+		RateLimitTrustedClientIDs:           envList("RATE_LIMIT_TRUSTED_CLIENT_IDS", []string{}),
+		RateLimitTrustedFactor:              trustedFactor,
+		TrustedProxies:                      trustedProxies,
+		PublicBaseURL:                       publicBaseURL,
+		DataKeys:                            dataKeys,
+		TokenHashKey:                        os.Getenv("TOKEN_HASH_KEY"),
+		PasswordDenylist:                    passwordDenylist,
+		BootstrapAppName:                    os.Getenv("BOOTSTRAP_APP_NAME"),
+		RevealDisabledStatus:                envBool("REVEAL_DISABLED_STATUS", false),
+		AdminAPIKey:                         os.Getenv("ADMIN_API_KEY"),
+		SecurityLogTarget:                   os.Getenv("SECURITY_LOG_TARGET"),
+		StepUpMaxAgeSecs:                    envInt64("STEP_UP_MAX_AGE_SECS", 0),
+		MaxPageSize:                         uint64(maxPageSize),
 	}, nil
 }
 
+// DataKeys is the AES-256-GCM key ring for data sealed at rest. Version is
+// the key new values are sealed with (0: no DATA_ENCRYPTION_KEY, so nothing
+// is sealed); Keys holds every key that can open stored values, by version.
+type DataKeys struct {
+	Version int
+	Keys    map[int][]byte
+}
+
+// envDataKeys reads DATA_ENCRYPTION_KEY (base64, 32 bytes) as the current
+// key, numbered DATA_ENCRYPTION_KEY_VERSION (default 1), plus retired keys
+// from DATA_ENCRYPTION_OLD_KEYS ("1:<base64>,2:<base64>") that stay readable
+// during a rotation. A malformed key is an error rather than a silent
+// fallback to storing values in the clear.
+func envDataKeys() (DataKeys, error) {
+	keys := DataKeys{Keys: map[int][]byte{}}
+	for i, entry := range envList("DATA_ENCRYPTION_OLD_KEYS", nil) {
+		v, k, _ := strings.Cut(entry, ":")
+		version, err := strconv.Atoi(v)
+		key := decodeDataKey(k)
+		if err != nil || version < 1 || key == nil {
+			return DataKeys{}, fmt.Errorf("DATA_ENCRYPTION_OLD_KEYS: entry %d is not <version>:<base64 32-byte key>", i+1)
+		}
+		keys.Keys[version] = key
+	}
+	raw := strings.TrimSpace(os.Getenv("DATA_ENCRYPTION_KEY"))
+	if raw == "" {
+		return keys, nil
+	}
+	key := decodeDataKey(raw)
+	if key == nil {
+		return DataKeys{}, fmt.Errorf("DATA_ENCRYPTION_KEY must be a base64-encoded 32-byte key")
+	}
+	version, err := strconv.Atoi(EnvOr("DATA_ENCRYPTION_KEY_VERSION", "1"))
+	if err != nil || version < 1 {
+		return DataKeys{}, fmt.Errorf("DATA_ENCRYPTION_KEY_VERSION must be a positive integer")
+	}
+	keys.Version = version
+	keys.Keys[version] = key
+	return keys, nil
+}
+
+func decodeDataKey(s string) []byte {
+	key, err := base64.StdEncoding.DecodeString(s)
+	if err != nil || len(key) != 32 {
+		return nil
+	}
+	return key
+}
+
+// loadPasswordDenylist reads one password per line, skipping blank lines and
+// # comments. An empty path yields nil.
+func loadPasswordDenylist(path string) (map[string]bool, error) {
+	if path == "" {
+		return nil, nil
+	}
+	data, err := os.ReadFile(path)
+	if err != nil {
+		return nil, err
+	}
+	list := map[string]bool{}
+	for _, line := range strings.Split(string(data), "\n") {
+		line = strings.TrimSpace(line)
+		if line == "" || strings.HasPrefix(line, "#") {
+			continue
+		}
+		list[strings.ToLower(line)] = true
+	}
+	return list, nil
+}
+
 // Addr returns the host:port the server should bind to.
 func (c *Config) Addr() string {
 	return fmt.Sprintf("%s:%d", c.ServerHost, c.ServerPort)
@@ -98,6 +579,41 @@ func envInt64(key string, def int64) int64 {
 	return def
 }
 
+// envList reads a comma-separated list, trimming blanks. An unset variable
+// yields def; a set-but-empty one yields an empty list.
+func envList(key string, def []string) []string {
+	v, ok := os.LookupEnv(key)
+	if !ok {
+		return def
+	}
+	out := []string{}
+	for _, part := range strings.Split(v, ",") {
+		if part = strings.TrimSpace(part); part != "" {
+			out = append(out, part)
+		}
+	}
+	return out
+}
+
+// envRolePermissions parses "role=perm,perm;role=perm" into a role ->
+// permissions map. Malformed entries are skipped.
+func envRolePermissions(key string) map[string][]string {
+	out := map[string][]string{}
+	for _, entry := range strings.Split(os.Getenv(key), ";") {
+		role, perms, ok := strings.Cut(entry, "=")
+		role = strings.TrimSpace(role)
+		if !ok || role == "" {
+			continue
+		}
+		for _, perm := range strings.Split(perms, ",") {
+			if perm = strings.TrimSpace(perm); perm != "" {
+				out[role] = append(out[role], perm)
+			}
+		}
+	}
+	return out
+}
+
 func envBool(key string, def bool) bool {
 	if v := os.Getenv(key); v != "" {
 		switch v {
@@ -56,6 +56,29 @@ func UserTypeFromString(s string) UserType {
 // Valid reports whether this value is a supported user type.
 func (t UserType) Valid() bool { return t == UserTypeRegular || t == UserTypeTesting }
 
+// LoginIdentifier is what an application's users sign in with alongside their
+// password.
+type LoginIdentifier string
+
+const (
+	LoginIdentifierEmail    LoginIdentifier = "email"
+	LoginIdentifierUsername LoginIdentifier = "username"
+)
+
+// LoginIdentifierFromString parses the stored value, defaulting to Email so
+// apps that predate the setting keep email login.
+func LoginIdentifierFromString(s string) LoginIdentifier {
+	if s == string(LoginIdentifierUsername) {
+		return LoginIdentifierUsername
+	}
+	return LoginIdentifierEmail
+}
+
+// Valid reports whether this value is a supported login identifier.
+func (l LoginIdentifier) Valid() bool {
+	return l == LoginIdentifierEmail || l == LoginIdentifierUsername
+}
+
 // InviteCodeKind is the reuse policy of an invite code.
 //
 // SingleUse codes are consumed by the first successful registration and then
@@ -79,6 +102,37 @@ func InviteKindFromString(s string) InviteCodeKind {
 	}
 }
 
+// Built-in role names. The allowlist and which roles carry admin privileges
+// are configured via config.Roles.
+const (
+	RoleUser    = "user"
+	RoleAdmin   = "admin"
+	RoleSupport = "support"
+)
+
+// Admin API permissions. Full admin roles hold all of them; other roles are
+// granted them via config.Roles.
+const (
+	PermUsersRead         = "users:read"
+	PermUsersWrite        = "users:write"
+	PermApplicationsRead  = "applications:read"
+	PermApplicationsWrite = "applications:write"
+	PermInviteCodesRead   = "invite_codes:read"
+	PermInviteCodesWrite  = "invite_codes:write"
+	PermTeamsWrite        = "teams:write"
+	PermStatsRead         = "stats:read"
+	PermTokensWrite       = "tokens:write"
+)
+
+// AdminPermissions lists every known admin API permission.
+var AdminPermissions = []string{
+	PermUsersRead, PermUsersWrite,
+	PermApplicationsRead, PermApplicationsWrite,
+	PermInviteCodesRead, PermInviteCodesWrite,
+	PermTeamsWrite, PermStatsRead,
+	PermTokensWrite,
+}
+
 // LoginRecord is a single login event (timestamp + IP).
 type LoginRecord struct {
 	At time.Time
@@ -92,7 +146,7 @@ type User struct {
 	Name          *string
 	AvatarURL     *string
 	EmailVerified bool
-	Role          string // "user" | "admin"
+	Role          string // one of the configured roles, e.g. "user" | "admin"
 	// UserType classifies whether this is a normal account or a testing account.
 	UserType UserType
 	IsActive bool
@@ -115,6 +169,28 @@ type User struct {
 	// MembershipExpiresAt is when a paid membership lapses. Nil means no expiry
 	// (permanent grant, or a Regular user).
 	MembershipExpiresAt *time.Time
+	// PasswordChangeRequired is set when the account must pick a new password,
+	// e.g. after refresh-token reuse was detected. Cleared by a password reset.
+	PasswordChangeRequired bool
+	// FailedLoginAttempts counts consecutive wrong passwords since the last
+	// successful login or lockout.
+	FailedLoginAttempts int
+	// LockedUntil blocks password login until it passes. Nil when unlocked.
+	LockedUntil *time.Time
+	// PendingEmail is the address the user asked to switch to. Email stays
+	// in use until the link carrying the token hashed in EmailChangeTokenHash
+	// is confirmed before EmailChangeExpiresAt.
+	PendingEmail         *string
+	EmailChangeTokenHash *string
+	EmailChangeExpiresAt *time.Time
+	// Username is an optional login name, unique regardless of case. Apps
+	// with username login look users up by it instead of by email.
+	Username *string
+}
+
+// IsLocked reports whether password login is locked out as of now.
+func (u *User) IsLocked(now time.Time) bool {
+	return u.LockedUntil != nil && u.LockedUntil.After(now)
 }
 
 // IsMembershipExpired reports whether a paid membership has lapsed as of now.
@@ -142,11 +218,74 @@ type Application struct {
 	ClientSecretHash string
 	RedirectURIs     string // JSON-encoded array
 	AllowedScopes    string // JSON-encoded array
-	IsActive         bool
+	// PreviousClientSecretHash is the secret a rotation with a grace period
+	// replaced. It keeps working until PreviousClientSecretExpiresAt so a
+	// fleet can switch over without an outage.
+	PreviousClientSecretHash      string
+	PreviousClientSecretExpiresAt *time.Time
+	// CustomClaims is a JSON-encoded object copied into the "ext" claim of
+	// every token issued for this app. Empty means none.
+	CustomClaims string
+	IsActive     bool
+	// AllowSelfRegistration lets end users sign themselves up through this
+	// app. When false, accounts come from the admin API only.
+	AllowSelfRegistration bool
+	// AllowedEmailDomains is a JSON-encoded array of the email domains users
+	// of this app may sign up with. Empty means any domain.
+	AllowedEmailDomains string
+	// DefaultScopes is a JSON-encoded subset of AllowedScopes granted when a
+	// client asks for no scopes. Empty means every allowed scope.
+	DefaultScopes string
+	// IncludeEmailClaim puts the user's email into access tokens granted the
+	// "email" scope. Off by default to keep PII out of tokens.
+	IncludeEmailClaim bool
+	// ClientPublicKey is a PEM public key (RSA or EC) the app signs
+	// private_key_jwt client assertions with. Empty disables that method.
+	ClientPublicKey string
+	// RotateRefreshTokens issues a new refresh token on every refresh (the
+	// default). When false the client keeps one token whose expiry is
+	// extended instead, for clients that can't store a changing token.
+	RotateRefreshTokens bool
+	// LoginIdentifier selects whether password login (and the password
+	// grant) identifies users by email, the default, or by username.
+	LoginIdentifier LoginIdentifier
+	// AccessTokenCookie makes the /api/auth token endpoints also set the
+	// access token as an HttpOnly cookie, for same-site web apps.
+	AccessTokenCookie bool
+	// IssueRefreshTokens hands out refresh tokens alongside access tokens (the
+	// default). API-only clients turn it off to get access tokens alone.
+	IssueRefreshTokens bool
+	// Audiences is a JSON-encoded array of extra aud values (e.g. the URL of
+	// an API) that access tokens carry after the client_id. Empty keeps aud
+	// a single string.
+	Audiences string
+	// RequireProviderEmail refuses to create a user from a provider login
+	// that brought no email (WeChat, Apple with a hidden address) until the
+	// client supplies one.
+	RequireProviderEmail bool
+	// Issuer, when set, replaces JWT_ISSUER as the iss of the app's user
+	// access tokens (an https URL per brand); nil uses the default.
+	Issuer *string
+	// CreatedBy and UpdatedBy are the user ids of the admins who created and
+	// last changed the app; nil for seeded apps and changes made before they
+	// were tracked.
+	CreatedBy *string
+	UpdatedBy *string
+	// Branding for hosted login and consent pages; nil when unset.
+	LogoURL          *string
+	HomepageURL      *string
+	PrivacyPolicyURL *string
+	TermsURL         *string
 	CreatedAt        time.Time
 	UpdatedAt        time.Time
 }
 
+// PreviousSecretActive reports whether the replaced client secret is still
+// inside its grace period at now.
+func (a *Application) PreviousSecretActive(now time.Time) bool {
+	return a.PreviousClientSecretHash != "" && a.PreviousClientSecretExpiresAt != nil && now.Before(*a.PreviousClientSecretExpiresAt)
+}
+
 // AppProvider is an auth-provider configuration attached to an Application.
 type AppProvider struct {
 	ID         string
@@ -155,6 +294,17 @@ type AppProvider struct {
 	Config     string // JSON-encoded provider config
 	IsActive   bool
 	CreatedAt  time.Time
+	// Display metadata for login UIs; SortOrder ascends (ties keep creation order).
+	DisplayName *string
+	IconURL     *string
+	SortOrder   int
+	// CreatedBy is the user id of the admin who added the provider. Providers
+	// are never edited in place, so there is no UpdatedBy.
+	CreatedBy *string
+	// EmailDomains is a JSON-encoded array of the email domains whose users
+	// sign in through this provider (home-realm discovery). A domain maps to
+	// at most one provider of an app.
+	EmailDomains string
 }
 
 // Account links a user to a provider identity (and, for password, a credential).
@@ -167,11 +317,14 @@ type Account struct {
 	ProviderMetadata  string // JSON-encoded
 	CreatedAt         time.Time
 	UpdatedAt         time.Time
+	// ProviderTokens is the sealed (auth.SealSecret) JSON of the provider's
+	// OAuth tokens. Backend-only; never surfaced via any API.
+	ProviderTokens *string
 }
 
 // AuthorizationCode is a short-lived OAuth2 authorization code (with PKCE).
 type AuthorizationCode struct {
-	Code                string
+	Code                string // hashed like refresh tokens (auth.HashToken)
 	AppID               string
 	UserID              string
 	RedirectURI         string
@@ -183,16 +336,30 @@ type AuthorizationCode struct {
 	CreatedAt           time.Time
 }
 
-// RefreshToken is a hashed, rotating refresh token.
+// RefreshToken is a hashed, rotating refresh token. ExpiryDays is the
+// lifetime the session was granted at login (e.g. "remember me"), carried
+// over to every replacement; 0 on tokens stored before it was recorded.
 type RefreshToken struct {
-	ID        string
-	UserID    string
-	AppID     string
+	ID         string
+	UserID     string
+	AppID      string
+	TokenHash  string
+	Scopes     string // JSON-encoded array
+	DeviceID   *string
+	ExpiresAt  time.Time
+	ExpiryDays int64
+	Revoked    bool
+	CreatedAt  time.Time
+}
+
+// AccessToken is the server-side record behind an opaque access token
+// (ACCESS_TOKEN_FORMAT=opaque). Only the token's hash is stored; Claims holds
+// the JSON payload a JWT would otherwise carry.
+type AccessToken struct {
 	TokenHash string
-	Scopes    string // JSON-encoded array
-	DeviceID  *string
+	Subject   string // user id, or app id for client-credentials tokens
+	Claims    string // JSON-encoded
 	ExpiresAt time.Time
-	Revoked   bool
 	CreatedAt time.Time
 }
 
@@ -233,3 +400,17 @@ type TeamMembership struct {
 	Role     string
 	JoinedAt time.Time
 }
+
+// AuditEvent is a persisted audit record (see package audit). UserID is the
+// user who acted, or the one an automatic event concerns; TargetID is the user
+// an admin acted on.
+type AuditEvent struct {
+	ID        string
+	Type      string
+	UserID    string
+	TargetID  string
+	AppID     string
+	IP        string
+	Details   string // JSON object
+	CreatedAt time.Time
+}
@@ -2,7 +2,12 @@ package handlers
 
 import (
 	"encoding/json"
+	"errors"
+	"fmt"
+	"io"
 	"net/http"
+	"net/url"
+	"slices"
 	"strconv"
 	"strings"
 	"time"
@@ -12,86 +17,224 @@ import (
 	"github.com/google/uuid"
 
 	"github.com/zhaochy1990/auth-service/internal/apperror"
+	"github.com/zhaochy1990/auth-service/internal/audit"
 	"github.com/zhaochy1990/auth-service/internal/auth"
+	"github.com/zhaochy1990/auth-service/internal/auth/providers"
 	"github.com/zhaochy1990/auth-service/internal/domain"
 	"github.com/zhaochy1990/auth-service/internal/middleware"
 	"github.com/zhaochy1990/auth-service/internal/repository"
+	"github.com/zhaochy1990/auth-service/internal/securitylog"
 )
 
 // --- Request / Response types ---
 
 type createApplicationRequest struct {
-	Name          string   `json:"name"`
-	RedirectURIs  []string `json:"redirect_uris"`
-	AllowedScopes []string `json:"allowed_scopes"`
+	Name                  string         `json:"name"`
+	RedirectURIs          []string       `json:"redirect_uris"`
+	AllowedScopes         []string       `json:"allowed_scopes"`
+	CustomClaims          map[string]any `json:"custom_claims"`
+	AllowSelfRegistration *bool          `json:"allow_self_registration"` // default true
+	AllowedEmailDomains   []string       `json:"allowed_email_domains"`
+	DefaultScopes         []string       `json:"default_scopes"`
+	IncludeEmailClaim     bool           `json:"include_email_claim"`
+	ClientPublicKey       string         `json:"client_public_key"`     // PEM; enables private_key_jwt
+	RotateRefreshTokens   *bool          `json:"rotate_refresh_tokens"` // default true
+	// LoginIdentifier is "email" (the default) or "username".
+	LoginIdentifier    domain.LoginIdentifier `json:"login_identifier"`
+	AccessTokenCookie  bool                   `json:"access_token_cookie"`
+	IssueRefreshTokens *bool                  `json:"issue_refresh_tokens"` // default true
+	Audiences          []string               `json:"audiences"`
+	Issuer             string                 `json:"issuer"` // https URL; empty uses JWT_ISSUER
+	// RequireProviderEmail makes a provider sign-up that brings no email
+	// fail with email_required.
+	RequireProviderEmail bool `json:"require_email_from_provider"`
+	appBranding
+	// Providers are configured along with the app; one invalid entry fails
+	// the whole request.
+	Providers []addProviderRequest `json:"providers"`
 }
 
 type createApplicationResponse struct {
-	ID            string   `json:"id"`
-	Name          string   `json:"name"`
-	ClientID      string   `json:"client_id"`
-	ClientSecret  string   `json:"client_secret"`
-	RedirectURIs  []string `json:"redirect_uris"`
-	AllowedScopes []string `json:"allowed_scopes"`
+	ID                    string             `json:"id"`
+	Name                  string             `json:"name"`
+	ClientID              string             `json:"client_id"`
+	ClientSecret          string             `json:"client_secret"`
+	RedirectURIs          []string           `json:"redirect_uris"`
+	AllowedScopes         []string           `json:"allowed_scopes"`
+	CustomClaims          map[string]any     `json:"custom_claims"`
+	AllowSelfRegistration bool               `json:"allow_self_registration"`
+	AllowedEmailDomains   []string           `json:"allowed_email_domains"`
+	DefaultScopes         []string           `json:"default_scopes"`
+	IncludeEmailClaim     bool               `json:"include_email_claim"`
+	ClientPublicKey       string             `json:"client_public_key,omitempty"`
+	RotateRefreshTokens   bool               `json:"rotate_refresh_tokens"`
+	LoginIdentifier       string             `json:"login_identifier"`
+	AccessTokenCookie     bool               `json:"access_token_cookie"`
+	IssueRefreshTokens    bool               `json:"issue_refresh_tokens"`
+	Audiences             []string           `json:"audiences"`
+	RequireProviderEmail  bool               `json:"require_email_from_provider"`
+	Issuer                *string            `json:"issuer"`
+	CreatedBy             *string            `json:"created_by"`
+	Providers             []providerResponse `json:"providers"`
+	appBranding
 }
 
 type updateApplicationRequest struct {
-	Name          *string   `json:"name"`
-	RedirectURIs  *[]string `json:"redirect_uris"`
-	AllowedScopes *[]string `json:"allowed_scopes"`
-	IsActive      *bool     `json:"is_active"`
+	Name                  *string         `json:"name"`
+	RedirectURIs          *[]string       `json:"redirect_uris"`
+	AllowedScopes         *[]string       `json:"allowed_scopes"`
+	CustomClaims          *map[string]any `json:"custom_claims"`
+	IsActive              *bool           `json:"is_active"`
+	AllowSelfRegistration *bool           `json:"allow_self_registration"`
+	AllowedEmailDomains   *[]string       `json:"allowed_email_domains"`
+	DefaultScopes         *[]string       `json:"default_scopes"`
+	IncludeEmailClaim     *bool           `json:"include_email_claim"`
+	ClientPublicKey       *string         `json:"client_public_key"` // "" removes it
+	RotateRefreshTokens   *bool           `json:"rotate_refresh_tokens"`
+	LoginIdentifier       *string         `json:"login_identifier"`
+	AccessTokenCookie     *bool           `json:"access_token_cookie"`
+	IssueRefreshTokens    *bool           `json:"issue_refresh_tokens"`
+	Audiences             *[]string       `json:"audiences"`
+	RequireProviderEmail  *bool           `json:"require_email_from_provider"`
+	Issuer                *string         `json:"issuer"` // "" removes it
+	// Branding URLs: omitted leaves a field as is, "" clears it.
+	appBranding
 }
 
 type applicationResponse struct {
-	ID            string   `json:"id"`
-	Name          string   `json:"name"`
-	ClientID      string   `json:"client_id"`
-	RedirectURIs  []string `json:"redirect_uris"`
-	AllowedScopes []string `json:"allowed_scopes"`
-	IsActive      bool     `json:"is_active"`
-	CreatedAt     string   `json:"created_at"`
+	ID                    string         `json:"id"`
+	Name                  string         `json:"name"`
+	ClientID              string         `json:"client_id"`
+	RedirectURIs          []string       `json:"redirect_uris"`
+	AllowedScopes         []string       `json:"allowed_scopes"`
+	CustomClaims          map[string]any `json:"custom_claims"`
+	IsActive              bool           `json:"is_active"`
+	AllowSelfRegistration bool           `json:"allow_self_registration"`
+	AllowedEmailDomains   []string       `json:"allowed_email_domains"`
+	DefaultScopes         []string       `json:"default_scopes"`
+	IncludeEmailClaim     bool           `json:"include_email_claim"`
+	ClientPublicKey       string         `json:"client_public_key,omitempty"`
+	RotateRefreshTokens   bool           `json:"rotate_refresh_tokens"`
+	LoginIdentifier       string         `json:"login_identifier"`
+	AccessTokenCookie     bool           `json:"access_token_cookie"`
+	IssueRefreshTokens    bool           `json:"issue_refresh_tokens"`
+	Audiences             []string       `json:"audiences"`
+	RequireProviderEmail  bool           `json:"require_email_from_provider"`
+	Issuer                *string        `json:"issuer"`
+	CreatedAt             string         `json:"created_at"`
+	CreatedBy             *string        `json:"created_by"`
+	UpdatedBy             *string        `json:"updated_by"`
+	appBranding
+}
+
+// appBranding is the client metadata a hosted login or consent page renders.
+type appBranding struct {
+	LogoURL          *string `json:"logo_url"`
+	HomepageURL      *string `json:"homepage_url"`
+	PrivacyPolicyURL *string `json:"privacy_policy_url"`
+	TermsURL         *string `json:"terms_url"`
+}
+
+func brandingOf(a *domain.Application) appBranding {
+	return appBranding{LogoURL: a.LogoURL, HomepageURL: a.HomepageURL, PrivacyPolicyURL: a.PrivacyPolicyURL, TermsURL: a.TermsURL}
+}
+
+// applyTo validates the URLs that are set and copies them onto a; "" clears
+// a field. Each must be an absolute http(s) URL.
+func (b appBranding) applyTo(a *domain.Application) error {
+	fields := []struct {
+		name string
+		in   *string
+		out  **string
+	}{
+		{"logo_url", b.LogoURL, &a.LogoURL},
+		{"homepage_url", b.HomepageURL, &a.HomepageURL},
+		{"privacy_policy_url", b.PrivacyPolicyURL, &a.PrivacyPolicyURL},
+		{"terms_url", b.TermsURL, &a.TermsURL},
+	}
+	for _, f := range fields {
+		if f.in == nil {
+			continue
+		}
+		v := strings.TrimSpace(*f.in)
+		if v == "" {
+			*f.out = nil
+			continue
+		}
+		u, err := url.Parse(v)
+		if err != nil || (u.Scheme != "https" && u.Scheme != "http") || u.Host == "" {
+			return apperror.BadRequest(f.name + " must be an absolute http(s) URL")
+		}
+		*f.out = &v
+	}
+	return nil
 }
 
 type addProviderRequest struct {
-	ProviderID string          `json:"provider_id"`
-	Config     json.RawMessage `json:"config"`
+	ProviderID   string          `json:"provider_id"`
+	Config       json.RawMessage `json:"config"`
+	DisplayName  *string         `json:"display_name"`
+	IconURL      *string         `json:"icon_url"`
+	SortOrder    int             `json:"sort_order"`
+	EmailDomains []string        `json:"email_domains"`
 }
 
 type providerResponse struct {
-	ID         string          `json:"id"`
-	ProviderID string          `json:"provider_id"`
-	Config     json.RawMessage `json:"config"`
-	IsActive   bool            `json:"is_active"`
-	CreatedAt  string          `json:"created_at"`
+	ID           string          `json:"id"`
+	ProviderID   string          `json:"provider_id"`
+	Config       json.RawMessage `json:"config"`
+	IsActive     bool            `json:"is_active"`
+	CreatedAt    string          `json:"created_at"`
+	DisplayName  *string         `json:"display_name"`
+	IconURL      *string         `json:"icon_url"`
+	SortOrder    int             `json:"sort_order"`
+	CreatedBy    *string         `json:"created_by"`
+	EmailDomains []string        `json:"email_domains"`
+}
+
+// rotateSecretRequest is the optional body of a secret rotation. A positive
+// grace_period_secs keeps the old secret valid that long.
+type rotateSecretRequest struct {
+	GracePeriodSecs int64 `json:"grace_period_secs"`
 }
 
 type rotateSecretResponse struct {
 	ClientID     string `json:"client_id"`
 	ClientSecret string `json:"client_secret"`
+	// PreviousSecretExpiresAt is when the replaced secret stops working;
+	// omitted when it stopped immediately.
+	PreviousSecretExpiresAt *string `json:"previous_secret_expires_at,omitempty"`
 }
 
+// maxSecretGracePeriodSecs bounds how long a rotated-out secret may live on.
+const maxSecretGracePeriodSecs = 30 * 24 * 60 * 60
+
 type loginRecordResponse struct {
 	At string `json:"at"`
 	IP string `json:"ip"`
 }
 
 type userResponse struct {
-	ID                  string                `json:"id"`
-	Email               *string               `json:"email"`
-	Name                *string               `json:"name"`
-	AvatarURL           *string               `json:"avatar_url"`
-	EmailVerified       bool                  `json:"email_verified"`
-	Role                string                `json:"role"`
-	UserType            domain.UserType       `json:"user_type"`
-	Membership          domain.MembershipTier `json:"membership"`
-	MembershipExpiresAt *string               `json:"membership_expires_at"`
-	IsActive            bool                  `json:"is_active"`
-	Note                *string               `json:"note"`
-	CustomAttributes    map[string]any        `json:"custom_attributes"`
-	CreatedAt           string                `json:"created_at"`
-	UpdatedAt           string                `json:"updated_at"`
-	LastLoginAt         *string               `json:"last_login_at"`
-	RecentLogins        []loginRecordResponse `json:"recent_logins"`
+	ID                     string                `json:"id"`
+	Email                  *string               `json:"email"`
+	Name                   *string               `json:"name"`
+	AvatarURL              *string               `json:"avatar_url"`
+	EmailVerified          bool                  `json:"email_verified"`
+	Role                   string                `json:"role"`
+	UserType               domain.UserType       `json:"user_type"`
+	Membership             domain.MembershipTier `json:"membership"`
+	MembershipExpiresAt    *string               `json:"membership_expires_at"`
+	IsActive               bool                  `json:"is_active"`
+	Note                   *string               `json:"note"`
+	CustomAttributes       map[string]any        `json:"custom_attributes"`
+	CreatedAt              string                `json:"created_at"`
+	UpdatedAt              string                `json:"updated_at"`
+	LastLoginAt            *string               `json:"last_login_at"`
+	RecentLogins           []loginRecordResponse `json:"recent_logins"`
+	PasswordChangeRequired bool                  `json:"password_change_required"`
+	FailedLoginAttempts    int                   `json:"failed_login_attempts"`
+	LockedUntil            *string               `json:"locked_until"`
+	Username               *string               `json:"username"`
 }
 
 func toUserResponse(u *domain.User) userResponse {
@@ -100,22 +243,26 @@ func toUserResponse(u *domain.User) userResponse {
 		logins = append(logins, loginRecordResponse{At: displayDT(r.At), IP: r.IP})
 	}
 	return userResponse{
-		ID:                  u.ID,
-		Email:               u.Email,
-		Name:                u.Name,
-		AvatarURL:           u.AvatarURL,
-		EmailVerified:       u.EmailVerified,
-		Role:                u.Role,
-		UserType:            domain.UserTypeFromString(string(u.UserType)),
-		Membership:          u.Membership,
-		MembershipExpiresAt: displayDTPtr(u.MembershipExpiresAt),
-		IsActive:            u.IsActive,
-		Note:                u.Note,
-		CustomAttributes:    customAttributesOrEmpty(u.CustomAttributes),
-		CreatedAt:           displayDT(u.CreatedAt),
-		UpdatedAt:           displayDT(u.UpdatedAt),
-		LastLoginAt:         displayDTPtr(u.LastLoginAt),
-		RecentLogins:        logins,
+		ID:                     u.ID,
+		Email:                  u.Email,
+		Name:                   u.Name,
+		AvatarURL:              u.AvatarURL,
+		EmailVerified:          u.EmailVerified,
+		Role:                   u.Role,
+		UserType:               domain.UserTypeFromString(string(u.UserType)),
+		Membership:             u.Membership,
+		MembershipExpiresAt:    displayDTPtr(u.MembershipExpiresAt),
+		IsActive:               u.IsActive,
+		Note:                   u.Note,
+		CustomAttributes:       customAttributesOrEmpty(u.CustomAttributes),
+		CreatedAt:              displayDT(u.CreatedAt),
+		UpdatedAt:              displayDT(u.UpdatedAt),
+		LastLoginAt:            displayDTPtr(u.LastLoginAt),
+		RecentLogins:           logins,
+		PasswordChangeRequired: u.PasswordChangeRequired,
+		FailedLoginAttempts:    u.FailedLoginAttempts,
+		LockedUntil:            displayDTPtr(u.LockedUntil),
+		Username:               u.Username,
 	}
 }
 
@@ -145,6 +292,8 @@ type createUserRequest struct {
 	Membership       *domain.MembershipTier `json:"membership"`
 	CustomAttributes map[string]any         `json:"custom_attributes"`
 	UserType         *domain.UserType       `json:"user_type"`
+	// Username is optional; with one, Email may be left empty.
+	Username *string `json:"username"`
 }
 
 type resetUserPasswordRequest struct {
@@ -157,6 +306,34 @@ type resetUserPasswordResponse struct {
 	RevokedSessions bool   `json:"revoked_sessions"`
 }
 
+type revokeTokensBeforeRequest struct {
+	Before *string `json:"before"`
+	AppID  *string `json:"app_id"`
+}
+
+type revokeTokensBeforeResponse struct {
+	Revoked int64  `json:"revoked"`
+	Before  string `json:"before"`
+}
+
+type auditEventResponse struct {
+	ID        string         `json:"id"`
+	Type      string         `json:"type"`
+	UserID    string         `json:"user_id,omitempty"`
+	TargetID  string         `json:"target_id,omitempty"`
+	AppID     string         `json:"app_id,omitempty"`
+	IP        string         `json:"ip,omitempty"`
+	Details   map[string]any `json:"details,omitempty"`
+	CreatedAt string         `json:"created_at"`
+}
+
+type auditEventListResponse struct {
+	Events  []auditEventResponse `json:"events"`
+	Total   uint64               `json:"total"`
+	Page    uint64               `json:"page"`
+	PerPage uint64               `json:"per_page"`
+}
+
 type userAccountResponse struct {
 	ID                string  `json:"id"`
 	ProviderID        string  `json:"provider_id"`
@@ -180,6 +357,49 @@ type userStats struct {
 	Recent uint64 `json:"recent"`
 }
 
+// loginStats is an app's sign-ins over the last 7 UTC days: the total and
+// the per-day counts, oldest first.
+type loginStats struct {
+	Recent uint64            `json:"recent"`
+	Daily  []timeSeriesPoint `json:"daily"`
+}
+
+// applicationStatsResponse reports one app's usage. Users are those holding a
+// refresh token for the app; Recent counts the ones registered in the last 7 days.
+type applicationStatsResponse struct {
+	AppID               string     `json:"app_id"`
+	Users               userStats  `json:"users"`
+	Logins              loginStats `json:"logins"`
+	ActiveRefreshTokens uint64     `json:"active_refresh_tokens"`
+}
+
+// rateLimitResponse is one limiter's entry in GET /admin/rate-limits.
+type rateLimitResponse struct {
+	Max             int                    `json:"max"`
+	WindowSecs      int64                  `json:"window_secs"`
+	ActiveKeys      int                    `json:"active_keys"`
+	Rejected        uint64                 `json:"rejected"`
+	RejectedByRoute map[string]uint64      `json:"rejected_by_route"`
+	TopKeys         []throttledKeyResponse `json:"top_keys"`
+}
+
+type throttledKeyResponse struct {
+	Key      string `json:"key"`
+	Requests int    `json:"requests"`
+	Rejected uint64 `json:"rejected"`
+}
+
+type timeSeriesResponse struct {
+	Metric  string            `json:"metric"`
+	Days    int               `json:"days"`
+	Buckets []timeSeriesPoint `json:"buckets"`
+}
+
+type timeSeriesPoint struct {
+	Date  string `json:"date"`
+	Count uint64 `json:"count"`
+}
+
 type inviteCodeResponse struct {
 	ID                   string                `json:"id"`
 	Code                 string                `json:"code"`
@@ -249,33 +469,164 @@ func (h *Handler) CreateApplication(c *gin.Context) {
 	if req.AllowedScopes == nil {
 		req.AllowedScopes = []string{}
 	}
+	if err := h.checkRegisteredScopes(req.AllowedScopes); err != nil {
+		middleware.RespondError(c, err)
+		return
+	}
+	if req.DefaultScopes == nil {
+		req.DefaultScopes = []string{}
+	}
+	if err := checkDefaultScopes(req.DefaultScopes, req.AllowedScopes); err != nil {
+		middleware.RespondError(c, err)
+		return
+	}
+	customClaims, err := encodeCustomClaims(req.CustomClaims)
+	if err != nil {
+		middleware.RespondError(c, err)
+		return
+	}
+	publicKey, err := normalizeClientPublicKey(req.ClientPublicKey)
+	if err != nil {
+		middleware.RespondError(c, err)
+		return
+	}
+	allowSelfRegistration := req.AllowSelfRegistration == nil || *req.AllowSelfRegistration
+	rotateRefreshTokens := req.RotateRefreshTokens == nil || *req.RotateRefreshTokens
+	issueRefreshTokens := req.IssueRefreshTokens == nil || *req.IssueRefreshTokens
+	loginIdentifier := domain.LoginIdentifierEmail
+	if req.LoginIdentifier != "" {
+		if !req.LoginIdentifier.Valid() {
+			middleware.RespondError(c, apperror.BadRequest("login_identifier must be 'email' or 'username'"))
+			return
+		}
+		loginIdentifier = req.LoginIdentifier
+	}
+	emailDomains, err := normalizeEmailDomains("allowed_email_domains", req.AllowedEmailDomains)
+	if err != nil {
+		middleware.RespondError(c, err)
+		return
+	}
+	emailDomainsJSON, _ := json.Marshal(emailDomains)
+	audiences, err := normalizeAudiences(req.Audiences)
+	if err != nil {
+		middleware.RespondError(c, err)
+		return
+	}
+	audiencesJSON, _ := json.Marshal(audiences)
+	issuer, err := normalizeIssuer(req.Issuer)
+	if err != nil {
+		middleware.RespondError(c, err)
+		return
+	}
 	clientID := auth.GenerateClientID()
-	secret := auth.RandomHex(32)
+	secret, secretHash := auth.GenerateClientSecret()
 	now := time.Now().UTC()
 	id := uuid.NewString()
+	adminID := nonEmptyPtr(middleware.Admin(c).UserID)
+	appProviders, err := h.newAppProviders(id, req.Providers, now, adminID)
+	if err != nil {
+		middleware.RespondError(c, err)
+		return
+	}
 	redirectJSON, _ := json.Marshal(req.RedirectURIs)
 	scopesJSON, _ := json.Marshal(req.AllowedScopes)
+	defaultScopesJSON, _ := json.Marshal(req.DefaultScopes)
 	app := &domain.Application{
-		ID:               id,
-		Name:             req.Name,
-		ClientID:         clientID,
-		ClientSecretHash: auth.HashClientSecret(secret),
-		RedirectURIs:     string(redirectJSON),
-		AllowedScopes:    string(scopesJSON),
-		IsActive:         true,
-		CreatedAt:        now,
-		UpdatedAt:        now,
+		ID:                    id,
+		Name:                  req.Name,
+		ClientID:              clientID,
+		ClientSecretHash:      secretHash,
+		RedirectURIs:          string(redirectJSON),
+		AllowedScopes:         string(scopesJSON),
+		CustomClaims:          customClaims,
+		IsActive:              true,
+		AllowSelfRegistration: allowSelfRegistration,
+		AllowedEmailDomains:   string(emailDomainsJSON),
+		DefaultScopes:         string(defaultScopesJSON),
+		IncludeEmailClaim:     req.IncludeEmailClaim,
+		ClientPublicKey:       publicKey,
+		RotateRefreshTokens:   rotateRefreshTokens,
+		LoginIdentifier:       loginIdentifier,
+		AccessTokenCookie:     req.AccessTokenCookie,
+		IssueRefreshTokens:    issueRefreshTokens,
+		Audiences:             string(audiencesJSON),
+		RequireProviderEmail:  req.RequireProviderEmail,
+		Issuer:                issuer,
+		CreatedBy:             adminID,
+		UpdatedBy:             adminID,
+		CreatedAt:             now,
+		UpdatedAt:             now,
+	}
+	if err := req.appBranding.applyTo(app); err != nil {
+		middleware.RespondError(c, err)
+		return
 	}
-	if err := h.Repo.Applications().Insert(c.Request.Context(), app); err != nil {
+	if err := h.Repo.Applications().InsertWithProviders(c.Request.Context(), app, appProviders); err != nil {
 		middleware.RespondError(c, err)
 		return
 	}
+	providerOut := make([]providerResponse, 0, len(appProviders))
+	for i := range appProviders {
+		providerOut = append(providerOut, toProviderResponse(&appProviders[i]))
+	}
 	c.JSON(http.StatusOK, createApplicationResponse{
 		ID: id, Name: req.Name, ClientID: clientID, ClientSecret: secret,
 		RedirectURIs: req.RedirectURIs, AllowedScopes: req.AllowedScopes,
+		CustomClaims: customClaimsOrEmpty(customClaims), AllowSelfRegistration: allowSelfRegistration,
+		AllowedEmailDomains: emailDomains, DefaultScopes: req.DefaultScopes, IncludeEmailClaim: req.IncludeEmailClaim,
+		ClientPublicKey: publicKey, RotateRefreshTokens: rotateRefreshTokens, IssueRefreshTokens: issueRefreshTokens, Audiences: audiences,
+		LoginIdentifier: string(loginIdentifier), AccessTokenCookie: req.AccessTokenCookie, RequireProviderEmail: req.RequireProviderEmail,
+		Issuer: issuer, CreatedBy: adminID, Providers: providerOut,
+		appBranding: brandingOf(app),
 	})
 }
 
+// newAppProviders builds the provider rows for a new app, checking each config
+// the way a login would load it so a bad entry fails before anything is
+// written.
+func (h *Handler) newAppProviders(appID string, reqs []addProviderRequest, now time.Time, createdBy *string) ([]domain.AppProvider, error) {
+	out := make([]domain.AppProvider, 0, len(reqs))
+	seen := map[string]bool{}
+	routed := map[string]string{}
+	for i, req := range reqs {
+		if req.ProviderID == "" {
+			return nil, apperror.BadRequest(fmt.Sprintf("providers[%d]: provider_id is required", i))
+		}
+		if seen[req.ProviderID] {
+			return nil, apperror.BadRequest(fmt.Sprintf("providers[%d]: %s is listed twice", i, req.ProviderID))
+		}
+		seen[req.ProviderID] = true
+		emailDomains, err := normalizeEmailDomains(fmt.Sprintf("providers[%d]: email_domains", i), req.EmailDomains)
+		if err != nil {
+			return nil, err
+		}
+		for _, d := range emailDomains {
+			if other, ok := routed[d]; ok {
+				return nil, apperror.BadRequest(fmt.Sprintf("providers[%d]: email domain %s is already routed to %s", i, d, other))
+			}
+			routed[d] = req.ProviderID
+		}
+		emailDomainsJSON, _ := json.Marshal(emailDomains)
+		cfg := string(req.Config)
+		if cfg == "" || cfg == "null" {
+			cfg = "{}"
+		}
+		if _, err := providers.Create(req.ProviderID, json.RawMessage(cfg), h.HTTP, h.Cfg.EnableTestProviders); err != nil {
+			msg := err.Error()
+			if ae, ok := apperror.As(err); ok {
+				msg = ae.Message
+			}
+			return nil, apperror.BadRequest(fmt.Sprintf("providers[%d]: %s", i, msg))
+		}
+		out = append(out, domain.AppProvider{
+			ID: uuid.NewString(), AppID: appID, ProviderID: req.ProviderID, Config: cfg, IsActive: true, CreatedAt: now,
+			DisplayName: req.DisplayName, IconURL: req.IconURL, SortOrder: req.SortOrder, CreatedBy: createdBy,
+			EmailDomains: string(emailDomainsJSON),
+		})
+	}
+	return out, nil
+}
+
 // ListApplications lists all applications.
 func (h *Handler) ListApplications(c *gin.Context) {
 	apps, err := h.Repo.Applications().FindAll(c.Request.Context())
@@ -287,19 +638,136 @@ func (h *Handler) ListApplications(c *gin.Context) {
 	for i := range apps {
 		out = append(out, toApplicationResponse(&apps[i]))
 	}
-	c.JSON(http.StatusOK, out)
+	jsonWithETag(c, http.StatusOK, out)
 }
 
 func toApplicationResponse(a *domain.Application) applicationResponse {
 	return applicationResponse{
-		ID:            a.ID,
-		Name:          a.Name,
-		ClientID:      a.ClientID,
-		RedirectURIs:  auth.DecodeStringArray(a.RedirectURIs),
-		AllowedScopes: auth.DecodeStringArray(a.AllowedScopes),
-		IsActive:      a.IsActive,
-		CreatedAt:     displayDT(a.CreatedAt),
+		ID:                    a.ID,
+		Name:                  a.Name,
+		ClientID:              a.ClientID,
+		RedirectURIs:          auth.DecodeStringArray(a.RedirectURIs),
+		AllowedScopes:         auth.DecodeStringArray(a.AllowedScopes),
+		CustomClaims:          customClaimsOrEmpty(a.CustomClaims),
+		IsActive:              a.IsActive,
+		AllowSelfRegistration: a.AllowSelfRegistration,
+		AllowedEmailDomains:   auth.DecodeStringArray(a.AllowedEmailDomains),
+		DefaultScopes:         auth.DecodeStringArray(a.DefaultScopes),
+		IncludeEmailClaim:     a.IncludeEmailClaim,
+		ClientPublicKey:       a.ClientPublicKey,
+		RotateRefreshTokens:   a.RotateRefreshTokens,
+		LoginIdentifier:       string(domain.LoginIdentifierFromString(string(a.LoginIdentifier))),
+		AccessTokenCookie:     a.AccessTokenCookie,
+		IssueRefreshTokens:    a.IssueRefreshTokens,
+		Audiences:             auth.DecodeStringArray(a.Audiences),
+		RequireProviderEmail:  a.RequireProviderEmail,
+		Issuer:                a.Issuer,
+		CreatedAt:             displayDT(a.CreatedAt),
+		CreatedBy:             a.CreatedBy,
+		UpdatedBy:             a.UpdatedBy,
+		appBranding:           brandingOf(a),
+	}
+}
+
+// maxCustomClaimsBytes bounds an app's custom claims, which ride along in
+// every token it is issued.
+const maxCustomClaimsBytes = 2048
+
+// encodeCustomClaims serializes an app's custom claims for storage; an empty
+// object clears them.
+func encodeCustomClaims(claims map[string]any) (string, error) {
+	if len(claims) == 0 {
+		return "", nil
+	}
+	b, err := json.Marshal(claims)
+	if err != nil {
+		return "", apperror.BadRequest("custom_claims must be a JSON object")
+	}
+	if len(b) > maxCustomClaimsBytes {
+		return "", apperror.BadRequest("custom_claims must not exceed " + strconv.Itoa(maxCustomClaimsBytes) + " bytes")
+	}
+	return string(b), nil
+}
+
+// checkDefaultScopes requires default_scopes to be a subset of allowed_scopes.
+func checkDefaultScopes(defaults, allowed []string) error {
+	for _, s := range defaults {
+		if !contains(allowed, s) {
+			return apperror.BadRequest("default_scopes must be a subset of allowed_scopes; " + strconv.Quote(s) + " is not allowed")
+		}
+	}
+	return nil
+}
+
+// normalizeEmailDomains lower-cases an email domain list (field names it in
+// errors) and drops a leading "@", so "@Example.com" and "example.com" mean
+// the same thing.
+func normalizeEmailDomains(field string, domains []string) ([]string, error) {
+	out := make([]string, 0, len(domains))
+	for _, d := range domains {
+		d = strings.ToLower(strings.TrimPrefix(strings.TrimSpace(d), "@"))
+		if d == "" || strings.ContainsAny(d, "@ ") {
+			return nil, apperror.BadRequest(field + " must contain domain names")
+		}
+		out = append(out, d)
+	}
+	return out, nil
+}
+
+// maxAudiences bounds an app's extra audiences, which every token carries.
+const maxAudiences = 10
+
+// normalizeAudiences trims an audiences list and drops duplicates. The
+// client_id is always the first audience, so it isn't listed here.
+func normalizeAudiences(audiences []string) ([]string, error) {
+	out := make([]string, 0, len(audiences))
+	for _, a := range audiences {
+		a = strings.TrimSpace(a)
+		if a == "" {
+			return nil, apperror.BadRequest("audiences must not contain empty values")
+		}
+		if !contains(out, a) {
+			out = append(out, a)
+		}
+	}
+	if len(out) > maxAudiences {
+		return nil, apperror.BadRequest(fmt.Sprintf("audiences must not exceed %d entries", maxAudiences))
 	}
+	return out, nil
+}
+
+// normalizeClientPublicKey trims a client_public_key and checks it parses, so
+// a bad key fails here rather than at the app's first token request.
+func normalizeClientPublicKey(key string) (string, error) {
+	key = strings.TrimSpace(key)
+	if key == "" {
+		return "", nil
+	}
+	if _, err := auth.ParseClientPublicKey(key); err != nil {
+		return "", apperror.BadRequest("client_public_key: " + err.Error())
+	}
+	return key, nil
+}
+
+// normalizeIssuer checks an app's issuer override: an https URL without a
+// query or fragment, as RFC 8414 requires of an issuer. Empty means none.
+func normalizeIssuer(issuer string) (*string, error) {
+	issuer = strings.TrimSpace(issuer)
+	if issuer == "" {
+		return nil, nil
+	}
+	u, err := url.Parse(issuer)
+	if err != nil || u.Scheme != "https" || u.Host == "" || u.RawQuery != "" || u.Fragment != "" || u.User != nil {
+		return nil, apperror.BadRequest("issuer must be an https URL without a query or fragment")
+	}
+	return &issuer, nil
+}
+
+func customClaimsOrEmpty(s string) map[string]any {
+	if m := auth.DecodeObject(s); m != nil {
+		return m
+	}
+	return map[string]any{}
 }
 
 // UpdateApplication patches an application.
@@ -327,13 +795,99 @@ func (h *Handler) UpdateApplication(c *gin.Context) {
 		app.RedirectURIs = string(b)
 	}
 	if req.AllowedScopes != nil {
+		if err := h.checkRegisteredScopes(*req.AllowedScopes); err != nil {
+			middleware.RespondError(c, err)
+			return
+		}
 		b, _ := json.Marshal(*req.AllowedScopes)
 		app.AllowedScopes = string(b)
 	}
+	if req.CustomClaims != nil {
+		encoded, err := encodeCustomClaims(*req.CustomClaims)
+		if err != nil {
+			middleware.RespondError(c, err)
+			return
+		}
+		app.CustomClaims = encoded
+	}
 	if req.IsActive != nil {
 		app.IsActive = *req.IsActive
 	}
+	if req.AllowSelfRegistration != nil {
+		app.AllowSelfRegistration = *req.AllowSelfRegistration
+	}
+	if req.AllowedEmailDomains != nil {
+		domains, err := normalizeEmailDomains("allowed_email_domains", *req.AllowedEmailDomains)
+		if err != nil {
+			middleware.RespondError(c, err)
+			return
+		}
+		b, _ := json.Marshal(domains)
+		app.AllowedEmailDomains = string(b)
+	}
+	if req.DefaultScopes != nil {
+		b, _ := json.Marshal(*req.DefaultScopes)
+		app.DefaultScopes = string(b)
+	}
+	if req.IncludeEmailClaim != nil {
+		app.IncludeEmailClaim = *req.IncludeEmailClaim
+	}
+	if req.ClientPublicKey != nil {
+		publicKey, err := normalizeClientPublicKey(*req.ClientPublicKey)
+		if err != nil {
+			middleware.RespondError(c, err)
+			return
+		}
+		app.ClientPublicKey = publicKey
+	}
+	if req.RotateRefreshTokens != nil {
+		app.RotateRefreshTokens = *req.RotateRefreshTokens
+	}
+	if req.LoginIdentifier != nil {
+		if !domain.LoginIdentifier(*req.LoginIdentifier).Valid() {
+			middleware.RespondError(c, apperror.BadRequest("login_identifier must be 'email' or 'username'"))
+			return
+		}
+		app.LoginIdentifier = domain.LoginIdentifier(*req.LoginIdentifier)
+	}
+	if req.AccessTokenCookie != nil {
+		app.AccessTokenCookie = *req.AccessTokenCookie
+	}
+	if req.IssueRefreshTokens != nil {
+		app.IssueRefreshTokens = *req.IssueRefreshTokens
+	}
+	if req.RequireProviderEmail != nil {
+		app.RequireProviderEmail = *req.RequireProviderEmail
+	}
+	if req.Issuer != nil {
+		issuer, err := normalizeIssuer(*req.Issuer)
+		if err != nil {
+			middleware.RespondError(c, err)
+			return
+		}
+		app.Issuer = issuer
+	}
+	if req.Audiences != nil {
+		audiences, err := normalizeAudiences(*req.Audiences)
+		if err != nil {
+			middleware.RespondError(c, err)
+			return
+		}
+		b, _ := json.Marshal(audiences)
+		app.Audiences = string(b)
+	}
+	if err := req.appBranding.applyTo(app); err != nil {
+		middleware.RespondError(c, err)
+		return
+	}
+	// Checked on every update: narrowing allowed_scopes must not strand a
+	// default scope.
+	if err := checkDefaultScopes(auth.DecodeStringArray(app.DefaultScopes), auth.DecodeStringArray(app.AllowedScopes)); err != nil {
+		middleware.RespondError(c, err)
+		return
+	}
 	app.UpdatedAt = time.Now().UTC()
+	app.UpdatedBy = nonEmptyPtr(middleware.Admin(c).UserID)
 	if err := h.Repo.Applications().Update(ctx, app); err != nil {
 		middleware.RespondError(c, err)
 		return
@@ -368,20 +922,43 @@ func (h *Handler) AddProvider(c *gin.Context) {
 		middleware.RespondError(c, apperror.BadRequest("Provider already configured for this application"))
 		return
 	}
+	emailDomains, err := normalizeEmailDomains("email_domains", req.EmailDomains)
+	if err != nil {
+		middleware.RespondError(c, err)
+		return
+	}
+	if len(emailDomains) > 0 {
+		configured, err := h.Repo.AppProviders().FindAllByApp(ctx, appID)
+		if err != nil {
+			middleware.RespondError(c, err)
+			return
+		}
+		for _, p := range configured {
+			for _, d := range auth.DecodeStringArray(p.EmailDomains) {
+				if contains(emailDomains, d) {
+					middleware.RespondError(c, apperror.BadRequest(fmt.Sprintf("email domain %s is already routed to %s", d, p.ProviderID)))
+					return
+				}
+			}
+		}
+	}
+	emailDomainsJSON, _ := json.Marshal(emailDomains)
 	cfg := string(req.Config)
 	if cfg == "" {
 		cfg = "{}"
 	}
 	now := time.Now().UTC()
 	id := uuid.NewString()
-	ap := &domain.AppProvider{ID: id, AppID: appID, ProviderID: req.ProviderID, Config: cfg, IsActive: true, CreatedAt: now}
+	ap := &domain.AppProvider{
+		ID: id, AppID: appID, ProviderID: req.ProviderID, Config: cfg, IsActive: true, CreatedAt: now,
+		DisplayName: req.DisplayName, IconURL: req.IconURL, SortOrder: req.SortOrder, CreatedBy: nonEmptyPtr(middleware.Admin(c).UserID),
+		EmailDomains: string(emailDomainsJSON),
+	}
 	if err := h.Repo.AppProviders().Insert(ctx, ap); err != nil {
 		middleware.RespondError(c, err)
 		return
 	}
-	c.JSON(http.StatusOK, providerResponse{
-		ID: id, ProviderID: req.ProviderID, Config: json.RawMessage(cfg), IsActive: true, CreatedAt: displayDT(now),
-	})
+	c.JSON(http.StatusOK, toProviderResponse(ap))
 }
 
 // RemoveProvider detaches a provider from an application.
@@ -403,8 +980,18 @@ func (h *Handler) RemoveProvider(c *gin.Context) {
 	c.JSON(http.StatusOK, gin.H{"status": "deleted"})
 }
 
-// RotateSecret rotates an application's client secret.
+// RotateSecret rotates an application's client secret. With a grace period
+// the old secret stays valid alongside the new one until it runs out.
 func (h *Handler) RotateSecret(c *gin.Context) {
+	var req rotateSecretRequest
+	if err := c.ShouldBindJSON(&req); err != nil && !errors.Is(err, io.EOF) {
+		middleware.RespondError(c, apperror.BadRequest("Invalid request body"))
+		return
+	}
+	if req.GracePeriodSecs < 0 || req.GracePeriodSecs > maxSecretGracePeriodSecs {
+		middleware.RespondError(c, apperror.BadRequest(fmt.Sprintf("grace_period_secs must be between 0 and %d", maxSecretGracePeriodSecs)))
+		return
+	}
 	ctx := c.Request.Context()
 	app, err := h.Repo.Applications().FindByID(ctx, c.Param("id"))
 	if err != nil {
@@ -415,14 +1002,25 @@ func (h *Handler) RotateSecret(c *gin.Context) {
 		middleware.RespondError(c, apperror.ApplicationNotFound())
 		return
 	}
-	secret := auth.RandomHex(32)
-	app.ClientSecretHash = auth.HashClientSecret(secret)
-	app.UpdatedAt = time.Now().UTC()
+	secret, secretHash := auth.GenerateClientSecret()
+	now := time.Now().UTC()
+	// A rotation during an earlier grace period ends it: only the secret
+	// being replaced now is kept.
+	app.PreviousClientSecretHash, app.PreviousClientSecretExpiresAt = "", nil
+	if req.GracePeriodSecs > 0 {
+		expires := now.Add(time.Duration(req.GracePeriodSecs) * time.Second)
+		app.PreviousClientSecretHash, app.PreviousClientSecretExpiresAt = app.ClientSecretHash, &expires
+	}
+	app.ClientSecretHash = secretHash
+	app.UpdatedAt = now
+	app.UpdatedBy = nonEmptyPtr(middleware.Admin(c).UserID)
 	if err := h.Repo.Applications().Update(ctx, app); err != nil {
 		middleware.RespondError(c, err)
 		return
 	}
-	c.JSON(http.StatusOK, rotateSecretResponse{ClientID: app.ClientID, ClientSecret: secret})
+	c.JSON(http.StatusOK, rotateSecretResponse{
+		ClientID: app.ClientID, ClientSecret: secret, PreviousSecretExpiresAt: displayDTPtr(app.PreviousClientSecretExpiresAt),
+	})
 }
 
 // ListProviders lists an application's providers.
@@ -444,29 +1042,32 @@ func (h *Handler) ListProviders(c *gin.Context) {
 		return
 	}
 	out := make([]providerResponse, 0, len(providers))
-	for _, p := range providers {
-		cfg := p.Config
-		if cfg == "" {
-			cfg = "{}"
-		}
-		out = append(out, providerResponse{
-			ID: p.ID, ProviderID: p.ProviderID, Config: json.RawMessage(cfg), IsActive: p.IsActive, CreatedAt: displayDT(p.CreatedAt),
-		})
+	for i := range providers {
+		out = append(out, toProviderResponse(&providers[i]))
 	}
 	c.JSON(http.StatusOK, out)
 }
 
+func toProviderResponse(p *domain.AppProvider) providerResponse {
+	cfg := p.Config
+	if cfg == "" {
+		cfg = "{}"
+	}
+	return providerResponse{
+		ID: p.ID, ProviderID: p.ProviderID, Config: json.RawMessage(cfg), IsActive: p.IsActive, CreatedAt: displayDT(p.CreatedAt),
+		DisplayName: p.DisplayName, IconURL: p.IconURL, SortOrder: p.SortOrder, CreatedBy: p.CreatedBy,
+		EmailDomains: auth.DecodeStringArray(p.EmailDomains),
+	}
+}
+
 // --- User handlers ---
 
 // ListUsers lists users with pagination and optional search.
 func (h *Handler) ListUsers(c *gin.Context) {
-	page := parseUintDefault(c.Query("page"), 1)
-	if page < 1 {
-		page = 1
-	}
-	perPage := parseUintDefault(c.Query("per_page"), 20)
-	if perPage > 100 {
-		perPage = 100
+	page, perPage, err := h.pagination(c)
+	if err != nil {
+		middleware.RespondError(c, err)
+		return
 	}
 	offset := (page - 1) * perPage
 	sort := repository.ParseUserListSort(c.Query("sort_by"), c.Query("sort_order"))
@@ -490,7 +1091,90 @@ func (h *Handler) ListUsers(c *gin.Context) {
 	for i := range users {
 		out = append(out, toUserResponse(&users[i]))
 	}
-	c.JSON(http.StatusOK, userListResponse{Users: out, Total: total, Page: page, PerPage: perPage})
+	jsonWithETag(c, http.StatusOK, userListResponse{Users: out, Total: total, Page: page, PerPage: perPage})
+}
+
+// searchResult is one hit of the admin search. Type says which of the
+// optional fields are set: "user" (email, name), "application" (name,
+// client_id) or "account" (user_id, provider_id, provider_account_id).
+type searchResult struct {
+	Type              string  `json:"type"`
+	ID                string  `json:"id"`
+	Email             *string `json:"email,omitempty"`
+	Name              *string `json:"name,omitempty"`
+	ClientID          string  `json:"client_id,omitempty"`
+	UserID            string  `json:"user_id,omitempty"`
+	ProviderID        string  `json:"provider_id,omitempty"`
+	ProviderAccountID *string `json:"provider_account_id,omitempty"`
+}
+
+// Search looks q up across users (email, name, id), applications (name,
+// client_id) and linked accounts (exact provider_account_id), at most limit
+// hits of each. Applications are left out unless the caller may read them.
+func (h *Handler) Search(c *gin.Context) {
+	q := strings.TrimSpace(c.Query("q"))
+	if q == "" {
+		middleware.RespondError(c, apperror.BadRequest("q is required"))
+		return
+	}
+	limit := parseUintDefault(c.Query("limit"), 10)
+	if limit < 1 || limit > 50 {
+		limit = 10
+	}
+	ctx := c.Request.Context()
+	out := []searchResult{}
+
+	seen := map[string]bool{}
+	sort := repository.ParseUserListSort("", "")
+	for _, byID := range []bool{false, true} {
+		search, idSearch := q, ""
+		if byID {
+			search, idSearch = "", q
+		}
+		users, _, err := h.Repo.Users().ListPaginated(ctx, search, idSearch, nil, sort, 0, limit)
+		if err != nil {
+			middleware.RespondError(c, err)
+			return
+		}
+		for _, u := range users {
+			if !seen[u.ID] && uint64(len(seen)) < limit {
+				seen[u.ID] = true
+				out = append(out, searchResult{Type: "user", ID: u.ID, Email: u.Email, Name: u.Name})
+			}
+		}
+	}
+
+	if h.Cfg.Roles.HasPermission(middleware.Admin(c).Role, domain.PermApplicationsRead) {
+		apps, err := h.Repo.Applications().FindAll(ctx)
+		if err != nil {
+			middleware.RespondError(c, err)
+			return
+		}
+		needle := strings.ToLower(q)
+		var n uint64
+		for i := range apps {
+			a := &apps[i]
+			if n < limit && (strings.Contains(strings.ToLower(a.Name), needle) || strings.Contains(strings.ToLower(a.ClientID), needle)) {
+				n++
+				out = append(out, searchResult{Type: "application", ID: a.ID, Name: strPtr(a.Name), ClientID: a.ClientID})
+			}
+		}
+	}
+
+	for _, providerID := range providers.ExternalIDs {
+		account, err := h.Repo.Accounts().FindByProviderAccount(ctx, providerID, q)
+		if err != nil {
+			middleware.RespondError(c, err)
+			return
+		}
+		if account != nil {
+			out = append(out, searchResult{
+				Type: "account", ID: account.ID, UserID: account.UserID,
+				ProviderID: account.ProviderID, ProviderAccountID: account.ProviderAccountID,
+			})
+		}
+	}
+	c.JSON(http.StatusOK, gin.H{"results": out})
 }
 
 // GetUser returns a single user.
@@ -525,6 +1209,11 @@ func (h *Handler) GetUserAccounts(c *gin.Context) {
 		middleware.RespondError(c, err)
 		return
 	}
+	accounts, err = h.paginateAccounts(c, accounts)
+	if err != nil {
+		middleware.RespondError(c, err)
+		return
+	}
 	out := make([]userAccountResponse, 0, len(accounts))
 	for _, a := range accounts {
 		out = append(out, userAccountResponse{
@@ -534,6 +1223,51 @@ func (h *Handler) GetUserAccounts(c *gin.Context) {
 	c.JSON(http.StatusOK, out)
 }
 
+// GetUserAudit pages through the audit events a user acted in or was the
+// target of, newest first, optionally only those of ?type=. Events outlive
+// the user, so a deleted account's trail can still be read.
+func (h *Handler) GetUserAudit(c *gin.Context) {
+	page, perPage, err := h.pagination(c)
+	if err != nil {
+		middleware.RespondError(c, err)
+		return
+	}
+	eventType := strings.TrimSpace(c.Query("type"))
+	if eventType != "" && !slices.Contains(audit.Types, eventType) {
+		middleware.RespondError(c, apperror.BadRequest("type must be one of: "+strings.Join(audit.Types, ", ")))
+		return
+	}
+	events, total, err := h.Repo.AuditEvents().ListByUser(c.Request.Context(), c.Param("id"), eventType, (page-1)*perPage, perPage)
+	if err != nil {
+		middleware.RespondError(c, err)
+		return
+	}
+	out := make([]auditEventResponse, 0, len(events))
+	for _, e := range events {
+		out = append(out, toAuditEventResponse(e))
+	}
+	c.JSON(http.StatusOK, auditEventListResponse{Events: out, Total: total, Page: page, PerPage: perPage})
+}
+
+// auditUserChange records an admin's change to the user targetID, so it
+// shows in that user's audit trail as well as the admin's.
+func auditUserChange(c *gin.Context, eventType, targetID string, details map[string]any) {
+	audit.Record(audit.Event{
+		Type:     eventType,
+		UserID:   middleware.Admin(c).UserID,
+		TargetID: targetID,
+		IP:       middleware.ClientIP(c, "unknown"),
+		Details:  details,
+	})
+}
+
+func toAuditEventResponse(e domain.AuditEvent) auditEventResponse {
+	return auditEventResponse{
+		ID: e.ID, Type: e.Type, UserID: e.UserID, TargetID: e.TargetID, AppID: e.AppID, IP: e.IP,
+		Details: auth.DecodeObject(e.Details), CreatedAt: displayDT(e.CreatedAt),
+	}
+}
+
 // CreateUser creates a user with a password account.
 func (h *Handler) CreateUser(c *gin.Context) {
 	var req createUserRequest
@@ -541,16 +1275,16 @@ func (h *Handler) CreateUser(c *gin.Context) {
 		middleware.RespondError(c, apperror.BadRequest("Invalid request body"))
 		return
 	}
-	if err := auth.ValidatePassword(req.Password); err != nil {
+	if err := h.validatePassword(req.Password); err != nil {
 		middleware.RespondError(c, err)
 		return
 	}
-	role := "user"
+	role := h.Cfg.Roles.Default
 	if req.Role != nil {
 		role = *req.Role
 	}
-	if role != "user" && role != "admin" {
-		middleware.RespondError(c, apperror.BadRequest("Role must be 'user' or 'admin'"))
+	if err := h.validateRole(c, role); err != nil {
+		middleware.RespondError(c, err)
 		return
 	}
 	membership := domain.MembershipRegular
@@ -565,44 +1299,77 @@ func (h *Handler) CreateUser(c *gin.Context) {
 		}
 		userType = *req.UserType
 	}
-	ctx := c.Request.Context()
-	existing, err := h.Repo.Users().FindByEmail(ctx, req.Email)
+	if err := validateCustomAttributes(req.CustomAttributes); err != nil {
+		middleware.RespondError(c, err)
+		return
+	}
+	username, err := normalizeUsername(req.Username, false)
 	if err != nil {
 		middleware.RespondError(c, err)
 		return
 	}
-	if existing != nil {
-		middleware.RespondError(c, apperror.UserAlreadyExists())
+	var email *string
+	if req.Email != "" || username == nil {
+		email = strPtr(req.Email)
+	}
+	hash, err := auth.HashPassword(req.Password)
+	if err != nil {
+		middleware.RespondError(c, err)
 		return
 	}
 	now := time.Now().UTC()
 	userID := uuid.NewString()
 	user := &domain.User{
-		ID: userID, Email: strPtr(req.Email), Name: req.Name, EmailVerified: false,
+		ID: userID, Email: email, Username: username, Name: req.Name, EmailVerified: false,
 		Role: role, UserType: userType, IsActive: true, CustomAttributes: req.CustomAttributes,
 		CreatedAt: now, UpdatedAt: now, Membership: membership,
 	}
-	if err := h.Repo.Users().Insert(ctx, user); err != nil {
-		middleware.RespondError(c, err)
-		return
-	}
-	hash, err := auth.HashPassword(req.Password)
-	if err != nil {
-		middleware.RespondError(c, err)
-		return
-	}
 	account := &domain.Account{
 		ID: uuid.NewString(), UserID: userID, ProviderID: "password",
-		ProviderAccountID: strPtr(req.Email), Credential: strPtr(hash),
+		ProviderAccountID: passwordAccountID(email, username), Credential: strPtr(hash),
 		ProviderMetadata: "{}", CreatedAt: now, UpdatedAt: now,
 	}
-	if err := h.Repo.Accounts().Insert(ctx, account); err != nil {
+	// A taken email or username trips a unique key and comes back as
+	// user_already_exists.
+	if err := h.Repo.Users().InsertWithAccount(c.Request.Context(), user, account); err != nil {
 		middleware.RespondError(c, err)
 		return
 	}
+	auditUserChange(c, audit.UserCreated, userID, map[string]any{"role": role})
 	c.JSON(http.StatusOK, toUserResponse(user))
 }
 
+// validateRole checks role against the configured allowlist, and that the
+// calling admin holds every permission it grants (Forbidden otherwise), so
+// users:write cannot hand out a stronger role than the caller's own.
+func (h *Handler) validateRole(c *gin.Context, role string) error {
+	if !h.Cfg.Roles.Valid(role) {
+		return apperror.BadRequest("Role must be one of: " + strings.Join(h.Cfg.Roles.Allowed, ", "))
+	}
+	if !h.Cfg.Roles.CanAssign(middleware.Admin(c).Role, role) {
+		return apperror.Forbidden()
+	}
+	return nil
+}
+
+// targetUser loads the user an admin write acts on. The caller must hold
+// every permission the user's role grants (Forbidden otherwise), so a role
+// with users:write alone cannot reset, disable, unlink or delete a stronger
+// admin and take the account over.
+func (h *Handler) targetUser(c *gin.Context, id string) (*domain.User, error) {
+	user, err := h.Repo.Users().FindByID(c.Request.Context(), id)
+	if err != nil {
+		return nil, err
+	}
+	if user == nil {
+		return nil, apperror.UserNotFound()
+	}
+	if !h.Cfg.Roles.CanAssign(middleware.Admin(c).Role, user.Role) {
+		return nil, apperror.Forbidden()
+	}
+	return user, nil
+}
+
 // UpdateUser patches a user.
 func (h *Handler) UpdateUser(c *gin.Context) {
 	var req updateUserRequest
@@ -611,21 +1378,17 @@ func (h *Handler) UpdateUser(c *gin.Context) {
 		return
 	}
 	ctx := c.Request.Context()
-	user, err := h.Repo.Users().FindByID(ctx, c.Param("id"))
+	user, err := h.targetUser(c, c.Param("id"))
 	if err != nil {
 		middleware.RespondError(c, err)
 		return
 	}
-	if user == nil {
-		middleware.RespondError(c, apperror.UserNotFound())
-		return
-	}
 	if req.Name != nil {
 		user.Name = req.Name
 	}
 	if req.Role != nil {
-		if *req.Role != "user" && *req.Role != "admin" {
-			middleware.RespondError(c, apperror.BadRequest("Role must be 'user' or 'admin'"))
+		if err := h.validateRole(c, *req.Role); err != nil {
+			middleware.RespondError(c, err)
 			return
 		}
 		user.Role = *req.Role
@@ -668,24 +1431,73 @@ func (h *Handler) UpdateUser(c *gin.Context) {
 	}
 	if req.CustomAttributes != nil {
 		user.CustomAttributes = mergeCustomAttributes(user.CustomAttributes, req.CustomAttributes)
+		if err := validateCustomAttributes(user.CustomAttributes); err != nil {
+			middleware.RespondError(c, err)
+			return
+		}
 	}
 	user.UpdatedAt = time.Now().UTC()
 	if err := h.Repo.Users().Update(ctx, user); err != nil {
 		middleware.RespondError(c, err)
 		return
 	}
+	auditUserChange(c, audit.UserUpdated, user.ID, map[string]any{"fields": req.fields()})
 	c.JSON(http.StatusOK, toUserResponse(user))
 }
 
+// fields names the attributes the request sets, for the audit trail.
+func (r *updateUserRequest) fields() []string {
+	var out []string
+	for name, set := range map[string]bool{
+		"name": r.Name != nil, "role": r.Role != nil, "membership": r.Membership != nil,
+		"user_type": r.UserType != nil, "membership_expires_at": r.MembershipExpiresAt != nil,
+		"is_active": r.IsActive != nil, "note": r.Note != nil, "custom_attributes": r.CustomAttributes != nil,
+	} {
+		if set {
+			out = append(out, name)
+		}
+	}
+	slices.Sort(out)
+	return out
+}
+
 // DeleteUser deletes a user account (admin).
 func (h *Handler) DeleteUser(c *gin.Context) {
-	if err := h.deleteUserAccount(c.Request.Context(), c.Param("id")); err != nil {
+	id := c.Param("id")
+	if _, err := h.targetUser(c, id); err != nil {
 		middleware.RespondError(c, err)
 		return
 	}
+	if err := h.deleteUserAccount(c.Request.Context(), id); err != nil {
+		middleware.RespondError(c, err)
+		return
+	}
+	auditUserChange(c, audit.UserDeleted, id, nil)
 	c.Status(http.StatusNoContent)
 }
 
+// UnlockUser clears a login lockout and the failed-attempt count ahead of the
+// lockout window. Re-enabling a disabled user stays on PATCH (is_active).
+func (h *Handler) UnlockUser(c *gin.Context) {
+	ctx := c.Request.Context()
+	id := c.Param("id")
+	user, err := h.targetUser(c, id)
+	if err != nil {
+		middleware.RespondError(c, err)
+		return
+	}
+	wasLocked := user.IsLocked(time.Now())
+	user.FailedLoginAttempts = 0
+	user.LockedUntil = nil
+	user.UpdatedAt = time.Now().UTC()
+	if err := h.Repo.Users().Update(ctx, user); err != nil {
+		middleware.RespondError(c, err)
+		return
+	}
+	auditUserChange(c, audit.AccountUnlocked, id, map[string]any{"user_id": id, "was_locked": wasLocked})
+	c.JSON(http.StatusOK, toUserResponse(user))
+}
+
 // ResetUserPassword sets a new password for a user, optionally revoking sessions.
 func (h *Handler) ResetUserPassword(c *gin.Context) {
 	var req resetUserPasswordRequest
@@ -697,21 +1509,17 @@ func (h *Handler) ResetUserPassword(c *gin.Context) {
 	if req.RevokeSessions != nil {
 		revoke = *req.RevokeSessions
 	}
-	if err := auth.ValidatePassword(req.Password); err != nil {
+	if err := h.validatePassword(req.Password); err != nil {
 		middleware.RespondError(c, err)
 		return
 	}
 	ctx := c.Request.Context()
 	id := c.Param("id")
-	user, err := h.Repo.Users().FindByID(ctx, id)
+	user, err := h.targetUser(c, id)
 	if err != nil {
 		middleware.RespondError(c, err)
 		return
 	}
-	if user == nil {
-		middleware.RespondError(c, apperror.UserNotFound())
-		return
-	}
 	account, err := h.Repo.Accounts().FindByUserAndProvider(ctx, id, "password")
 	if err != nil {
 		middleware.RespondError(c, err)
@@ -726,18 +1534,28 @@ func (h *Handler) ResetUserPassword(c *gin.Context) {
 		middleware.RespondError(c, err)
 		return
 	}
+	now := time.Now().UTC()
 	account.Credential = strPtr(hash)
-	account.UpdatedAt = time.Now().UTC()
+	account.UpdatedAt = now
 	if err := h.Repo.Accounts().Update(ctx, account); err != nil {
 		middleware.RespondError(c, err)
 		return
 	}
+	if user.PasswordChangeRequired {
+		user.PasswordChangeRequired = false
+		user.UpdatedAt = now
+		if err := h.Repo.Users().Update(ctx, user); err != nil {
+			middleware.RespondError(c, err)
+			return
+		}
+	}
 	if revoke {
-		if err := h.Repo.RefreshTokens().DeleteAllByUser(ctx, id); err != nil {
+		if err := h.revokeSessions(ctx, id); err != nil {
 			middleware.RespondError(c, err)
 			return
 		}
 	}
+	auditUserChange(c, audit.PasswordReset, id, map[string]any{"revoked_sessions": revoke})
 	c.JSON(http.StatusOK, resetUserPasswordResponse{UserID: id, RevokedSessions: revoke})
 }
 
@@ -746,15 +1564,10 @@ func (h *Handler) AdminUnlinkAccount(c *gin.Context) {
 	ctx := c.Request.Context()
 	userID := c.Param("id")
 	providerID := c.Param("provider_id")
-	user, err := h.Repo.Users().FindByID(ctx, userID)
-	if err != nil {
+	if _, err := h.targetUser(c, userID); err != nil {
 		middleware.RespondError(c, err)
 		return
 	}
-	if user == nil {
-		middleware.RespondError(c, apperror.UserNotFound())
-		return
-	}
 	account, err := h.Repo.Accounts().FindByUserAndProvider(ctx, userID, providerID)
 	if err != nil {
 		middleware.RespondError(c, err)
@@ -777,6 +1590,7 @@ func (h *Handler) AdminUnlinkAccount(c *gin.Context) {
 		middleware.RespondError(c, err)
 		return
 	}
+	auditUserChange(c, audit.AccountUnlinked, userID, map[string]any{"provider_id": providerID})
 	c.JSON(http.StatusOK, gin.H{"status": "unlinked"})
 }
 
@@ -809,6 +1623,189 @@ func (h *Handler) Stats(c *gin.Context) {
 	})
 }
 
+// tokenPreviewResponse shows what a token issued for an app now would hold.
+// DroppedScopes are requested scopes outside allowed_scopes.
+type tokenPreviewResponse struct {
+	Claims                auth.AccessClaims `json:"claims"`
+	Scopes                []string          `json:"scopes"`
+	DroppedScopes         []string          `json:"dropped_scopes"`
+	TokenFormat           string            `json:"token_format"`
+	ExpiresIn             int64             `json:"expires_in"`
+	RefreshToken          bool              `json:"refresh_token"`
+	RefreshTokenExpiresIn int64             `json:"refresh_token_expires_in,omitempty"`
+	SampleUser            bool              `json:"sample_user"`
+}
+
+// sampleUser stands in for a real user in a token preview.
+func (h *Handler) sampleUser() *domain.User {
+	return &domain.User{
+		ID:       "00000000-0000-0000-0000-000000000000",
+		Email:    strPtr("user@example.com"),
+		Name:     strPtr("Sample User"),
+		Role:     h.Cfg.Roles.Default,
+		UserType: domain.UserTypeRegular,
+	}
+}
+
+// TokenPreview returns the claims an access token for the app would carry,
+// without issuing one. The user is user_id when given, else a sample user;
+// since a real user's claims carry their profile, user_id also takes
+// users:read. scope works as on the password grant, defaulting to the app's
+// default scopes. Nothing is written, not even a lapsed membership.
+func (h *Handler) TokenPreview(c *gin.Context) {
+	ctx := c.Request.Context()
+	app, err := h.Repo.Applications().FindByID(ctx, c.Param("id"))
+	if err != nil {
+		middleware.RespondError(c, err)
+		return
+	}
+	if app == nil {
+		middleware.RespondError(c, apperror.ApplicationNotFound())
+		return
+	}
+	user, sample := h.sampleUser(), true
+	if id := strings.TrimSpace(c.Query("user_id")); id != "" {
+		if !h.Cfg.Roles.HasPermission(middleware.Admin(c).Role, domain.PermUsersRead) {
+			middleware.RespondError(c, apperror.Forbidden())
+			return
+		}
+		sample = false
+		user, err = h.Repo.Users().FindByID(ctx, id)
+		if err != nil {
+			middleware.RespondError(c, err)
+			return
+		}
+		if user == nil {
+			middleware.RespondError(c, apperror.UserNotFound())
+			return
+		}
+	}
+	scopes, dropped := h.defaultScopes(app), []string{}
+	if scope, ok := c.GetQuery("scope"); ok {
+		allowed := auth.DecodeStringArray(app.AllowedScopes)
+		scopes = []string{}
+		for _, s := range strings.Fields(scope) {
+			if contains(allowed, s) {
+				scopes = append(scopes, s)
+			} else {
+				dropped = append(dropped, s)
+			}
+		}
+	}
+	claims := h.JWT.AccessClaims(user.ID, app.ClientID, scopes, user.Role, user.EffectiveMembership(time.Now().UTC()),
+		user.UserType, user.Name, h.appTokenExtras(app, user.CustomAttributes, user.Email))
+	resp := tokenPreviewResponse{
+		Claims:        claims,
+		Scopes:        claims.Scopes,
+		DroppedScopes: dropped,
+		TokenFormat:   h.Cfg.AccessTokenFormat,
+		ExpiresIn:     h.JWT.AccessTokenExpirySecs(),
+		RefreshToken:  app.IssueRefreshTokens,
+		SampleUser:    sample,
+	}
+	if app.IssueRefreshTokens {
+		resp.RefreshTokenExpiresIn = refreshExpiresIn(h.Cfg.JWTRefreshTokenExpiryDays)
+	}
+	c.JSON(http.StatusOK, resp)
+}
+
+// ApplicationStats returns user, login and session counts for a single
+// application.
+func (h *Handler) ApplicationStats(c *gin.Context) {
+	ctx := c.Request.Context()
+	app, err := h.Repo.Applications().FindByID(ctx, c.Param("id"))
+	if err != nil {
+		middleware.RespondError(c, err)
+		return
+	}
+	if app == nil {
+		middleware.RespondError(c, apperror.ApplicationNotFound())
+		return
+	}
+	now := time.Now().UTC()
+	usage, err := h.Repo.RefreshTokens().UsageByApp(ctx, app.ID, now, now.Add(-7*24*time.Hour))
+	if err != nil {
+		middleware.RespondError(c, err)
+		return
+	}
+	today := now.Truncate(24 * time.Hour)
+	since := today.AddDate(0, 0, -6)
+	counts, err := h.Repo.Applications().CountLoginsByDay(ctx, app.ID, since)
+	if err != nil {
+		middleware.RespondError(c, err)
+		return
+	}
+	logins := loginStats{Daily: dailyBuckets(counts, since, today)}
+	for _, p := range logins.Daily {
+		logins.Recent += p.Count
+	}
+	c.JSON(http.StatusOK, applicationStatsResponse{
+		AppID:               app.ID,
+		Users:               userStats{Total: usage.Users, Recent: usage.NewUsers},
+		Logins:              logins,
+		ActiveRefreshTokens: usage.ActiveTokens,
+	})
+}
+
+// StatsTimeSeries returns per-day counts of a metric over the last `days`
+// UTC days (today included), oldest first, with empty days as zero.
+// Only "registrations" is supported; logins are counted per app only.
+func (h *Handler) StatsTimeSeries(c *gin.Context) {
+	metric := c.DefaultQuery("metric", "registrations")
+	if metric != "registrations" {
+		middleware.RespondError(c, apperror.BadRequest("Unsupported metric "+strconv.Quote(metric)+"; supported: registrations"))
+		return
+	}
+	days, err := strconv.Atoi(c.DefaultQuery("days", "30"))
+	if err != nil || days < 1 || days > 365 {
+		middleware.RespondError(c, apperror.BadRequest("days must be between 1 and 365"))
+		return
+	}
+	today := time.Now().UTC().Truncate(24 * time.Hour)
+	since := today.AddDate(0, 0, -(days - 1))
+	counts, err := h.Repo.Users().CountCreatedByDay(c.Request.Context(), since)
+	if err != nil {
+		middleware.RespondError(c, err)
+		return
+	}
+	c.JSON(http.StatusOK, timeSeriesResponse{Metric: metric, Days: days, Buckets: dailyBuckets(counts, since, today)})
+}
+
+// dailyBuckets lays per-day counts out from since to today, oldest first,
+// with empty days as zero.
+func dailyBuckets(counts map[string]uint64, since, today time.Time) []timeSeriesPoint {
+	var buckets []timeSeriesPoint
+	for d := since; !d.After(today); d = d.AddDate(0, 0, 1) {
+		day := d.Format("2006-01-02")
+		buckets = append(buckets, timeSeriesPoint{Date: day, Count: counts[day]})
+	}
+	return buckets
+}
+
+// RateLimits reports each rate limiter's refusals since startup and its
+// currently throttled keys, so limits can be tuned and abuse spotted. The
+// counters are per instance, like the limiters themselves.
+func (h *Handler) RateLimits(c *gin.Context) {
+	top, err := strconv.Atoi(c.DefaultQuery("top", "10"))
+	if err != nil || top < 1 || top > 100 {
+		middleware.RespondError(c, apperror.BadRequest("top must be between 1 and 100"))
+		return
+	}
+	out := make(map[string]rateLimitResponse, len(h.RateLimiters))
+	for name, l := range h.RateLimiters {
+		s := l.Stats(top)
+		keys := make([]throttledKeyResponse, 0, len(s.TopKeys))
+		for _, k := range s.TopKeys {
+			keys = append(keys, throttledKeyResponse{Key: k.Key, Requests: k.Requests, Rejected: k.Rejected})
+		}
+		out[name] = rateLimitResponse{
+			Max: s.Max, WindowSecs: int64(s.Window / time.Second), ActiveKeys: s.ActiveKeys,
+			Rejected: s.Rejected, RejectedByRoute: s.RejectedByRoute, TopKeys: keys,
+		}
+	}
+	c.JSON(http.StatusOK, gin.H{"limiters": out})
+}
+
 // --- Invite code handlers ---
 
 // CreateInviteCode mints an invite code.
@@ -840,7 +1837,7 @@ func (h *Handler) CreateInviteCode(c *gin.Context) {
 			grantsUserType = &t
 		}
 	}
-	code, err := h.Repo.InviteCodes().Create(c.Request.Context(), middleware.UserID(c), kind, grants, days, grantsUserType)
+	code, err := h.Repo.InviteCodes().Create(c.Request.Context(), middleware.Admin(c).UserID, kind, grants, days, grantsUserType)
 	if err != nil {
 		middleware.RespondError(c, err)
 		return
@@ -1013,6 +2010,54 @@ func (h *Handler) AdminRemoveTeamMember(c *gin.Context) {
 	c.JSON(http.StatusOK, gin.H{"status": "removed"})
 }
 
+// RevokeTokensBefore revokes every refresh token issued before a cutoff
+// (default: now), optionally for a single app. Access tokens are cut off
+// separately with JWT_MIN_IAT.
+func (h *Handler) RevokeTokensBefore(c *gin.Context) {
+	var req revokeTokensBeforeRequest
+	if err := c.ShouldBindJSON(&req); err != nil {
+		middleware.RespondError(c, apperror.BadRequest("Invalid request body"))
+		return
+	}
+	ctx := c.Request.Context()
+	before := time.Now().UTC()
+	if req.Before != nil {
+		t, err := time.Parse(time.RFC3339, strings.TrimSpace(*req.Before))
+		if err != nil {
+			middleware.RespondError(c, apperror.BadRequest("before must be an RFC 3339 timestamp"))
+			return
+		}
+		before = t.UTC()
+	}
+	appID := ""
+	if req.AppID != nil && *req.AppID != "" {
+		app, err := h.Repo.Applications().FindByID(ctx, *req.AppID)
+		if err != nil {
+			middleware.RespondError(c, err)
+			return
+		}
+		if app == nil {
+			middleware.RespondError(c, apperror.ApplicationNotFound())
+			return
+		}
+		appID = app.ID
+	}
+	n, err := h.Repo.RefreshTokens().RevokeIssuedBefore(ctx, before, appID)
+	if err != nil {
+		middleware.RespondError(c, err)
+		return
+	}
+	securityLog(c, securitylog.Event{Type: securitylog.TokenRevoked, Outcome: securitylog.Success, Method: "revoke_before", UserID: middleware.Admin(c).UserID})
+	audit.Record(audit.Event{
+		Type:    audit.RefreshTokensRevoked,
+		UserID:  middleware.Admin(c).UserID,
+		AppID:   appID,
+		IP:      middleware.ClientIP(c, "unknown"),
+		Details: map[string]any{"before": displayDT(before), "revoked": n},
+	})
+	c.JSON(http.StatusOK, revokeTokensBeforeResponse{Revoked: n, Before: displayDT(before)})
+}
+
 // --- Helpers ---
 
 func parseMembershipExpiry(s string) (time.Time, error) {
@@ -1029,6 +2074,73 @@ func parseMembershipExpiry(s string) (time.Time, error) {
 	return time.Time{}, apperror.BadRequest("membership_expires_at must be an ISO 8601 date or datetime")
 }
 
+// paginateAccounts applies optional page/per_page query params to an account
+// list while keeping the plain-array response. X-Total-Count always carries
+// the unpaginated count.
+func (h *Handler) paginateAccounts(c *gin.Context, accounts []domain.Account) ([]domain.Account, error) {
+	total := uint64(len(accounts))
+	c.Header("X-Total-Count", strconv.FormatUint(total, 10))
+	if c.Query("page") == "" && c.Query("per_page") == "" {
+		return accounts, nil
+	}
+	page, perPage, err := h.pagination(c)
+	if err != nil {
+		return nil, err
+	}
+	start := (page - 1) * perPage
+	if start >= total {
+		return []domain.Account{}, nil
+	}
+	end := start + perPage
+	if end > total {
+		end = total
+	}
+	return accounts[start:end], nil
+}
+
+// defaultPerPage is per_page when the request leaves it out;
+// defaultMaxPageSize caps it when MaxPageSize is unset.
+const (
+	defaultPerPage     = 20
+	defaultMaxPageSize = 100
+)
+
+// pagination reads the page and per_page query params shared by every
+// paginated endpoint. Absent values default to page 1 and defaultPerPage;
+// numbers out of range are clamped to page >= 1 and per_page within
+// [1, MAX_PAGE_SIZE]. Anything that isn't an integer is a 400 rather than a
+// silent default.
+func (h *Handler) pagination(c *gin.Context) (page, perPage uint64, err error) {
+	maxSize := h.Cfg.MaxPageSize
+	if maxSize == 0 {
+		maxSize = defaultMaxPageSize
+	}
+	p, err := queryInt(c, "page", 1)
+	if err != nil {
+		return 0, 0, err
+	}
+	pp, err := queryInt(c, "per_page", defaultPerPage)
+	if err != nil {
+		return 0, 0, err
+	}
+	page = uint64(max(p, 1))
+	perPage = min(uint64(max(pp, 1)), maxSize)
+	return page, perPage, nil
+}
+
+// queryInt parses an integer query param, returning def when it is absent.
+func queryInt(c *gin.Context, name string, def int64) (int64, error) {
+	s := c.Query(name)
+	if s == "" {
+		return def, nil
+	}
+	n, err := strconv.ParseInt(s, 10, 64)
+	if err != nil {
+		return 0, apperror.BadRequest(name + " must be an integer")
+	}
+	return n, nil
+}
+
 func parseUintDefault(s string, def uint64) uint64 {
 	if s == "" {
 		return def
@@ -1,36 +1,55 @@
 package handlers
 
 import (
+	"context"
 	"encoding/json"
 	"net/http"
+	netmail "net/mail"
+	"sort"
+	"strconv"
+	"strings"
 	"time"
 
 	"github.com/gin-gonic/gin"
 	"github.com/google/uuid"
 
 	"github.com/zhaochy1990/auth-service/internal/apperror"
+	"github.com/zhaochy1990/auth-service/internal/audit"
 	"github.com/zhaochy1990/auth-service/internal/auth"
 	"github.com/zhaochy1990/auth-service/internal/auth/providers"
 	"github.com/zhaochy1990/auth-service/internal/domain"
 	"github.com/zhaochy1990/auth-service/internal/middleware"
+	"github.com/zhaochy1990/auth-service/internal/securitylog"
+	"github.com/zhaochy1990/auth-service/internal/seed"
 )
 
 // --- Request / Response types ---
 
+// registerRequest's Username is required, and Email optional, for apps with
+// username login.
 type registerRequest struct {
-	Email      string  `json:"email"`
-	Password   string  `json:"password"`
-	Name       *string `json:"name"`
-	InviteCode *string `json:"invite_code"`
+	Email        string  `json:"email"`
+	Username     *string `json:"username"`
+	Password     string  `json:"password"`
+	Name         *string `json:"name"`
+	InviteCode   *string `json:"invite_code"`
+	CaptchaToken string  `json:"captcha_token"`
 }
 
+// loginRequest names the user by Email, or by Username for apps with username
+// login.
 type loginRequest struct {
-	Email    string `json:"email"`
-	Password string `json:"password"`
+	Email      string `json:"email"`
+	Username   string `json:"username"`
+	Password   string `json:"password"`
+	RememberMe *bool  `json:"remember_me"`
 }
 
 type providerLoginRequest struct {
 	Credential json.RawMessage `json:"credential"`
+	// Email is used for a new user when the provider supplies none; it is
+	// how a client answers email_required.
+	Email *string `json:"email"`
 }
 
 type refreshRequest struct {
@@ -41,19 +60,61 @@ type logoutRequest struct {
 	RefreshToken string `json:"refresh_token"`
 }
 
+type sessionResponse struct {
+	Active    bool     `json:"active"`
+	UserID    string   `json:"user_id"`
+	ExpiresAt string   `json:"expires_at"`
+	Scopes    []string `json:"scopes"`
+}
+
+// tokenResponse is the user-facing login/refresh response. Scope carries the
+// granted scopes space-joined, as in the /oauth/token response, and
+// RefreshTokenExpiresIn is the refresh token's lifetime in seconds.
 type tokenResponse struct {
-	AccessToken  string `json:"access_token"`
-	RefreshToken string `json:"refresh_token"`
-	TokenType    string `json:"token_type"`
-	ExpiresIn    int64  `json:"expires_in"`
+	AccessToken           string  `json:"access_token"`
+	RefreshToken          string  `json:"refresh_token,omitempty"`
+	TokenType             string  `json:"token_type"`
+	ExpiresIn             int64   `json:"expires_in"`
+	RefreshTokenExpiresIn int64   `json:"refresh_token_expires_in,omitempty"`
+	Scope                 *string `json:"scope,omitempty"`
+}
+
+// publicProviderResponse is the login-UI view of an app provider. It never
+// carries the provider config, which may hold secrets.
+type publicProviderResponse struct {
+	ProviderID  string  `json:"provider_id"`
+	DisplayName *string `json:"display_name"`
+	IconURL     *string `json:"icon_url"`
+	SortOrder   int     `json:"sort_order"`
+}
+
+// discoverResponse names the provider an email address signs in with; a nil
+// Provider means the regular login form.
+type discoverResponse struct {
+	Provider *publicProviderResponse `json:"provider"`
+}
+
+// publicAppResponse is what a hosted login or consent page shows about the
+// X-Client-Id app.
+type publicAppResponse struct {
+	Name string `json:"name"`
+	appBranding
+}
+
+// bootstrapInfoResponse is what a frontend needs before anyone signs in. It
+// never carries the client secret.
+type bootstrapInfoResponse struct {
+	ClientID string `json:"client_id"`
 }
 
 type registerResponse struct {
-	UserID       string `json:"user_id"`
-	AccessToken  string `json:"access_token"`
-	RefreshToken string `json:"refresh_token"`
-	TokenType    string `json:"token_type"`
-	ExpiresIn    int64  `json:"expires_in"`
+	UserID                string  `json:"user_id"`
+	AccessToken           string  `json:"access_token"`
+	RefreshToken          string  `json:"refresh_token,omitempty"`
+	TokenType             string  `json:"token_type"`
+	ExpiresIn             int64   `json:"expires_in"`
+	RefreshTokenExpiresIn int64   `json:"refresh_token_expires_in,omitempty"`
+	Scope                 *string `json:"scope,omitempty"`
 }
 
 // --- Handlers ---
@@ -62,45 +123,91 @@ type registerResponse struct {
 func (h *Handler) Register(c *gin.Context) {
 	var req registerRequest
 	if err := c.ShouldBindJSON(&req); err != nil {
-		middleware.RespondError(c, apperror.BadRequest("Invalid request body"))
+		registerFailed(c, apperror.BadRequest("Invalid request body"))
 		return
 	}
 	ctx := c.Request.Context()
 
-	if err := auth.ValidatePassword(req.Password); err != nil {
-		middleware.RespondError(c, err)
+	// Closed apps onboard users through the admin API only.
+	if app := middleware.App(c); app != nil && !app.AllowSelfRegistration {
+		registerFailed(c, apperror.Forbidden())
+		return
+	}
+	if err := h.requireCaptcha(c, req.CaptchaToken); err != nil {
+		registerFailed(c, err)
 		return
 	}
+	if err := h.validatePassword(req.Password); err != nil {
+		registerFailed(c, err)
+		return
+	}
+	byUsername := usernameLogin(middleware.App(c))
+	username, err := normalizeUsername(req.Username, byUsername)
+	if err != nil {
+		registerFailed(c, err)
+		return
+	}
+	var email *string
+	if req.Email != "" || !byUsername {
+		email = strPtr(req.Email)
+	}
+	if email != nil {
+		if err := checkEmailDomain(c, *email); err != nil {
+			registerFailed(c, err)
+			return
+		}
+	}
 
 	var inviteRecord *domain.InviteCode
 	if requireInviteCode() {
 		if req.InviteCode == nil || *req.InviteCode == "" {
-			middleware.RespondError(c, apperror.BadRequest("invite_code is required"))
+			registerFailed(c, apperror.BadRequest("invite_code is required"))
 			return
 		}
 		record, err := h.Repo.InviteCodes().GetByCode(ctx, *req.InviteCode)
 		if err != nil {
-			middleware.RespondError(c, err)
+			registerFailed(c, err)
 			return
 		}
 		if record == nil || record.IsRevoked {
-			middleware.RespondError(c, apperror.InviteCodeNotFound())
+			registerFailed(c, apperror.InviteCodeNotFound())
 			return
 		}
 		if record.Kind == domain.InviteSingleUse && record.UsedAt != nil {
-			middleware.RespondError(c, apperror.InviteCodeAlreadyUsed())
+			registerFailed(c, apperror.InviteCodeAlreadyUsed())
 			return
 		}
 		inviteRecord = record
 	}
 
-	existing, err := h.Repo.Users().FindByEmail(ctx, req.Email)
-	if err != nil {
-		middleware.RespondError(c, err)
-		return
+	// The unique email and username keys are what reject duplicates (see
+	// InsertWithAccount); these early checks only keep a taken email or
+	// username from burning an invite code.
+	if email != nil {
+		existing, err := h.Repo.Users().FindByEmail(ctx, *email)
+		if err != nil {
+			registerFailed(c, err)
+			return
+		}
+		if existing != nil {
+			registerFailed(c, apperror.UserAlreadyExists())
+			return
+		}
+	}
+	if username != nil {
+		existing, err := h.Repo.Users().FindByUsername(ctx, *username)
+		if err != nil {
+			registerFailed(c, err)
+			return
+		}
+		if existing != nil {
+			registerFailed(c, apperror.UserAlreadyExists())
+			return
+		}
 	}
-	if existing != nil {
-		middleware.RespondError(c, apperror.UserAlreadyExists())
+	hash, err := auth.HashPassword(req.Password)
+	if err != nil {
+		registerFailed(c, err)
 		return
 	}
 
@@ -110,7 +217,7 @@ func (h *Handler) Register(c *gin.Context) {
 	// Claim a single-use invite code first (ETag-atomic) so a race leaves no orphan rows.
 	if inviteRecord != nil && inviteRecord.Kind == domain.InviteSingleUse {
 		if err := h.Repo.InviteCodes().MarkUsed(ctx, inviteRecord.Code, userID); err != nil {
-			middleware.RespondError(c, err)
+			registerFailed(c, err)
 			return
 		}
 	}
@@ -137,10 +244,11 @@ func (h *Handler) Register(c *gin.Context) {
 
 	user := &domain.User{
 		ID:                  userID,
-		Email:               strPtr(req.Email),
+		Email:               email,
+		Username:            username,
 		Name:                req.Name,
 		EmailVerified:       false,
-		Role:                "user",
+		Role:                h.Cfg.Roles.Default,
 		UserType:            userType,
 		IsActive:            true,
 		CustomAttributes:    map[string]any{},
@@ -150,59 +258,51 @@ func (h *Handler) Register(c *gin.Context) {
 		Membership:          membership,
 		MembershipExpiresAt: membershipExpires,
 	}
-	if err := h.Repo.Users().Insert(ctx, user); err != nil {
-		middleware.RespondError(c, err)
-		return
-	}
-
-	hash, err := auth.HashPassword(req.Password)
-	if err != nil {
-		middleware.RespondError(c, err)
-		return
-	}
 	accountID := uuid.NewString()
 	account := &domain.Account{
 		ID:                accountID,
 		UserID:            userID,
 		ProviderID:        "password",
-		ProviderAccountID: strPtr(req.Email),
+		ProviderAccountID: passwordAccountID(email, username),
 		Credential:        strPtr(hash),
 		ProviderMetadata:  "{}",
 		CreatedAt:         now,
 		UpdatedAt:         now,
 	}
-	if err := h.Repo.Accounts().Insert(ctx, account); err != nil {
-		_ = h.Repo.Accounts().DeleteByID(ctx, accountID)
-		_ = h.Repo.Users().DeleteByID(ctx, userID)
-		middleware.RespondError(c, err)
+	if err := h.Repo.Users().InsertWithAccount(ctx, user, account); err != nil {
+		registerFailed(c, err)
 		return
 	}
 
 	// Record initial login (best-effort).
-	_ = h.Repo.Users().RecordLogin(ctx, userID, middleware.ClientIP(c, "unknown"))
+	h.recordLogin(c, userID, middleware.AppID(c))
 
-	scopes := middleware.AllowedScopes(c)
-	accessToken, err := h.JWT.IssueAccessToken(userID, middleware.ClientID(c), scopes, "user", user.Membership, user.UserType, user.Name)
+	scopes := h.clientDefaultScopes(c)
+	accessToken, err := h.JWT.IssueAccessToken(ctx, userID, middleware.ClientID(c), scopes, user.Role, user.Membership, user.UserType, user.Name, h.loginTokenExtras(c, user.CustomAttributes, user.Email))
 	if err != nil {
 		_ = h.Repo.Accounts().DeleteByID(ctx, accountID)
 		_ = h.Repo.Users().DeleteByID(ctx, userID)
-		middleware.RespondError(c, err)
+		registerFailed(c, err)
 		return
 	}
-	refreshToken := auth.GenerateRefreshToken()
-	if err := auth.StoreRefreshToken(ctx, h.Repo, userID, middleware.AppID(c), refreshToken, scopes, nil, h.Cfg.JWTRefreshTokenExpiryDays); err != nil {
+	refreshToken, refreshTTL, err := h.issueRefreshToken(c, userID, scopes, h.Cfg.JWTRefreshTokenExpiryDays)
+	if err != nil {
 		_ = h.Repo.Accounts().DeleteByID(ctx, accountID)
 		_ = h.Repo.Users().DeleteByID(ctx, userID)
-		middleware.RespondError(c, err)
+		registerFailed(c, err)
 		return
 	}
 
+	securityLog(c, securitylog.Event{Type: securitylog.Register, Outcome: securitylog.Success, Method: "password", UserID: userID})
+	h.setAccessTokenCookie(c, accessToken)
 	c.JSON(http.StatusCreated, registerResponse{
-		UserID:       userID,
-		AccessToken:  accessToken,
-		RefreshToken: refreshToken,
-		TokenType:    "Bearer",
-		ExpiresIn:    h.Cfg.JWTAccessTokenExpirySecs,
+		UserID:                userID,
+		AccessToken:           accessToken,
+		RefreshToken:          refreshToken,
+		TokenType:             "Bearer",
+		ExpiresIn:             h.Cfg.JWTAccessTokenExpirySecs,
+		RefreshTokenExpiresIn: refreshTTL,
+		Scope:                 strPtr(strings.Join(scopes, " ")),
 	})
 }
 
@@ -215,60 +315,316 @@ func (h *Handler) Login(c *gin.Context) {
 	}
 	ctx := c.Request.Context()
 
-	user, err := h.Repo.Users().FindByEmail(ctx, req.Email)
+	app := middleware.App(c)
+	identifier := req.Email
+	if usernameLogin(app) {
+		identifier = req.Username
+	}
+	user, err := h.authenticatePassword(c, app, identifier, req.Password)
 	if err != nil {
 		middleware.RespondError(c, err)
 		return
 	}
-	if user == nil {
-		middleware.RespondError(c, apperror.InvalidCredentials())
+	h.recordLogin(c, user.ID, middleware.AppID(c))
+
+	membership := h.resolveMembership(ctx, user)
+	scopes := h.clientDefaultScopes(c)
+	accessToken, err := h.JWT.IssueAccessToken(ctx, user.ID, middleware.ClientID(c), scopes, user.Role, membership, user.UserType, user.Name, h.loginTokenExtras(c, user.CustomAttributes, user.Email))
+	if err != nil {
+		middleware.RespondError(c, err)
 		return
 	}
-	if !user.IsActive {
-		middleware.RespondError(c, apperror.UserDisabled())
+	refreshToken, refreshTTL, err := h.issueRefreshToken(c, user.ID, scopes, h.loginRefreshExpiryDays(req.RememberMe))
+	if err != nil {
+		middleware.RespondError(c, err)
 		return
 	}
 
+	securityLog(c, securitylog.Event{Type: securitylog.Login, Outcome: securitylog.Success, Method: "password", UserID: user.ID})
+	h.setAccessTokenCookie(c, accessToken)
+	c.JSON(http.StatusOK, tokenResponse{
+		AccessToken:           accessToken,
+		RefreshToken:          refreshToken,
+		TokenType:             "Bearer",
+		ExpiresIn:             h.Cfg.JWTAccessTokenExpirySecs,
+		RefreshTokenExpiresIn: refreshTTL,
+		Scope:                 strPtr(strings.Join(scopes, " ")),
+	})
+}
+
+// authenticatePassword checks a password login the same way for
+// /api/auth/login and the password grant: the (identifier, IP) pair throttle,
+// the user lookup, disabled status, the account lockout and the password,
+// counting every failure, then refuses an account flagged for a password
+// change. A failure is logged with its real cause and comes back as the
+// error to answer with, which may blur that cause.
+func (h *Handler) authenticatePassword(c *gin.Context, app *domain.Application, identifier, password string) (*domain.User, error) {
+	ctx := c.Request.Context()
+	if err := h.loginPairBlocked(c, identifier); err != nil {
+		return nil, err
+	}
+	user, err := h.findLoginUser(ctx, app, identifier)
+	if err != nil {
+		return nil, err
+	}
+	if user == nil {
+		loginFailed(c, "", "unknown_user")
+		return nil, h.failDecoyLogin(c, identifier)
+	}
+	if !user.IsActive {
+		loginFailed(c, user.ID, "user_disabled")
+		if h.Cfg.RevealDisabledStatus {
+			h.failLoginPair(c, identifier)
+			return nil, apperror.UserDisabled()
+		}
+		return nil, h.failDecoyLogin(c, identifier)
+	}
+	if h.Cfg.LoginMaxFailedAttempts > 0 && user.IsLocked(time.Now()) {
+		loginFailed(c, user.ID, "account_locked")
+		return nil, apperror.AccountLocked()
+	}
+
 	account, err := h.Repo.Accounts().FindByUserAndProvider(ctx, user.ID, "password")
+	if err != nil {
+		return nil, err
+	}
+	if account == nil || account.Credential == nil {
+		loginFailed(c, user.ID, "no_password")
+		return nil, h.failDecoyLogin(c, identifier)
+	}
+	ok, err := auth.VerifyPassword(password, *account.Credential)
+	if err != nil {
+		return nil, err
+	}
+	if !ok {
+		loginFailed(c, user.ID, "invalid_password")
+		h.failLoginPair(c, identifier)
+		if err := h.recordFailedLogin(c, user); err != nil {
+			return nil, err
+		}
+		return nil, apperror.InvalidCredentials()
+	}
+	h.LoginGuard.Reset(identifier, middleware.RemoteIP(c))
+
+	if user.FailedLoginAttempts > 0 || user.LockedUntil != nil {
+		if err := h.Repo.Users().ResetFailedLogins(ctx, user.ID); err != nil {
+			return nil, err
+		}
+		user.FailedLoginAttempts = 0
+		user.LockedUntil = nil
+	}
+	// Checked only once the password is right, so the flag tells nothing to
+	// someone guessing it.
+	if user.PasswordChangeRequired {
+		loginFailed(c, user.ID, "password_change_required")
+		return nil, apperror.PasswordChangeRequired()
+	}
+	return user, nil
+}
+
+// registerFailed logs a refused registration and answers with err.
+func registerFailed(c *gin.Context, err error) {
+	ae, _ := apperror.As(err)
+	securityLog(c, securitylog.Event{Type: securitylog.Register, Outcome: securitylog.Failure, Reason: ae.Type, Method: "password"})
+	middleware.RespondError(c, err)
+}
+
+// recordFailedLogin counts a wrong password against the account and, once the
+// configured limit is reached, locks password login for LoginLockoutSecs. It
+// sets X-Login-Attempts-Remaining from the stored count.
+func (h *Handler) recordFailedLogin(c *gin.Context, user *domain.User) error {
+	limit := h.Cfg.LoginMaxFailedAttempts
+	if limit <= 0 {
+		return nil
+	}
+	now := time.Now().UTC()
+	until := now.Add(time.Duration(h.Cfg.LoginLockoutSecs) * time.Second)
+	attempts, lockedUntil, err := h.Repo.Users().RecordFailedLogin(c.Request.Context(), user.ID, limit, until)
+	if err != nil {
+		return err
+	}
+	remaining := limit - int64(attempts)
+	if lockedUntil != nil && lockedUntil.After(now) {
+		remaining = 0
+		if !user.IsLocked(now) {
+			audit.Record(audit.Event{
+				Type:    audit.AccountLocked,
+				UserID:  user.ID,
+				AppID:   middleware.AppID(c),
+				IP:      middleware.ClientIP(c, "unknown"),
+				Details: map[string]any{"locked_until": until},
+			})
+		}
+	}
+	c.Header("X-Login-Attempts-Remaining", strconv.FormatInt(remaining, 10))
+	return nil
+}
+
+// failDecoyLogin answers a password login that found no account with a
+// password (unknown, disabled or provider-only) as a wrong password on a real
+// account would be answered. DecoyLockout keeps the identifier's count, so
+// X-Login-Attempts-Remaining falls the same way and account_locked follows
+// at the limit. It returns the error to answer with.
+func (h *Handler) failDecoyLogin(c *gin.Context, identifier string) error {
+	if !h.DecoyLockout.BlockedUntil(identifier).IsZero() {
+		return apperror.AccountLocked()
+	}
+	h.failLoginPair(c, identifier)
+	if h.DecoyLockout != nil {
+		remaining := h.DecoyLockout.Fail(identifier)
+		c.Header("X-Login-Attempts-Remaining", strconv.Itoa(remaining))
+	}
+	return apperror.InvalidCredentials()
+}
+
+// loginPairBlocked refuses a password login, with Retry-After, while the
+// LoginGuard holds its (identifier, RemoteIP) pair blocked. It is checked
+// before the user is looked up, so a blocked pair learns nothing and adds
+// nothing to the account's lockout count.
+func (h *Handler) loginPairBlocked(c *gin.Context, identifier string) error {
+	until := h.LoginGuard.BlockedUntil(identifier, middleware.RemoteIP(c))
+	if until.IsZero() {
+		return nil
+	}
+	loginFailed(c, "", "pair_throttled")
+	c.Header("Retry-After", strconv.Itoa(int(time.Until(until).Seconds())+1))
+	return apperror.LoginThrottled()
+}
+
+// failLoginPair counts a failed password login against its (identifier,
+// RemoteIP) pair, whether or not the user exists.
+func (h *Handler) failLoginPair(c *gin.Context, identifier string) {
+	h.LoginGuard.Fail(identifier, middleware.RemoteIP(c))
+}
+
+// refreshExpiresIn converts a refresh TTL in days to the seconds reported as
+// refresh_token_expires_in.
+func refreshExpiresIn(days int64) int64 {
+	return days * 24 * 60 * 60
+}
+
+// issueRefreshToken stores a new refresh token for the user and returns it
+// with its lifetime in seconds. For apps with issue_refresh_tokens off it
+// stores nothing and returns "" and 0, leaving the client an access token
+// only. Scopes in NON_REFRESHABLE_SCOPES stay out of the stored token.
+func (h *Handler) issueRefreshToken(c *gin.Context, userID string, scopes []string, expiryDays int64) (string, int64, error) {
+	if app := middleware.App(c); app != nil && !app.IssueRefreshTokens {
+		return "", 0, nil
+	}
+	token := auth.GenerateRefreshToken()
+	scopes = auth.RefreshableScopes(scopes, h.Cfg.NonRefreshableScopes)
+	if err := auth.StoreRefreshToken(c.Request.Context(), h.Repo, userID, middleware.AppID(c), token, scopes, nil, expiryDays); err != nil {
+		return "", 0, err
+	}
+	return token, refreshExpiresIn(expiryDays), nil
+}
+
+// loginRefreshExpiryDays maps the login form's "remember me" checkbox onto a
+// refresh TTL. Clients that don't send the flag get the global default.
+func (h *Handler) loginRefreshExpiryDays(rememberMe *bool) int64 {
+	switch {
+	case rememberMe == nil:
+		return h.Cfg.JWTRefreshTokenExpiryDays
+	case *rememberMe:
+		return h.Cfg.JWTRefreshTokenRememberMeExpiryDays
+	default:
+		return h.Cfg.JWTRefreshTokenShortExpiryDays
+	}
+}
+
+// suppliedEmail validates an email the client typed in for a provider
+// sign-up: a plain address, allowed by the app, and not already in use.
+func (h *Handler) suppliedEmail(c *gin.Context, raw string) (string, error) {
+	email := strings.TrimSpace(raw)
+	if addr, err := netmail.ParseAddress(email); err != nil || addr.Address != email {
+		return "", apperror.BadRequest("Invalid email address")
+	}
+	if err := checkEmailDomain(c, email); err != nil {
+		return "", err
+	}
+	taken, err := h.Repo.Users().FindByEmail(c.Request.Context(), email)
+	if err != nil {
+		return "", err
+	}
+	if taken != nil {
+		return "", apperror.UserAlreadyExists()
+	}
+	return email, nil
+}
+
+// ListClientProviders returns the active providers of the X-Client-Id app, in
+// display order, so a login UI can render its buttons.
+func (h *Handler) ListClientProviders(c *gin.Context) {
+	list, err := h.Repo.AppProviders().FindAllByApp(c.Request.Context(), middleware.AppID(c))
 	if err != nil {
 		middleware.RespondError(c, err)
 		return
 	}
-	if account == nil || account.Credential == nil {
-		middleware.RespondError(c, apperror.InvalidCredentials())
+	sort.SliceStable(list, func(i, j int) bool { return list[i].SortOrder < list[j].SortOrder })
+	out := make([]publicProviderResponse, 0, len(list))
+	for _, p := range list {
+		if !p.IsActive {
+			continue
+		}
+		out = append(out, publicProviderResponse{
+			ProviderID: p.ProviderID, DisplayName: p.DisplayName, IconURL: p.IconURL, SortOrder: p.SortOrder,
+		})
+	}
+	c.JSON(http.StatusOK, out)
+}
+
+// Discover implements home-realm discovery: it returns the active provider of
+// the X-Client-Id app whose email_domains include the domain of the email
+// query param, so a login UI can route the user straight to their
+// organization's sign-in. It says nothing about whether the user exists.
+func (h *Handler) Discover(c *gin.Context) {
+	email := strings.TrimSpace(c.Query("email"))
+	at := strings.LastIndexByte(email, '@')
+	if at < 0 || at == len(email)-1 {
+		middleware.RespondError(c, apperror.BadRequest("email must be an email address"))
 		return
 	}
-	ok, err := auth.VerifyPassword(req.Password, *account.Credential)
+	host := strings.ToLower(email[at+1:])
+	list, err := h.Repo.AppProviders().FindAllByApp(c.Request.Context(), middleware.AppID(c))
 	if err != nil {
 		middleware.RespondError(c, err)
 		return
 	}
-	if !ok {
-		middleware.RespondError(c, apperror.InvalidCredentials())
-		return
+	for _, p := range list {
+		if p.IsActive && contains(auth.DecodeStringArray(p.EmailDomains), host) {
+			c.JSON(http.StatusOK, discoverResponse{Provider: &publicProviderResponse{
+				ProviderID: p.ProviderID, DisplayName: p.DisplayName, IconURL: p.IconURL, SortOrder: p.SortOrder,
+			}})
+			return
+		}
 	}
+	c.JSON(http.StatusOK, discoverResponse{})
+}
 
-	_ = h.Repo.Users().RecordLogin(ctx, user.ID, middleware.ClientIP(c, "unknown"))
+// GetClientApp returns the X-Client-Id app's name and branding.
+func (h *Handler) GetClientApp(c *gin.Context) {
+	app := middleware.App(c)
+	c.JSON(http.StatusOK, publicAppResponse{Name: app.Name, appBranding: brandingOf(app)})
+}
 
-	membership := h.resolveMembership(ctx, user)
-	scopes := middleware.AllowedScopes(c)
-	accessToken, err := h.JWT.IssueAccessToken(user.ID, middleware.ClientID(c), scopes, user.Role, membership, user.UserType, user.Name)
+// BootstrapInfo publishes the client_id of the dashboard app (the seeded
+// Admin Dashboard unless BOOTSTRAP_APP_NAME says otherwise) so a deployed
+// frontend can discover it at runtime instead of baking it into its build.
+func (h *Handler) BootstrapInfo(c *gin.Context) {
+	name := h.Cfg.BootstrapAppName
+	if name == "" {
+		name = seed.DefaultAppName
+	}
+	app, err := h.Repo.Applications().FindByName(c.Request.Context(), name)
 	if err != nil {
 		middleware.RespondError(c, err)
 		return
 	}
-	refreshToken := auth.GenerateRefreshToken()
-	if err := auth.StoreRefreshToken(ctx, h.Repo, user.ID, middleware.AppID(c), refreshToken, scopes, nil, h.Cfg.JWTRefreshTokenExpiryDays); err != nil {
-		middleware.RespondError(c, err)
+	if app == nil || !app.IsActive {
+		middleware.RespondError(c, apperror.ApplicationNotFound())
 		return
 	}
-
-	c.JSON(http.StatusOK, tokenResponse{
-		AccessToken:  accessToken,
-		RefreshToken: refreshToken,
-		TokenType:    "Bearer",
-		ExpiresIn:    h.Cfg.JWTAccessTokenExpirySecs,
-	})
+	c.JSON(http.StatusOK, bootstrapInfoResponse{ClientID: app.ClientID})
 }
 
 // ProviderLogin authenticates via an external provider, creating the user on
@@ -292,37 +648,49 @@ func (h *Handler) ProviderLogin(c *gin.Context) {
 		return
 	}
 
-	provider, err := providers.Create(providerID, json.RawMessage(appProvider.Config), h.Cfg.EnableTestProviders)
+	provider, err := providers.Create(providerID, json.RawMessage(appProvider.Config), h.HTTP, h.Cfg.EnableTestProviders)
 	if err != nil {
 		middleware.RespondError(c, err)
 		return
 	}
 	info, err := provider.Authenticate(ctx, req.Credential)
 	if err != nil {
+		securityLog(c, securitylog.Event{Type: securitylog.Login, Outcome: securitylog.Failure, Reason: "provider_rejected", Method: "provider:" + providerID})
 		middleware.RespondError(c, err)
 		return
 	}
-
-	now := time.Now().UTC()
-
-	var userID, userRole string
-	var userName *string
-	var membership domain.MembershipTier
-	userType := domain.UserTypeRegular
+	if info.Email != nil {
+		if err := checkEmailDomain(c, *info.Email); err != nil {
+			middleware.RespondError(c, err)
+			return
+		}
+	}
 
 	existingAccount, err := h.Repo.Accounts().FindByProviderAccount(ctx, providerID, info.ProviderAccountID)
 	if err != nil {
 		middleware.RespondError(c, err)
 		return
 	}
+	sealedTokens, err := auth.SealProviderTokens(info.Tokens)
+	if err != nil {
+		middleware.RespondError(c, err)
+		return
+	}
+	var user *domain.User
+	var membership domain.MembershipTier
 	if existingAccount != nil {
 		existingAccount.ProviderMetadata = string(info.Metadata)
-		existingAccount.UpdatedAt = now
+		if sealedTokens != nil {
+			// Providers often issue a refresh token only at first consent;
+			// keep the stored one when a later login brings none.
+			existingAccount.ProviderTokens = sealedTokens
+		}
+		existingAccount.UpdatedAt = time.Now().UTC()
 		if err := h.Repo.Accounts().Update(ctx, existingAccount); err != nil {
 			middleware.RespondError(c, err)
 			return
 		}
-		user, err := h.Repo.Users().FindByID(ctx, existingAccount.UserID)
+		user, err = h.Repo.Users().FindByID(ctx, existingAccount.UserID)
 		if err != nil {
 			middleware.RespondError(c, err)
 			return
@@ -332,66 +700,181 @@ func (h *Handler) ProviderLogin(c *gin.Context) {
 			return
 		}
 		if !user.IsActive {
+			securityLog(c, securitylog.Event{Type: securitylog.Login, Outcome: securitylog.Failure, Reason: "user_disabled", Method: "provider:" + providerID, UserID: user.ID})
 			middleware.RespondError(c, apperror.UserDisabled())
 			return
 		}
+		if user.PasswordChangeRequired {
+			securityLog(c, securitylog.Event{Type: securitylog.Login, Outcome: securitylog.Failure, Reason: "password_change_required", Method: "provider:" + providerID, UserID: user.ID})
+			middleware.RespondError(c, apperror.PasswordChangeRequired())
+			return
+		}
 		membership = h.resolveMembership(ctx, user)
-		userID, userRole, userName, userType = user.ID, user.Role, user.Name, domain.UserTypeFromString(string(user.UserType))
 	} else {
-		userID = uuid.NewString()
-		user := &domain.User{
-			ID:               userID,
-			Email:            info.Email,
-			Name:             info.Name,
-			AvatarURL:        info.AvatarURL,
-			EmailVerified:    false,
-			Role:             "user",
-			UserType:         domain.UserTypeRegular,
-			IsActive:         true,
-			CustomAttributes: map[string]any{},
-			CreatedAt:        now,
-			UpdatedAt:        now,
-			Membership:       domain.MembershipRegular,
+		reg := auth.RegistrationClaims{
+			ProviderID:        providerID,
+			ProviderAccountID: info.ProviderAccountID,
+			Name:              info.Name,
+			AvatarURL:         info.AvatarURL,
+			Metadata:          string(info.Metadata),
+			Tokens:            sealedTokens,
 		}
-		if err := h.Repo.Users().Insert(ctx, user); err != nil {
-			middleware.RespondError(c, err)
-			return
+		email := info.Email
+		if email == nil && req.Email != nil {
+			supplied, err := h.suppliedEmail(c, *req.Email)
+			if err != nil {
+				middleware.RespondError(c, err)
+				return
+			}
+			email = &supplied
 		}
-		account := &domain.Account{
-			ID:                uuid.NewString(),
-			UserID:            userID,
-			ProviderID:        providerID,
-			ProviderAccountID: strPtr(info.ProviderAccountID),
-			ProviderMetadata:  string(info.Metadata),
-			CreatedAt:         now,
-			UpdatedAt:         now,
+		if email == nil && middleware.App(c).RequireProviderEmail {
+			// The credential may not survive a second use, so hand out a
+			// token that finishes the sign-up via CompleteRegistration.
+			regToken, err := h.JWT.IssueRegistrationToken(ctx, middleware.ClientID(c), reg)
+			if err != nil {
+				middleware.RespondError(c, err)
+				return
+			}
+			middleware.RespondError(c, apperror.EmailRequired().WithDetails(map[string]any{
+				"provider_id": providerID, "name": info.Name,
+				"registration_token": regToken, "expires_in": int64(auth.RegistrationTokenTTL.Seconds()),
+			}))
+			return
 		}
-		if err := h.Repo.Accounts().Insert(ctx, account); err != nil {
+		if user, err = h.createProviderUser(ctx, &reg, email); err != nil {
 			middleware.RespondError(c, err)
 			return
 		}
-		userRole, userName, membership = "user", info.Name, domain.MembershipRegular
+		membership = domain.MembershipRegular
+	}
+	h.respondProviderLogin(c, providerID, user, membership)
+}
+
+// completeRegistrationRequest answers email_required: the registration token
+// from that error plus the missing email.
+type completeRegistrationRequest struct {
+	RegistrationToken string `json:"registration_token"`
+	Email             string `json:"email"`
+}
+
+// CompleteRegistration finishes a provider sign-up that stopped at
+// email_required, creating the user with the supplied email and signing it in
+// as ProviderLogin would have.
+func (h *Handler) CompleteRegistration(c *gin.Context) {
+	var req completeRegistrationRequest
+	if err := c.ShouldBindJSON(&req); err != nil {
+		middleware.RespondError(c, apperror.BadRequest("Invalid request body"))
+		return
+	}
+	if req.RegistrationToken == "" || strings.TrimSpace(req.Email) == "" {
+		middleware.RespondError(c, apperror.BadRequest("registration_token and email are required"))
+		return
+	}
+	ctx := c.Request.Context()
+
+	reg, err := h.JWT.VerifyRegistrationToken(ctx, req.RegistrationToken, middleware.ClientID(c))
+	if err != nil {
+		middleware.RespondError(c, err)
+		return
 	}
+	appProvider, err := h.Repo.AppProviders().FindByAppAndProvider(ctx, middleware.AppID(c), reg.ProviderID)
+	if err != nil {
+		middleware.RespondError(c, err)
+		return
+	}
+	if appProvider == nil || !appProvider.IsActive {
+		middleware.RespondError(c, apperror.ProviderNotConfigured())
+		return
+	}
+	email, err := h.suppliedEmail(c, req.Email)
+	if err != nil {
+		middleware.RespondError(c, err)
+		return
+	}
+	// Replaying the token fails here with ProviderAccountInUse once the
+	// first completion has linked the provider account.
+	user, err := h.createProviderUser(ctx, reg, &email)
+	if err != nil {
+		middleware.RespondError(c, err)
+		return
+	}
+	_ = h.JWT.RevokeAccessToken(ctx, req.RegistrationToken)
+	h.respondProviderLogin(c, reg.ProviderID, user, domain.MembershipRegular)
+}
+
+// createProviderUser creates a user together with its linked provider
+// account.
+func (h *Handler) createProviderUser(ctx context.Context, reg *auth.RegistrationClaims, email *string) (*domain.User, error) {
+	now := time.Now().UTC()
+	user := &domain.User{
+		ID:               uuid.NewString(),
+		Email:            email,
+		Name:             reg.Name,
+		AvatarURL:        reg.AvatarURL,
+		EmailVerified:    false,
+		Role:             h.Cfg.Roles.Default,
+		UserType:         domain.UserTypeRegular,
+		IsActive:         true,
+		CustomAttributes: map[string]any{},
+		CreatedAt:        now,
+		UpdatedAt:        now,
+		Membership:       domain.MembershipRegular,
+	}
+	account := &domain.Account{
+		ID:                uuid.NewString(),
+		UserID:            user.ID,
+		ProviderID:        reg.ProviderID,
+		ProviderAccountID: strPtr(reg.ProviderAccountID),
+		ProviderMetadata:  reg.Metadata,
+		ProviderTokens:    reg.Tokens,
+		CreatedAt:         now,
+		UpdatedAt:         now,
+	}
+	// A concurrent login for the same provider account that wins the race
+	// makes this fail with ProviderAccountInUse, and no user is kept.
+	if err := h.Repo.Users().InsertWithAccount(ctx, user, account); err != nil {
+		return nil, err
+	}
+	return user, nil
+}
 
+// recordLogin notes a successful sign-in on the user's recent logins and on
+// the app's daily login count. Both are best-effort.
+func (h *Handler) recordLogin(c *gin.Context, userID, appID string) {
+	ctx := c.Request.Context()
 	_ = h.Repo.Users().RecordLogin(ctx, userID, middleware.ClientIP(c, "unknown"))
+	_ = h.Repo.Applications().RecordLogin(ctx, appID, time.Now())
+}
+
+// respondProviderLogin records the sign-in and responds with the tokens of a
+// successful provider login.
+func (h *Handler) respondProviderLogin(c *gin.Context, providerID string, user *domain.User, membership domain.MembershipTier) {
+	ctx := c.Request.Context()
+	h.recordLogin(c, user.ID, middleware.AppID(c))
 
-	scopes := middleware.AllowedScopes(c)
-	accessToken, err := h.JWT.IssueAccessToken(userID, middleware.ClientID(c), scopes, userRole, membership, userType, userName)
+	scopes := h.clientDefaultScopes(c)
+	userType := domain.UserTypeFromString(string(user.UserType))
+	accessToken, err := h.JWT.IssueAccessToken(ctx, user.ID, middleware.ClientID(c), scopes, user.Role, membership, userType, user.Name, h.loginTokenExtras(c, user.CustomAttributes, user.Email))
 	if err != nil {
 		middleware.RespondError(c, err)
 		return
 	}
-	refreshToken := auth.GenerateRefreshToken()
-	if err := auth.StoreRefreshToken(ctx, h.Repo, userID, middleware.AppID(c), refreshToken, scopes, nil, h.Cfg.JWTRefreshTokenExpiryDays); err != nil {
+	refreshToken, refreshTTL, err := h.issueRefreshToken(c, user.ID, scopes, h.Cfg.JWTRefreshTokenExpiryDays)
+	if err != nil {
 		middleware.RespondError(c, err)
 		return
 	}
 
+	securityLog(c, securitylog.Event{Type: securitylog.Login, Outcome: securitylog.Success, Method: "provider:" + providerID, UserID: user.ID})
+	h.setAccessTokenCookie(c, accessToken)
 	c.JSON(http.StatusOK, tokenResponse{
-		AccessToken:  accessToken,
-		RefreshToken: refreshToken,
-		TokenType:    "Bearer",
-		ExpiresIn:    h.Cfg.JWTAccessTokenExpirySecs,
+		AccessToken:           accessToken,
+		RefreshToken:          refreshToken,
+		TokenType:             "Bearer",
+		ExpiresIn:             h.Cfg.JWTAccessTokenExpirySecs,
+		RefreshTokenExpiresIn: refreshTTL,
+		Scope:                 strPtr(strings.Join(scopes, " ")),
 	})
 }
 
@@ -404,11 +887,12 @@ func (h *Handler) Refresh(c *gin.Context) {
 	}
 	ctx := c.Request.Context()
 
-	userID, newRefreshToken, scopes, err := auth.RotateRefreshToken(ctx, h.Repo, req.RefreshToken, middleware.AppID(c), h.Cfg.JWTRefreshTokenExpiryDays)
+	rotated, err := h.rotateRefreshToken(c, req.RefreshToken, nil)
 	if err != nil {
 		middleware.RespondError(c, err)
 		return
 	}
+	userID, scopes := rotated.UserID, rotated.Scopes
 	user, err := h.Repo.Users().FindByID(ctx, userID)
 	if err != nil {
 		middleware.RespondError(c, err)
@@ -423,19 +907,81 @@ func (h *Handler) Refresh(c *gin.Context) {
 		return
 	}
 	membership := h.resolveMembership(ctx, user)
-	accessToken, err := h.JWT.IssueAccessToken(userID, middleware.ClientID(c), scopes, user.Role, membership, user.UserType, user.Name)
+	accessToken, err := h.JWT.IssueAccessToken(ctx, userID, middleware.ClientID(c), scopes, user.Role, membership, user.UserType, user.Name, h.tokenExtras(c, user.CustomAttributes, user.Email))
 	if err != nil {
 		middleware.RespondError(c, err)
 		return
 	}
+	tokenIssued(c, "refresh_token", userID)
+	h.setAccessTokenCookie(c, accessToken)
 	c.JSON(http.StatusOK, tokenResponse{
-		AccessToken:  accessToken,
-		RefreshToken: newRefreshToken,
-		TokenType:    "Bearer",
-		ExpiresIn:    h.Cfg.JWTAccessTokenExpirySecs,
+		AccessToken:           accessToken,
+		RefreshToken:          rotated.Token,
+		TokenType:             "Bearer",
+		ExpiresIn:             h.Cfg.JWTAccessTokenExpirySecs,
+		RefreshTokenExpiresIn: refreshExpiresIn(rotated.ExpiryDays),
+		Scope:                 strPtr(strings.Join(scopes, " ")),
 	})
 }
 
+// rotateRefreshToken wraps auth.RotateRefreshToken for both refresh paths,
+// honoring the app's rotate_refresh_tokens setting, and turns detected reuse
+// into an audit event (plus the optional lockdown).
+func (h *Handler) rotateRefreshToken(c *gin.Context, token string, narrow []string) (*auth.RotatedRefreshToken, error) {
+	rotate := true
+	if app := middleware.App(c); app != nil {
+		rotate = app.RotateRefreshTokens
+	}
+	rotated, err := auth.RotateRefreshToken(c.Request.Context(), h.Repo, token, middleware.AppID(c), h.Cfg.JWTRefreshTokenExpiryDays, rotate, narrow, h.Cfg.NonRefreshableScopes)
+	if err != nil {
+		userID := ""
+		if rotated != nil {
+			userID = rotated.UserID
+		}
+		tokenFailed(c, "refresh_token", userID, err)
+		if ae, _ := apperror.As(err); ae.Type == apperror.TokenRevoked().Type && rotated != nil {
+			h.handleRefreshReuse(c, rotated.UserID)
+		}
+		return nil, err
+	}
+	return rotated, nil
+}
+
+// handleRefreshReuse records a refresh-token reuse incident and, when
+// configured, revokes every session of the user and flags the account for a
+// password change. All steps are best-effort: the caller already fails, so
+// a step that fails is logged and the rest still run.
+func (h *Handler) handleRefreshReuse(c *gin.Context, userID string) {
+	ctx := c.Request.Context()
+	forced := h.Cfg.RefreshReuseForcePasswordChange
+	audit.Record(audit.Event{
+		Type:    audit.RefreshTokenReuse,
+		UserID:  userID,
+		AppID:   middleware.AppID(c),
+		IP:      middleware.ClientIP(c, "unknown"),
+		Details: map[string]any{"forced_password_change": forced},
+	})
+	if !forced {
+		return
+	}
+	if err := h.revokeSessions(ctx, userID); err != nil {
+		audit.Warn("refresh reuse: revoking sessions failed", "user_id", userID, "error", err)
+	}
+	user, err := h.Repo.Users().FindByID(ctx, userID)
+	if err != nil {
+		audit.Warn("refresh reuse: loading user failed", "user_id", userID, "error", err)
+		return
+	}
+	if user == nil {
+		return
+	}
+	user.PasswordChangeRequired = true
+	user.UpdatedAt = time.Now().UTC()
+	if err := h.Repo.Users().Update(ctx, user); err != nil {
+		audit.Warn("refresh reuse: flagging password change failed", "user_id", userID, "error", err)
+	}
+}
+
 // Logout revokes a refresh token.
 func (h *Handler) Logout(c *gin.Context) {
 	var req logoutRequest
@@ -447,5 +993,37 @@ func (h *Handler) Logout(c *gin.Context) {
 		middleware.RespondError(c, err)
 		return
 	}
+	securityLog(c, securitylog.Event{Type: securitylog.TokenRevoked, Outcome: securitylog.Success, Method: "logout"})
+	if _, err := c.Cookie(middleware.AccessTokenCookie); err == nil {
+		c.SetSameSite(http.SameSiteLaxMode)
+		c.SetCookie(middleware.AccessTokenCookie, "", -1, "/", "", true, true)
+	}
 	c.JSON(http.StatusOK, gin.H{"status": "ok"})
 }
+
+// setAccessTokenCookie also hands the access token to the browser as a
+// Secure, HttpOnly, SameSite=Lax cookie when the app opted in with
+// access_token_cookie. It lives as long as the token.
+func (h *Handler) setAccessTokenCookie(c *gin.Context, token string) {
+	if app := middleware.App(c); app == nil || !app.AccessTokenCookie {
+		return
+	}
+	c.SetSameSite(http.SameSiteLaxMode)
+	c.SetCookie(middleware.AccessTokenCookie, token, int(h.Cfg.JWTAccessTokenExpirySecs), "/", "", true, true)
+}
+
+// Session is a cheap "am I still logged in" check for the Bearer token. The
+// AuthenticatedUser guard has already verified the token (including
+// revocation and JWT_MIN_IAT) and that the user exists and is active.
+func (h *Handler) Session(c *gin.Context) {
+	scopes := middleware.Scopes(c)
+	if scopes == nil {
+		scopes = []string{}
+	}
+	c.JSON(http.StatusOK, sessionResponse{
+		Active:    true,
+		UserID:    middleware.UserID(c),
+		ExpiresAt: displayDT(middleware.ExpiresAt(c)),
+		Scopes:    scopes,
+	})
+}
@@ -7,16 +7,28 @@ package handlers
 
 import (
 	"context"
+	"crypto/sha256"
+	"encoding/hex"
+	"encoding/json"
 	"fmt"
+	"net/http"
 	"os"
 	"reflect"
 	"strings"
 	"time"
 
+	"github.com/gin-gonic/gin"
+
+	"github.com/zhaochy1990/auth-service/internal/apperror"
 	"github.com/zhaochy1990/auth-service/internal/auth"
+	"github.com/zhaochy1990/auth-service/internal/auth/captcha"
+	"github.com/zhaochy1990/auth-service/internal/auth/providers"
 	"github.com/zhaochy1990/auth-service/internal/config"
 	"github.com/zhaochy1990/auth-service/internal/domain"
+	"github.com/zhaochy1990/auth-service/internal/mail"
+	"github.com/zhaochy1990/auth-service/internal/middleware"
 	"github.com/zhaochy1990/auth-service/internal/repository"
+	"github.com/zhaochy1990/auth-service/internal/securitylog"
 )
 
 // Handler bundles the dependencies shared by all HTTP handlers.
@@ -24,11 +36,64 @@ type Handler struct {
 	Repo repository.Repository
 	JWT  *auth.JWTManager
 	Cfg  *config.Config
+	// HTTP is the outbound client handed to every auth provider.
+	HTTP *http.Client
+	// Captcha verifies bot-check tokens; nil unless CaptchaEnabled.
+	Captcha captcha.Verifier
+	// Mail sends transactional email; nil unless SMTPHost is set.
+	Mail mail.Sender
+	// RateLimiters are the router's limiters by name, for GET
+	// /admin/rate-limits.
+	RateLimiters map[string]*middleware.RateLimiter
+	// LoginGuard throttles password logins per (identifier, IP) pair; nil
+	// unless LoginPairMaxFailures is set.
+	LoginGuard *middleware.LoginGuard
+	// DecoyLockout mirrors the account lockout for logins matching no
+	// password account; nil unless LoginMaxFailedAttempts is set.
+	DecoyLockout *middleware.DecoyLockout
 }
 
 // New builds a Handler.
 func New(repo repository.Repository, jwt *auth.JWTManager, cfg *config.Config) *Handler {
-	return &Handler{Repo: repo, JWT: jwt, Cfg: cfg}
+	client := providers.NewHTTPClient(
+		time.Duration(cfg.ProviderHTTPConnectTimeoutSecs)*time.Second,
+		time.Duration(cfg.ProviderHTTPTimeoutSecs)*time.Second,
+		"auth-service/"+appVersion(),
+	)
+	h := &Handler{Repo: repo, JWT: jwt, Cfg: cfg, HTTP: client}
+	h.LoginGuard = middleware.NewLoginGuard(int(cfg.LoginPairMaxFailures),
+		time.Duration(cfg.LoginPairFailureWindowSecs)*time.Second, time.Duration(cfg.LoginPairBlockSecs)*time.Second)
+	h.DecoyLockout = middleware.NewDecoyLockout(int(cfg.LoginMaxFailedAttempts), time.Duration(cfg.LoginLockoutSecs)*time.Second)
+	if cfg.CaptchaEnabled {
+		// FromEnv rejects unknown providers; a hand-built bad config leaves
+		// Captcha nil and requireCaptcha fails closed.
+		h.Captcha, _ = captcha.New(cfg.CaptchaProvider, cfg.CaptchaSecret, client)
+	}
+	if cfg.SMTPHost != "" {
+		h.Mail = mail.NewSMTP(cfg.SMTPHost, cfg.SMTPPort, cfg.SMTPUsername, cfg.SMTPPassword, cfg.SMTPFrom)
+	}
+	return h
+}
+
+// validatePassword applies the complexity rules, then the configured denylist.
+func (h *Handler) validatePassword(password string) error {
+	if err := auth.ValidatePassword(password); err != nil {
+		return err
+	}
+	return auth.CheckPasswordDenylist(password, h.Cfg.PasswordDenylist)
+}
+
+// requireCaptcha checks the request's bot-check token when CAPTCHA_ENABLED is
+// set. Only registration calls it today; password-reset and magic-link
+// endpoints should too once they exist.
+func (h *Handler) requireCaptcha(c *gin.Context, token string) error {
+	if !h.Cfg.CaptchaEnabled {
+		return nil
+	}
+	if h.Captcha == nil {
+		return apperror.Internal()
+	}
+	return h.Captcha.Verify(c.Request.Context(), token, middleware.ClientIP(c, ""))
 }
 
 // resolveMembership returns the user's effective tier, lazily downgrading an
@@ -44,6 +109,40 @@ func (h *Handler) resolveMembership(ctx context.Context, user *domain.User) doma
 	return user.Membership
 }
 
+// revokeSessions deletes every refresh token of the user and, when access
+// tokens are opaque, every access token too (signed JWTs live until expiry).
+func (h *Handler) revokeSessions(ctx context.Context, userID string) error {
+	if err := h.Repo.RefreshTokens().DeleteAllByUser(ctx, userID); err != nil {
+		return err
+	}
+	return h.Repo.AccessTokens().DeleteAllByUser(ctx, userID)
+}
+
+// securityLog emits a security log line for the request, stamped with its
+// client id and IP.
+func securityLog(c *gin.Context, e securitylog.Event) {
+	e.ClientID = middleware.ClientID(c)
+	e.IP = middleware.ClientIP(c, "")
+	securitylog.Emit(e)
+}
+
+// loginFailed logs a rejected password login with the real cause, which the
+// response may deliberately blur.
+func loginFailed(c *gin.Context, userID, reason string) {
+	securityLog(c, securitylog.Event{Type: securitylog.Login, Outcome: securitylog.Failure, Reason: reason, Method: "password", UserID: userID})
+}
+
+// tokenIssued and tokenFailed log the outcome of a token grant. A failure's
+// reason is the error code the client got.
+func tokenIssued(c *gin.Context, grant, userID string) {
+	securityLog(c, securitylog.Event{Type: securitylog.TokenIssued, Outcome: securitylog.Success, Method: grant, UserID: userID})
+}
+
+func tokenFailed(c *gin.Context, grant, userID string, err error) {
+	ae, _ := apperror.As(err)
+	securityLog(c, securitylog.Event{Type: securitylog.TokenIssued, Outcome: securitylog.Failure, Reason: ae.Type, Method: grant, UserID: userID})
+}
+
 // requireInviteCode reports whether registration is invite-gated. The env flag
 // is read per request so runtime config changes take effect without restart.
 func requireInviteCode() bool {
@@ -57,21 +156,21 @@ func appVersion() string {
 	return "dev"
 }
 
-// displayDT formats a time as "YYYY-MM-DD HH:MM:SS" with an optional
-// fractional part of 3, 6, or 9 digits.
+// displayDT formats a time as RFC 3339 UTC ("YYYY-MM-DDTHH:MM:SSZ") with an
+// optional fractional part of 3, 6, or 9 digits.
 func displayDT(t time.Time) string {
 	t = t.UTC()
-	base := t.Format("2006-01-02 15:04:05")
+	base := t.Format("2006-01-02T15:04:05")
 	ns := t.Nanosecond()
 	switch {
 	case ns == 0:
-		return base
+		return base + "Z"
 	case ns%1_000_000 == 0:
-		return fmt.Sprintf("%s.%03d", base, ns/1_000_000)
+		return fmt.Sprintf("%s.%03dZ", base, ns/1_000_000)
 	case ns%1_000 == 0:
-		return fmt.Sprintf("%s.%06d", base, ns/1_000)
+		return fmt.Sprintf("%s.%06dZ", base, ns/1_000)
 	default:
-		return fmt.Sprintf("%s.%09d", base, ns)
+		return fmt.Sprintf("%s.%09dZ", base, ns)
 	}
 }
 
@@ -83,8 +182,120 @@ func displayDTPtr(t *time.Time) *string {
 	return &s
 }
 
+// jsonWithETag writes body as JSON with a weak ETag over the serialized bytes
+// and answers 304 when If-None-Match already names it. Meant for list
+// endpoints the dashboard polls; "private, no-cache" makes browsers revalidate.
+func jsonWithETag(c *gin.Context, status int, body any) {
+	b, err := json.Marshal(body)
+	if err != nil {
+		middleware.RespondError(c, apperror.Internal())
+		return
+	}
+	sum := sha256.Sum256(b)
+	etag := `W/"` + hex.EncodeToString(sum[:16]) + `"`
+	c.Header("ETag", etag)
+	c.Header("Cache-Control", "private, no-cache")
+	if etagMatches(c.GetHeader("If-None-Match"), etag) {
+		c.Status(http.StatusNotModified)
+		return
+	}
+	c.Data(status, "application/json; charset=utf-8", b)
+}
+
+// etagMatches applies the weak comparison If-None-Match requires.
+func etagMatches(header, etag string) bool {
+	opaque := strings.TrimPrefix(etag, "W/")
+	for _, candidate := range strings.Split(header, ",") {
+		candidate = strings.TrimSpace(candidate)
+		if candidate == "*" || strings.TrimPrefix(candidate, "W/") == opaque {
+			return true
+		}
+	}
+	return false
+}
+
+// usernameLogin reports whether app signs users in by username rather than
+// email.
+func usernameLogin(app *domain.Application) bool {
+	return app != nil && app.LoginIdentifier == domain.LoginIdentifierUsername
+}
+
+// findLoginUser looks up the user a password login names, by username or
+// email according to the app's login identifier. A blank identifier names
+// nobody.
+func (h *Handler) findLoginUser(ctx context.Context, app *domain.Application, identifier string) (*domain.User, error) {
+	if strings.TrimSpace(identifier) == "" {
+		return nil, nil
+	}
+	if usernameLogin(app) {
+		return h.Repo.Users().FindByUsername(ctx, identifier)
+	}
+	return h.Repo.Users().FindByEmail(ctx, identifier)
+}
+
+// normalizeUsername trims and checks an optional username: 3 to 64 letters,
+// digits, '.', '_' or '-'. An absent or blank one yields nil, or an error when
+// required.
+func normalizeUsername(raw *string, required bool) (*string, error) {
+	if raw == nil || strings.TrimSpace(*raw) == "" {
+		if required {
+			return nil, apperror.BadRequest("username is required")
+		}
+		return nil, nil
+	}
+	name := strings.TrimSpace(*raw)
+	if len(name) < 3 || len(name) > 64 {
+		return nil, apperror.BadRequest("username must be 3 to 64 characters")
+	}
+	for _, r := range name {
+		if !(r >= 'a' && r <= 'z' || r >= 'A' && r <= 'Z' || r >= '0' && r <= '9' || r == '.' || r == '_' || r == '-') {
+			return nil, apperror.BadRequest("username may only contain letters, digits, '.', '_' and '-'")
+		}
+	}
+	return &name, nil
+}
+
+// checkEmailDomain enforces the requesting app's allowed_email_domains, if it
+// has any. Domains compare case-insensitively.
+func checkEmailDomain(c *gin.Context, email string) error {
+	app := middleware.App(c)
+	if app == nil {
+		return nil
+	}
+	allowed := auth.DecodeStringArray(app.AllowedEmailDomains)
+	if len(allowed) == 0 {
+		return nil
+	}
+	if at := strings.LastIndexByte(email, '@'); at >= 0 {
+		host := email[at+1:]
+		for _, d := range allowed {
+			if strings.EqualFold(d, host) {
+				return nil
+			}
+		}
+	}
+	return apperror.BadRequest("Email domain is not allowed for this application")
+}
+
 func strPtr(s string) *string { return &s }
 
+// nonEmptyPtr is strPtr with "" mapped to nil, for omitempty fields.
+func nonEmptyPtr(s string) *string {
+	if s == "" {
+		return nil
+	}
+	return &s
+}
+
+// passwordAccountID is the provider_account_id of a password account: the
+// email, or the username for users registered without one.
+func passwordAccountID(email, username *string) *string {
+	if email == nil {
+		return username
+	}
+	return email
+}
+
 func customAttributesOrEmpty(attributes map[string]any) map[string]any {
 	if attributes == nil {
 		return map[string]any{}
@@ -92,6 +303,61 @@ func customAttributesOrEmpty(attributes map[string]any) map[string]any {
 	return attributes
 }
 
+// maxCustomAttributesBytes bounds a user's serialized custom attributes.
+const maxCustomAttributesBytes = 8192
+
+// validateCustomAttributes enforces the size limit on a merged attribute set.
+func validateCustomAttributes(attributes map[string]any) error {
+	b, err := json.Marshal(attributes)
+	if err != nil {
+		return apperror.BadRequest("custom_attributes must be a JSON object")
+	}
+	if len(b) > maxCustomAttributesBytes {
+		return apperror.BadRequest(fmt.Sprintf("custom_attributes must not exceed %d bytes", maxCustomAttributesBytes))
+	}
+	return nil
+}
+
+// tokenExtras gathers the optional claims of a user access token: the app's
+// custom claims and extra audiences, the user's attributes named in
+// CustomAttributesTokenClaims, and the email when the app opted in with
+// include_email_claim.
+func (h *Handler) tokenExtras(c *gin.Context, attributes map[string]any, email *string) auth.TokenExtras {
+	return h.appTokenExtras(middleware.App(c), attributes, email)
+}
+
+// appTokenExtras is tokenExtras for an app at hand rather than the request's.
+func (h *Handler) appTokenExtras(app *domain.Application, attributes map[string]any, email *string) auth.TokenExtras {
+	var extras auth.TokenExtras
+	if app != nil {
+		extras.App = auth.DecodeObject(app.CustomClaims)
+		if app.IncludeEmailClaim {
+			extras.Email = email
+		}
+		extras.Audiences = auth.DecodeStringArray(app.Audiences)
+		if app.Issuer != nil {
+			extras.Issuer = *app.Issuer
+		}
+	}
+	for _, key := range h.Cfg.CustomAttributesTokenClaims {
+		if value, ok := attributes[key]; ok {
+			if extras.Attributes == nil {
+				extras.Attributes = map[string]any{}
+			}
+			extras.Attributes[key] = value
+		}
+	}
+	return extras
+}
+
+// loginTokenExtras is tokenExtras for a token minted right after the user
+// presented credentials, so it carries auth_time.
+func (h *Handler) loginTokenExtras(c *gin.Context, attributes map[string]any, email *string) auth.TokenExtras {
+	extras := h.tokenExtras(c, attributes, email)
+	extras.AuthTime = time.Now()
+	return extras
+}
+
 func mergeCustomAttributes(target map[string]any, patch map[string]any) map[string]any {
 	if target == nil {
 		target = map[string]any{}
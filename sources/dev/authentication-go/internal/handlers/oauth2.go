@@ -1,55 +1,90 @@
 package handlers
 
 import (
+	"context"
+	"fmt"
 	"net/http"
+	"sort"
 	"strings"
 
 	"github.com/gin-gonic/gin"
+	"github.com/gin-gonic/gin/binding"
 
 	"github.com/zhaochy1990/auth-service/internal/apperror"
 	"github.com/zhaochy1990/auth-service/internal/auth"
+	"github.com/zhaochy1990/auth-service/internal/domain"
 	"github.com/zhaochy1990/auth-service/internal/middleware"
+	"github.com/zhaochy1990/auth-service/internal/securitylog"
 )
 
 // --- Request / Response types ---
 
+// tokenRequest is bound from a form-encoded (RFC 6749) or JSON body.
 type tokenRequest struct {
-	GrantType string `json:"grant_type"`
+	GrantType string `json:"grant_type" form:"grant_type"`
 	// authorization_code flow
-	Code         *string `json:"code"`
-	RedirectURI  *string `json:"redirect_uri"`
-	CodeVerifier *string `json:"code_verifier"`
+	Code         *string `json:"code" form:"code"`
+	RedirectURI  *string `json:"redirect_uri" form:"redirect_uri"`
+	CodeVerifier *string `json:"code_verifier" form:"code_verifier"`
 	// password flow
-	Username *string `json:"username"`
-	Password *string `json:"password"`
+	Username *string `json:"username" form:"username"`
+	Password *string `json:"password" form:"password"`
 	// refresh_token flow
-	RefreshToken *string `json:"refresh_token"`
+	RefreshToken *string `json:"refresh_token" form:"refresh_token"`
 	// common
-	Scope *string `json:"scope"`
+	Scope *string `json:"scope" form:"scope"`
 }
 
 type oauthTokenResponse struct {
-	AccessToken  string  `json:"access_token"`
-	RefreshToken *string `json:"refresh_token,omitempty"`
-	TokenType    string  `json:"token_type"`
-	ExpiresIn    int64   `json:"expires_in"`
-	Scope        *string `json:"scope,omitempty"`
+	AccessToken           string  `json:"access_token"`
+	RefreshToken          *string `json:"refresh_token,omitempty"`
+	TokenType             string  `json:"token_type"`
+	ExpiresIn             int64   `json:"expires_in"`
+	RefreshTokenExpiresIn int64   `json:"refresh_token_expires_in,omitempty"`
+	Scope                 *string `json:"scope,omitempty"`
 }
 
+// revokeRequest and introspectRequest are bound like tokenRequest. The
+// token_type_hint is accepted but only advisory (RFC 7009 section 2.1): every
+// kind of token is looked up regardless.
 type revokeRequest struct {
-	Token string `json:"token"`
+	Token         string `json:"token" form:"token"`
+	TokenTypeHint string `json:"token_type_hint" form:"token_type_hint"`
 }
 
 type introspectRequest struct {
-	Token string `json:"token"`
+	Token         string `json:"token" form:"token"`
+	TokenTypeHint string `json:"token_type_hint" form:"token_type_hint"`
 }
 
+// introspectBatchRequest carries up to maxIntrospectBatch tokens; a form body
+// repeats the tokens field.
+type introspectBatchRequest struct {
+	Tokens []string `json:"tokens" form:"tokens"`
+}
+
+// maxIntrospectBatch caps one batch introspection request.
+const maxIntrospectBatch = 100
+
+type verifyClientResponse struct {
+	Valid    bool   `json:"valid"`
+	AppID    string `json:"app_id"`
+	ClientID string `json:"client_id"`
+}
+
+// introspectResponse follows RFC 7662 section 2.2. Only active is set for an
+// inactive token.
 type introspectResponse struct {
-	Active bool    `json:"active"`
-	Sub    *string `json:"sub,omitempty"`
-	Aud    *string `json:"aud,omitempty"`
-	Exp    *int64  `json:"exp,omitempty"`
-	Scope  *string `json:"scope,omitempty"`
+	Active    bool          `json:"active"`
+	Sub       *string       `json:"sub,omitempty"`
+	Aud       auth.Audience `json:"aud,omitempty"`
+	ClientID  *string       `json:"client_id,omitempty"`
+	Username  *string       `json:"username,omitempty"`
+	TokenType *string       `json:"token_type,omitempty"`
+	Exp       *int64        `json:"exp,omitempty"`
+	Iat       *int64        `json:"iat,omitempty"`
+	Nbf       *int64        `json:"nbf,omitempty"`
+	Scope     *string       `json:"scope,omitempty"`
 }
 
 // --- Handlers ---
@@ -57,10 +92,23 @@ type introspectResponse struct {
 // Token implements the OAuth2 token endpoint (multiple grant types).
 func (h *Handler) Token(c *gin.Context) {
 	var req tokenRequest
-	if err := c.ShouldBindJSON(&req); err != nil {
+	if err := bindOAuthRequest(c, &req); err != nil {
 		middleware.RespondError(c, apperror.BadRequest("Invalid request body"))
 		return
 	}
+	// Re-check the application on every grant: codes and refresh tokens can
+	// outlive a deactivation, and not every caller goes through Basic auth.
+	app, err := h.Repo.Applications().FindByID(c.Request.Context(), middleware.AppID(c))
+	if err == nil && app == nil {
+		err = apperror.ApplicationNotFound()
+	} else if err == nil && !app.IsActive {
+		err = apperror.ApplicationNotActive()
+	}
+	if err != nil {
+		tokenFailed(c, req.GrantType, "", err)
+		middleware.RespondError(c, err)
+		return
+	}
 	switch req.GrantType {
 	case "authorization_code":
 		h.handleAuthorizationCode(c, &req)
@@ -69,7 +117,7 @@ func (h *Handler) Token(c *gin.Context) {
 	case "refresh_token":
 		h.handleRefreshTokenGrant(c, &req)
 	case "password":
-		h.handlePasswordGrant(c, &req)
+		h.handlePasswordGrant(c, &req, app)
 	default:
 		middleware.RespondError(c, apperror.BadRequest("Unsupported grant_type: "+req.GrantType))
 	}
@@ -87,6 +135,7 @@ func (h *Handler) handleAuthorizationCode(c *gin.Context, req *tokenRequest) {
 	}
 	userID, scopes, err := auth.ExchangeAuthCode(ctx, h.Repo, *req.Code, middleware.AppID(c), *req.RedirectURI, req.CodeVerifier)
 	if err != nil {
+		tokenFailed(c, "authorization_code", "", err)
 		middleware.RespondError(c, err)
 		return
 	}
@@ -103,33 +152,43 @@ func (h *Handler) handleAuthorizationCode(c *gin.Context, req *tokenRequest) {
 		middleware.RespondError(c, apperror.Forbidden())
 		return
 	}
+	if user.PasswordChangeRequired {
+		err := apperror.PasswordChangeRequired()
+		tokenFailed(c, "authorization_code", userID, err)
+		middleware.RespondError(c, err)
+		return
+	}
 	membership := h.resolveMembership(ctx, user)
-	accessToken, err := h.JWT.IssueAccessToken(userID, middleware.ClientID(c), scopes, user.Role, membership, user.UserType, user.Name)
+	accessToken, err := h.JWT.IssueAccessToken(ctx, userID, middleware.ClientID(c), scopes, user.Role, membership, user.UserType, user.Name, h.tokenExtras(c, user.CustomAttributes, user.Email))
 	if err != nil {
 		middleware.RespondError(c, err)
 		return
 	}
-	refreshToken := auth.GenerateRefreshToken()
-	if err := auth.StoreRefreshToken(ctx, h.Repo, userID, middleware.AppID(c), refreshToken, scopes, nil, h.Cfg.JWTRefreshTokenExpiryDays); err != nil {
+	refreshToken, refreshTTL, err := h.issueRefreshToken(c, userID, scopes, h.Cfg.JWTRefreshTokenExpiryDays)
+	if err != nil {
 		middleware.RespondError(c, err)
 		return
 	}
+	tokenIssued(c, "authorization_code", userID)
 	scopeStr := strings.Join(scopes, " ")
 	c.JSON(http.StatusOK, oauthTokenResponse{
-		AccessToken:  accessToken,
-		RefreshToken: strPtr(refreshToken),
-		TokenType:    "Bearer",
-		ExpiresIn:    h.Cfg.JWTAccessTokenExpirySecs,
-		Scope:        &scopeStr,
+		AccessToken:           accessToken,
+		RefreshToken:          nonEmptyPtr(refreshToken),
+		TokenType:             "Bearer",
+		ExpiresIn:             h.Cfg.JWTAccessTokenExpirySecs,
+		RefreshTokenExpiresIn: refreshTTL,
+		Scope:                 &scopeStr,
 	})
 }
 
 func (h *Handler) handleClientCredentials(c *gin.Context) {
-	accessToken, err := h.JWT.IssueAppToken(middleware.AppID(c))
+	accessToken, err := h.JWT.IssueAppToken(c.Request.Context(), middleware.AppID(c), middleware.CustomClaims(c))
 	if err != nil {
+		tokenFailed(c, "client_credentials", "", err)
 		middleware.RespondError(c, err)
 		return
 	}
+	tokenIssued(c, "client_credentials", "")
 	c.JSON(http.StatusOK, oauthTokenResponse{
 		AccessToken: accessToken,
 		TokenType:   "Bearer",
@@ -143,11 +202,12 @@ func (h *Handler) handleRefreshTokenGrant(c *gin.Context, req *tokenRequest) {
 		middleware.RespondError(c, apperror.BadRequest("Missing 'refresh_token' parameter"))
 		return
 	}
-	userID, newRefreshToken, scopes, err := auth.RotateRefreshToken(ctx, h.Repo, *req.RefreshToken, middleware.AppID(c), h.Cfg.JWTRefreshTokenExpiryDays)
+	rotated, err := h.rotateRefreshToken(c, *req.RefreshToken, refreshScopes(req.Scope))
 	if err != nil {
 		middleware.RespondError(c, err)
 		return
 	}
+	userID, scopes := rotated.UserID, rotated.Scopes
 	user, err := h.Repo.Users().FindByID(ctx, userID)
 	if err != nil {
 		middleware.RespondError(c, err)
@@ -162,22 +222,36 @@ func (h *Handler) handleRefreshTokenGrant(c *gin.Context, req *tokenRequest) {
 		return
 	}
 	membership := h.resolveMembership(ctx, user)
-	accessToken, err := h.JWT.IssueAccessToken(userID, middleware.ClientID(c), scopes, user.Role, membership, user.UserType, user.Name)
+	accessToken, err := h.JWT.IssueAccessToken(ctx, userID, middleware.ClientID(c), scopes, user.Role, membership, user.UserType, user.Name, h.tokenExtras(c, user.CustomAttributes, user.Email))
 	if err != nil {
 		middleware.RespondError(c, err)
 		return
 	}
+	tokenIssued(c, "refresh_token", userID)
 	scopeStr := strings.Join(scopes, " ")
 	c.JSON(http.StatusOK, oauthTokenResponse{
-		AccessToken:  accessToken,
-		RefreshToken: strPtr(newRefreshToken),
-		TokenType:    "Bearer",
-		ExpiresIn:    h.Cfg.JWTAccessTokenExpirySecs,
-		Scope:        &scopeStr,
+		AccessToken:           accessToken,
+		RefreshToken:          strPtr(rotated.Token),
+		TokenType:             "Bearer",
+		ExpiresIn:             h.Cfg.JWTAccessTokenExpirySecs,
+		RefreshTokenExpiresIn: refreshExpiresIn(rotated.ExpiryDays),
+		Scope:                 &scopeStr,
 	})
 }
 
-func (h *Handler) handlePasswordGrant(c *gin.Context, req *tokenRequest) {
+// refreshScopes parses the scope a refresh_token grant asks for. Absent or
+// blank means the stored scopes are kept.
+func refreshScopes(scope *string) []string {
+	if scope == nil {
+		return nil
+	}
+	if scopes := strings.Fields(*scope); len(scopes) > 0 {
+		return scopes
+	}
+	return nil
+}
+
+func (h *Handler) handlePasswordGrant(c *gin.Context, req *tokenRequest, app *domain.Application) {
 	ctx := c.Request.Context()
 	if req.Username == nil {
 		middleware.RespondError(c, apperror.BadRequest("Missing 'username' parameter"))
@@ -187,43 +261,14 @@ func (h *Handler) handlePasswordGrant(c *gin.Context, req *tokenRequest) {
 		middleware.RespondError(c, apperror.BadRequest("Missing 'password' parameter"))
 		return
 	}
-	user, err := h.Repo.Users().FindByEmail(ctx, *req.Username)
-	if err != nil {
-		middleware.RespondError(c, err)
-		return
-	}
-	if user == nil {
-		middleware.RespondError(c, apperror.InvalidCredentials())
-		return
-	}
-	account, err := h.Repo.Accounts().FindByUserAndProvider(ctx, user.ID, "password")
+	user, err := h.authenticatePassword(c, app, *req.Username, *req.Password)
 	if err != nil {
+		tokenFailed(c, "password", "", err)
 		middleware.RespondError(c, err)
 		return
 	}
-	if account == nil || account.Credential == nil {
-		middleware.RespondError(c, apperror.InvalidCredentials())
-		return
-	}
-	ok, err := auth.VerifyPassword(*req.Password, *account.Credential)
-	if err != nil {
-		middleware.RespondError(c, err)
-		return
-	}
-	if !ok {
-		middleware.RespondError(c, apperror.InvalidCredentials())
-		return
-	}
+	h.recordLogin(c, user.ID, app.ID)
 
-	app, err := h.Repo.Applications().FindByID(ctx, middleware.AppID(c))
-	if err != nil {
-		middleware.RespondError(c, err)
-		return
-	}
-	if app == nil {
-		middleware.RespondError(c, apperror.ApplicationNotFound())
-		return
-	}
 	allowedScopes := auth.DecodeStringArray(app.AllowedScopes)
 
 	var scopes []string
@@ -234,68 +279,263 @@ func (h *Handler) handlePasswordGrant(c *gin.Context, req *tokenRequest) {
 			}
 		}
 	} else {
-		scopes = allowedScopes
+		scopes = h.defaultScopes(app)
 	}
 
-	if !user.IsActive {
-		middleware.RespondError(c, apperror.Forbidden())
-		return
-	}
 	membership := h.resolveMembership(ctx, user)
-	accessToken, err := h.JWT.IssueAccessToken(user.ID, middleware.ClientID(c), scopes, user.Role, membership, user.UserType, user.Name)
+	accessToken, err := h.JWT.IssueAccessToken(ctx, user.ID, middleware.ClientID(c), scopes, user.Role, membership, user.UserType, user.Name, h.loginTokenExtras(c, user.CustomAttributes, user.Email))
 	if err != nil {
 		middleware.RespondError(c, err)
 		return
 	}
-	refreshToken := auth.GenerateRefreshToken()
-	if err := auth.StoreRefreshToken(ctx, h.Repo, user.ID, middleware.AppID(c), refreshToken, scopes, nil, h.Cfg.JWTRefreshTokenExpiryDays); err != nil {
+	refreshToken, refreshTTL, err := h.issueRefreshToken(c, user.ID, scopes, h.Cfg.JWTRefreshTokenExpiryDays)
+	if err != nil {
 		middleware.RespondError(c, err)
 		return
 	}
+	securityLog(c, securitylog.Event{Type: securitylog.Login, Outcome: securitylog.Success, Method: "password", UserID: user.ID})
+	tokenIssued(c, "password", user.ID)
 	scopeStr := strings.Join(scopes, " ")
 	c.JSON(http.StatusOK, oauthTokenResponse{
-		AccessToken:  accessToken,
-		RefreshToken: strPtr(refreshToken),
-		TokenType:    "Bearer",
-		ExpiresIn:    h.Cfg.JWTAccessTokenExpirySecs,
-		Scope:        &scopeStr,
+		AccessToken:           accessToken,
+		RefreshToken:          nonEmptyPtr(refreshToken),
+		TokenType:             "Bearer",
+		ExpiresIn:             h.Cfg.JWTAccessTokenExpirySecs,
+		RefreshTokenExpiresIn: refreshTTL,
+		Scope:                 &scopeStr,
 	})
 }
 
-// Revoke revokes a refresh token. Per RFC 7009, always returns 200.
+// VerifyClient confirms the client's credentials without minting a token.
+// AuthenticatedApp has already rejected bad ones, so reaching here means valid.
+func (h *Handler) VerifyClient(c *gin.Context) {
+	c.JSON(http.StatusOK, verifyClientResponse{Valid: true, AppID: middleware.AppID(c), ClientID: middleware.ClientID(c)})
+}
+
+// Revoke revokes a refresh token, or an opaque access token. Per RFC 7009,
+// always returns 200.
 func (h *Handler) Revoke(c *gin.Context) {
 	var req revokeRequest
-	if err := c.ShouldBindJSON(&req); err != nil {
+	if err := bindOAuthRequest(c, &req); err != nil {
 		middleware.RespondError(c, apperror.BadRequest("Invalid request body"))
 		return
 	}
 	_ = auth.RevokeRefreshToken(c.Request.Context(), h.Repo, req.Token)
+	_ = h.JWT.RevokeAccessToken(c.Request.Context(), req.Token)
+	securityLog(c, securitylog.Event{Type: securitylog.TokenRevoked, Outcome: securitylog.Success, Method: "revoke"})
 	c.JSON(http.StatusOK, gin.H{})
 }
 
-// Introspect reports whether an access token is active (RFC 7662 subset).
+// Introspect reports whether an access token is active (RFC 7662). Tokens are
+// audienced to the client they were issued to, so client_id is the aud, and
+// nbf is the issue time since tokens are valid from then on. username is the
+// user's username, or their email without one.
 func (h *Handler) Introspect(c *gin.Context) {
 	var req introspectRequest
-	if err := c.ShouldBindJSON(&req); err != nil {
+	if err := bindOAuthRequest(c, &req); err != nil {
 		middleware.RespondError(c, apperror.BadRequest("Invalid request body"))
 		return
 	}
-	claims, err := h.JWT.VerifyAccessToken(req.Token)
+	resp, err := h.introspect(c.Request.Context(), req.Token)
 	if err != nil {
-		c.JSON(http.StatusOK, introspectResponse{Active: false})
+		middleware.RespondError(c, err)
 		return
 	}
+	c.JSON(http.StatusOK, resp)
+}
+
+// IntrospectBatch introspects several tokens in one call, answering with an
+// array of Introspect responses in the order the tokens were given.
+func (h *Handler) IntrospectBatch(c *gin.Context) {
+	var req introspectBatchRequest
+	if err := bindOAuthRequest(c, &req); err != nil {
+		middleware.RespondError(c, apperror.BadRequest("Invalid request body"))
+		return
+	}
+	if len(req.Tokens) == 0 {
+		middleware.RespondError(c, apperror.BadRequest("tokens is required"))
+		return
+	}
+	if len(req.Tokens) > maxIntrospectBatch {
+		middleware.RespondError(c, apperror.BadRequest(fmt.Sprintf("tokens must not exceed %d entries", maxIntrospectBatch)))
+		return
+	}
+	results := make([]introspectResponse, len(req.Tokens))
+	for i, token := range req.Tokens {
+		resp, err := h.introspect(c.Request.Context(), token)
+		if err != nil {
+			middleware.RespondError(c, err)
+			return
+		}
+		results[i] = resp
+	}
+	c.JSON(http.StatusOK, results)
+}
+
+func (h *Handler) introspect(ctx context.Context, token string) (introspectResponse, error) {
+	claims, err := h.JWT.VerifyAccessToken(ctx, token)
+	if err != nil {
+		return introspectResponse{Active: false}, nil
+	}
+	user, err := h.Repo.Users().FindByID(ctx, claims.Sub)
+	if err != nil {
+		return introspectResponse{}, err
+	}
+	var username *string
+	if user != nil {
+		username = user.Username
+		if username == nil {
+			username = user.Email
+		}
+	}
 	scope := strings.Join(claims.Scopes, " ")
-	exp := claims.Exp
-	c.JSON(http.StatusOK, introspectResponse{
-		Active: true,
-		Sub:    strPtr(claims.Sub),
-		Aud:    strPtr(claims.Aud),
-		Exp:    &exp,
-		Scope:  &scope,
+	exp, iat := claims.Exp, claims.Iat
+	return introspectResponse{
+		Active:    true,
+		Sub:       strPtr(claims.Sub),
+		Aud:       claims.Aud,
+		ClientID:  strPtr(claims.Aud.ClientID()),
+		Username:  username,
+		TokenType: strPtr("Bearer"),
+		Exp:       &exp,
+		Iat:       &iat,
+		Nbf:       &iat,
+		Scope:     &scope,
+	}, nil
+}
+
+// KeysPEM returns the PEM-encoded verification key with its issuer,
+// algorithm, and kid, for scripts where a JWK is awkward.
+func (h *Handler) KeysPEM(c *gin.Context) {
+	if h.Cfg.JWTKeysRequireAdmin {
+		c.Header("Cache-Control", "private, max-age=3600")
+	} else {
+		c.Header("Cache-Control", "public, max-age=3600")
+	}
+	c.JSON(http.StatusOK, h.JWT.KeyInfo())
+}
+
+// clientAuthMethods are the client authentication methods AuthenticatedApp
+// accepts; private_key_jwt needs a registered client_public_key.
+var clientAuthMethods = []string{"client_secret_basic", "client_secret_post", "private_key_jwt"}
+
+type metadataResponse struct {
+	Issuer                                     string   `json:"issuer"`
+	TokenEndpoint                              string   `json:"token_endpoint"`
+	RevocationEndpoint                         string   `json:"revocation_endpoint"`
+	IntrospectionEndpoint                      string   `json:"introspection_endpoint"`
+	ScopesSupported                            []string `json:"scopes_supported,omitempty"`
+	GrantTypesSupported                        []string `json:"grant_types_supported"`
+	TokenEndpointAuthMethodsSupported          []string `json:"token_endpoint_auth_methods_supported"`
+	TokenEndpointAuthSigningAlgValuesSupported []string `json:"token_endpoint_auth_signing_alg_values_supported"`
+	RevocationEndpointAuthMethodsSupported     []string `json:"revocation_endpoint_auth_methods_supported"`
+	IntrospectionEndpointAuthMethodsSupported  []string `json:"introspection_endpoint_auth_methods_supported"`
+}
+
+// Metadata serves the RFC 8414 authorization server metadata. Endpoint URLs
+// are built from PUBLIC_BASE_URL, or else the host the request was addressed
+// to. With ?client_id= the
+// issuer is that app's, for apps with an issuer override.
+func (h *Handler) Metadata(c *gin.Context) {
+	issuer := h.JWT.KeyInfo().Issuer
+	if clientID := c.Query("client_id"); clientID != "" {
+		app, err := h.Repo.Applications().FindByClientID(c.Request.Context(), clientID)
+		if err != nil {
+			middleware.RespondError(c, err)
+			return
+		}
+		if app == nil || !app.IsActive {
+			middleware.RespondError(c, apperror.ApplicationNotFound())
+			return
+		}
+		if app.Issuer != nil {
+			issuer = *app.Issuer
+		}
+	}
+	base := h.Cfg.PublicBaseURL
+	if base == "" {
+		base = middleware.BaseURL(c)
+	}
+	scopes := make([]string, 0, len(h.Cfg.ScopeRegistry))
+	for scope := range h.Cfg.ScopeRegistry {
+		scopes = append(scopes, scope)
+	}
+	sort.Strings(scopes)
+	c.Header("Cache-Control", "public, max-age=3600")
+	c.JSON(http.StatusOK, metadataResponse{
+		Issuer:                            issuer,
+		TokenEndpoint:                     base + "/oauth/token",
+		RevocationEndpoint:                base + "/oauth/revoke",
+		IntrospectionEndpoint:             base + "/oauth/introspect",
+		ScopesSupported:                   scopes,
+		GrantTypesSupported:               []string{"authorization_code", "client_credentials", "refresh_token", "password"},
+		TokenEndpointAuthMethodsSupported: clientAuthMethods,
+		TokenEndpointAuthSigningAlgValuesSupported: []string{"RS256", "PS256", "ES256"},
+		RevocationEndpointAuthMethodsSupported:     clientAuthMethods,
+		IntrospectionEndpointAuthMethodsSupported:  clientAuthMethods,
 	})
 }
 
+type scopeResponse struct {
+	Scope       string `json:"scope"`
+	DisplayName string `json:"display_name"`
+	Description string `json:"description"`
+}
+
+// ListScopes returns the scope registry, sorted by scope, for consent screens.
+func (h *Handler) ListScopes(c *gin.Context) {
+	out := make([]scopeResponse, 0, len(h.Cfg.ScopeRegistry))
+	for scope, info := range h.Cfg.ScopeRegistry {
+		out = append(out, scopeResponse{Scope: scope, DisplayName: info.DisplayName, Description: info.Description})
+	}
+	sort.Slice(out, func(i, j int) bool { return out[i].Scope < out[j].Scope })
+	c.Header("Cache-Control", "public, max-age=3600")
+	c.JSON(http.StatusOK, out)
+}
+
+// checkRegisteredScopes rejects scopes missing from the registry when
+// SCOPE_REGISTRY_STRICT is set.
+func (h *Handler) checkRegisteredScopes(scopes []string) error {
+	if !h.Cfg.ScopeRegistryStrict {
+		return nil
+	}
+	for _, s := range scopes {
+		if _, ok := h.Cfg.ScopeRegistry[s]; !ok {
+			return apperror.BadRequest("Unregistered scope: " + s)
+		}
+	}
+	return nil
+}
+
+// defaultScopes is what a client of app is granted when it requests no
+// scopes: the app's default_scopes, else the configured DEFAULT_SCOPES, else
+// every allowed scope. Anything outside allowed_scopes is dropped.
+func (h *Handler) defaultScopes(app *domain.Application) []string {
+	allowed := auth.DecodeStringArray(app.AllowedScopes)
+	defaults := auth.DecodeStringArray(app.DefaultScopes)
+	if len(defaults) == 0 {
+		defaults = h.Cfg.DefaultScopes
+	}
+	if len(defaults) == 0 {
+		return allowed
+	}
+	out := make([]string, 0, len(defaults))
+	for _, s := range defaults {
+		if contains(allowed, s) {
+			out = append(out, s)
+		}
+	}
+	return out
+}
+
+// clientDefaultScopes is defaultScopes for the app resolved by ClientApp.
+func (h *Handler) clientDefaultScopes(c *gin.Context) []string {
+	if app := middleware.App(c); app != nil {
+		return h.defaultScopes(app)
+	}
+	return middleware.AllowedScopes(c)
+}
+
 func contains(ss []string, s string) bool {
 	for _, v := range ss {
 		if v == s {
@@ -304,3 +544,12 @@ func contains(ss []string, s string) bool {
 	}
 	return false
 }
+
+// bindOAuthRequest decodes an OAuth2 endpoint's body: form-encoded, as RFC 6749
+// requires, or JSON, which earlier clients of this service send.
+func bindOAuthRequest(c *gin.Context, req any) error {
+	if c.ContentType() == binding.MIMEPOSTForm {
+		return c.ShouldBindWith(req, binding.Form)
+	}
+	return c.ShouldBindJSON(req)
+}
@@ -4,30 +4,39 @@ import (
 	"context"
 	"encoding/json"
 	"net/http"
+	netmail "net/mail"
+	"net/url"
+	"strings"
 	"time"
 
 	"github.com/gin-gonic/gin"
 	"github.com/google/uuid"
 
 	"github.com/zhaochy1990/auth-service/internal/apperror"
+	"github.com/zhaochy1990/auth-service/internal/audit"
+	"github.com/zhaochy1990/auth-service/internal/auth"
 	"github.com/zhaochy1990/auth-service/internal/auth/providers"
 	"github.com/zhaochy1990/auth-service/internal/domain"
+	"github.com/zhaochy1990/auth-service/internal/mail"
 	"github.com/zhaochy1990/auth-service/internal/middleware"
 )
 
 // --- Request / Response types ---
 
 type userProfileResponse struct {
-	ID                  string                `json:"id"`
-	Email               *string               `json:"email"`
-	Name                *string               `json:"name"`
-	AvatarURL           *string               `json:"avatar_url"`
-	EmailVerified       bool                  `json:"email_verified"`
-	UserType            domain.UserType       `json:"user_type"`
-	Membership          domain.MembershipTier `json:"membership"`
-	MembershipExpiresAt *string               `json:"membership_expires_at"`
-	CustomAttributes    map[string]any        `json:"custom_attributes"`
-	CreatedAt           string                `json:"created_at"`
+	ID                     string                `json:"id"`
+	Email                  *string               `json:"email"`
+	Name                   *string               `json:"name"`
+	AvatarURL              *string               `json:"avatar_url"`
+	EmailVerified          bool                  `json:"email_verified"`
+	UserType               domain.UserType       `json:"user_type"`
+	Membership             domain.MembershipTier `json:"membership"`
+	MembershipExpiresAt    *string               `json:"membership_expires_at"`
+	CustomAttributes       map[string]any        `json:"custom_attributes"`
+	CreatedAt              string                `json:"created_at"`
+	PasswordChangeRequired bool                  `json:"password_change_required"`
+	PendingEmail           *string               `json:"pending_email"`
+	Username               *string               `json:"username"`
 }
 
 type updateProfileRequest struct {
@@ -36,12 +45,43 @@ type updateProfileRequest struct {
 	CustomAttributes map[string]any `json:"custom_attributes"`
 }
 
+type emailChangeRequest struct {
+	Email string `json:"email"`
+}
+
+type confirmEmailRequest struct {
+	Token string `json:"token"`
+}
+
 type accountResponse struct {
+	ID                string  `json:"id"`
 	ProviderID        string  `json:"provider_id"`
 	ProviderAccountID *string `json:"provider_account_id"`
 	CreatedAt         string  `json:"created_at"`
 }
 
+// exportResponse is the GET /api/users/me/export bundle.
+type exportResponse struct {
+	ExportedAt  string                  `json:"exported_at"`
+	Profile     userProfileResponse     `json:"profile"`
+	Accounts    []accountResponse       `json:"accounts"`
+	Sessions    []exportSessionResponse `json:"sessions"`
+	AuditEvents []auditEventResponse    `json:"audit_events"`
+}
+
+// exportSessionResponse describes a live refresh token without its hash.
+type exportSessionResponse struct {
+	ID        string   `json:"id"`
+	AppID     string   `json:"app_id"`
+	DeviceID  *string  `json:"device_id"`
+	Scopes    []string `json:"scopes"`
+	CreatedAt string   `json:"created_at"`
+	ExpiresAt string   `json:"expires_at"`
+}
+
+// exportAuditPageSize is how many audit events ExportMe reads per query.
+const exportAuditPageSize = 100
+
 type linkAccountRequest struct {
 	Credential json.RawMessage `json:"credential"`
 }
@@ -60,19 +100,26 @@ func (h *Handler) GetProfile(c *gin.Context) {
 		middleware.RespondError(c, apperror.UserNotFound())
 		return
 	}
+	c.JSON(http.StatusOK, h.profileResponse(ctx, user))
+}
+
+func (h *Handler) profileResponse(ctx context.Context, user *domain.User) userProfileResponse {
 	membership := h.resolveMembership(ctx, user)
-	c.JSON(http.StatusOK, userProfileResponse{
-		ID:                  user.ID,
-		Email:               user.Email,
-		Name:                user.Name,
-		AvatarURL:           user.AvatarURL,
-		EmailVerified:       user.EmailVerified,
-		UserType:            domain.UserTypeFromString(string(user.UserType)),
-		Membership:          membership,
-		MembershipExpiresAt: displayDTPtr(user.MembershipExpiresAt),
-		CustomAttributes:    customAttributesOrEmpty(user.CustomAttributes),
-		CreatedAt:           displayDT(user.CreatedAt),
-	})
+	return userProfileResponse{
+		ID:                     user.ID,
+		Email:                  user.Email,
+		Name:                   user.Name,
+		AvatarURL:              user.AvatarURL,
+		EmailVerified:          user.EmailVerified,
+		UserType:               domain.UserTypeFromString(string(user.UserType)),
+		Membership:             membership,
+		MembershipExpiresAt:    displayDTPtr(user.MembershipExpiresAt),
+		CustomAttributes:       customAttributesOrEmpty(user.CustomAttributes),
+		CreatedAt:              displayDT(user.CreatedAt),
+		PasswordChangeRequired: user.PasswordChangeRequired,
+		PendingEmail:           user.PendingEmail,
+		Username:               user.Username,
+	}
 }
 
 // UpdateProfile updates the authenticated user's name/avatar.
@@ -99,7 +146,17 @@ func (h *Handler) UpdateProfile(c *gin.Context) {
 		user.AvatarURL = req.AvatarURL
 	}
 	if req.CustomAttributes != nil {
+		for key := range req.CustomAttributes {
+			if contains(h.Cfg.CustomAttributesAdminOnly, key) {
+				middleware.RespondError(c, apperror.BadRequest("custom attribute "+key+" can only be set by an admin"))
+				return
+			}
+		}
 		user.CustomAttributes = mergeCustomAttributes(user.CustomAttributes, req.CustomAttributes)
+		if err := validateCustomAttributes(user.CustomAttributes); err != nil {
+			middleware.RespondError(c, err)
+			return
+		}
 	}
 	now := time.Now().UTC()
 	user.UpdatedAt = now
@@ -108,19 +165,167 @@ func (h *Handler) UpdateProfile(c *gin.Context) {
 		return
 	}
 	c.JSON(http.StatusOK, userProfileResponse{
-		ID:                  user.ID,
-		Email:               user.Email,
-		Name:                user.Name,
-		AvatarURL:           user.AvatarURL,
-		EmailVerified:       user.EmailVerified,
-		UserType:            domain.UserTypeFromString(string(user.UserType)),
-		Membership:          user.EffectiveMembership(now),
-		MembershipExpiresAt: displayDTPtr(user.MembershipExpiresAt),
-		CustomAttributes:    customAttributesOrEmpty(user.CustomAttributes),
-		CreatedAt:           displayDT(user.CreatedAt),
+		ID:                     user.ID,
+		Email:                  user.Email,
+		Name:                   user.Name,
+		AvatarURL:              user.AvatarURL,
+		EmailVerified:          user.EmailVerified,
+		UserType:               domain.UserTypeFromString(string(user.UserType)),
+		Membership:             user.EffectiveMembership(now),
+		MembershipExpiresAt:    displayDTPtr(user.MembershipExpiresAt),
+		CustomAttributes:       customAttributesOrEmpty(user.CustomAttributes),
+		CreatedAt:              displayDT(user.CreatedAt),
+		PasswordChangeRequired: user.PasswordChangeRequired,
+		PendingEmail:           user.PendingEmail,
+		Username:               user.Username,
 	})
 }
 
+// RequestEmailChange starts an email change: the new address is stored as
+// pending and sent a confirmation link. The current address stays in use
+// until ConfirmEmailChange completes.
+func (h *Handler) RequestEmailChange(c *gin.Context) {
+	var req emailChangeRequest
+	if err := c.ShouldBindJSON(&req); err != nil {
+		middleware.RespondError(c, apperror.BadRequest("Invalid request body"))
+		return
+	}
+	if h.Mail == nil {
+		middleware.RespondError(c, apperror.EmailUnavailable())
+		return
+	}
+	email := strings.TrimSpace(req.Email)
+	if addr, err := netmail.ParseAddress(email); err != nil || addr.Address != email {
+		middleware.RespondError(c, apperror.BadRequest("Invalid email address"))
+		return
+	}
+	ctx := c.Request.Context()
+	user, err := h.Repo.Users().FindByID(ctx, middleware.UserID(c))
+	if err != nil {
+		middleware.RespondError(c, err)
+		return
+	}
+	if user == nil {
+		middleware.RespondError(c, apperror.UserNotFound())
+		return
+	}
+	if user.Email != nil && strings.EqualFold(*user.Email, email) {
+		middleware.RespondError(c, apperror.BadRequest("New email matches the current one"))
+		return
+	}
+	taken, err := h.Repo.Users().FindByEmail(ctx, email)
+	if err != nil {
+		middleware.RespondError(c, err)
+		return
+	}
+	if taken != nil {
+		middleware.RespondError(c, apperror.UserAlreadyExists())
+		return
+	}
+
+	// The user id prefix lets confirmation find the row without an index.
+	token := user.ID + "." + auth.RandomHex(32)
+	now := time.Now().UTC()
+	expires := now.Add(time.Duration(h.Cfg.EmailChangeTokenTTLSecs) * time.Second)
+	user.PendingEmail = &email
+	user.EmailChangeTokenHash = strPtr(auth.HashToken(token))
+	user.EmailChangeExpiresAt = &expires
+	user.UpdatedAt = now
+	if err := h.Repo.Users().Update(ctx, user); err != nil {
+		middleware.RespondError(c, err)
+		return
+	}
+
+	link := h.Cfg.EmailConfirmURL
+	if strings.Contains(link, "?") {
+		link += "&token=" + url.QueryEscape(token)
+	} else {
+		link += "?token=" + url.QueryEscape(token)
+	}
+	body := "Open this link to use " + email + " for your account:\n\n" + link +
+		"\n\nThe link expires at " + displayDT(expires) + ". If you did not ask for this, ignore this email.\n"
+	if err := h.Mail.Send(ctx, mail.Message{To: email, Subject: "Confirm your new email address", Body: body}); err != nil {
+		middleware.RespondError(c, apperror.EmailUnavailable())
+		return
+	}
+	c.JSON(http.StatusAccepted, gin.H{"pending_email": email, "expires_at": displayDT(expires)})
+}
+
+// ConfirmEmailChange swaps in the pending email named by a confirmation
+// token. It needs no session: the link may be opened on another device.
+func (h *Handler) ConfirmEmailChange(c *gin.Context) {
+	var req confirmEmailRequest
+	if err := c.ShouldBindJSON(&req); err != nil {
+		middleware.RespondError(c, apperror.BadRequest("Invalid request body"))
+		return
+	}
+	invalid := apperror.BadRequest("Invalid or expired email confirmation token")
+	userID, _, ok := strings.Cut(req.Token, ".")
+	if !ok || userID == "" {
+		middleware.RespondError(c, invalid)
+		return
+	}
+	ctx := c.Request.Context()
+	user, err := h.Repo.Users().FindByID(ctx, userID)
+	if err != nil {
+		middleware.RespondError(c, err)
+		return
+	}
+	now := time.Now().UTC()
+	if user == nil || user.PendingEmail == nil || user.EmailChangeTokenHash == nil ||
+		user.EmailChangeExpiresAt == nil || !user.EmailChangeExpiresAt.After(now) ||
+		!auth.VerifyTokenHash(req.Token, *user.EmailChangeTokenHash) {
+		middleware.RespondError(c, invalid)
+		return
+	}
+	email := *user.PendingEmail
+	taken, err := h.Repo.Users().FindByEmail(ctx, email)
+	if err != nil {
+		middleware.RespondError(c, err)
+		return
+	}
+	if taken != nil && taken.ID != user.ID {
+		middleware.RespondError(c, apperror.UserAlreadyExists())
+		return
+	}
+
+	oldEmail := user.Email
+	user.Email = &email
+	// The link proved control of the new address.
+	user.EmailVerified = true
+	user.PendingEmail, user.EmailChangeTokenHash, user.EmailChangeExpiresAt = nil, nil, nil
+	user.UpdatedAt = now
+	if err := h.Repo.Users().Update(ctx, user); err != nil {
+		middleware.RespondError(c, err)
+		return
+	}
+	// The password account is keyed by the login email.
+	account, err := h.Repo.Accounts().FindByUserAndProvider(ctx, user.ID, "password")
+	if err != nil {
+		middleware.RespondError(c, err)
+		return
+	}
+	if account != nil {
+		account.ProviderAccountID = &email
+		account.UpdatedAt = now
+		if err := h.Repo.Accounts().Update(ctx, account); err != nil {
+			middleware.RespondError(c, err)
+			return
+		}
+	}
+	// Whoever held a session under the old address is signed out with it.
+	if err := h.revokeSessions(ctx, user.ID); err != nil {
+		middleware.RespondError(c, err)
+		return
+	}
+	details := map[string]any{"new_email": email}
+	if oldEmail != nil {
+		details["old_email"] = *oldEmail
+	}
+	audit.Record(audit.Event{Type: audit.EmailChanged, UserID: user.ID, IP: middleware.ClientIP(c, ""), Details: details})
+	c.JSON(http.StatusOK, gin.H{"email": email, "email_verified": true})
+}
+
 // ListAccounts lists the authenticated user's linked accounts.
 func (h *Handler) ListAccounts(c *gin.Context) {
 	accounts, err := h.Repo.Accounts().FindAllByUser(c.Request.Context(), middleware.UserID(c))
@@ -128,17 +333,176 @@ func (h *Handler) ListAccounts(c *gin.Context) {
 		middleware.RespondError(c, err)
 		return
 	}
+	accounts, err = h.paginateAccounts(c, accounts)
+	if err != nil {
+		middleware.RespondError(c, err)
+		return
+	}
+	c.JSON(http.StatusOK, toAccountResponses(accounts))
+}
+
+// toAccountResponses leaves out credentials: only the provider and the
+// account id it knows the user by are shown.
+func toAccountResponses(accounts []domain.Account) []accountResponse {
 	out := make([]accountResponse, 0, len(accounts))
 	for _, a := range accounts {
 		out = append(out, accountResponse{
+			ID:                a.ID,
 			ProviderID:        a.ProviderID,
 			ProviderAccountID: a.ProviderAccountID,
 			CreatedAt:         displayDT(a.CreatedAt),
 		})
 	}
+	return out
+}
+
+// consentResponse is an app the user has authorized. There are no consent
+// records, so it is derived from the user's live refresh tokens for the app:
+// Scopes is the union of theirs and GrantedAt when the oldest was issued.
+type consentResponse struct {
+	AppID     string   `json:"app_id"`
+	AppName   string   `json:"app_name"`
+	LogoURL   *string  `json:"logo_url"`
+	Scopes    []string `json:"scopes"`
+	GrantedAt string   `json:"granted_at"`
+	Sessions  int      `json:"sessions"`
+}
+
+// ListConsents lists the apps holding a live refresh token for the
+// authenticated user, most recently active first, for a "connected apps"
+// screen.
+func (h *Handler) ListConsents(c *gin.Context) {
+	ctx := c.Request.Context()
+	tokens, err := h.Repo.RefreshTokens().ListActiveByUser(ctx, middleware.UserID(c), time.Now().UTC())
+	if err != nil {
+		middleware.RespondError(c, err)
+		return
+	}
+	out := []consentResponse{}
+	index := map[string]int{}
+	// Tokens come newest first, so the last one seen for an app is its
+	// oldest.
+	for _, t := range tokens {
+		i, seen := index[t.AppID]
+		if !seen {
+			app, err := h.Repo.Applications().FindByID(ctx, t.AppID)
+			if err != nil {
+				middleware.RespondError(c, err)
+				return
+			}
+			i = -1
+			if app != nil {
+				i = len(out)
+				out = append(out, consentResponse{AppID: app.ID, AppName: app.Name, LogoURL: app.LogoURL, Scopes: []string{}})
+			}
+			index[t.AppID] = i
+		}
+		if i < 0 {
+			continue
+		}
+		consent := &out[i]
+		for _, s := range auth.DecodeStringArray(t.Scopes) {
+			if !contains(consent.Scopes, s) {
+				consent.Scopes = append(consent.Scopes, s)
+			}
+		}
+		consent.GrantedAt = displayDT(t.CreatedAt)
+		consent.Sessions++
+	}
 	c.JSON(http.StatusOK, out)
 }
 
+// RevokeConsent disconnects an app from the authenticated user by deleting
+// their refresh tokens for it. Access tokens already issued run out at their
+// expiry.
+func (h *Handler) RevokeConsent(c *gin.Context) {
+	ctx := c.Request.Context()
+	userID, appID := middleware.UserID(c), c.Param("app_id")
+	if uuid.Validate(appID) != nil {
+		middleware.RespondError(c, apperror.BadRequest("Invalid app_id"))
+		return
+	}
+	n, err := h.Repo.RefreshTokens().DeleteByUserAndApp(ctx, userID, appID)
+	if err != nil {
+		middleware.RespondError(c, err)
+		return
+	}
+	if n == 0 {
+		middleware.RespondError(c, apperror.ApplicationNotFound())
+		return
+	}
+	audit.Record(audit.Event{
+		Type:    audit.ConsentRevoked,
+		UserID:  userID,
+		AppID:   appID,
+		IP:      middleware.ClientIP(c, "unknown"),
+		Details: map[string]any{"revoked": n},
+	})
+	c.Status(http.StatusNoContent)
+}
+
+// ExportMe returns everything the service holds about the authenticated user
+// as one JSON document, for data-subject access requests: the profile, linked
+// accounts, live sessions and the audit events naming them. Credentials,
+// password hashes and token hashes are never included.
+func (h *Handler) ExportMe(c *gin.Context) {
+	ctx := c.Request.Context()
+	userID := middleware.UserID(c)
+	user, err := h.Repo.Users().FindByID(ctx, userID)
+	if err != nil {
+		middleware.RespondError(c, err)
+		return
+	}
+	if user == nil {
+		middleware.RespondError(c, apperror.UserNotFound())
+		return
+	}
+	accounts, err := h.Repo.Accounts().FindAllByUser(ctx, userID)
+	if err != nil {
+		middleware.RespondError(c, err)
+		return
+	}
+	now := time.Now().UTC()
+	tokens, err := h.Repo.RefreshTokens().ListActiveByUser(ctx, userID, now)
+	if err != nil {
+		middleware.RespondError(c, err)
+		return
+	}
+	sessions := make([]exportSessionResponse, 0, len(tokens))
+	for _, t := range tokens {
+		sessions = append(sessions, exportSessionResponse{
+			ID:        t.ID,
+			AppID:     t.AppID,
+			DeviceID:  t.DeviceID,
+			Scopes:    auth.DecodeStringArray(t.Scopes),
+			CreatedAt: displayDT(t.CreatedAt),
+			ExpiresAt: displayDT(t.ExpiresAt),
+		})
+	}
+	events := []auditEventResponse{}
+	for offset := uint64(0); ; offset += exportAuditPageSize {
+		page, total, err := h.Repo.AuditEvents().ListByUser(ctx, userID, "", offset, exportAuditPageSize)
+		if err != nil {
+			middleware.RespondError(c, err)
+			return
+		}
+		for _, e := range page {
+			events = append(events, toAuditEventResponse(e))
+		}
+		if len(page) == 0 || offset+uint64(len(page)) >= total {
+			break
+		}
+	}
+	c.Header("Content-Disposition", `attachment; filename="personal-data.json"`)
+	c.JSON(http.StatusOK, exportResponse{
+		ExportedAt:  displayDT(now),
+		Profile:     h.profileResponse(ctx, user),
+		Accounts:    toAccountResponses(accounts),
+		Sessions:    sessions,
+		AuditEvents: events,
+	})
+}
+
 // LinkAccount links an external provider account to the authenticated user.
 func (h *Handler) LinkAccount(c *gin.Context) {
 	providerID := c.Param("provider_id")
@@ -179,7 +543,7 @@ func (h *Handler) LinkAccount(c *gin.Context) {
 		return
 	}
 
-	provider, err := providers.Create(providerID, json.RawMessage(appProvider.Config), h.Cfg.EnableTestProviders)
+	provider, err := providers.Create(providerID, json.RawMessage(appProvider.Config), h.HTTP, h.Cfg.EnableTestProviders)
 	if err != nil {
 		middleware.RespondError(c, err)
 		return
@@ -196,10 +560,15 @@ func (h *Handler) LinkAccount(c *gin.Context) {
 		return
 	}
 	if alreadyLinked != nil {
-		middleware.RespondError(c, apperror.AccountAlreadyLinked())
+		middleware.RespondError(c, apperror.ProviderAccountInUse())
 		return
 	}
 
+	sealedTokens, err := auth.SealProviderTokens(info.Tokens)
+	if err != nil {
+		middleware.RespondError(c, err)
+		return
+	}
 	now := time.Now().UTC()
 	account := &domain.Account{
 		ID:                uuid.NewString(),
@@ -207,6 +576,7 @@ func (h *Handler) LinkAccount(c *gin.Context) {
 		ProviderID:        providerID,
 		ProviderAccountID: strPtr(info.ProviderAccountID),
 		ProviderMetadata:  string(info.Metadata),
+		ProviderTokens:    sealedTokens,
 		CreatedAt:         now,
 		UpdatedAt:         now,
 	}
@@ -215,6 +585,7 @@ func (h *Handler) LinkAccount(c *gin.Context) {
 		return
 	}
 	c.JSON(http.StatusOK, accountResponse{
+		ID:                account.ID,
 		ProviderID:        providerID,
 		ProviderAccountID: strPtr(info.ProviderAccountID),
 		CreatedAt:         displayDT(now),
@@ -280,7 +651,7 @@ func (h *Handler) deleteUserAccount(ctx context.Context, userID string) error {
 	if len(owned) > 0 {
 		return apperror.UserOwnsTeams(len(owned))
 	}
-	if err := h.Repo.RefreshTokens().DeleteAllByUser(ctx, userID); err != nil {
+	if err := h.revokeSessions(ctx, userID); err != nil {
 		return err
 	}
 	if err := h.Repo.AuthCodes().DeleteAllByUser(ctx, userID); err != nil {
@@ -0,0 +1,136 @@
+// Package i18n translates the user-facing error messages by Accept-Language.
+// Messages are looked up by key (an error's MessageKey); English is the
+// built-in default and needs no catalog entry. A bundled set of locales ships
+// embedded, and LOCALES_DIR can add or override languages.
+package i18n
+
+import (
+	"embed"
+	"encoding/json"
+	"fmt"
+	"io/fs"
+	"os"
+	"path"
+	"sort"
+	"strconv"
+	"strings"
+)
+
+//go:embed locales/*.json
+var bundled embed.FS
+
+// defaultLanguage is the language the messages are written in.
+const defaultLanguage = "en"
+
+// Catalog maps a lower-cased language tag ("fr", "pt-br") to its messages by
+// key.
+type Catalog map[string]map[string]string
+
+// Bundled returns the locales shipped with the service.
+func Bundled() Catalog {
+	c, err := load(bundled, "locales")
+	if err != nil {
+		panic(err) // embedded at build time
+	}
+	return c
+}
+
+// Load returns the bundled locales overlaid with the <tag>.json files in dir,
+// each a JSON object of key to message. An empty dir loads the bundle alone.
+func Load(dir string) (Catalog, error) {
+	c := Bundled()
+	if dir == "" {
+		return c, nil
+	}
+	extra, err := load(os.DirFS(dir), ".")
+	if err != nil {
+		return nil, err
+	}
+	for lang, messages := range extra {
+		if c[lang] == nil {
+			c[lang] = map[string]string{}
+		}
+		for key, msg := range messages {
+			c[lang][key] = msg
+		}
+	}
+	return c, nil
+}
+
+func load(fsys fs.FS, dir string) (Catalog, error) {
+	paths, err := fs.Glob(fsys, path.Join(dir, "*.json"))
+	if err != nil {
+		return nil, err
+	}
+	c := Catalog{}
+	for _, p := range paths {
+		b, err := fs.ReadFile(fsys, p)
+		if err != nil {
+			return nil, err
+		}
+		var messages map[string]string
+		if err := json.Unmarshal(b, &messages); err != nil {
+			return nil, fmt.Errorf("locale file %s: %v", p, err)
+		}
+		c[strings.ToLower(strings.TrimSuffix(path.Base(p), ".json"))] = messages
+	}
+	return c, nil
+}
+
+// Translate returns the message for key in the caller's most preferred
+// language the catalog knows. It reports false when that language is English
+// or lacks the key, leaving the default message in place.
+func (c Catalog) Translate(acceptLanguage, key string) (msg, lang string, ok bool) {
+	for _, tag := range preferredLanguages(acceptLanguage) {
+		for _, candidate := range []string{tag, primarySubtag(tag)} {
+			if candidate == defaultLanguage {
+				return "", "", false
+			}
+			if messages, found := c[candidate]; found {
+				msg, ok = messages[key]
+				return msg, candidate, ok
+			}
+		}
+	}
+	return "", "", false
+}
+
+// preferredLanguages parses an Accept-Language header into lower-cased tags,
+// most preferred first. Wildcards and q=0 entries are dropped.
+func preferredLanguages(header string) []string {
+	type weighted struct {
+		tag string
+		q   float64
+	}
+	var tags []weighted
+	for _, part := range strings.Split(header, ",") {
+		tag, params, _ := strings.Cut(strings.TrimSpace(part), ";")
+		tag = strings.ToLower(strings.TrimSpace(tag))
+		if tag == "" || tag == "*" {
+			continue
+		}
+		q := 1.0
+		if v, found := strings.CutPrefix(strings.TrimSpace(params), "q="); found {
+			parsed, err := strconv.ParseFloat(v, 64)
+			if err != nil {
+				continue
+			}
+			q = parsed
+		}
+		if q <= 0 {
+			continue
+		}
+		tags = append(tags, weighted{tag, q})
+	}
+	sort.SliceStable(tags, func(i, j int) bool { return tags[i].q > tags[j].q })
+	out := make([]string, len(tags))
+	for i, t := range tags {
+		out[i] = t.tag
+	}
+	return out
+}
+
+func primarySubtag(tag string) string {
+	primary, _, _ := strings.Cut(tag, "-")
+	return primary
+}
@@ -0,0 +1,71 @@
+// Package mail sends transactional email over SMTP. Only email-change
+// confirmations use it today.
+package mail
+
+import (
+	"context"
+	"net"
+	"net/smtp"
+	"strconv"
+	"strings"
+	"time"
+)
+
+// Message is a plain-text email.
+type Message struct {
+	To      string
+	Subject string
+	Body    string
+}
+
+// Sender delivers a Message.
+type Sender interface {
+	Send(ctx context.Context, msg Message) error
+}
+
+// NewSMTP builds a sender relaying through host:port. Credentials are
+// optional; net/smtp only sends them over TLS (STARTTLS) or to localhost.
+func NewSMTP(host string, port int, username, password, from string) Sender {
+	return &smtpSender{addr: net.JoinHostPort(host, strconv.Itoa(port)), host: host, username: username, password: password, from: from}
+}
+
+type smtpSender struct {
+	addr     string
+	host     string
+	username string
+	password string
+	from     string
+}
+
+func (s *smtpSender) Send(ctx context.Context, msg Message) error {
+	var auth smtp.Auth
+	if s.username != "" {
+		auth = smtp.PlainAuth("", s.username, s.password, s.host)
+	}
+	done := make(chan error, 1)
+	go func() { done <- smtp.SendMail(s.addr, auth, s.from, []string{msg.To}, s.render(msg)) }()
+	select {
+	case err := <-done:
+		return err
+	case <-ctx.Done():
+		return ctx.Err()
+	}
+}
+
+func (s *smtpSender) render(msg Message) []byte {
+	var b strings.Builder
+	header := func(k, v string) {
+		// Header values come from config and validated addresses; strip
+		// line breaks anyway so nothing can inject extra headers.
+		b.WriteString(k + ": " + strings.NewReplacer("\r", "", "\n", "").Replace(v) + "\r\n")
+	}
+	header("From", s.from)
+	header("To", msg.To)
+	header("Subject", msg.Subject)
+	header("Date", time.Now().UTC().Format(time.RFC1123Z))
+	header("MIME-Version", "1.0")
+	header("Content-Type", "text/plain; charset=UTF-8")
+	b.WriteString("\r\n")
+	b.WriteString(strings.ReplaceAll(msg.Body, "\n", "\r\n"))
+	return []byte(b.String())
+}
@@ -1,22 +1,42 @@
 // Package middleware holds the Gin middleware for bearer-token user auth,
-// X-Client-Id app resolution, Basic-auth client auth, and admin-role gating,
-// plus the per-IP rate limiter, CORS, and the shared error responder. Handlers
-// read the values these middlewares stash on the gin.Context via the typed
-// getters below.
+// X-Client-Id app resolution, OAuth2 client auth, and admin-role gating,
+// plus the per-IP rate limiter, idempotency-key replay, CORS, gzip
+// compression, and the shared error responder. Handlers read the values these
+// middlewares stash on the gin.Context via the typed getters below.
 package middleware
 
 import (
+	"bytes"
+	"compress/gzip"
+	"context"
+	"crypto/sha256"
+	"crypto/subtle"
 	"encoding/base64"
+	"encoding/hex"
+	"encoding/json"
+	"errors"
+	"io"
+	"net"
 	"net/http"
+	"net/url"
+	"slices"
+	"sort"
+	"strconv"
 	"strings"
 	"sync"
 	"time"
 
 	"github.com/gin-gonic/gin"
+	"github.com/gin-gonic/gin/binding"
 
 	"github.com/zhaochy1990/auth-service/internal/apperror"
+	"github.com/zhaochy1990/auth-service/internal/audit"
 	"github.com/zhaochy1990/auth-service/internal/auth"
+	"github.com/zhaochy1990/auth-service/internal/config"
+	"github.com/zhaochy1990/auth-service/internal/domain"
+	"github.com/zhaochy1990/auth-service/internal/i18n"
 	"github.com/zhaochy1990/auth-service/internal/repository"
+	"github.com/zhaochy1990/auth-service/internal/securitylog"
 )
 
 // Context keys.
@@ -26,12 +46,72 @@ const (
 	ctxScopes        = "auth.scopes"
 	ctxAppID         = "auth.app_id"
 	ctxAllowedScopes = "auth.allowed_scopes"
+	ctxRole          = "auth.role"
+	ctxExpiresAt     = "auth.expires_at"
+	ctxCustomClaims  = "auth.custom_claims"
+	ctxApp           = "auth.app"
+	ctxAuthTime      = "auth.auth_time"
+	ctxAdmin         = "auth.admin"
+	ctxClientAuth    = "auth.client_auth"
+	ctxProblemJSON   = "error.problem_json"
+	ctxLocales       = "error.locales"
 )
 
 // RespondError writes a typed application error as a JSON response and aborts.
+// Once the request's Timeout budget is spent, any failure is reported as 504.
 func RespondError(c *gin.Context, err error) {
+	if errors.Is(c.Request.Context().Err(), context.DeadlineExceeded) {
+		err = apperror.RequestTimeout()
+	}
 	ae, _ := apperror.As(err)
-	c.AbortWithStatusJSON(ae.Status, gin.H{"error": ae.Type, "message": ae.Message})
+	if locales, ok := c.Get(ctxLocales); ok {
+		if msg, lang, ok := locales.(i18n.Catalog).Translate(c.GetHeader("Accept-Language"), ae.MessageKey()); ok {
+			translated := *ae
+			translated.Message = msg
+			ae = &translated
+			c.Header("Content-Language", lang)
+		}
+	}
+	if c.GetBool(ctxProblemJSON) {
+		c.Header("Content-Type", "application/problem+json")
+		c.AbortWithStatusJSON(ae.Status, problemDetails{
+			Type: ae.Type, Title: http.StatusText(ae.Status), Status: ae.Status, Detail: ae.Message, Details: ae.Details,
+		})
+		return
+	}
+	body := gin.H{"error": ae.Type, "message": ae.Message}
+	if ae.Details != nil {
+		body["details"] = ae.Details
+	}
+	c.AbortWithStatusJSON(ae.Status, body)
+}
+
+// problemDetails is the RFC 7807 error body. Type is the error code the
+// default format reports as "error".
+type problemDetails struct {
+	Type    string         `json:"type"`
+	Title   string         `json:"title"`
+	Status  int            `json:"status"`
+	Detail  string         `json:"detail"`
+	Details map[string]any `json:"details,omitempty"`
+}
+
+// Localize makes RespondError translate messages into the caller's
+// Accept-Language for the rest of the chain. Error codes stay as they are.
+func Localize(locales i18n.Catalog) gin.HandlerFunc {
+	return func(c *gin.Context) {
+		c.Set(ctxLocales, locales)
+		c.Next()
+	}
+}
+
+// ProblemJSONErrors makes RespondError answer with application/problem+json
+// for the rest of the chain (ERROR_FORMAT=problemjson).
+func ProblemJSONErrors() gin.HandlerFunc {
+	return func(c *gin.Context) {
+		c.Set(ctxProblemJSON, true)
+		c.Next()
+	}
 }
 
 // --- Context getters ---
@@ -41,6 +121,66 @@ func ClientID(c *gin.Context) string        { return getString(c, ctxClientID) }
 func AppID(c *gin.Context) string           { return getString(c, ctxAppID) }
 func Scopes(c *gin.Context) []string        { return getStrings(c, ctxScopes) }
 func AllowedScopes(c *gin.Context) []string { return getStrings(c, ctxAllowedScopes) }
+func Role(c *gin.Context) string            { return getString(c, ctxRole) }
+
+// CustomClaims is the resolved application's custom token claims, or nil.
+func CustomClaims(c *gin.Context) map[string]any {
+	if v, ok := c.Get(ctxCustomClaims); ok {
+		if m, ok := v.(map[string]any); ok {
+			return m
+		}
+	}
+	return nil
+}
+
+// App is the application resolved by ClientApp or AuthenticatedApp, or nil.
+func App(c *gin.Context) *domain.Application {
+	if v, ok := c.Get(ctxApp); ok {
+		if a, ok := v.(*domain.Application); ok {
+			return a
+		}
+	}
+	return nil
+}
+
+// AuthTime is when the token's user last authenticated with credentials, or
+// the zero time when the token does not say (e.g. it came from a refresh).
+func AuthTime(c *gin.Context) time.Time {
+	if v, ok := c.Get(ctxAuthTime); ok {
+		if t, ok := v.(time.Time); ok {
+			return t
+		}
+	}
+	return time.Time{}
+}
+
+// AdminIdentity is the admin acting on an /admin route: the user id from the
+// verified token and the role it was authorized under.
+type AdminIdentity struct {
+	UserID string
+	Role   string
+}
+
+// Admin is the identity AdminAuth (or AdminOrAppTokenAuth, for a user token)
+// admitted, or the zero value when the caller is not an admin user.
+func Admin(c *gin.Context) AdminIdentity {
+	if v, ok := c.Get(ctxAdmin); ok {
+		if a, ok := v.(AdminIdentity); ok {
+			return a
+		}
+	}
+	return AdminIdentity{}
+}
+
+// ExpiresAt is the expiry of the request's access token.
+func ExpiresAt(c *gin.Context) time.Time {
+	if v, ok := c.Get(ctxExpiresAt); ok {
+		if t, ok := v.(time.Time); ok {
+			return t
+		}
+	}
+	return time.Time{}
+}
 
 func getString(c *gin.Context, key string) string {
 	if v, ok := c.Get(key); ok {
@@ -73,30 +213,101 @@ func ClientIP(c *gin.Context, fallback string) string {
 	return fallback
 }
 
+// RemoteIP is the caller's address for security decisions: the connection's
+// peer, or the X-Forwarded-For / X-Real-IP address it reports when the peer
+// is one of the engine's trusted proxies (TRUSTED_PROXIES). Unlike ClientIP,
+// a client cannot choose it.
+func RemoteIP(c *gin.Context) string {
+	return c.ClientIP()
+}
+
+// WarnUntrustedForwarding calls warn once, on the first request carrying
+// X-Forwarded-For from a peer outside TRUSTED_PROXIES. Behind an unlisted
+// proxy every caller has the proxy's address, so rate limits, the login
+// pair throttle and the admin guard would lump all clients together.
+func WarnUntrustedForwarding(warn func(msg string, keysAndValues ...interface{})) gin.HandlerFunc {
+	var once sync.Once
+	return func(c *gin.Context) {
+		if c.GetHeader("X-Forwarded-For") != "" && c.ClientIP() == c.RemoteIP() {
+			once.Do(func() {
+				warn("X-Forwarded-For ignored from a peer not in TRUSTED_PROXIES; list the reverse proxy there or all callers share its address", "peer", c.RemoteIP())
+			})
+		}
+		c.Next()
+	}
+}
+
 func bearer(c *gin.Context) (string, bool) {
 	h := c.GetHeader("Authorization")
 	return strings.CutPrefix(h, "Bearer ")
 }
 
+// AccessTokenCookie names the cookie that carries the access token for apps
+// with access_token_cookie set.
+const AccessTokenCookie = "access_token"
+
+// userToken is the Bearer token or, without an Authorization header, the
+// access-token cookie; fromCookie tells which.
+func userToken(c *gin.Context) (token string, fromCookie, ok bool) {
+	if c.GetHeader("Authorization") != "" {
+		token, ok = bearer(c)
+		return token, false, ok
+	}
+	token, err := c.Cookie(AccessTokenCookie)
+	return token, true, err == nil && token != ""
+}
+
+// safeMethod reports whether method cannot change state, so a cross-site
+// request with it needs no origin check.
+func safeMethod(method string) bool {
+	return method == http.MethodGet || method == http.MethodHead || method == http.MethodOptions
+}
+
 // Auth bundles the dependencies the auth middlewares need.
 type Auth struct {
-	Repo repository.Repository
-	JWT  *auth.JWTManager
+	Repo  repository.Repository
+	JWT   *auth.JWTManager
+	Roles config.Roles
+	// AdminGuard, when set, blocks IPs that keep failing AdminAuth.
+	AdminGuard *AdminGuard
+	// AdminAPIKey, when set, is a static key an X-Admin-Key header may
+	// present instead of an admin Bearer token.
+	AdminAPIKey string
+	// CookieOrigins is the CORS_ALLOWED_ORIGINS list. A state-changing
+	// request authenticated by the access-token cookie must come from one of
+	// them; a wildcard admits none.
+	CookieOrigins string
+	// PublicBaseURL is PUBLIC_BASE_URL, the service's address as clients
+	// know it, or "" when unset.
+	PublicBaseURL string
+
+	assertions assertionCache
 }
 
-// AuthenticatedUser validates a Bearer token and loads the active user.
+// AuthenticatedUser validates a Bearer token, or the access-token cookie when
+// there is no Authorization header, and loads the active user. The cookie
+// counts only for a token whose app set access_token_cookie, and, since the
+// browser sends it on its own, a state-changing request using it must carry
+// an Origin from CookieOrigins.
 func (a *Auth) AuthenticatedUser() gin.HandlerFunc {
+	origins, _ := originSet(a.CookieOrigins)
 	return func(c *gin.Context) {
-		token, ok := bearer(c)
+		token, fromCookie, ok := userToken(c)
 		if !ok {
 			RespondError(c, apperror.Unauthorized())
 			return
 		}
-		claims, err := a.JWT.VerifyAccessToken(token)
+		claims, err := a.JWT.VerifyAccessToken(c.Request.Context(), token)
 		if err != nil {
 			RespondError(c, err)
 			return
 		}
+		if fromCookie {
+			if err := a.checkCookieAuth(c, claims.Aud.ClientID(), origins); err != nil {
+				RespondError(c, err)
+				return
+			}
+		}
 		user, err := a.Repo.Users().FindByID(c.Request.Context(), claims.Sub)
 		if err != nil {
 			RespondError(c, err)
@@ -111,12 +322,32 @@ func (a *Auth) AuthenticatedUser() gin.HandlerFunc {
 			return
 		}
 		c.Set(ctxUserID, claims.Sub)
-		c.Set(ctxClientID, claims.Aud)
+		c.Set(ctxClientID, claims.Aud.ClientID())
 		c.Set(ctxScopes, claims.Scopes)
+		c.Set(ctxExpiresAt, time.Unix(claims.Exp, 0).UTC())
+		if claims.AuthTime > 0 {
+			c.Set(ctxAuthTime, time.Unix(claims.AuthTime, 0).UTC())
+		}
 		c.Next()
 	}
 }
 
+// checkCookieAuth admits a request authenticated by the access-token cookie
+// of clientID's token.
+func (a *Auth) checkCookieAuth(c *gin.Context, clientID string, origins map[string]bool) error {
+	app, err := a.Repo.Applications().FindByClientID(c.Request.Context(), clientID)
+	if err != nil {
+		return err
+	}
+	if app == nil || !app.AccessTokenCookie {
+		return apperror.Unauthorized()
+	}
+	if !safeMethod(c.Request.Method) && !origins[c.GetHeader("Origin")] {
+		return apperror.Forbidden()
+	}
+	return nil
+}
+
 // ClientApp resolves the active application from the X-Client-Id header.
 func (a *Auth) ClientApp() gin.HandlerFunc {
 	return func(c *gin.Context) {
@@ -141,55 +372,234 @@ func (a *Auth) ClientApp() gin.HandlerFunc {
 		c.Set(ctxAppID, app.ID)
 		c.Set(ctxClientID, app.ClientID)
 		c.Set(ctxAllowedScopes, auth.DecodeStringArray(app.AllowedScopes))
+		c.Set(ctxCustomClaims, auth.DecodeObject(app.CustomClaims))
+		c.Set(ctxApp, app)
 		c.Next()
 	}
 }
 
-// AuthenticatedApp authenticates a client application via Basic auth.
+// AuthenticatedApp authenticates a client application at the OAuth2 endpoints
+// with client_secret_basic, client_secret_post, or private_key_jwt.
 func (a *Auth) AuthenticatedApp() gin.HandlerFunc {
 	return func(c *gin.Context) {
-		header := c.GetHeader("Authorization")
-		encoded, ok := strings.CutPrefix(header, "Basic ")
-		if !ok {
-			RespondError(c, apperror.InvalidCredentials())
+		if err := a.authenticateApp(c); err != nil {
+			clientAuthFailed(c, err)
+			RespondError(c, err)
 			return
 		}
+		c.Next()
+	}
+}
+
+// clientAuthFailed logs a refused client authentication under the client_id
+// the client claimed, if any.
+func clientAuthFailed(c *gin.Context, err error) {
+	creds, _ := clientCredentials(c)
+	method := "client_secret_post"
+	switch {
+	case creds.ClientAssertion != "":
+		method = "private_key_jwt"
+	case strings.HasPrefix(c.GetHeader("Authorization"), "Basic "):
+		method = "client_secret_basic"
+	}
+	ae, _ := apperror.As(err)
+	securitylog.Emit(securitylog.Event{
+		Type: securitylog.ClientAuth, Outcome: securitylog.Failure, Reason: ae.Type,
+		Method: method, ClientID: creds.ClientID, IP: ClientIP(c, ""),
+	})
+}
+
+// AuthenticatedClientID checks the request's client credentials as
+// AuthenticatedApp does and returns the authenticated client_id, or "" when
+// there are none or they fail. The rate limiters use it to grant a trusted
+// client its raised limit.
+func (a *Auth) AuthenticatedClientID(c *gin.Context) string {
+	if a.authenticateApp(c) != nil {
+		return ""
+	}
+	return ClientID(c)
+}
+
+// authenticateApp authenticates the request's client once and stores the
+// app in the context on success. The outcome is kept on the request, so a
+// later call (the limiter, then AuthenticatedApp) neither repeats the work
+// nor trips the single-use check on a client assertion's jti.
+func (a *Auth) authenticateApp(c *gin.Context) error {
+	if v, ok := c.Get(ctxClientAuth); ok {
+		err, _ := v.(error)
+		return err
+	}
+	err := a.verifyApp(c)
+	c.Set(ctxClientAuth, err)
+	return err
+}
+
+func (a *Auth) verifyApp(c *gin.Context) error {
+	creds, ok := clientCredentials(c)
+	if !ok {
+		return apperror.InvalidCredentials()
+	}
+	app, err := a.Repo.Applications().FindByClientID(c.Request.Context(), creds.ClientID)
+	if err != nil {
+		return err
+	}
+	if app == nil {
+		return apperror.ApplicationNotFound()
+	}
+	if !app.IsActive {
+		return apperror.ApplicationNotActive()
+	}
+	if err := a.authenticateClient(c, app, creds); err != nil {
+		return err
+	}
+	c.Set(ctxAppID, app.ID)
+	c.Set(ctxClientID, app.ClientID)
+	c.Set(ctxCustomClaims, auth.DecodeObject(app.CustomClaims))
+	c.Set(ctxApp, app)
+	return nil
+}
+
+// clientCreds is what a client presents to AuthenticatedApp: a secret, or a
+// signed assertion.
+type clientCreds struct {
+	ClientID            string `json:"client_id"`
+	ClientSecret        string `json:"client_secret"`
+	ClientAssertionType string `json:"client_assertion_type"`
+	ClientAssertion     string `json:"client_assertion"`
+}
+
+// clientCredentials reads the client's credentials from the Basic header or,
+// without one, from the form-encoded or JSON request body, which is restored
+// for the handler.
+func clientCredentials(c *gin.Context) (clientCreds, bool) {
+	var creds clientCreds
+	if encoded, ok := strings.CutPrefix(c.GetHeader("Authorization"), "Basic "); ok {
 		decoded, err := base64.StdEncoding.DecodeString(encoded)
 		if err != nil {
-			RespondError(c, apperror.InvalidCredentials())
-			return
+			return creds, false
 		}
-		clientID, secret, ok := strings.Cut(string(decoded), ":")
-		if !ok {
-			RespondError(c, apperror.InvalidCredentials())
-			return
-		}
-		app, err := a.Repo.Applications().FindByClientID(c.Request.Context(), clientID)
+		creds.ClientID, creds.ClientSecret, ok = strings.Cut(string(decoded), ":")
+		return creds, ok
+	}
+	if c.Request.Body == nil {
+		return creds, false
+	}
+	body, err := io.ReadAll(c.Request.Body)
+	if err != nil {
+		return creds, false
+	}
+	c.Request.Body = io.NopCloser(bytes.NewReader(body))
+	if c.ContentType() == binding.MIMEPOSTForm {
+		form, err := url.ParseQuery(string(body))
 		if err != nil {
-			RespondError(c, err)
-			return
+			return creds, false
 		}
-		if app == nil {
-			RespondError(c, apperror.ApplicationNotFound())
-			return
+		creds.ClientID = form.Get("client_id")
+		creds.ClientSecret = form.Get("client_secret")
+		creds.ClientAssertionType = form.Get("client_assertion_type")
+		creds.ClientAssertion = form.Get("client_assertion")
+	} else if json.Unmarshal(body, &creds) != nil {
+		return creds, false
+	}
+	if creds.ClientAssertion != "" {
+		if creds.ClientAssertionType != auth.ClientAssertionType {
+			return creds, false
 		}
-		if !app.IsActive {
-			RespondError(c, apperror.ApplicationNotActive())
-			return
+		if creds.ClientID == "" {
+			creds.ClientID = auth.ClientAssertionSubject(creds.ClientAssertion)
 		}
-		valid, err := auth.VerifyClientSecret(secret, app.ClientSecretHash)
+		return creds, creds.ClientID != ""
+	}
+	return creds, creds.ClientID != "" && creds.ClientSecret != ""
+}
+
+// authenticateClient checks creds against app: the assertion against the
+// registered public key (each jti usable once), otherwise the secret, or the
+// previous secret while its rotation grace period lasts.
+func (a *Auth) authenticateClient(c *gin.Context, app *domain.Application, creds clientCreds) error {
+	if creds.ClientAssertion == "" {
+		valid, err := auth.VerifyClientSecret(creds.ClientSecret, app.ClientSecretHash)
 		if err != nil {
-			RespondError(c, err)
-			return
+			return err
+		}
+		if !valid && app.PreviousSecretActive(time.Now()) {
+			if valid, err = auth.VerifyClientSecret(creds.ClientSecret, app.PreviousClientSecretHash); err != nil {
+				return err
+			}
 		}
 		if !valid {
-			RespondError(c, apperror.InvalidCredentials())
-			return
+			return apperror.InvalidCredentials()
 		}
-		c.Set(ctxAppID, app.ID)
-		c.Set(ctxClientID, app.ClientID)
-		c.Next()
+		return nil
+	}
+	if app.ClientPublicKey == "" {
+		return apperror.InvalidCredentials()
+	}
+	jti, exp, err := auth.VerifyClientAssertion(creds.ClientAssertion, app.ClientID, app.ClientPublicKey, a.assertionAudiences(c, app))
+	if err != nil {
+		return err
+	}
+	if !a.assertions.claim(app.ClientID+":"+jti, exp) {
+		return apperror.InvalidCredentials()
+	}
+	return nil
+}
+
+// assertionAudiences lists the aud values a client assertion may carry: the
+// token issuer (the app's own, if it has one) and, under PublicBaseURL, the
+// token endpoint or the endpoint being called. The request's Host and
+// X-Forwarded-Proto are the client's to choose, so they are never used here.
+func (a *Auth) assertionAudiences(c *gin.Context, app *domain.Application) []string {
+	audiences := []string{a.JWT.KeyInfo().Issuer}
+	if app.Issuer != nil {
+		audiences = append(audiences, *app.Issuer)
+	}
+	if a.PublicBaseURL != "" {
+		audiences = append(audiences, a.PublicBaseURL+"/oauth/token", a.PublicBaseURL+c.FullPath())
 	}
+	return audiences
+}
+
+// BaseURL is the scheme and host the request was addressed to, honoring
+// X-Forwarded-Proto from a TLS-terminating proxy.
+func BaseURL(c *gin.Context) string {
+	if isHTTPS(c) {
+		return "https://" + c.Request.Host
+	}
+	return "http://" + c.Request.Host
+}
+
+func isHTTPS(c *gin.Context) bool {
+	return c.Request.TLS != nil || c.GetHeader("X-Forwarded-Proto") == "https"
+}
+
+// assertionCache remembers used client assertions until they expire so a
+// captured one can't be replayed. It is per instance: behind a load balancer
+// an assertion may be used once on each instance within its lifetime (at most
+// five minutes). The zero value is ready to use.
+type assertionCache struct {
+	mu   sync.Mutex
+	seen map[string]time.Time
+}
+
+// claim records key and reports whether it had not been seen before.
+func (s *assertionCache) claim(key string, exp time.Time) bool {
+	s.mu.Lock()
+	defer s.mu.Unlock()
+	now := time.Now()
+	for k, e := range s.seen {
+		if now.After(e) {
+			delete(s.seen, k)
+		}
+	}
+	if _, ok := s.seen[key]; ok {
+		return false
+	}
+	if s.seen == nil {
+		s.seen = make(map[string]time.Time)
+	}
+	s.seen[key] = exp
+	return true
 }
 
 // AppTokenAuth requires a Bearer token issued via the client_credentials grant.
@@ -208,17 +618,77 @@ func (a *Auth) AppTokenAuth() gin.HandlerFunc {
 	}
 }
 
-// AdminAuth requires an active admin user with a Bearer token carrying the
-// admin role.
+// AdminKeyHeader carries the static ADMIN_API_KEY for server-to-server
+// automation.
+const AdminKeyHeader = "X-Admin-Key"
+
+// SystemAdminID is the user id requests authenticated by AdminKeyHeader act
+// under; it names no real user.
+const SystemAdminID = "system"
+
+// AdminAuth requires an active user with a Bearer token carrying a privileged
+// role, or the configured admin key in X-Admin-Key. Per-route access is then
+// narrowed by RequirePermission.
 func (a *Auth) AdminAuth() gin.HandlerFunc {
 	return func(c *gin.Context) {
+		ip := RemoteIP(c)
+		if a.adminBlocked(c, ip) {
+			return
+		}
 		token, ok := bearer(c)
 		if !ok {
-			RespondError(c, apperror.Unauthorized())
+			if err := a.authenticateAdminKey(c); err != nil {
+				a.rejectAdmin(c, ip, err)
+				return
+			}
+			c.Next()
 			return
 		}
 		if err := a.authenticateAdminToken(c, token); err != nil {
-			RespondError(c, err)
+			a.rejectAdmin(c, ip, err)
+			return
+		}
+		c.Next()
+	}
+}
+
+// adminBlocked rejects the request when ip is serving an AdminGuard block.
+func (a *Auth) adminBlocked(c *gin.Context, ip string) bool {
+	until := a.AdminGuard.blockedUntil(ip)
+	if until.IsZero() {
+		return false
+	}
+	c.Header("Retry-After", strconv.Itoa(int(time.Until(until).Seconds())+1))
+	RespondError(c, apperror.AdminIPBlocked())
+	return true
+}
+
+// rejectAdmin responds with err, counting it against ip when it is an
+// authentication failure. A 403 for a genuine user without an admin role, or
+// a database error, is not probing and does not count.
+func (a *Auth) rejectAdmin(c *gin.Context, ip string, err error) {
+	if ae, ok := apperror.As(err); ok && ae.Status == http.StatusUnauthorized {
+		a.AdminGuard.fail(ip)
+	}
+	RespondError(c, err)
+}
+
+// RequirePermission allows the request only when the admin user's role grants
+// perm. It must run after AdminAuth or AdminOrAppTokenAuth; application tokens
+// admitted by the latter are not role-bound and pass through.
+func (a *Auth) RequirePermission(perm string) gin.HandlerFunc {
+	return func(c *gin.Context) {
+		role, isUser := c.Get(ctxRole)
+		if !isUser {
+			if AppID(c) == "" {
+				RespondError(c, apperror.Forbidden())
+				return
+			}
+			c.Next()
+			return
+		}
+		if r, _ := role.(string); !a.Roles.HasPermission(r, perm) {
+			RespondError(c, apperror.Forbidden())
 			return
 		}
 		c.Next()
@@ -226,18 +696,27 @@ func (a *Auth) AdminAuth() gin.HandlerFunc {
 }
 
 // AdminOrAppTokenAuth accepts either an admin user Bearer token or an active
-// application Bearer token minted with the client_credentials grant.
+// application Bearer token minted with the client_credentials grant. Without
+// a Bearer token it falls back to the admin key, as AdminAuth does.
 func (a *Auth) AdminOrAppTokenAuth() gin.HandlerFunc {
 	return func(c *gin.Context) {
+		ip := RemoteIP(c)
+		if a.adminBlocked(c, ip) {
+			return
+		}
 		token, ok := bearer(c)
 		if !ok {
-			RespondError(c, apperror.Unauthorized())
+			if err := a.authenticateAdminKey(c); err != nil {
+				a.rejectAdmin(c, ip, err)
+				return
+			}
+			c.Next()
 			return
 		}
 
-		if claims, err := a.JWT.VerifyAccessToken(token); err == nil {
+		if claims, err := a.JWT.VerifyAccessToken(c.Request.Context(), token); err == nil {
 			if err := a.authorizeAdminClaims(c, claims); err != nil {
-				RespondError(c, err)
+				a.rejectAdmin(c, ip, err)
 				return
 			}
 			c.Next()
@@ -245,15 +724,33 @@ func (a *Auth) AdminOrAppTokenAuth() gin.HandlerFunc {
 		}
 
 		if err := a.authenticateAppToken(c, token); err != nil {
-			RespondError(c, err)
+			a.rejectAdmin(c, ip, err)
 			return
 		}
 		c.Next()
 	}
 }
 
+// authenticateAdminKey admits a request whose X-Admin-Key matches
+// AdminAPIKey as the admin role under SystemAdminID. With no key configured,
+// or none sent, it is plain Unauthorized. Hashing both sides first keeps the
+// comparison constant-time regardless of length.
+func (a *Auth) authenticateAdminKey(c *gin.Context) error {
+	key := c.GetHeader(AdminKeyHeader)
+	if a.AdminAPIKey == "" || key == "" {
+		return apperror.Unauthorized()
+	}
+	got, want := sha256.Sum256([]byte(key)), sha256.Sum256([]byte(a.AdminAPIKey))
+	if subtle.ConstantTimeCompare(got[:], want[:]) != 1 {
+		return apperror.Unauthorized()
+	}
+	c.Set(ctxRole, domain.RoleAdmin)
+	c.Set(ctxAdmin, AdminIdentity{UserID: SystemAdminID, Role: domain.RoleAdmin})
+	return nil
+}
+
 func (a *Auth) authenticateAdminToken(c *gin.Context, token string) error {
-	claims, err := a.JWT.VerifyAccessToken(token)
+	claims, err := a.JWT.VerifyAccessToken(c.Request.Context(), token)
 	if err != nil {
 		return err
 	}
@@ -261,7 +758,7 @@ func (a *Auth) authenticateAdminToken(c *gin.Context, token string) error {
 }
 
 func (a *Auth) authorizeAdminClaims(c *gin.Context, claims *auth.AccessClaims) error {
-	if claims.Role != "admin" {
+	if !a.Roles.IsPrivileged(claims.Role) {
 		return apperror.Forbidden()
 	}
 	user, err := a.Repo.Users().FindByID(c.Request.Context(), claims.Sub)
@@ -274,17 +771,19 @@ func (a *Auth) authorizeAdminClaims(c *gin.Context, claims *auth.AccessClaims) e
 	if !user.IsActive {
 		return apperror.UserDisabled()
 	}
-	if user.Role != "admin" {
+	if !a.Roles.IsPrivileged(user.Role) {
 		return apperror.Forbidden()
 	}
 	c.Set(ctxUserID, claims.Sub)
-	c.Set(ctxClientID, claims.Aud)
+	c.Set(ctxRole, user.Role)
+	c.Set(ctxAdmin, AdminIdentity{UserID: claims.Sub, Role: user.Role})
+	c.Set(ctxClientID, claims.Aud.ClientID())
 	c.Set(ctxScopes, claims.Scopes)
 	return nil
 }
 
 func (a *Auth) authenticateAppToken(c *gin.Context, token string) error {
-	claims, err := a.JWT.VerifyAppToken(token)
+	claims, err := a.JWT.VerifyAppToken(c.Request.Context(), token)
 	if err != nil {
 		return err
 	}
@@ -313,14 +812,129 @@ type RateLimiter struct {
 	lastCleanup time.Time
 	max         int
 	window      time.Duration
+	// rejected counts refusals since startup; rejectedByRoute splits them by
+	// route pattern. rejections holds per-key refusals and is pruned with
+	// buckets, so it only ever covers live keys.
+	rejected        uint64
+	rejectedByRoute map[string]uint64
+	rejections      map[string]uint64
+	// Exemptions, when set, lets trusted callers past or raises their limit.
+	Exemptions *RateLimitExemptions
+}
+
+// RateLimitStats is a point-in-time view of a limiter. Rejected and
+// RejectedByRoute count since startup; ActiveKeys and TopKeys cover the keys
+// with requests still inside the window.
+type RateLimitStats struct {
+	Max             int
+	Window          time.Duration
+	ActiveKeys      int
+	Rejected        uint64
+	RejectedByRoute map[string]uint64
+	TopKeys         []ThrottledKey
+}
+
+// ThrottledKey is a live bucket that has been refused requests: a client IP,
+// or "client:<id>" for a trusted client_id. Requests is its count inside the
+// window.
+type ThrottledKey struct {
+	Key      string
+	Requests int
+	Rejected uint64
+}
+
+// RateLimitExemptions describes the trusted callers of the rate limiters.
+// Requests from an exempt network (by RemoteIP) skip limiting. A trusted
+// client_id is counted in its own bucket with factor times the limit, but
+// only once the request has authenticated as that client.
+type RateLimitExemptions struct {
+	networks  []*net.IPNet
+	clientIDs map[string]bool
+	factor    int
+	// Authenticate returns the client_id whose credentials the request
+	// carries and that check out, or "". Nil grants no client a raised limit.
+	Authenticate func(c *gin.Context) string
+}
+
+// NewRateLimitExemptions parses cidrs (bare IPs are single hosts) and returns
+// nil when nothing is trusted. Unparseable entries are skipped; config
+// validation rejects them first.
+func NewRateLimitExemptions(cidrs, clientIDs []string, factor int) *RateLimitExemptions {
+	if len(cidrs) == 0 && len(clientIDs) == 0 {
+		return nil
+	}
+	factor = max(factor, 1)
+	ex := &RateLimitExemptions{clientIDs: make(map[string]bool, len(clientIDs)), factor: factor}
+	for _, entry := range cidrs {
+		if !strings.Contains(entry, "/") {
+			if ip := net.ParseIP(entry); ip != nil && ip.To4() != nil {
+				entry += "/32"
+			} else {
+				entry += "/128"
+			}
+		}
+		if _, network, err := net.ParseCIDR(entry); err == nil {
+			ex.networks = append(ex.networks, network)
+		}
+	}
+	for _, id := range clientIDs {
+		ex.clientIDs[id] = true
+	}
+	return ex
+}
+
+func (ex *RateLimitExemptions) exemptIP(ip string) bool {
+	parsed := net.ParseIP(ip)
+	if parsed == nil {
+		return false
+	}
+	for _, network := range ex.networks {
+		if network.Contains(parsed) {
+			return true
+		}
+	}
+	return false
+}
+
+// trustedClientID is the trusted client_id the request has authenticated
+// as, or "". The claimed id (X-Client-Id or the Basic username) is checked
+// against the list first, so other requests never pay for authentication.
+func (ex *RateLimitExemptions) trustedClientID(c *gin.Context) string {
+	if ex.Authenticate == nil || !ex.clientIDs[requestClientID(c)] {
+		return ""
+	}
+	if id := ex.Authenticate(c); ex.clientIDs[id] {
+		return id
+	}
+	return ""
+}
+
+// requestClientID is the client_id a request claims, from X-Client-Id or the
+// Basic username. Unverified.
+func requestClientID(c *gin.Context) string {
+	if id := c.GetHeader("X-Client-Id"); id != "" {
+		return id
+	}
+	if encoded, ok := strings.CutPrefix(c.GetHeader("Authorization"), "Basic "); ok {
+		if decoded, err := base64.StdEncoding.DecodeString(encoded); err == nil {
+			id, _, _ := strings.Cut(string(decoded), ":")
+			return id
+		}
+	}
+	return ""
 }
 
 // NewRateLimiter builds a limiter allowing max requests per window.
 func NewRateLimiter(max int, window time.Duration) *RateLimiter {
-	return &RateLimiter{buckets: make(map[string][]time.Time), lastCleanup: time.Now(), max: max, window: window}
+	return &RateLimiter{
+		buckets: make(map[string][]time.Time), lastCleanup: time.Now(), max: max, window: window,
+		rejectedByRoute: make(map[string]uint64), rejections: make(map[string]uint64),
+	}
 }
 
-func (l *RateLimiter) check(key string) bool {
+// check records a request for key and reports whether it is within max.
+// route is the matched route pattern, used only to count refusals.
+func (l *RateLimiter) check(key, route string, max int) bool {
 	l.mu.Lock()
 	defer l.mu.Unlock()
 	now := time.Now()
@@ -329,6 +943,7 @@ func (l *RateLimiter) check(key string) bool {
 		for k, ts := range l.buckets {
 			if len(ts) == 0 || now.Sub(ts[len(ts)-1]) >= l.window {
 				delete(l.buckets, k)
+				delete(l.rejections, k)
 			}
 		}
 		l.lastCleanup = now
@@ -341,8 +956,11 @@ func (l *RateLimiter) check(key string) bool {
 			kept = append(kept, t)
 		}
 	}
-	if len(kept) >= l.max {
+	if len(kept) >= max {
 		l.buckets[key] = kept
+		l.rejected++
+		l.rejectedByRoute[route]++
+		l.rejections[key]++
 		return false
 	}
 	kept = append(kept, now)
@@ -350,35 +968,614 @@ func (l *RateLimiter) check(key string) bool {
 	return true
 }
 
-// Middleware rate-limits by client IP.
+// Middleware rate-limits by RemoteIP. Exemptions are checked before any
+// bucket is touched.
 func (l *RateLimiter) Middleware() gin.HandlerFunc {
 	return func(c *gin.Context) {
-		key := ClientIP(c, "global")
-		if !l.check(key) {
-			c.AbortWithStatusJSON(http.StatusTooManyRequests, gin.H{
-				"error":   "rate_limited",
-				"message": "Too many requests. Please try again later.",
-			})
+		key, max := RemoteIP(c), l.max
+		if ex := l.Exemptions; ex != nil {
+			if ex.exemptIP(key) {
+				c.Next()
+				return
+			}
+			if id := ex.trustedClientID(c); id != "" {
+				key, max = "client:"+id, l.max*ex.factor
+			}
+		}
+		if !l.check(key, c.FullPath(), max) {
+			RespondError(c, apperror.RateLimited())
+			return
+		}
+		c.Next()
+	}
+}
+
+// Stats snapshots the limiter's counters, with up to top of the live keys
+// refused most often, most refusals first.
+func (l *RateLimiter) Stats(top int) RateLimitStats {
+	l.mu.Lock()
+	defer l.mu.Unlock()
+	now := time.Now()
+	stats := RateLimitStats{
+		Max: l.max, Window: l.window, Rejected: l.rejected,
+		RejectedByRoute: make(map[string]uint64, len(l.rejectedByRoute)),
+	}
+	for route, n := range l.rejectedByRoute {
+		stats.RejectedByRoute[route] = n
+	}
+	live := func(ts []time.Time) int {
+		n := 0
+		for _, t := range ts {
+			if now.Sub(t) < l.window {
+				n++
+			}
+		}
+		return n
+	}
+	for key, ts := range l.buckets {
+		requests := live(ts)
+		if requests == 0 {
+			continue
+		}
+		stats.ActiveKeys++
+		if n := l.rejections[key]; n > 0 {
+			stats.TopKeys = append(stats.TopKeys, ThrottledKey{Key: key, Requests: requests, Rejected: n})
+		}
+	}
+	sort.Slice(stats.TopKeys, func(i, j int) bool {
+		if stats.TopKeys[i].Rejected != stats.TopKeys[j].Rejected {
+			return stats.TopKeys[i].Rejected > stats.TopKeys[j].Rejected
+		}
+		return stats.TopKeys[i].Key < stats.TopKeys[j].Key
+	})
+	if len(stats.TopKeys) > top {
+		stats.TopKeys = stats.TopKeys[:top]
+	}
+	return stats
+}
+
+// --- Admin abuse guard ---
+
+// AdminGuard counts failed admin authentications per IP. Once an IP reaches
+// max failures inside window it is blocked for cooldown and an audit alert is
+// raised. It is separate from the rate limiter, whose window is short and
+// which counts successful and failed requests alike. State is per instance;
+// a nil *AdminGuard never blocks.
+type AdminGuard struct {
+	guard failureGuard
+}
+
+// NewAdminGuard builds a guard, or returns nil when max <= 0.
+func NewAdminGuard(max int, window, cooldown time.Duration) *AdminGuard {
+	if max <= 0 {
+		return nil
+	}
+	g := &AdminGuard{}
+	g.guard.init(max, window, cooldown)
+	return g
+}
+
+// blockedUntil is when ip's block ends, or the zero time if it is not blocked.
+func (g *AdminGuard) blockedUntil(ip string) time.Time {
+	if g == nil {
+		return time.Time{}
+	}
+	return g.guard.blockedUntil(ip)
+}
+
+func (g *AdminGuard) fail(ip string) {
+	if g == nil {
+		return
+	}
+	until, failures := g.guard.fail(ip)
+	if until.IsZero() {
+		return
+	}
+	audit.Record(audit.Event{Type: audit.AdminAuthAbuse, IP: ip, Details: map[string]any{
+		"failures":      failures,
+		"window_secs":   int64(g.guard.window.Seconds()),
+		"blocked_until": until.UTC().Format(time.RFC3339),
+	}})
+}
+
+// --- Login guard ---
+
+// LoginGuard throttles password logins per (identifier, IP) pair, the IP
+// being RemoteIP so a client cannot pick it: once a pair reaches max
+// failures inside window it is refused for cooldown. One
+// attacker guessing an account from one address is stopped without the
+// account being locked for its owner elsewhere, which is left to the higher
+// per-account limit. State is per instance; a nil *LoginGuard never blocks.
+type LoginGuard struct {
+	guard failureGuard
+}
+
+// NewLoginGuard builds a guard, or returns nil when max <= 0.
+func NewLoginGuard(max int, window, cooldown time.Duration) *LoginGuard {
+	if max <= 0 {
+		return nil
+	}
+	g := &LoginGuard{}
+	g.guard.init(max, window, cooldown)
+	return g
+}
+
+// loginGuardKey pairs an email or username, case-folded, with the client IP.
+func loginGuardKey(identifier, ip string) string {
+	return strings.ToLower(strings.TrimSpace(identifier)) + "|" + ip
+}
+
+// BlockedUntil is when the pair's block ends, or the zero time if it is not
+// blocked.
+func (g *LoginGuard) BlockedUntil(identifier, ip string) time.Time {
+	if g == nil {
+		return time.Time{}
+	}
+	return g.guard.blockedUntil(loginGuardKey(identifier, ip))
+}
+
+// Fail counts a failed login for the pair.
+func (g *LoginGuard) Fail(identifier, ip string) {
+	if g == nil {
+		return
+	}
+	g.guard.fail(loginGuardKey(identifier, ip))
+}
+
+// Reset forgets the pair's failures after a successful login.
+func (g *LoginGuard) Reset(identifier, ip string) {
+	if g == nil {
+		return
+	}
+	g.guard.reset(loginGuardKey(identifier, ip))
+}
+
+// --- Decoy lockout ---
+
+// DecoyLockout stands in for the per-account lockout on password logins that
+// match no account with a password. It counts their failures per identifier
+// and "locks" them the same way, so X-Login-Attempts-Remaining and
+// account_locked say nothing about which identifiers exist. State is per
+// instance and forgotten after a day; a nil *DecoyLockout never blocks.
+type DecoyLockout struct {
+	guard failureGuard
+}
+
+// NewDecoyLockout builds a lockout after max failures lasting lockout, or
+// returns nil when max <= 0.
+func NewDecoyLockout(max int, lockout time.Duration) *DecoyLockout {
+	if max <= 0 {
+		return nil
+	}
+	g := &DecoyLockout{}
+	g.guard.init(max, 24*time.Hour, lockout)
+	return g
+}
+
+// BlockedUntil is when identifier's lock ends, or the zero time if it is not
+// locked.
+func (g *DecoyLockout) BlockedUntil(identifier string) time.Time {
+	if g == nil {
+		return time.Time{}
+	}
+	return g.guard.blockedUntil(strings.ToLower(strings.TrimSpace(identifier)))
+}
+
+// Fail counts a failed login for identifier and returns the attempts left
+// before it locks, 0 once it has.
+func (g *DecoyLockout) Fail(identifier string) int {
+	if g == nil {
+		return 0
+	}
+	_, failures := g.guard.fail(strings.ToLower(strings.TrimSpace(identifier)))
+	return g.guard.max - failures
+}
+
+// failureGuard counts failures per key inside a sliding window and blocks a
+// key for cooldown once it reaches max. AdminGuard, LoginGuard and
+// DecoyLockout wrap it.
+type failureGuard struct {
+	mu          sync.Mutex
+	failures    map[string][]time.Time
+	blocked     map[string]time.Time
+	lastCleanup time.Time
+	max         int
+	window      time.Duration
+	cooldown    time.Duration
+}
+
+func (g *failureGuard) init(max int, window, cooldown time.Duration) {
+	g.failures, g.blocked = make(map[string][]time.Time), make(map[string]time.Time)
+	g.lastCleanup, g.max, g.window, g.cooldown = time.Now(), max, window, cooldown
+}
+
+func (g *failureGuard) blockedUntil(key string) time.Time {
+	g.mu.Lock()
+	defer g.mu.Unlock()
+	until, ok := g.blocked[key]
+	if !ok {
+		return time.Time{}
+	}
+	if !time.Now().Before(until) {
+		delete(g.blocked, key)
+		return time.Time{}
+	}
+	return until
+}
+
+// fail records a failure for key. When it reaches max, key is blocked and
+// fail returns when the block ends and the failures counted; otherwise the
+// zero time.
+func (g *failureGuard) fail(key string) (time.Time, int) {
+	g.mu.Lock()
+	defer g.mu.Unlock()
+	now := time.Now()
+	if now.Sub(g.lastCleanup) > 60*time.Second {
+		for k, ts := range g.failures {
+			if len(ts) == 0 || now.Sub(ts[len(ts)-1]) >= g.window {
+				delete(g.failures, k)
+			}
+		}
+		for k, until := range g.blocked {
+			if !now.Before(until) {
+				delete(g.blocked, k)
+			}
+		}
+		g.lastCleanup = now
+	}
+	kept := g.failures[key][:0]
+	for _, t := range g.failures[key] {
+		if now.Sub(t) < g.window {
+			kept = append(kept, t)
+		}
+	}
+	kept = append(kept, now)
+	if len(kept) < g.max {
+		g.failures[key] = kept
+		return time.Time{}, len(kept)
+	}
+	delete(g.failures, key)
+	until := now.Add(g.cooldown)
+	g.blocked[key] = until
+	return until, len(kept)
+}
+
+func (g *failureGuard) reset(key string) {
+	g.mu.Lock()
+	defer g.mu.Unlock()
+	delete(g.failures, key)
+}
+
+// --- Idempotency keys ---
+
+// Idempotency replays the stored response when a client repeats a request
+// with the same Idempotency-Key. Only successful responses are kept, so a
+// retry after a failure runs the handler again. State is per instance.
+type Idempotency struct {
+	mu          sync.Mutex
+	entries     map[string]*idempotentResponse
+	lastCleanup time.Time
+	ttl         time.Duration
+}
+
+type idempotentResponse struct {
+	fingerprint string // hash of the request path and body
+	expires     time.Time
+	done        bool
+	status      int
+	contentType string
+	body        []byte
+}
+
+// NewIdempotency builds a store keeping responses for ttl; ttl <= 0 makes
+// Middleware a no-op.
+func NewIdempotency(ttl time.Duration) *Idempotency {
+	return &Idempotency{entries: make(map[string]*idempotentResponse), lastCleanup: time.Now(), ttl: ttl}
+}
+
+// Middleware must run after client authentication: keys are scoped to the
+// resolved client id, so one client can never replay another's response.
+func (s *Idempotency) Middleware() gin.HandlerFunc {
+	return func(c *gin.Context) {
+		key := c.GetHeader("Idempotency-Key")
+		if key == "" || s.ttl <= 0 {
+			c.Next()
+			return
+		}
+		if len(key) > 255 {
+			RespondError(c, apperror.BadRequest("Idempotency-Key must be at most 255 characters"))
+			return
+		}
+		body, err := io.ReadAll(c.Request.Body)
+		if err != nil {
+			RespondError(c, apperror.BadRequest("Invalid request body"))
 			return
 		}
+		c.Request.Body = io.NopCloser(bytes.NewReader(body))
+		sum := sha256.Sum256(append([]byte(c.FullPath()+"\n"), body...))
+		fingerprint := hex.EncodeToString(sum[:])
+		key = ClientID(c) + "\n" + key
+
+		entry, err := s.begin(key, fingerprint)
+		if err != nil {
+			RespondError(c, err)
+			return
+		}
+		if entry != nil {
+			c.Header("Idempotent-Replayed", "true")
+			c.Data(entry.status, entry.contentType, entry.body)
+			c.Abort()
+			return
+		}
+
+		w := &recordingWriter{ResponseWriter: c.Writer}
+		c.Writer = w
+		defer s.finish(key, w)
 		c.Next()
 	}
 }
 
+// begin returns the completed response stored under key, or reserves the key
+// and returns nil when the handler should run.
+func (s *Idempotency) begin(key, fingerprint string) (*idempotentResponse, error) {
+	s.mu.Lock()
+	defer s.mu.Unlock()
+	now := time.Now()
+
+	if now.Sub(s.lastCleanup) > 60*time.Second {
+		for k, e := range s.entries {
+			if now.After(e.expires) {
+				delete(s.entries, k)
+			}
+		}
+		s.lastCleanup = now
+	}
+
+	if e, ok := s.entries[key]; ok && now.Before(e.expires) {
+		switch {
+		case e.fingerprint != fingerprint:
+			return nil, apperror.IdempotencyKeyReused()
+		case !e.done:
+			return nil, apperror.IdempotencyKeyInUse()
+		}
+		return e, nil
+	}
+	s.entries[key] = &idempotentResponse{fingerprint: fingerprint, expires: now.Add(s.ttl)}
+	return nil, nil
+}
+
+func (s *Idempotency) finish(key string, w *recordingWriter) {
+	s.mu.Lock()
+	defer s.mu.Unlock()
+	e, ok := s.entries[key]
+	if !ok {
+		return
+	}
+	// The status the handler set, not Written(): Compress sits outside this
+	// writer and holds back small bodies, so nothing has reached the client
+	// yet when a gzip-accepting request finishes.
+	if w.status < 200 || w.status >= 300 {
+		delete(s.entries, key)
+		return
+	}
+	e.done = true
+	e.status = w.status
+	e.contentType = w.Header().Get("Content-Type")
+	e.body = w.body
+}
+
+// recordingWriter keeps a copy of the response status and body as the
+// handler writes them. status stays 0 if the handler never responds.
+type recordingWriter struct {
+	gin.ResponseWriter
+	status int
+	body   []byte
+}
+
+func (w *recordingWriter) WriteHeader(code int) {
+	w.status = code
+	w.ResponseWriter.WriteHeader(code)
+}
+
+func (w *recordingWriter) Write(p []byte) (int, error) {
+	if w.status == 0 {
+		w.status = w.ResponseWriter.Status()
+	}
+	w.body = append(w.body, p...)
+	return w.ResponseWriter.Write(p)
+}
+
+func (w *recordingWriter) WriteString(s string) (int, error) { return w.Write([]byte(s)) }
+
 // --- CORS ---
 
-// CORS mirrors the tower-http CorsLayer: echo allowed origins (or "*"), allow
-// any method/header, and short-circuit preflight requests.
-func CORS(allowedOrigins string) gin.HandlerFunc {
-	wildcard := strings.TrimSpace(allowedOrigins) == "*"
-	set := map[string]bool{}
-	if !wildcard {
-		for _, o := range strings.Split(allowedOrigins, ",") {
-			if t := strings.TrimSpace(o); t != "" {
-				set[t] = true
+// Timeout gives each request a deadline on its context, so storage and provider
+// calls made with it give up once the budget is spent and RespondError answers
+// 504. Routes in overrides (keyed by gin route path) get their own budget; a
+// non-positive duration disables the deadline.
+func Timeout(d time.Duration, overrides map[string]time.Duration) gin.HandlerFunc {
+	return func(c *gin.Context) {
+		budget := d
+		if o, ok := overrides[c.FullPath()]; ok {
+			budget = o
+		}
+		if budget <= 0 {
+			c.Next()
+			return
+		}
+		ctx, cancel := context.WithTimeout(c.Request.Context(), budget)
+		defer cancel()
+		c.Request = c.Request.WithContext(ctx)
+		c.Next()
+	}
+}
+
+// SecurityHeaders sets conservative defaults on every response. hstsMaxAge > 0
+// adds Strict-Transport-Security; only enable it when served over TLS.
+func SecurityHeaders(hstsMaxAge int64) gin.HandlerFunc {
+	hsts := ""
+	if hstsMaxAge > 0 {
+		hsts = "max-age=" + strconv.FormatInt(hstsMaxAge, 10) + "; includeSubDomains"
+	}
+	return func(c *gin.Context) {
+		h := c.Writer.Header()
+		h.Set("X-Content-Type-Options", "nosniff")
+		h.Set("X-Frame-Options", "DENY")
+		h.Set("Referrer-Policy", "no-referrer")
+		if hsts != "" {
+			h.Set("Strict-Transport-Security", hsts)
+		}
+		c.Next()
+	}
+}
+
+// RequireHTTPS rejects requests that reached the service over plain HTTP
+// (REQUIRE_HTTPS). The exempt paths, e.g. a health probe, are let through.
+func RequireHTTPS(exempt ...string) gin.HandlerFunc {
+	return func(c *gin.Context) {
+		if !isHTTPS(c) && !slices.Contains(exempt, c.Request.URL.Path) {
+			RespondError(c, apperror.HTTPSRequired())
+			return
+		}
+		c.Next()
+	}
+}
+
+// RequireRecentAuth guards a sensitive route behind a Bearer middleware: the
+// token's auth_time must be at most maxAgeSecs old, so a stolen or long-lived
+// session can't make the change. Tokens without auth_time (minted from a
+// refresh token) never pass. A maxAgeSecs of 0 or less disables the check.
+func RequireRecentAuth(maxAgeSecs int64) gin.HandlerFunc {
+	return func(c *gin.Context) {
+		if maxAgeSecs > 0 {
+			authTime := AuthTime(c)
+			if authTime.IsZero() || time.Since(authTime) > time.Duration(maxAgeSecs)*time.Second {
+				RespondError(c, apperror.ReauthenticationRequired())
+				return
 			}
 		}
+		c.Next()
 	}
+}
+
+// NoStore marks responses as uncacheable. Required on responses carrying
+// tokens (RFC 6749 §5.1); handlers may still override it.
+func NoStore() gin.HandlerFunc {
+	return func(c *gin.Context) {
+		c.Header("Cache-Control", "no-store")
+		c.Header("Pragma", "no-cache")
+		c.Next()
+	}
+}
+
+// Compress gzips responses for clients that send Accept-Encoding: gzip. Bodies
+// smaller than minSize are sent as-is: the framing would cost more than it
+// saves.
+func Compress(minSize int) gin.HandlerFunc {
+	return func(c *gin.Context) {
+		if c.Request.Method == http.MethodHead || !acceptsGzip(c.GetHeader("Accept-Encoding")) {
+			c.Next()
+			return
+		}
+		w := &gzipWriter{ResponseWriter: c.Writer, minSize: minSize}
+		c.Writer = w
+		defer w.finish()
+		c.Next()
+	}
+}
+
+func acceptsGzip(header string) bool {
+	for _, part := range strings.Split(header, ",") {
+		coding, params, _ := strings.Cut(strings.TrimSpace(part), ";")
+		if strings.EqualFold(strings.TrimSpace(coding), "gzip") {
+			return strings.ReplaceAll(params, " ", "") != "q=0"
+		}
+	}
+	return false
+}
+
+// gzipWriter buffers the first minSize bytes, then switches to gzip. Anything
+// that finishes below the threshold is flushed uncompressed.
+type gzipWriter struct {
+	gin.ResponseWriter
+	minSize     int
+	buf         []byte
+	gz          *gzip.Writer
+	passthrough bool
+}
+
+func (w *gzipWriter) Write(p []byte) (int, error) {
+	switch {
+	case w.gz != nil:
+		return w.gz.Write(p)
+	case w.passthrough:
+		return w.ResponseWriter.Write(p)
+	}
+	w.buf = append(w.buf, p...)
+	if len(w.buf) < w.minSize {
+		return len(p), nil
+	}
+	if !w.compressible() {
+		w.passthrough = true
+	} else {
+		h := w.Header()
+		h.Set("Content-Encoding", "gzip")
+		h.Add("Vary", "Accept-Encoding")
+		h.Del("Content-Length")
+		w.gz = gzip.NewWriter(w.ResponseWriter)
+	}
+	if err := w.drain(); err != nil {
+		return 0, err
+	}
+	return len(p), nil
+}
+
+func (w *gzipWriter) WriteString(s string) (int, error) { return w.Write([]byte(s)) }
+
+// Flush sends what is buffered so far; streaming responses skip the threshold.
+func (w *gzipWriter) Flush() {
+	if w.gz == nil && !w.passthrough {
+		w.passthrough = true
+		_ = w.drain()
+	}
+	if w.gz != nil {
+		_ = w.gz.Flush()
+	}
+	w.ResponseWriter.Flush()
+}
+
+func (w *gzipWriter) compressible() bool {
+	status := w.Status()
+	return w.Header().Get("Content-Encoding") == "" &&
+		status != http.StatusNoContent && status != http.StatusNotModified
+}
+
+func (w *gzipWriter) drain() error {
+	buf := w.buf
+	w.buf = nil
+	if len(buf) == 0 {
+		return nil
+	}
+	var err error
+	if w.gz != nil {
+		_, err = w.gz.Write(buf)
+	} else {
+		_, err = w.ResponseWriter.Write(buf)
+	}
+	return err
+}
+
+func (w *gzipWriter) finish() {
+	_ = w.drain()
+	if w.gz != nil {
+		_ = w.gz.Close()
+	}
+}
+
+// CORS mirrors the tower-http CorsLayer: echo allowed origins (or "*"), allow
+// any method/header, and short-circuit preflight requests.
+func CORS(allowedOrigins string) gin.HandlerFunc {
+	set, wildcard := originSet(allowedOrigins)
 	return func(c *gin.Context) {
 		origin := c.GetHeader("Origin")
 		switch {
@@ -397,3 +1594,18 @@ func CORS(allowedOrigins string) gin.HandlerFunc {
 		c.Next()
 	}
 }
+
+// originSet parses a comma-separated origin list. A lone "*" yields an empty
+// set and wildcard.
+func originSet(allowedOrigins string) (set map[string]bool, wildcard bool) {
+	set = map[string]bool{}
+	if strings.TrimSpace(allowedOrigins) == "*" {
+		return set, true
+	}
+	for _, o := range strings.Split(allowedOrigins, ",") {
+		if t := strings.TrimSpace(o); t != "" {
+			set[t] = true
+		}
+	}
+	return set, false
+}
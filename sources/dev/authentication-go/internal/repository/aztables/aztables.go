@@ -37,9 +37,11 @@ const (
 	tableAppProviders    = "authappproviders"
 	tableAuthCodes       = "authauthcodes"
 	tableRefreshTokens   = "authrefreshtokens"
+	tableAccessTokens    = "authaccesstokens"
 	tableInviteCodes     = "authinvitecodes"
 	tableTeams           = "authteams"
 	tableTeamMemberships = "authteammemberships"
+	tableAuditEvents     = "authauditevents"
 )
 
 // ─── DateTime helpers ────────────────────────────────────────────────────────
@@ -170,6 +172,12 @@ func deleteEntity(ctx context.Context, c *aztables.Client, pk, rk string) error
 	return nil
 }
 
+// odataString quotes s as an OData string literal, doubling any single quote
+// so caller input cannot close the literal and extend the filter.
+func odataString(s string) string {
+	return "'" + strings.ReplaceAll(s, "'", "''") + "'"
+}
+
 // queryEntities pages through a filter, decoding each entity into a fresh T.
 func queryEntities[T any](ctx context.Context, c *aztables.Client, filter string) ([]T, error) {
 	f := filter
@@ -255,9 +263,11 @@ type Repository struct {
 	appProviders    *aztables.Client
 	authCodes       *aztables.Client
 	refreshTokens   *aztables.Client
+	accessTokens    *aztables.Client
 	inviteCodes     *aztables.Client
 	teams           *aztables.Client
 	teamMemberships *aztables.Client
+	auditEvents     *aztables.Client
 
 	userRepo       *userRepo
 	appRepo        *appRepo
@@ -265,9 +275,11 @@ type Repository struct {
 	appProvRepo    *appProviderRepo
 	authCodeRepo   *authCodeRepo
 	refreshRepo    *refreshTokenRepo
+	accessRepo     *accessTokenRepo
 	inviteRepo     *inviteCodeRepo
 	teamRepo       *teamRepo
 	membershipRepo *teamMembershipRepo
+	auditRepo      *auditEventRepo
 }
 
 // New builds a Repository from an Azure Storage connection string (supports the
@@ -286,26 +298,30 @@ func New(connectionString string) (*Repository, error) {
 		appProviders:    svc.NewClient(tableAppProviders),
 		authCodes:       svc.NewClient(tableAuthCodes),
 		refreshTokens:   svc.NewClient(tableRefreshTokens),
+		accessTokens:    svc.NewClient(tableAccessTokens),
 		inviteCodes:     svc.NewClient(tableInviteCodes),
 		teams:           svc.NewClient(tableTeams),
 		teamMemberships: svc.NewClient(tableTeamMemberships),
+		auditEvents:     svc.NewClient(tableAuditEvents),
 	}
-	r.userRepo = &userRepo{c: r.users, sortIndexes: r.userSortIndexes}
-	r.appRepo = &appRepo{c: r.applications}
 	r.accountRepo = &accountRepo{c: r.accounts}
+	r.userRepo = &userRepo{c: r.users, sortIndexes: r.userSortIndexes, accounts: r.accountRepo}
 	r.appProvRepo = &appProviderRepo{c: r.appProviders}
+	r.appRepo = &appRepo{c: r.applications, providers: r.appProvRepo}
 	r.authCodeRepo = &authCodeRepo{c: r.authCodes}
-	r.refreshRepo = &refreshTokenRepo{c: r.refreshTokens}
+	r.refreshRepo = &refreshTokenRepo{c: r.refreshTokens, users: r.userRepo}
+	r.accessRepo = &accessTokenRepo{c: r.accessTokens}
 	r.inviteRepo = &inviteCodeRepo{c: r.inviteCodes}
 	r.teamRepo = &teamRepo{c: r.teams}
 	r.membershipRepo = &teamMembershipRepo{c: r.teamMemberships}
+	r.auditRepo = &auditEventRepo{c: r.auditEvents}
 	return r, nil
 }
 
 func (r *Repository) allTables() []*aztables.Client {
 	return []*aztables.Client{
 		r.applications, r.users, r.userSortIndexes, r.accounts, r.appProviders, r.authCodes,
-		r.refreshTokens, r.inviteCodes, r.teams, r.teamMemberships,
+		r.refreshTokens, r.accessTokens, r.inviteCodes, r.teams, r.teamMemberships, r.auditEvents,
 	}
 }
 
@@ -450,9 +466,11 @@ func (r *Repository) Accounts() repository.AccountRepository               { ret
 func (r *Repository) AppProviders() repository.AppProviderRepository       { return r.appProvRepo }
 func (r *Repository) AuthCodes() repository.AuthCodeRepository             { return r.authCodeRepo }
 func (r *Repository) RefreshTokens() repository.RefreshTokenRepository     { return r.refreshRepo }
+func (r *Repository) AccessTokens() repository.AccessTokenRepository       { return r.accessRepo }
 func (r *Repository) InviteCodes() repository.InviteCodeRepository         { return r.inviteRepo }
 func (r *Repository) Teams() repository.TeamRepository                     { return r.teamRepo }
 func (r *Repository) TeamMemberships() repository.TeamMembershipRepository { return r.membershipRepo }
+func (r *Repository) AuditEvents() repository.AuditEventRepository         { return r.auditRepo }
 
 // ─── User ────────────────────────────────────────────────────────────────────
 
@@ -462,24 +480,31 @@ type loginPersist struct {
 }
 
 type userEntity struct {
-	PartitionKey        string  `json:"PartitionKey"`
-	RowKey              string  `json:"RowKey"`
-	Email               *string `json:"email,omitempty"`
-	Name                *string `json:"name,omitempty"`
-	AvatarURL           *string `json:"avatar_url,omitempty"`
-	EmailVerified       bool    `json:"email_verified"`
-	Role                string  `json:"role"`
-	UserType            string  `json:"user_type"`
-	IsActive            *bool   `json:"is_active,omitempty"`
-	Note                *string `json:"note,omitempty"`
-	CustomAttributes    string  `json:"custom_attributes"`
-	CreatedAt           string  `json:"created_at"`
-	UpdatedAt           string  `json:"updated_at"`
-	LastLoginAt         *string `json:"last_login_at,omitempty"`
-	RecentLogins        *string `json:"recent_logins,omitempty"`
-	InviteCode          *string `json:"invite_code,omitempty"`
-	Membership          string  `json:"membership"`
-	MembershipExpiresAt *string `json:"membership_expires_at,omitempty"`
+	PartitionKey           string  `json:"PartitionKey"`
+	RowKey                 string  `json:"RowKey"`
+	Email                  *string `json:"email,omitempty"`
+	Name                   *string `json:"name,omitempty"`
+	AvatarURL              *string `json:"avatar_url,omitempty"`
+	EmailVerified          bool    `json:"email_verified"`
+	Role                   string  `json:"role"`
+	UserType               string  `json:"user_type"`
+	IsActive               *bool   `json:"is_active,omitempty"`
+	Note                   *string `json:"note,omitempty"`
+	CustomAttributes       string  `json:"custom_attributes"`
+	CreatedAt              string  `json:"created_at"`
+	UpdatedAt              string  `json:"updated_at"`
+	LastLoginAt            *string `json:"last_login_at,omitempty"`
+	RecentLogins           *string `json:"recent_logins,omitempty"`
+	InviteCode             *string `json:"invite_code,omitempty"`
+	Membership             string  `json:"membership"`
+	MembershipExpiresAt    *string `json:"membership_expires_at,omitempty"`
+	PasswordChangeRequired bool    `json:"password_change_required"`
+	FailedLoginAttempts    int     `json:"failed_login_attempts"`
+	LockedUntil            *string `json:"locked_until,omitempty"`
+	PendingEmail           *string `json:"pending_email,omitempty"`
+	EmailChangeTokenHash   *string `json:"email_change_token_hash,omitempty"`
+	EmailChangeExpiresAt   *string `json:"email_change_expires_at,omitempty"`
+	Username               *string `json:"username,omitempty"`
 }
 
 func serializeLogins(records []domain.LoginRecord) *string {
@@ -546,24 +571,31 @@ func userToEntity(u *domain.User) userEntity {
 		role = "user"
 	}
 	return userEntity{
-		PartitionKey:        "user",
-		RowKey:              u.ID,
-		Email:               u.Email,
-		Name:                u.Name,
-		AvatarURL:           u.AvatarURL,
-		EmailVerified:       u.EmailVerified,
-		Role:                role,
-		UserType:            string(userType),
-		IsActive:            boolPtr(u.IsActive),
-		Note:                u.Note,
-		CustomAttributes:    serializeCustomAttributes(u.CustomAttributes),
-		CreatedAt:           fmtDT(u.CreatedAt),
-		UpdatedAt:           fmtDT(u.UpdatedAt),
-		LastLoginAt:         fmtDTPtr(u.LastLoginAt),
-		RecentLogins:        serializeLogins(u.RecentLogins),
-		InviteCode:          u.InviteCode,
-		Membership:          membership,
-		MembershipExpiresAt: fmtDTPtr(u.MembershipExpiresAt),
+		PartitionKey:           "user",
+		RowKey:                 u.ID,
+		Email:                  u.Email,
+		Name:                   u.Name,
+		AvatarURL:              u.AvatarURL,
+		EmailVerified:          u.EmailVerified,
+		Role:                   role,
+		UserType:               string(userType),
+		IsActive:               boolPtr(u.IsActive),
+		Note:                   u.Note,
+		CustomAttributes:       serializeCustomAttributes(u.CustomAttributes),
+		CreatedAt:              fmtDT(u.CreatedAt),
+		UpdatedAt:              fmtDT(u.UpdatedAt),
+		LastLoginAt:            fmtDTPtr(u.LastLoginAt),
+		RecentLogins:           serializeLogins(u.RecentLogins),
+		InviteCode:             u.InviteCode,
+		Membership:             membership,
+		MembershipExpiresAt:    fmtDTPtr(u.MembershipExpiresAt),
+		PasswordChangeRequired: u.PasswordChangeRequired,
+		FailedLoginAttempts:    u.FailedLoginAttempts,
+		LockedUntil:            fmtDTPtr(u.LockedUntil),
+		PendingEmail:           u.PendingEmail,
+		EmailChangeTokenHash:   u.EmailChangeTokenHash,
+		EmailChangeExpiresAt:   fmtDTPtr(u.EmailChangeExpiresAt),
+		Username:               u.Username,
 	}
 }
 
@@ -577,29 +609,37 @@ func (e *userEntity) toModel() *domain.User {
 		membership = string(domain.MembershipRegular)
 	}
 	return &domain.User{
-		ID:                  e.RowKey,
-		Email:               e.Email,
-		Name:                e.Name,
-		AvatarURL:           e.AvatarURL,
-		EmailVerified:       e.EmailVerified,
-		Role:                role,
-		UserType:            domain.UserTypeFromString(e.UserType),
-		IsActive:            boolOr(e.IsActive, true),
-		Note:                e.Note,
-		CustomAttributes:    deserializeCustomAttributes(e.CustomAttributes),
-		CreatedAt:           parseDT(e.CreatedAt),
-		UpdatedAt:           parseDT(e.UpdatedAt),
-		LastLoginAt:         parseDTPtr(e.LastLoginAt),
-		RecentLogins:        deserializeLogins(e.RecentLogins),
-		InviteCode:          e.InviteCode,
-		Membership:          domain.MembershipFromString(membership),
-		MembershipExpiresAt: parseDTPtr(e.MembershipExpiresAt),
+		ID:                     e.RowKey,
+		Email:                  e.Email,
+		Name:                   e.Name,
+		AvatarURL:              e.AvatarURL,
+		EmailVerified:          e.EmailVerified,
+		Role:                   role,
+		UserType:               domain.UserTypeFromString(e.UserType),
+		IsActive:               boolOr(e.IsActive, true),
+		Note:                   e.Note,
+		CustomAttributes:       deserializeCustomAttributes(e.CustomAttributes),
+		CreatedAt:              parseDT(e.CreatedAt),
+		UpdatedAt:              parseDT(e.UpdatedAt),
+		LastLoginAt:            parseDTPtr(e.LastLoginAt),
+		RecentLogins:           deserializeLogins(e.RecentLogins),
+		InviteCode:             e.InviteCode,
+		Membership:             domain.MembershipFromString(membership),
+		MembershipExpiresAt:    parseDTPtr(e.MembershipExpiresAt),
+		PasswordChangeRequired: e.PasswordChangeRequired,
+		FailedLoginAttempts:    e.FailedLoginAttempts,
+		LockedUntil:            parseDTPtr(e.LockedUntil),
+		PendingEmail:           e.PendingEmail,
+		EmailChangeTokenHash:   e.EmailChangeTokenHash,
+		EmailChangeExpiresAt:   parseDTPtr(e.EmailChangeExpiresAt),
+		Username:               e.Username,
 	}
 }
 
 type userRepo struct {
 	c           *aztables.Client
 	sortIndexes *aztables.Client
+	accounts    *accountRepo
 }
 
 func (r *userRepo) FindByID(ctx context.Context, id string) (*domain.User, error) {
@@ -620,26 +660,69 @@ func (r *userRepo) FindByEmail(ctx context.Context, email string) (*domain.User,
 	return r.FindByID(ctx, idx.TargetID)
 }
 
+func (r *userRepo) FindByUsername(ctx context.Context, username string) (*domain.User, error) {
+	var idx indexEntity
+	ok, err := getEntity(ctx, r.c, "idx_username", strings.ToLower(username), &idx)
+	if err != nil || !ok {
+		return nil, err
+	}
+	return r.FindByID(ctx, idx.TargetID)
+}
+
 func (r *userRepo) Insert(ctx context.Context, u *domain.User) error {
 	if u.Email != nil {
 		idx := indexEntity{PartitionKey: "idx_email", RowKey: strings.ToLower(*u.Email), TargetID: u.ID}
 		if err := addEntity(ctx, r.c, &idx); err != nil {
 			if isConflict(err) {
-				return apperror.Database("Email already exists")
+				return apperror.UserAlreadyExists()
+			}
+			return dbErr(err)
+		}
+	}
+	removeIndexes := func() {
+		if u.Email != nil {
+			_ = deleteEntity(ctx, r.c, "idx_email", strings.ToLower(*u.Email))
+		}
+		if u.Username != nil {
+			_ = deleteEntity(ctx, r.c, "idx_username", strings.ToLower(*u.Username))
+		}
+	}
+	if u.Username != nil {
+		idx := indexEntity{PartitionKey: "idx_username", RowKey: strings.ToLower(*u.Username), TargetID: u.ID}
+		if err := addEntity(ctx, r.c, &idx); err != nil {
+			if u.Email != nil {
+				_ = deleteEntity(ctx, r.c, "idx_email", strings.ToLower(*u.Email))
+			}
+			if isConflict(err) {
+				return apperror.UserAlreadyExists()
 			}
 			return dbErr(err)
 		}
 	}
 	e := userToEntity(u)
 	if err := addEntity(ctx, r.c, &e); err != nil {
+		removeIndexes()
 		return dbErr(err)
 	}
 	if err := r.upsertSortIndexes(ctx, &e); err != nil {
 		_ = r.deleteSortIndexes(ctx, &e)
 		_ = deleteEntity(ctx, r.c, "user", u.ID)
-		if u.Email != nil {
-			_ = deleteEntity(ctx, r.c, "idx_email", strings.ToLower(*u.Email))
-		}
+		removeIndexes()
+		return err
+	}
+	return nil
+}
+
+// InsertWithAccount writes the user, then the account, deleting the user
+// again if the account insert fails (there are no cross-table transactions).
+// The email index row makes a taken email fail before anything is written.
+func (r *userRepo) InsertWithAccount(ctx context.Context, u *domain.User, a *domain.Account) error {
+	if err := r.Insert(ctx, u); err != nil {
+		return err
+	}
+	if err := r.accounts.Insert(ctx, a); err != nil {
+		_ = r.accounts.DeleteByID(ctx, a.ID)
+		_ = r.DeleteByID(ctx, u.ID)
 		return err
 	}
 	return nil
@@ -678,6 +761,9 @@ func (r *userRepo) Update(ctx context.Context, u *domain.User) error {
 				}
 			}
 		}
+		if err := r.moveUsernameIndex(ctx, current.Username, u.Username, u.ID); err != nil {
+			return err
+		}
 	}
 	e := userToEntity(u)
 	if err := upsertEntity(ctx, r.c, &e); err != nil {
@@ -701,10 +787,43 @@ func (r *userRepo) DeleteByID(ctx context.Context, id string) error {
 				return err
 			}
 		}
+		if e.Username != nil {
+			if err := deleteEntity(ctx, r.c, "idx_username", strings.ToLower(*e.Username)); err != nil {
+				return err
+			}
+		}
 	}
 	return deleteEntity(ctx, r.c, "user", id)
 }
 
+// moveUsernameIndex repoints idx_username when a user's username changes. A
+// username held by someone else fails with user-already-exists.
+func (r *userRepo) moveUsernameIndex(ctx context.Context, oldName, newName *string, userID string) error {
+	var oldKey, newKey string
+	if oldName != nil {
+		oldKey = strings.ToLower(*oldName)
+	}
+	if newName != nil {
+		newKey = strings.ToLower(*newName)
+	}
+	if oldKey == newKey {
+		return nil
+	}
+	if newKey != "" {
+		idx := indexEntity{PartitionKey: "idx_username", RowKey: newKey, TargetID: userID}
+		if err := addEntity(ctx, r.c, &idx); err != nil {
+			if isConflict(err) {
+				return apperror.UserAlreadyExists()
+			}
+			return dbErr(err)
+		}
+	}
+	if oldKey != "" {
+		return deleteEntity(ctx, r.c, "idx_username", oldKey)
+	}
+	return nil
+}
+
 func (r *userRepo) CountAll(ctx context.Context) (uint64, error) {
 	es, err := queryEntities[userEntity](ctx, r.c, "PartitionKey eq 'user'")
 	if err != nil {
@@ -728,6 +847,21 @@ func (r *userRepo) CountSince(ctx context.Context, since time.Time) (uint64, err
 	return n, nil
 }
 
+func (r *userRepo) CountCreatedByDay(ctx context.Context, since time.Time) (map[string]uint64, error) {
+	es, err := queryEntities[userEntity](ctx, r.c, "PartitionKey eq 'user'")
+	if err != nil {
+		return nil, err
+	}
+	sinceStr := fmtDT(since)
+	out := map[string]uint64{}
+	for _, e := range es {
+		if e.CreatedAt >= sinceStr {
+			out[parseDT(e.CreatedAt).Format("2006-01-02")]++
+		}
+	}
+	return out, nil
+}
+
 func (r *userRepo) RecordLogin(ctx context.Context, userID, ip string) error {
 	u, err := r.FindByID(ctx, userID)
 	if err != nil || u == nil {
@@ -744,6 +878,62 @@ func (r *userRepo) RecordLogin(ctx context.Context, userID, ip string) error {
 	return r.Update(ctx, u)
 }
 
+func (r *userRepo) RecordFailedLogin(ctx context.Context, userID string, max int64, lockUntil time.Time) (int, *time.Time, error) {
+	e, err := r.updateUnderETag(ctx, userID, func(e *userEntity) {
+		e.FailedLoginAttempts++
+		if int64(e.FailedLoginAttempts) >= max {
+			e.LockedUntil = fmtDTPtr(&lockUntil)
+			e.FailedLoginAttempts = 0
+		}
+	})
+	if err != nil || e == nil {
+		return 0, nil, err
+	}
+	return e.FailedLoginAttempts, parseDTPtr(e.LockedUntil), nil
+}
+
+func (r *userRepo) ResetFailedLogins(ctx context.Context, userID string) error {
+	_, err := r.updateUnderETag(ctx, userID, func(e *userEntity) {
+		e.FailedLoginAttempts = 0
+		e.LockedUntil = nil
+	})
+	return err
+}
+
+// updateUnderETag applies fn to the stored user and replaces it under the
+// entity's ETag, re-reading and retrying when another writer got there first.
+// It returns the entity as written, or nil if the user does not exist. fn
+// must only touch fields no index is built from.
+func (r *userRepo) updateUnderETag(ctx context.Context, userID string, fn func(*userEntity)) (*userEntity, error) {
+	for {
+		resp, err := r.c.GetEntity(ctx, "user", userID, nil)
+		if err != nil {
+			if isNotFound(err) {
+				return nil, nil
+			}
+			return nil, dbErr(err)
+		}
+		var e userEntity
+		if err := json.Unmarshal(resp.Value, &e); err != nil {
+			return nil, dbErr(err)
+		}
+		fn(&e)
+		e.UpdatedAt = fmtDT(time.Now().UTC())
+		b, err := json.Marshal(&e)
+		if err != nil {
+			return nil, dbErr(err)
+		}
+		etag := resp.ETag
+		_, err = r.c.UpdateEntity(ctx, b, &aztables.UpdateEntityOptions{IfMatch: &etag, UpdateMode: aztables.UpdateModeReplace})
+		if err == nil {
+			return &e, nil
+		}
+		if !isPreconditionFailed(err) {
+			return nil, dbErr(err)
+		}
+	}
+}
+
 func normalizeUserSortName(value string) string {
 	a := pinyin.NewArgs()
 	a.Style = pinyin.Normal
@@ -987,17 +1177,47 @@ type appEntity struct {
 	ClientSecretHash string `json:"client_secret_hash"`
 	RedirectURIs     string `json:"redirect_uris"`
 	AllowedScopes    string `json:"allowed_scopes"`
+	CustomClaims     string `json:"custom_claims,omitempty"`
 	IsActive         *bool  `json:"is_active,omitempty"`
-	CreatedAt        string `json:"created_at"`
-	UpdatedAt        string `json:"updated_at"`
+	// Absent on apps written before the flag existed; those default to true.
+	AllowSelfRegistration *bool   `json:"allow_self_registration,omitempty"`
+	AllowedEmailDomains   string  `json:"allowed_email_domains,omitempty"`
+	DefaultScopes         string  `json:"default_scopes,omitempty"`
+	IncludeEmailClaim     bool    `json:"include_email_claim,omitempty"`
+	ClientPublicKey       string  `json:"client_public_key,omitempty"`
+	LogoURL               *string `json:"logo_url,omitempty"`
+	HomepageURL           *string `json:"homepage_url,omitempty"`
+	PrivacyPolicyURL      *string `json:"privacy_policy_url,omitempty"`
+	TermsURL              *string `json:"terms_url,omitempty"`
+	RotateRefreshTokens   *bool   `json:"rotate_refresh_tokens,omitempty"` // absent means true
+	LoginIdentifier       string  `json:"login_identifier,omitempty"`
+	AccessTokenCookie     bool    `json:"access_token_cookie,omitempty"`
+	IssueRefreshTokens    *bool   `json:"issue_refresh_tokens,omitempty"` // absent means true
+	CreatedBy             *string `json:"created_by,omitempty"`
+	UpdatedBy             *string `json:"updated_by,omitempty"`
+	// The secret replaced by a rotation with a grace period, and its expiry.
+	PreviousClientSecretHash      string  `json:"previous_client_secret_hash,omitempty"`
+	PreviousClientSecretExpiresAt *string `json:"previous_client_secret_expires_at,omitempty"`
+	Audiences                     string  `json:"audiences,omitempty"`
+	RequireProviderEmail          bool    `json:"require_email_from_provider,omitempty"`
+	Issuer                        *string `json:"issuer,omitempty"`
+	CreatedAt                     string  `json:"created_at"`
+	UpdatedAt                     string  `json:"updated_at"`
 }
 
 func appToEntity(a *domain.Application) appEntity {
 	return appEntity{
 		PartitionKey: "app", RowKey: a.ID, Name: a.Name, ClientID: a.ClientID,
 		ClientSecretHash: a.ClientSecretHash, RedirectURIs: a.RedirectURIs,
-		AllowedScopes: a.AllowedScopes, IsActive: boolPtr(a.IsActive),
-		CreatedAt: fmtDT(a.CreatedAt), UpdatedAt: fmtDT(a.UpdatedAt),
+		AllowedScopes: a.AllowedScopes, CustomClaims: a.CustomClaims, IsActive: boolPtr(a.IsActive),
+		AllowSelfRegistration: boolPtr(a.AllowSelfRegistration), AllowedEmailDomains: a.AllowedEmailDomains,
+		DefaultScopes: a.DefaultScopes, IncludeEmailClaim: a.IncludeEmailClaim, ClientPublicKey: a.ClientPublicKey,
+		LogoURL: a.LogoURL, HomepageURL: a.HomepageURL, PrivacyPolicyURL: a.PrivacyPolicyURL, TermsURL: a.TermsURL,
+		RotateRefreshTokens: boolPtr(a.RotateRefreshTokens), LoginIdentifier: string(a.LoginIdentifier),
+		AccessTokenCookie: a.AccessTokenCookie, IssueRefreshTokens: boolPtr(a.IssueRefreshTokens),
+		CreatedBy: a.CreatedBy, UpdatedBy: a.UpdatedBy, PreviousClientSecretHash: a.PreviousClientSecretHash,
+		PreviousClientSecretExpiresAt: fmtDTPtr(a.PreviousClientSecretExpiresAt), Audiences: a.Audiences,
+		RequireProviderEmail: a.RequireProviderEmail, Issuer: a.Issuer, CreatedAt: fmtDT(a.CreatedAt), UpdatedAt: fmtDT(a.UpdatedAt),
 	}
 }
 
@@ -1005,12 +1225,22 @@ func (e *appEntity) toModel() *domain.Application {
 	return &domain.Application{
 		ID: e.RowKey, Name: e.Name, ClientID: e.ClientID,
 		ClientSecretHash: e.ClientSecretHash, RedirectURIs: e.RedirectURIs,
-		AllowedScopes: e.AllowedScopes, IsActive: boolOr(e.IsActive, false),
-		CreatedAt: parseDT(e.CreatedAt), UpdatedAt: parseDT(e.UpdatedAt),
+		AllowedScopes: e.AllowedScopes, CustomClaims: e.CustomClaims, IsActive: boolOr(e.IsActive, false),
+		AllowSelfRegistration: boolOr(e.AllowSelfRegistration, true), AllowedEmailDomains: e.AllowedEmailDomains,
+		DefaultScopes: e.DefaultScopes, IncludeEmailClaim: e.IncludeEmailClaim, ClientPublicKey: e.ClientPublicKey,
+		LogoURL: e.LogoURL, HomepageURL: e.HomepageURL, PrivacyPolicyURL: e.PrivacyPolicyURL, TermsURL: e.TermsURL,
+		RotateRefreshTokens: boolOr(e.RotateRefreshTokens, true), LoginIdentifier: domain.LoginIdentifierFromString(e.LoginIdentifier),
+		AccessTokenCookie: e.AccessTokenCookie, IssueRefreshTokens: boolOr(e.IssueRefreshTokens, true),
+		CreatedBy: e.CreatedBy, UpdatedBy: e.UpdatedBy, PreviousClientSecretHash: e.PreviousClientSecretHash,
+		PreviousClientSecretExpiresAt: parseDTPtr(e.PreviousClientSecretExpiresAt), Audiences: e.Audiences,
+		RequireProviderEmail: e.RequireProviderEmail, Issuer: e.Issuer, CreatedAt: parseDT(e.CreatedAt), UpdatedAt: parseDT(e.UpdatedAt),
 	}
 }
 
-type appRepo struct{ c *aztables.Client }
+type appRepo struct {
+	c         *aztables.Client
+	providers *appProviderRepo
+}
 
 func (r *appRepo) FindByID(ctx context.Context, id string) (*domain.Application, error) {
 	var e appEntity
@@ -1069,6 +1299,36 @@ func (r *appRepo) Insert(ctx context.Context, a *domain.Application) error {
 	return nil
 }
 
+// InsertWithProviders writes the app, then its providers. Table storage has
+// no cross-table transactions, so a failed provider insert is undone by
+// deleting the rows already written.
+func (r *appRepo) InsertWithProviders(ctx context.Context, a *domain.Application, providers []domain.AppProvider) error {
+	if err := r.Insert(ctx, a); err != nil {
+		return err
+	}
+	for i := range providers {
+		if err := r.providers.Insert(ctx, &providers[i]); err != nil {
+			for j := 0; j <= i; j++ {
+				_ = r.providers.DeleteByID(ctx, providers[j].ID)
+			}
+			r.deleteInserted(ctx, a)
+			return err
+		}
+	}
+	return nil
+}
+
+// deleteInserted removes an app written by Insert, leaving a name index row
+// that belongs to another app alone.
+func (r *appRepo) deleteInserted(ctx context.Context, a *domain.Application) {
+	_ = deleteEntity(ctx, r.c, "app", a.ID)
+	_ = deleteEntity(ctx, r.c, "idx_clientid", a.ClientID)
+	var nameIdx indexEntity
+	if ok, err := getEntity(ctx, r.c, "idx_name", a.Name, &nameIdx); err == nil && ok && nameIdx.TargetID == a.ID {
+		_ = deleteEntity(ctx, r.c, "idx_name", a.Name)
+	}
+}
+
 func (r *appRepo) Update(ctx context.Context, a *domain.Application) error {
 	var current appEntity
 	ok, err := getEntity(ctx, r.c, "app", a.ID, &current)
@@ -1110,6 +1370,66 @@ func (r *appRepo) CountActive(ctx context.Context) (uint64, error) {
 	return n, nil
 }
 
+// appLoginEntity counts one app's sign-ins on one UTC day. The counters
+// share the applications table under a "logins_<app id>" partition keyed by
+// day, so a range of days is a single partition query.
+type appLoginEntity struct {
+	PartitionKey string `json:"PartitionKey"`
+	RowKey       string `json:"RowKey"`
+	Logins       int64  `json:"logins"`
+}
+
+// RecordLogin increments the day's counter under its ETag, re-reading and
+// retrying when another instance got there first.
+func (r *appRepo) RecordLogin(ctx context.Context, appID string, at time.Time) error {
+	pk, day := "logins_"+appID, at.UTC().Format("2006-01-02")
+	for {
+		resp, err := r.c.GetEntity(ctx, pk, day, nil)
+		if err != nil {
+			if !isNotFound(err) {
+				return dbErr(err)
+			}
+			err = addEntity(ctx, r.c, &appLoginEntity{PartitionKey: pk, RowKey: day, Logins: 1})
+			if err == nil {
+				return nil
+			}
+			if !isConflict(err) {
+				return dbErr(err)
+			}
+			continue
+		}
+		var e appLoginEntity
+		if err := json.Unmarshal(resp.Value, &e); err != nil {
+			return dbErr(err)
+		}
+		e.Logins++
+		b, err := json.Marshal(&e)
+		if err != nil {
+			return dbErr(err)
+		}
+		etag := resp.ETag
+		_, err = r.c.UpdateEntity(ctx, b, &aztables.UpdateEntityOptions{IfMatch: &etag, UpdateMode: aztables.UpdateModeReplace})
+		if err == nil {
+			return nil
+		}
+		if !isPreconditionFailed(err) {
+			return dbErr(err)
+		}
+	}
+}
+
+func (r *appRepo) CountLoginsByDay(ctx context.Context, appID string, since time.Time) (map[string]uint64, error) {
+	es, err := queryEntities[appLoginEntity](ctx, r.c, "PartitionKey eq "+odataString("logins_"+appID)+" and RowKey ge '"+since.UTC().Format("2006-01-02")+"'")
+	if err != nil {
+		return nil, err
+	}
+	out := map[string]uint64{}
+	for _, e := range es {
+		out[e.RowKey] = uint64(e.Logins)
+	}
+	return out, nil
+}
+
 // ─── Account ─────────────────────────────────────────────────────────────────
 
 type accountEntity struct {
@@ -1121,6 +1441,7 @@ type accountEntity struct {
 	ProviderMetadata  string  `json:"provider_metadata"`
 	CreatedAt         string  `json:"created_at"`
 	UpdatedAt         string  `json:"updated_at"`
+	ProviderTokens    *string `json:"provider_tokens,omitempty"`
 }
 
 func accountToEntity(a *domain.Account) accountEntity {
@@ -1132,6 +1453,7 @@ func accountToEntity(a *domain.Account) accountEntity {
 		PartitionKey: a.UserID, RowKey: a.ProviderID, ID: a.ID,
 		ProviderAccountID: a.ProviderAccountID, Credential: a.Credential,
 		ProviderMetadata: meta, CreatedAt: fmtDT(a.CreatedAt), UpdatedAt: fmtDT(a.UpdatedAt),
+		ProviderTokens: a.ProviderTokens,
 	}
 }
 
@@ -1144,6 +1466,7 @@ func (e *accountEntity) toModel() *domain.Account {
 		ID: e.ID, UserID: e.PartitionKey, ProviderID: e.RowKey,
 		ProviderAccountID: e.ProviderAccountID, Credential: e.Credential,
 		ProviderMetadata: meta, CreatedAt: parseDT(e.CreatedAt), UpdatedAt: parseDT(e.UpdatedAt),
+		ProviderTokens: e.ProviderTokens,
 	}
 }
 
@@ -1176,6 +1499,13 @@ func (r *accountRepo) FindAllByUser(ctx context.Context, userID string) ([]domai
 	for i := range es {
 		out = append(out, *es[i].toModel())
 	}
+	// Match the MySQL ordering; row keys (provider ids) are not chronological.
+	sort.SliceStable(out, func(i, j int) bool {
+		if !out[i].CreatedAt.Equal(out[j].CreatedAt) {
+			return out[i].CreatedAt.Before(out[j].CreatedAt)
+		}
+		return out[i].ID < out[j].ID
+	})
 	return out, nil
 }
 
@@ -1193,7 +1523,18 @@ func (r *accountRepo) Insert(ctx context.Context, a *domain.Account) error {
 			PartitionKey: providerAccountIndexPK(a.ProviderID), RowKey: *a.ProviderAccountID,
 			PK: a.UserID, RK: a.ProviderID,
 		}
-		_ = addEntity(ctx, r.c, &idx) // best-effort
+		// The index row doubles as the (provider_id, provider_account_id)
+		// uniqueness guard; a stale row whose account is gone is taken over.
+		if err := addEntity(ctx, r.c, &idx); err != nil && isConflict(err) {
+			owner, err := r.FindByProviderAccount(ctx, a.ProviderID, *a.ProviderAccountID)
+			if err != nil {
+				return err
+			}
+			if owner != nil && owner.ProviderAccountID != nil && *owner.ProviderAccountID == *a.ProviderAccountID {
+				return apperror.ProviderAccountInUse()
+			}
+			_ = upsertEntity(ctx, r.c, &idx)
+		}
 	}
 	idIdx := compositeIndexEntity{PartitionKey: "idx_id", RowKey: a.ID, PK: a.UserID, RK: a.ProviderID}
 	_ = addEntity(ctx, r.c, &idIdx)
@@ -1201,7 +1542,7 @@ func (r *accountRepo) Insert(ctx context.Context, a *domain.Account) error {
 	e := accountToEntity(a)
 	if err := addEntity(ctx, r.c, &e); err != nil {
 		if isConflict(err) {
-			return apperror.Database("Account already exists")
+			return apperror.AccountAlreadyLinked()
 		}
 		return dbErr(err)
 	}
@@ -1209,6 +1550,29 @@ func (r *accountRepo) Insert(ctx context.Context, a *domain.Account) error {
 }
 
 func (r *accountRepo) Update(ctx context.Context, a *domain.Account) error {
+	// Move the provider-account index row when the account id changes (a
+	// password account follows its user's email).
+	var prev accountEntity
+	ok, err := getEntity(ctx, r.c, a.UserID, a.ProviderID, &prev)
+	if err != nil {
+		return err
+	}
+	if ok && !eqStrPtr(prev.ProviderAccountID, a.ProviderAccountID) {
+		if a.ProviderAccountID != nil {
+			idx := compositeIndexEntity{
+				PartitionKey: providerAccountIndexPK(a.ProviderID), RowKey: *a.ProviderAccountID,
+				PK: a.UserID, RK: a.ProviderID,
+			}
+			if err := upsertEntity(ctx, r.c, &idx); err != nil {
+				return err
+			}
+		}
+		if prev.ProviderAccountID != nil {
+			if err := deleteEntity(ctx, r.c, providerAccountIndexPK(a.ProviderID), *prev.ProviderAccountID); err != nil {
+				return err
+			}
+		}
+	}
 	e := accountToEntity(a)
 	return upsertEntity(ctx, r.c, &e)
 }
@@ -1251,12 +1615,17 @@ func (r *accountRepo) DeleteAllByUser(ctx context.Context, userID string) error
 // ─── AppProvider ─────────────────────────────────────────────────────────────
 
 type appProviderEntity struct {
-	PartitionKey string `json:"PartitionKey"` // app_id
-	RowKey       string `json:"RowKey"`       // provider_id
-	ID           string `json:"id"`
-	Config       string `json:"config"`
-	IsActive     bool   `json:"is_active"`
-	CreatedAt    string `json:"created_at"`
+	PartitionKey string  `json:"PartitionKey"` // app_id
+	RowKey       string  `json:"RowKey"`       // provider_id
+	ID           string  `json:"id"`
+	Config       string  `json:"config"`
+	IsActive     bool    `json:"is_active"`
+	CreatedAt    string  `json:"created_at"`
+	DisplayName  *string `json:"display_name,omitempty"`
+	IconURL      *string `json:"icon_url,omitempty"`
+	SortOrder    int     `json:"sort_order"`
+	CreatedBy    *string `json:"created_by,omitempty"`
+	EmailDomains string  `json:"email_domains,omitempty"`
 }
 
 func appProviderToEntity(p *domain.AppProvider) appProviderEntity {
@@ -1267,6 +1636,8 @@ func appProviderToEntity(p *domain.AppProvider) appProviderEntity {
 	return appProviderEntity{
 		PartitionKey: p.AppID, RowKey: p.ProviderID, ID: p.ID,
 		Config: cfg, IsActive: p.IsActive, CreatedAt: fmtDT(p.CreatedAt),
+		DisplayName: p.DisplayName, IconURL: p.IconURL, SortOrder: p.SortOrder, CreatedBy: p.CreatedBy,
+		EmailDomains: p.EmailDomains,
 	}
 }
 
@@ -1278,6 +1649,8 @@ func (e *appProviderEntity) toModel() *domain.AppProvider {
 	return &domain.AppProvider{
 		ID: e.ID, AppID: e.PartitionKey, ProviderID: e.RowKey,
 		Config: cfg, IsActive: e.IsActive, CreatedAt: parseDT(e.CreatedAt),
+		DisplayName: e.DisplayName, IconURL: e.IconURL, SortOrder: e.SortOrder, CreatedBy: e.CreatedBy,
+		EmailDomains: e.EmailDomains,
 	}
 }
 
@@ -1424,6 +1797,7 @@ type refreshTokenEntity struct {
 	Scopes       string  `json:"scopes"`
 	DeviceID     *string `json:"device_id,omitempty"`
 	ExpiresAt    string  `json:"expires_at"`
+	ExpiryDays   int64   `json:"expiry_days,omitempty"`
 	Revoked      bool    `json:"revoked"`
 	CreatedAt    string  `json:"created_at"`
 }
@@ -1436,7 +1810,8 @@ func refreshTokenToEntity(t *domain.RefreshToken) refreshTokenEntity {
 	return refreshTokenEntity{
 		PartitionKey: "rt", RowKey: t.ID, UserID: t.UserID, AppID: t.AppID,
 		TokenHash: t.TokenHash, Scopes: scopes, DeviceID: t.DeviceID,
-		ExpiresAt: fmtDT(t.ExpiresAt), Revoked: t.Revoked, CreatedAt: fmtDT(t.CreatedAt),
+		ExpiresAt: fmtDT(t.ExpiresAt), ExpiryDays: t.ExpiryDays, Revoked: t.Revoked,
+		CreatedAt: fmtDT(t.CreatedAt),
 	}
 }
 
@@ -1448,11 +1823,14 @@ func (e *refreshTokenEntity) toModel() *domain.RefreshToken {
 	return &domain.RefreshToken{
 		ID: e.RowKey, UserID: e.UserID, AppID: e.AppID, TokenHash: e.TokenHash,
 		Scopes: scopes, DeviceID: e.DeviceID, ExpiresAt: parseDT(e.ExpiresAt),
-		Revoked: e.Revoked, CreatedAt: parseDT(e.CreatedAt),
+		ExpiryDays: e.ExpiryDays, Revoked: e.Revoked, CreatedAt: parseDT(e.CreatedAt),
 	}
 }
 
-type refreshTokenRepo struct{ c *aztables.Client }
+type refreshTokenRepo struct {
+	c     *aztables.Client
+	users *userRepo
+}
 
 func (r *refreshTokenRepo) FindByTokenHash(ctx context.Context, hash string) (*domain.RefreshToken, error) {
 	var idx indexEntity
@@ -1489,6 +1867,140 @@ func (r *refreshTokenRepo) Revoke(ctx context.Context, id string) error {
 	return upsertEntity(ctx, r.c, &e)
 }
 
+// RevokeIfActive flips revoked under the entity's ETag; losing the race to
+// another writer counts as already revoked.
+func (r *refreshTokenRepo) RevokeIfActive(ctx context.Context, id string) (bool, error) {
+	resp, err := r.c.GetEntity(ctx, "rt", id, nil)
+	if err != nil {
+		if isNotFound(err) {
+			return false, nil
+		}
+		return false, dbErr(err)
+	}
+	var e refreshTokenEntity
+	if err := json.Unmarshal(resp.Value, &e); err != nil {
+		return false, dbErr(err)
+	}
+	if e.Revoked {
+		return false, nil
+	}
+	e.Revoked = true
+	b, err := json.Marshal(&e)
+	if err != nil {
+		return false, dbErr(err)
+	}
+	etag := resp.ETag
+	_, err = r.c.UpdateEntity(ctx, b, &aztables.UpdateEntityOptions{IfMatch: &etag, UpdateMode: aztables.UpdateModeReplace})
+	if err != nil {
+		if isPreconditionFailed(err) {
+			return false, nil
+		}
+		return false, dbErr(err)
+	}
+	return true, nil
+}
+
+func (r *refreshTokenRepo) Extend(ctx context.Context, id string, expiresAt time.Time) error {
+	var e refreshTokenEntity
+	ok, err := getEntity(ctx, r.c, "rt", id, &e)
+	if err != nil || !ok || e.Revoked {
+		return err
+	}
+	e.ExpiresAt = fmtDT(expiresAt)
+	return upsertEntity(ctx, r.c, &e)
+}
+
+func (r *refreshTokenRepo) RevokeIssuedBefore(ctx context.Context, before time.Time, appID string) (int64, error) {
+	filter := "PartitionKey eq 'rt' and revoked eq false"
+	if appID != "" {
+		filter += " and app_id eq '" + appID + "'"
+	}
+	es, err := queryEntities[refreshTokenEntity](ctx, r.c, filter)
+	if err != nil {
+		return 0, err
+	}
+	var n int64
+	for i := range es {
+		if !parseDT(es[i].CreatedAt).Before(before) {
+			continue
+		}
+		if err := deleteEntity(ctx, r.c, "idx_hash", es[i].TokenHash); err != nil {
+			return n, err
+		}
+		if err := deleteEntity(ctx, r.c, "rt", es[i].RowKey); err != nil {
+			return n, err
+		}
+		n++
+	}
+	return n, nil
+}
+
+func (r *refreshTokenRepo) UsageByApp(ctx context.Context, appID string, now, since time.Time) (repository.AppUsage, error) {
+	es, err := queryEntities[refreshTokenEntity](ctx, r.c, "PartitionKey eq 'rt' and app_id eq "+odataString(appID))
+	if err != nil {
+		return repository.AppUsage{}, err
+	}
+	// One query for every user created since, rather than a lookup per
+	// token holder.
+	recent, err := queryEntities[userEntity](ctx, r.users.c, "PartitionKey eq 'user' and created_at ge '"+fmtDT(since)+"'")
+	if err != nil {
+		return repository.AppUsage{}, err
+	}
+	isRecent := make(map[string]bool, len(recent))
+	for _, e := range recent {
+		isRecent[e.RowKey] = true
+	}
+	var u repository.AppUsage
+	seen := map[string]bool{}
+	for i := range es {
+		if !es[i].Revoked && parseDT(es[i].ExpiresAt).After(now) {
+			u.ActiveTokens++
+		}
+		if seen[es[i].UserID] {
+			continue
+		}
+		seen[es[i].UserID] = true
+		u.Users++
+		if isRecent[es[i].UserID] {
+			u.NewUsers++
+		}
+	}
+	return u, nil
+}
+
+func (r *refreshTokenRepo) ListActiveByUser(ctx context.Context, userID string, now time.Time) ([]domain.RefreshToken, error) {
+	es, err := queryEntities[refreshTokenEntity](ctx, r.c, "PartitionKey eq 'rt' and user_id eq '"+userID+"' and revoked eq false")
+	if err != nil {
+		return nil, err
+	}
+	var out []domain.RefreshToken
+	for i := range es {
+		if t := es[i].toModel(); t.ExpiresAt.After(now) {
+			out = append(out, *t)
+		}
+	}
+	sort.Slice(out, func(i, j int) bool { return out[i].CreatedAt.After(out[j].CreatedAt) })
+	return out, nil
+}
+
+func (r *refreshTokenRepo) DeleteByUserAndApp(ctx context.Context, userID, appID string) (int64, error) {
+	es, err := queryEntities[refreshTokenEntity](ctx, r.c, "PartitionKey eq 'rt' and user_id eq "+odataString(userID)+" and app_id eq "+odataString(appID)+" and revoked eq false")
+	if err != nil {
+		return 0, err
+	}
+	var n int64
+	for _, e := range es {
+		if err := deleteEntity(ctx, r.c, "idx_hash", e.TokenHash); err != nil {
+			return n, err
+		}
+		if err := deleteEntity(ctx, r.c, "rt", e.RowKey); err != nil {
+			return n, err
+		}
+		n++
+	}
+	return n, nil
+}
+
 func (r *refreshTokenRepo) DeleteAllByUser(ctx context.Context, userID string) error {
 	es, err := queryEntities[refreshTokenEntity](ctx, r.c, "PartitionKey eq 'rt' and user_id eq '"+userID+"'")
 	if err != nil {
@@ -1505,6 +2017,59 @@ func (r *refreshTokenRepo) DeleteAllByUser(ctx context.Context, userID string) e
 	return nil
 }
 
+// ─── AccessToken ─────────────────────────────────────────────────────────────
+
+type accessTokenEntity struct {
+	PartitionKey string `json:"PartitionKey"` // "at"
+	RowKey       string `json:"RowKey"`       // token hash
+	Subject      string `json:"subject"`
+	Claims       string `json:"claims"`
+	ExpiresAt    string `json:"expires_at"`
+	CreatedAt    string `json:"created_at"`
+}
+
+type accessTokenRepo struct{ c *aztables.Client }
+
+func (r *accessTokenRepo) FindByTokenHash(ctx context.Context, hash string) (*domain.AccessToken, error) {
+	var e accessTokenEntity
+	ok, err := getEntity(ctx, r.c, "at", hash, &e)
+	if err != nil || !ok {
+		return nil, err
+	}
+	return &domain.AccessToken{
+		TokenHash: e.RowKey, Subject: e.Subject, Claims: e.Claims,
+		ExpiresAt: parseDT(e.ExpiresAt), CreatedAt: parseDT(e.CreatedAt),
+	}, nil
+}
+
+func (r *accessTokenRepo) Insert(ctx context.Context, t *domain.AccessToken) error {
+	e := accessTokenEntity{
+		PartitionKey: "at", RowKey: t.TokenHash, Subject: t.Subject, Claims: t.Claims,
+		ExpiresAt: fmtDT(t.ExpiresAt), CreatedAt: fmtDT(t.CreatedAt),
+	}
+	if err := addEntity(ctx, r.c, &e); err != nil {
+		return dbErr(err)
+	}
+	return nil
+}
+
+func (r *accessTokenRepo) DeleteByTokenHash(ctx context.Context, hash string) error {
+	return deleteEntity(ctx, r.c, "at", hash)
+}
+
+func (r *accessTokenRepo) DeleteAllByUser(ctx context.Context, userID string) error {
+	es, err := queryEntities[accessTokenEntity](ctx, r.c, "PartitionKey eq 'at' and subject eq '"+userID+"'")
+	if err != nil {
+		return err
+	}
+	for _, e := range es {
+		if err := deleteEntity(ctx, r.c, "at", e.RowKey); err != nil {
+			return err
+		}
+	}
+	return nil
+}
+
 // ─── InviteCode ──────────────────────────────────────────────────────────────
 
 type inviteCodeEntity struct {
@@ -1845,6 +2410,57 @@ func (r *teamMembershipRepo) DeleteAllByUser(ctx context.Context, userID string)
 	return nil
 }
 
+// ─── AuditEvent ──────────────────────────────────────────────────────────────
+
+type auditEventEntity struct {
+	PartitionKey string `json:"PartitionKey"` // "audit"
+	RowKey       string `json:"RowKey"`       // event id
+	Type         string `json:"type"`
+	UserID       string `json:"user_id,omitempty"`
+	TargetID     string `json:"target_id,omitempty"`
+	AppID        string `json:"app_id,omitempty"`
+	IP           string `json:"ip,omitempty"`
+	Details      string `json:"details,omitempty"`
+	CreatedAt    string `json:"created_at"`
+}
+
+type auditEventRepo struct{ c *aztables.Client }
+
+func (r *auditEventRepo) Insert(ctx context.Context, e *domain.AuditEvent) error {
+	entity := auditEventEntity{
+		PartitionKey: "audit", RowKey: e.ID, Type: e.Type, UserID: e.UserID, TargetID: e.TargetID,
+		AppID: e.AppID, IP: e.IP, Details: e.Details, CreatedAt: fmtDT(e.CreatedAt),
+	}
+	if err := addEntity(ctx, r.c, &entity); err != nil {
+		return dbErr(err)
+	}
+	return nil
+}
+
+func (r *auditEventRepo) ListByUser(ctx context.Context, userID, eventType string, offset, limit uint64) ([]domain.AuditEvent, uint64, error) {
+	filter := "PartitionKey eq 'audit' and (user_id eq " + odataString(userID) + " or target_id eq " + odataString(userID) + ")"
+	if eventType != "" {
+		filter += " and type eq " + odataString(eventType)
+	}
+	es, err := queryEntities[auditEventEntity](ctx, r.c, filter)
+	if err != nil {
+		return nil, 0, err
+	}
+	all := make([]domain.AuditEvent, 0, len(es))
+	for _, e := range es {
+		all = append(all, domain.AuditEvent{
+			ID: e.RowKey, Type: e.Type, UserID: e.UserID, TargetID: e.TargetID,
+			AppID: e.AppID, IP: e.IP, Details: e.Details, CreatedAt: parseDT(e.CreatedAt),
+		})
+	}
+	sort.SliceStable(all, func(i, j int) bool { return all[i].CreatedAt.After(all[j].CreatedAt) })
+	total := uint64(len(all))
+	if offset >= total {
+		return []domain.AuditEvent{}, total, nil
+	}
+	return all[offset:min(offset+limit, total)], total, nil
+}
+
 // ─── Migrations ──────────────────────────────────────────────────────────────
 
 // MigrateInviteCodeKinds backfills the `kind` field on every invite-code row.
@@ -47,9 +47,11 @@ type Repository struct {
 	appProvRepo    *appProviderRepo
 	authCodeRepo   *authCodeRepo
 	refreshRepo    *refreshTokenRepo
+	accessRepo     *accessTokenRepo
 	inviteRepo     *inviteCodeRepo
 	teamRepo       *teamRepo
 	membershipRepo *teamMembershipRepo
+	auditRepo      *auditEventRepo
 }
 
 type dbConn interface {
@@ -59,8 +61,9 @@ type dbConn interface {
 }
 
 var dataTables = []string{
-	"auth_team_memberships", "auth_refresh_tokens", "auth_auth_codes", "auth_accounts",
+	"auth_team_memberships", "auth_access_tokens", "auth_refresh_tokens", "auth_auth_codes", "auth_accounts",
 	"auth_app_providers", "auth_invite_codes", "auth_teams", "auth_users", "auth_applications",
+	"auth_app_logins",
 }
 
 // New opens a MySQL repository, verifies connectivity, and ensures the schema.
@@ -96,9 +99,11 @@ func NewWithOptions(ctx context.Context, dsn string, opts Options) (*Repository,
 	r.appProvRepo = &appProviderRepo{db: db}
 	r.authCodeRepo = &authCodeRepo{db: db}
 	r.refreshRepo = &refreshTokenRepo{db: db}
+	r.accessRepo = &accessTokenRepo{db: db}
 	r.inviteRepo = &inviteCodeRepo{db: db}
 	r.teamRepo = &teamRepo{db: db}
 	r.membershipRepo = &teamMembershipRepo{db: db}
+	r.auditRepo = &auditEventRepo{db: db}
 	return r, nil
 }
 
@@ -197,9 +202,11 @@ func (r *Repository) Accounts() repository.AccountRepository               { ret
 func (r *Repository) AppProviders() repository.AppProviderRepository       { return r.appProvRepo }
 func (r *Repository) AuthCodes() repository.AuthCodeRepository             { return r.authCodeRepo }
 func (r *Repository) RefreshTokens() repository.RefreshTokenRepository     { return r.refreshRepo }
+func (r *Repository) AccessTokens() repository.AccessTokenRepository       { return r.accessRepo }
 func (r *Repository) InviteCodes() repository.InviteCodeRepository         { return r.inviteRepo }
 func (r *Repository) Teams() repository.TeamRepository                     { return r.teamRepo }
 func (r *Repository) TeamMemberships() repository.TeamMembershipRepository { return r.membershipRepo }
+func (r *Repository) AuditEvents() repository.AuditEventRepository         { return r.auditRepo }
 
 // EnsureSchema creates the MySQL schema used by the auth service.
 func (r *Repository) EnsureSchema(ctx context.Context) error {
@@ -220,9 +227,113 @@ func (r *Repository) EnsureSchema(ctx context.Context) error {
 	if _, err := r.db.ExecContext(ctx, "ALTER TABLE auth_users MODIFY COLUMN custom_attributes TEXT NOT NULL"); err != nil {
 		return err
 	}
+	if err := r.ensureColumn(ctx, "auth_users", "password_change_required", "BOOLEAN NOT NULL DEFAULT FALSE AFTER membership_expires_at"); err != nil {
+		return err
+	}
+	if err := r.ensureColumn(ctx, "auth_users", "failed_login_attempts", "INT NOT NULL DEFAULT 0 AFTER password_change_required"); err != nil {
+		return err
+	}
+	if err := r.ensureColumn(ctx, "auth_users", "locked_until", "DATETIME(6) NULL AFTER failed_login_attempts"); err != nil {
+		return err
+	}
+	if err := r.ensureColumn(ctx, "auth_users", "pending_email", "VARCHAR(320) NULL AFTER locked_until"); err != nil {
+		return err
+	}
+	if err := r.ensureColumn(ctx, "auth_users", "email_change_token_hash", "VARCHAR(128) NULL AFTER pending_email"); err != nil {
+		return err
+	}
+	if err := r.ensureColumn(ctx, "auth_users", "email_change_expires_at", "DATETIME(6) NULL AFTER email_change_token_hash"); err != nil {
+		return err
+	}
+	if err := r.ensureColumn(ctx, "auth_users", "username", "VARCHAR(255) NULL AFTER email_change_expires_at"); err != nil {
+		return err
+	}
+	if err := r.ensureColumn(ctx, "auth_users", "username_lookup", "VARCHAR(255) NULL UNIQUE AFTER username"); err != nil {
+		return err
+	}
+	if err := r.ensureColumn(ctx, "auth_accounts", "provider_tokens", "TEXT NULL AFTER updated_at"); err != nil {
+		return err
+	}
+	if err := r.ensureColumn(ctx, "auth_applications", "custom_claims", "TEXT NULL AFTER allowed_scopes"); err != nil {
+		return err
+	}
+	if err := r.ensureColumn(ctx, "auth_applications", "allow_self_registration", "BOOLEAN NOT NULL DEFAULT TRUE AFTER is_active"); err != nil {
+		return err
+	}
+	if err := r.ensureColumn(ctx, "auth_applications", "allowed_email_domains", "TEXT NULL AFTER allow_self_registration"); err != nil {
+		return err
+	}
+	if err := r.ensureColumn(ctx, "auth_applications", "default_scopes", "TEXT NULL AFTER allowed_email_domains"); err != nil {
+		return err
+	}
+	if err := r.ensureColumn(ctx, "auth_applications", "include_email_claim", "BOOLEAN NOT NULL DEFAULT FALSE AFTER default_scopes"); err != nil {
+		return err
+	}
+	if err := r.ensureColumn(ctx, "auth_applications", "client_public_key", "TEXT NULL AFTER include_email_claim"); err != nil {
+		return err
+	}
+	for _, col := range []struct{ name, after string }{
+		{"logo_url", "client_public_key"}, {"homepage_url", "logo_url"},
+		{"privacy_policy_url", "homepage_url"}, {"terms_url", "privacy_policy_url"},
+	} {
+		if err := r.ensureColumn(ctx, "auth_applications", col.name, "VARCHAR(2048) NULL AFTER "+col.after); err != nil {
+			return err
+		}
+	}
+	if err := r.ensureColumn(ctx, "auth_applications", "rotate_refresh_tokens", "BOOLEAN NOT NULL DEFAULT TRUE AFTER terms_url"); err != nil {
+		return err
+	}
+	if err := r.ensureColumn(ctx, "auth_applications", "login_identifier", "VARCHAR(16) NOT NULL DEFAULT 'email' AFTER rotate_refresh_tokens"); err != nil {
+		return err
+	}
+	if err := r.ensureColumn(ctx, "auth_applications", "access_token_cookie", "BOOLEAN NOT NULL DEFAULT FALSE AFTER login_identifier"); err != nil {
+		return err
+	}
+	if err := r.ensureColumn(ctx, "auth_applications", "issue_refresh_tokens", "BOOLEAN NOT NULL DEFAULT TRUE AFTER access_token_cookie"); err != nil {
+		return err
+	}
+	if err := r.ensureColumn(ctx, "auth_applications", "created_by", "VARCHAR(64) NULL AFTER issue_refresh_tokens"); err != nil {
+		return err
+	}
+	if err := r.ensureColumn(ctx, "auth_applications", "updated_by", "VARCHAR(64) NULL AFTER created_by"); err != nil {
+		return err
+	}
+	if err := r.ensureColumn(ctx, "auth_applications", "previous_client_secret_hash", "TEXT NULL AFTER updated_by"); err != nil {
+		return err
+	}
+	if err := r.ensureColumn(ctx, "auth_applications", "previous_client_secret_expires_at", "DATETIME(6) NULL AFTER previous_client_secret_hash"); err != nil {
+		return err
+	}
+	if err := r.ensureColumn(ctx, "auth_applications", "audiences", "TEXT NULL AFTER previous_client_secret_expires_at"); err != nil {
+		return err
+	}
+	if err := r.ensureColumn(ctx, "auth_applications", "require_email_from_provider", "BOOLEAN NOT NULL DEFAULT FALSE AFTER audiences"); err != nil {
+		return err
+	}
+	if err := r.ensureColumn(ctx, "auth_applications", "issuer", "VARCHAR(2048) NULL AFTER require_email_from_provider"); err != nil {
+		return err
+	}
+	if err := r.ensureColumn(ctx, "auth_app_providers", "display_name", "VARCHAR(255) NULL AFTER created_at"); err != nil {
+		return err
+	}
+	if err := r.ensureColumn(ctx, "auth_app_providers", "icon_url", "VARCHAR(2048) NULL AFTER display_name"); err != nil {
+		return err
+	}
+	if err := r.ensureColumn(ctx, "auth_app_providers", "sort_order", "INT NOT NULL DEFAULT 0 AFTER icon_url"); err != nil {
+		return err
+	}
+	if err := r.ensureColumn(ctx, "auth_app_providers", "created_by", "VARCHAR(64) NULL AFTER sort_order"); err != nil {
+		return err
+	}
+	if err := r.ensureColumn(ctx, "auth_app_providers", "email_domains", "TEXT NULL AFTER created_by"); err != nil {
+		return err
+	}
 	if err := r.ensureColumn(ctx, "auth_invite_codes", "grants_user_type", "VARCHAR(32) NULL AFTER grants_membership_days"); err != nil {
 		return err
 	}
+	if err := r.ensureColumn(ctx, "auth_refresh_tokens", "expiry_days", "BIGINT NOT NULL DEFAULT 0 AFTER expires_at"); err != nil {
+		return err
+	}
 	return nil
 }
 
@@ -236,9 +347,14 @@ func (r *Repository) ensureColumn(ctx context.Context, table, column, definition
 	return err
 }
 
-// ClearAllTables removes all data. It is intended for integration tests only.
+// ClearAllTables removes all data, audit events included. It is intended for
+// integration tests only.
 func (r *Repository) ClearAllTables(ctx context.Context) error {
-	return clearTables(ctx, r.db)
+	if err := clearTables(ctx, r.db); err != nil {
+		return err
+	}
+	_, err := r.db.ExecContext(ctx, "DELETE FROM auth_audit_events")
+	return err
 }
 
 func clearTables(ctx context.Context, db dbConn) error {
@@ -282,7 +398,28 @@ var schemaStatements = []string{
 		client_secret_hash TEXT NOT NULL,
 		redirect_uris TEXT NOT NULL,
 		allowed_scopes TEXT NOT NULL,
+		custom_claims TEXT NULL,
 		is_active BOOLEAN NOT NULL,
+		allow_self_registration BOOLEAN NOT NULL DEFAULT TRUE,
+		allowed_email_domains TEXT NULL,
+		default_scopes TEXT NULL,
+		include_email_claim BOOLEAN NOT NULL DEFAULT FALSE,
+		client_public_key TEXT NULL,
+		logo_url VARCHAR(2048) NULL,
+		homepage_url VARCHAR(2048) NULL,
+		privacy_policy_url VARCHAR(2048) NULL,
+		terms_url VARCHAR(2048) NULL,
+		rotate_refresh_tokens BOOLEAN NOT NULL DEFAULT TRUE,
+		login_identifier VARCHAR(16) NOT NULL DEFAULT 'email',
+		access_token_cookie BOOLEAN NOT NULL DEFAULT FALSE,
+		issue_refresh_tokens BOOLEAN NOT NULL DEFAULT TRUE,
+		created_by VARCHAR(64) NULL,
+		updated_by VARCHAR(64) NULL,
+		previous_client_secret_hash TEXT NULL,
+		previous_client_secret_expires_at DATETIME(6) NULL,
+		audiences TEXT NULL,
+		require_email_from_provider BOOLEAN NOT NULL DEFAULT FALSE,
+		issuer VARCHAR(2048) NULL,
 		created_at DATETIME(6) NOT NULL,
 		updated_at DATETIME(6) NOT NULL,
 		UNIQUE KEY uq_auth_applications_client_id (client_id),
@@ -308,7 +445,16 @@ var schemaStatements = []string{
 		invite_code VARCHAR(64) NULL,
 		membership VARCHAR(32) NOT NULL DEFAULT 'regular',
 		membership_expires_at DATETIME(6) NULL,
+		password_change_required BOOLEAN NOT NULL DEFAULT FALSE,
+		failed_login_attempts INT NOT NULL DEFAULT 0,
+		locked_until DATETIME(6) NULL,
+		pending_email VARCHAR(320) NULL,
+		email_change_token_hash VARCHAR(128) NULL,
+		email_change_expires_at DATETIME(6) NULL,
+		username VARCHAR(255) NULL,
+		username_lookup VARCHAR(255) NULL,
 		UNIQUE KEY uq_auth_users_email_lookup (email_lookup),
+		UNIQUE KEY uq_auth_users_username_lookup (username_lookup),
 		KEY idx_auth_users_created_at (created_at)
 	) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4 COLLATE=utf8mb4_unicode_ci`,
 	`CREATE TABLE IF NOT EXISTS auth_accounts (
@@ -320,6 +466,7 @@ var schemaStatements = []string{
 		provider_metadata TEXT NOT NULL,
 		created_at DATETIME(6) NOT NULL,
 		updated_at DATETIME(6) NOT NULL,
+		provider_tokens TEXT NULL,
 		UNIQUE KEY uq_auth_accounts_user_provider (user_id, provider_id),
 		UNIQUE KEY uq_auth_accounts_provider_account (provider_id, provider_account_id),
 		KEY idx_auth_accounts_user_id (user_id)
@@ -331,6 +478,11 @@ var schemaStatements = []string{
 		config TEXT NOT NULL,
 		is_active BOOLEAN NOT NULL,
 		created_at DATETIME(6) NOT NULL,
+		display_name VARCHAR(255) NULL,
+		icon_url VARCHAR(2048) NULL,
+		sort_order INT NOT NULL DEFAULT 0,
+		created_by VARCHAR(64) NULL,
+		email_domains TEXT NULL,
 		UNIQUE KEY uq_auth_app_providers_app_provider (app_id, provider_id),
 		KEY idx_auth_app_providers_app_id (app_id)
 	) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4 COLLATE=utf8mb4_unicode_ci`,
@@ -356,12 +508,22 @@ var schemaStatements = []string{
 		scopes TEXT NOT NULL,
 		device_id VARCHAR(255) NULL,
 		expires_at DATETIME(6) NOT NULL,
+		expiry_days BIGINT NOT NULL DEFAULT 0,
 		revoked BOOLEAN NOT NULL DEFAULT FALSE,
 		created_at DATETIME(6) NOT NULL,
 		UNIQUE KEY uq_auth_refresh_tokens_hash (token_hash),
 		KEY idx_auth_refresh_tokens_user_id (user_id),
 		KEY idx_auth_refresh_tokens_expires_at (expires_at)
 	) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4 COLLATE=utf8mb4_unicode_ci`,
+	`CREATE TABLE IF NOT EXISTS auth_access_tokens (
+		token_hash VARCHAR(128) NOT NULL PRIMARY KEY,
+		subject VARCHAR(64) NOT NULL,
+		claims TEXT NOT NULL,
+		expires_at DATETIME(6) NOT NULL,
+		created_at DATETIME(6) NOT NULL,
+		KEY idx_auth_access_tokens_subject (subject),
+		KEY idx_auth_access_tokens_expires_at (expires_at)
+	) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4 COLLATE=utf8mb4_unicode_ci`,
 	`CREATE TABLE IF NOT EXISTS auth_invite_codes (
 		id VARCHAR(64) NOT NULL PRIMARY KEY,
 		code VARCHAR(64) NOT NULL,
@@ -397,6 +559,24 @@ var schemaStatements = []string{
 		PRIMARY KEY (team_id, user_id),
 		KEY idx_auth_team_memberships_user_id (user_id)
 	) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4 COLLATE=utf8mb4_unicode_ci`,
+	`CREATE TABLE IF NOT EXISTS auth_audit_events (
+		id VARCHAR(64) NOT NULL PRIMARY KEY,
+		type VARCHAR(64) NOT NULL,
+		user_id VARCHAR(64) NOT NULL DEFAULT '',
+		target_id VARCHAR(64) NOT NULL DEFAULT '',
+		app_id VARCHAR(64) NOT NULL DEFAULT '',
+		ip VARCHAR(64) NOT NULL DEFAULT '',
+		details TEXT NOT NULL,
+		created_at DATETIME(6) NOT NULL,
+		KEY idx_auth_audit_events_user_id (user_id, created_at),
+		KEY idx_auth_audit_events_target_id (target_id, created_at)
+	) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4 COLLATE=utf8mb4_unicode_ci`,
+	`CREATE TABLE IF NOT EXISTS auth_app_logins (
+		app_id VARCHAR(64) NOT NULL,
+		day DATE NOT NULL,
+		logins BIGINT UNSIGNED NOT NULL DEFAULT 0,
+		PRIMARY KEY (app_id, day)
+	) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4 COLLATE=utf8mb4_unicode_ci`,
 }
 
 type rowScanner interface{ Scan(dest ...any) error }
@@ -408,6 +588,26 @@ func dbErr(err error) error {
 	return apperror.Database(err.Error())
 }
 
+// inTx runs fn inside a transaction on db, or straight on db when db is
+// already a transaction. The transaction rolls back unless fn succeeds.
+func inTx(ctx context.Context, db dbConn, fn func(dbConn) error) error {
+	beginner, ok := db.(interface {
+		BeginTx(ctx context.Context, opts *sql.TxOptions) (*sql.Tx, error)
+	})
+	if !ok {
+		return fn(db)
+	}
+	tx, err := beginner.BeginTx(ctx, nil)
+	if err != nil {
+		return dbErr(err)
+	}
+	defer func() { _ = tx.Rollback() }()
+	if err := fn(tx); err != nil {
+		return err
+	}
+	return dbErr(tx.Commit())
+}
+
 func nullString(p *string) sql.NullString {
 	if p == nil {
 		return sql.NullString{}
@@ -445,11 +645,13 @@ func ptrTime(nt sql.NullTime) *time.Time {
 	return &v
 }
 
-func emailLookup(email *string) sql.NullString {
-	if email == nil {
+// lookupKey lowercases a case-insensitive unique value (email, username) for
+// its *_lookup column.
+func lookupKey(v *string) sql.NullString {
+	if v == nil {
 		return sql.NullString{}
 	}
-	return sql.NullString{String: strings.ToLower(*email), Valid: true}
+	return sql.NullString{String: strings.ToLower(*v), Valid: true}
 }
 
 func defaultJSONObj(s string) string {
@@ -610,15 +812,15 @@ func isDuplicate(err error) bool {
 	return errors.As(err, &me) && me.Number == 1062
 }
 
-const userColumns = `id, email, name, avatar_url, email_verified, role, user_type, is_active, note, custom_attributes, created_at, updated_at, last_login_at, recent_logins, invite_code, membership, membership_expires_at`
+const userColumns = `id, email, name, avatar_url, email_verified, role, user_type, is_active, note, custom_attributes, created_at, updated_at, last_login_at, recent_logins, invite_code, membership, membership_expires_at, password_change_required, failed_login_attempts, locked_until, pending_email, email_change_token_hash, email_change_expires_at, username`
 
 type userRepo struct{ db dbConn }
 
 func scanUser(s rowScanner) (*domain.User, error) {
 	var u domain.User
-	var email, name, avatar, note, customAttrs, recent, invite, membership, userType sql.NullString
-	var lastLogin, membershipExpires sql.NullTime
-	if err := s.Scan(&u.ID, &email, &name, &avatar, &u.EmailVerified, &u.Role, &userType, &u.IsActive, &note, &customAttrs, &u.CreatedAt, &u.UpdatedAt, &lastLogin, &recent, &invite, &membership, &membershipExpires); err != nil {
+	var email, name, avatar, note, customAttrs, recent, invite, membership, userType, pendingEmail, emailChangeToken, username sql.NullString
+	var lastLogin, membershipExpires, lockedUntil, emailChangeExpires sql.NullTime
+	if err := s.Scan(&u.ID, &email, &name, &avatar, &u.EmailVerified, &u.Role, &userType, &u.IsActive, &note, &customAttrs, &u.CreatedAt, &u.UpdatedAt, &lastLogin, &recent, &invite, &membership, &membershipExpires, &u.PasswordChangeRequired, &u.FailedLoginAttempts, &lockedUntil, &pendingEmail, &emailChangeToken, &emailChangeExpires, &username); err != nil {
 		return nil, err
 	}
 	if u.Role == "" {
@@ -639,6 +841,11 @@ func scanUser(s rowScanner) (*domain.User, error) {
 	u.InviteCode = ptrString(invite)
 	u.Membership = domain.MembershipFromString(mem)
 	u.MembershipExpiresAt = ptrTime(membershipExpires)
+	u.LockedUntil = ptrTime(lockedUntil)
+	u.PendingEmail = ptrString(pendingEmail)
+	u.EmailChangeTokenHash = ptrString(emailChangeToken)
+	u.EmailChangeExpiresAt = ptrTime(emailChangeExpires)
+	u.Username = ptrString(username)
 	u.CreatedAt = u.CreatedAt.UTC()
 	u.UpdatedAt = u.UpdatedAt.UTC()
 	return &u, nil
@@ -666,6 +873,17 @@ func (r *userRepo) FindByEmail(ctx context.Context, email string) (*domain.User,
 	return u, nil
 }
 
+func (r *userRepo) FindByUsername(ctx context.Context, username string) (*domain.User, error) {
+	u, err := scanUser(r.db.QueryRowContext(ctx, "SELECT "+userColumns+" FROM auth_users WHERE username_lookup = ?", strings.ToLower(username)))
+	if errors.Is(err, sql.ErrNoRows) {
+		return nil, nil
+	}
+	if err != nil {
+		return nil, dbErr(err)
+	}
+	return u, nil
+}
+
 func (r *userRepo) Insert(ctx context.Context, u *domain.User) error {
 	role := u.Role
 	if role == "" {
@@ -677,18 +895,27 @@ func (r *userRepo) Insert(ctx context.Context, u *domain.User) error {
 	}
 	userType := string(defaultUserType(u.UserType))
 	_, err := r.db.ExecContext(ctx, `INSERT INTO auth_users
-		(id, email, email_lookup, name, avatar_url, email_verified, role, user_type, is_active, note, custom_attributes, created_at, updated_at, last_login_at, recent_logins, invite_code, membership, membership_expires_at)
-		VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)`,
-		u.ID, nullString(u.Email), emailLookup(u.Email), nullString(u.Name), nullString(u.AvatarURL), u.EmailVerified, role, userType, u.IsActive, nullString(u.Note), serializeCustomAttributes(u.CustomAttributes), u.CreatedAt.UTC(), u.UpdatedAt.UTC(), nullTime(u.LastLoginAt), serializeLogins(u.RecentLogins), nullString(u.InviteCode), membership, nullTime(u.MembershipExpiresAt))
+		(id, email, email_lookup, name, avatar_url, email_verified, role, user_type, is_active, note, custom_attributes, created_at, updated_at, last_login_at, recent_logins, invite_code, membership, membership_expires_at, password_change_required, failed_login_attempts, locked_until, pending_email, email_change_token_hash, email_change_expires_at, username, username_lookup)
+		VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)`,
+		u.ID, nullString(u.Email), lookupKey(u.Email), nullString(u.Name), nullString(u.AvatarURL), u.EmailVerified, role, userType, u.IsActive, nullString(u.Note), serializeCustomAttributes(u.CustomAttributes), u.CreatedAt.UTC(), u.UpdatedAt.UTC(), nullTime(u.LastLoginAt), serializeLogins(u.RecentLogins), nullString(u.InviteCode), membership, nullTime(u.MembershipExpiresAt), u.PasswordChangeRequired, u.FailedLoginAttempts, nullTime(u.LockedUntil), nullString(u.PendingEmail), nullString(u.EmailChangeTokenHash), nullTime(u.EmailChangeExpiresAt), nullString(u.Username), lookupKey(u.Username))
 	if err != nil {
 		if isDuplicate(err) {
-			return apperror.Database("user already exists")
+			return apperror.UserAlreadyExists()
 		}
 		return dbErr(err)
 	}
 	return nil
 }
 
+func (r *userRepo) InsertWithAccount(ctx context.Context, u *domain.User, a *domain.Account) error {
+	return inTx(ctx, r.db, func(db dbConn) error {
+		if err := (&userRepo{db: db}).Insert(ctx, u); err != nil {
+			return err
+		}
+		return (&accountRepo{db: db}).Insert(ctx, a)
+	})
+}
+
 func (r *userRepo) Update(ctx context.Context, u *domain.User) error {
 	role := u.Role
 	if role == "" {
@@ -700,10 +927,13 @@ func (r *userRepo) Update(ctx context.Context, u *domain.User) error {
 	}
 	userType := string(defaultUserType(u.UserType))
 	_, err := r.db.ExecContext(ctx, `UPDATE auth_users SET
-		email = ?, email_lookup = ?, name = ?, avatar_url = ?, email_verified = ?, role = ?, user_type = ?, is_active = ?, note = ?, custom_attributes = ?, updated_at = ?, last_login_at = ?, recent_logins = ?, invite_code = ?, membership = ?, membership_expires_at = ?
+		email = ?, email_lookup = ?, name = ?, avatar_url = ?, email_verified = ?, role = ?, user_type = ?, is_active = ?, note = ?, custom_attributes = ?, updated_at = ?, last_login_at = ?, recent_logins = ?, invite_code = ?, membership = ?, membership_expires_at = ?, password_change_required = ?, failed_login_attempts = ?, locked_until = ?, pending_email = ?, email_change_token_hash = ?, email_change_expires_at = ?, username = ?, username_lookup = ?
 		WHERE id = ?`,
-		nullString(u.Email), emailLookup(u.Email), nullString(u.Name), nullString(u.AvatarURL), u.EmailVerified, role, userType, u.IsActive, nullString(u.Note), serializeCustomAttributes(u.CustomAttributes), u.UpdatedAt.UTC(), nullTime(u.LastLoginAt), serializeLogins(u.RecentLogins), nullString(u.InviteCode), membership, nullTime(u.MembershipExpiresAt), u.ID)
+		nullString(u.Email), lookupKey(u.Email), nullString(u.Name), nullString(u.AvatarURL), u.EmailVerified, role, userType, u.IsActive, nullString(u.Note), serializeCustomAttributes(u.CustomAttributes), u.UpdatedAt.UTC(), nullTime(u.LastLoginAt), serializeLogins(u.RecentLogins), nullString(u.InviteCode), membership, nullTime(u.MembershipExpiresAt), u.PasswordChangeRequired, u.FailedLoginAttempts, nullTime(u.LockedUntil), nullString(u.PendingEmail), nullString(u.EmailChangeTokenHash), nullTime(u.EmailChangeExpiresAt), nullString(u.Username), lookupKey(u.Username), u.ID)
 	if err != nil {
+		if isDuplicate(err) {
+			return apperror.UserAlreadyExists()
+		}
 		return dbErr(err)
 	}
 	return nil
@@ -730,6 +960,24 @@ func (r *userRepo) CountSince(ctx context.Context, since time.Time) (uint64, err
 	return n, nil
 }
 
+func (r *userRepo) CountCreatedByDay(ctx context.Context, since time.Time) (map[string]uint64, error) {
+	rows, err := r.db.QueryContext(ctx, "SELECT DATE_FORMAT(created_at, '%Y-%m-%d') AS day, COUNT(*) FROM auth_users WHERE created_at >= ? GROUP BY day", since.UTC())
+	if err != nil {
+		return nil, dbErr(err)
+	}
+	defer rows.Close()
+	out := map[string]uint64{}
+	for rows.Next() {
+		var day string
+		var n uint64
+		if err := rows.Scan(&day, &n); err != nil {
+			return nil, dbErr(err)
+		}
+		out[day] = n
+	}
+	return out, dbErr(rows.Err())
+}
+
 func (r *userRepo) ListPaginated(ctx context.Context, search, idSearch string, userType *domain.UserType, sortSpec repository.UserListSort, offset, limit uint64) ([]domain.User, uint64, error) {
 	if limit < 1 {
 		limit = 20
@@ -801,15 +1049,51 @@ func (r *userRepo) RecordLogin(ctx context.Context, userID, ip string) error {
 	return r.Update(ctx, u)
 }
 
-const appColumns = `id, name, client_id, client_secret_hash, redirect_uris, allowed_scopes, is_active, created_at, updated_at`
+func (r *userRepo) RecordFailedLogin(ctx context.Context, userID string, max int64, lockUntil time.Time) (int, *time.Time, error) {
+	var attempts int
+	var locked sql.NullTime
+	// Single-table UPDATE assigns left to right, so locked_until still sees
+	// the count from before this failure.
+	err := inTx(ctx, r.db, func(db dbConn) error {
+		if _, err := db.ExecContext(ctx, `UPDATE auth_users SET
+			locked_until = IF(failed_login_attempts + 1 >= ?, ?, locked_until),
+			failed_login_attempts = IF(failed_login_attempts + 1 >= ?, 0, failed_login_attempts + 1),
+			updated_at = ?
+			WHERE id = ?`, max, lockUntil.UTC(), max, time.Now().UTC(), userID); err != nil {
+			return dbErr(err)
+		}
+		return dbErr(db.QueryRowContext(ctx, "SELECT failed_login_attempts, locked_until FROM auth_users WHERE id = ?", userID).Scan(&attempts, &locked))
+	})
+	if err != nil {
+		return 0, nil, err
+	}
+	return attempts, ptrTime(locked), nil
+}
+
+func (r *userRepo) ResetFailedLogins(ctx context.Context, userID string) error {
+	_, err := r.db.ExecContext(ctx, "UPDATE auth_users SET failed_login_attempts = 0, locked_until = NULL, updated_at = ? WHERE id = ?", time.Now().UTC(), userID)
+	return dbErr(err)
+}
+
+const appColumns = `id, name, client_id, client_secret_hash, redirect_uris, allowed_scopes, custom_claims, is_active, allow_self_registration, allowed_email_domains, default_scopes, include_email_claim, client_public_key, logo_url, homepage_url, privacy_policy_url, terms_url, rotate_refresh_tokens, login_identifier, access_token_cookie, issue_refresh_tokens, created_by, updated_by, previous_client_secret_hash, previous_client_secret_expires_at, audiences, require_email_from_provider, issuer, created_at, updated_at`
 
 type appRepo struct{ db dbConn }
 
 func scanApp(s rowScanner) (*domain.Application, error) {
 	var a domain.Application
-	if err := s.Scan(&a.ID, &a.Name, &a.ClientID, &a.ClientSecretHash, &a.RedirectURIs, &a.AllowedScopes, &a.IsActive, &a.CreatedAt, &a.UpdatedAt); err != nil {
+	var customClaims, emailDomains, defaultScopes, publicKey, loginIdentifier, previousSecret, audiences sql.NullString
+	var previousSecretExpires sql.NullTime
+	if err := s.Scan(&a.ID, &a.Name, &a.ClientID, &a.ClientSecretHash, &a.RedirectURIs, &a.AllowedScopes, &customClaims, &a.IsActive, &a.AllowSelfRegistration, &emailDomains, &defaultScopes, &a.IncludeEmailClaim, &publicKey, &a.LogoURL, &a.HomepageURL, &a.PrivacyPolicyURL, &a.TermsURL, &a.RotateRefreshTokens, &loginIdentifier, &a.AccessTokenCookie, &a.IssueRefreshTokens, &a.CreatedBy, &a.UpdatedBy, &previousSecret, &previousSecretExpires, &audiences, &a.RequireProviderEmail, &a.Issuer, &a.CreatedAt, &a.UpdatedAt); err != nil {
 		return nil, err
 	}
+	a.CustomClaims = customClaims.String
+	a.AllowedEmailDomains = defaultJSONArr(emailDomains.String)
+	a.DefaultScopes = defaultJSONArr(defaultScopes.String)
+	a.ClientPublicKey = publicKey.String
+	a.LoginIdentifier = domain.LoginIdentifierFromString(loginIdentifier.String)
+	a.PreviousClientSecretHash = previousSecret.String
+	a.PreviousClientSecretExpiresAt = ptrTime(previousSecretExpires)
+	a.Audiences = defaultJSONArr(audiences.String)
 	a.CreatedAt = a.CreatedAt.UTC()
 	a.UpdatedAt = a.UpdatedAt.UTC()
 	a.RedirectURIs = defaultJSONArr(a.RedirectURIs)
@@ -868,15 +1152,30 @@ func (r *appRepo) FindAll(ctx context.Context) ([]domain.Application, error) {
 }
 
 func (r *appRepo) Insert(ctx context.Context, a *domain.Application) error {
-	_, err := r.db.ExecContext(ctx, `INSERT INTO auth_applications (id, name, client_id, client_secret_hash, redirect_uris, allowed_scopes, is_active, created_at, updated_at) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)`, a.ID, a.Name, a.ClientID, a.ClientSecretHash, defaultJSONArr(a.RedirectURIs), defaultJSONArr(a.AllowedScopes), a.IsActive, a.CreatedAt.UTC(), a.UpdatedAt.UTC())
+	_, err := r.db.ExecContext(ctx, `INSERT INTO auth_applications (id, name, client_id, client_secret_hash, redirect_uris, allowed_scopes, custom_claims, is_active, allow_self_registration, allowed_email_domains, default_scopes, include_email_claim, client_public_key, logo_url, homepage_url, privacy_policy_url, terms_url, rotate_refresh_tokens, login_identifier, access_token_cookie, issue_refresh_tokens, created_by, updated_by, previous_client_secret_hash, previous_client_secret_expires_at, audiences, require_email_from_provider, issuer, created_at, updated_at) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)`, a.ID, a.Name, a.ClientID, a.ClientSecretHash, defaultJSONArr(a.RedirectURIs), defaultJSONArr(a.AllowedScopes), a.CustomClaims, a.IsActive, a.AllowSelfRegistration, defaultJSONArr(a.AllowedEmailDomains), defaultJSONArr(a.DefaultScopes), a.IncludeEmailClaim, a.ClientPublicKey, a.LogoURL, a.HomepageURL, a.PrivacyPolicyURL, a.TermsURL, a.RotateRefreshTokens, string(domain.LoginIdentifierFromString(string(a.LoginIdentifier))), a.AccessTokenCookie, a.IssueRefreshTokens, a.CreatedBy, a.UpdatedBy, a.PreviousClientSecretHash, nullTime(a.PreviousClientSecretExpiresAt), defaultJSONArr(a.Audiences), a.RequireProviderEmail, a.Issuer, a.CreatedAt.UTC(), a.UpdatedAt.UTC())
 	if err != nil {
 		return dbErr(err)
 	}
 	return nil
 }
 
+func (r *appRepo) InsertWithProviders(ctx context.Context, a *domain.Application, providers []domain.AppProvider) error {
+	return inTx(ctx, r.db, func(db dbConn) error {
+		if err := (&appRepo{db: db}).Insert(ctx, a); err != nil {
+			return err
+		}
+		appProviders := &appProviderRepo{db: db}
+		for i := range providers {
+			if err := appProviders.Insert(ctx, &providers[i]); err != nil {
+				return err
+			}
+		}
+		return nil
+	})
+}
+
 func (r *appRepo) Update(ctx context.Context, a *domain.Application) error {
-	_, err := r.db.ExecContext(ctx, `UPDATE auth_applications SET name = ?, client_id = ?, client_secret_hash = ?, redirect_uris = ?, allowed_scopes = ?, is_active = ?, updated_at = ? WHERE id = ?`, a.Name, a.ClientID, a.ClientSecretHash, defaultJSONArr(a.RedirectURIs), defaultJSONArr(a.AllowedScopes), a.IsActive, a.UpdatedAt.UTC(), a.ID)
+	_, err := r.db.ExecContext(ctx, `UPDATE auth_applications SET name = ?, client_id = ?, client_secret_hash = ?, redirect_uris = ?, allowed_scopes = ?, custom_claims = ?, is_active = ?, allow_self_registration = ?, allowed_email_domains = ?, default_scopes = ?, include_email_claim = ?, client_public_key = ?, logo_url = ?, homepage_url = ?, privacy_policy_url = ?, terms_url = ?, rotate_refresh_tokens = ?, login_identifier = ?, access_token_cookie = ?, issue_refresh_tokens = ?, updated_by = ?, previous_client_secret_hash = ?, previous_client_secret_expires_at = ?, audiences = ?, require_email_from_provider = ?, issuer = ?, updated_at = ? WHERE id = ?`, a.Name, a.ClientID, a.ClientSecretHash, defaultJSONArr(a.RedirectURIs), defaultJSONArr(a.AllowedScopes), a.CustomClaims, a.IsActive, a.AllowSelfRegistration, defaultJSONArr(a.AllowedEmailDomains), defaultJSONArr(a.DefaultScopes), a.IncludeEmailClaim, a.ClientPublicKey, a.LogoURL, a.HomepageURL, a.PrivacyPolicyURL, a.TermsURL, a.RotateRefreshTokens, string(domain.LoginIdentifierFromString(string(a.LoginIdentifier))), a.AccessTokenCookie, a.IssueRefreshTokens, a.UpdatedBy, a.PreviousClientSecretHash, nullTime(a.PreviousClientSecretExpiresAt), defaultJSONArr(a.Audiences), a.RequireProviderEmail, a.Issuer, a.UpdatedAt.UTC(), a.ID)
 	return dbErr(err)
 }
 
@@ -890,19 +1189,41 @@ func (r *appRepo) CountActive(ctx context.Context) (uint64, error) {
 	err := r.db.QueryRowContext(ctx, "SELECT COUNT(*) FROM auth_applications WHERE is_active = TRUE").Scan(&n)
 	return n, dbErr(err)
 }
+func (r *appRepo) RecordLogin(ctx context.Context, appID string, at time.Time) error {
+	_, err := r.db.ExecContext(ctx, "INSERT INTO auth_app_logins (app_id, day, logins) VALUES (?, ?, 1) ON DUPLICATE KEY UPDATE logins = logins + 1", appID, at.UTC().Format("2006-01-02"))
+	return dbErr(err)
+}
+func (r *appRepo) CountLoginsByDay(ctx context.Context, appID string, since time.Time) (map[string]uint64, error) {
+	rows, err := r.db.QueryContext(ctx, "SELECT DATE_FORMAT(day, '%Y-%m-%d'), logins FROM auth_app_logins WHERE app_id = ? AND day >= ?", appID, since.UTC().Format("2006-01-02"))
+	if err != nil {
+		return nil, dbErr(err)
+	}
+	defer rows.Close()
+	out := map[string]uint64{}
+	for rows.Next() {
+		var day string
+		var n uint64
+		if err := rows.Scan(&day, &n); err != nil {
+			return nil, dbErr(err)
+		}
+		out[day] = n
+	}
+	return out, dbErr(rows.Err())
+}
 
-const accountColumns = `id, user_id, provider_id, provider_account_id, credential, provider_metadata, created_at, updated_at`
+const accountColumns = `id, user_id, provider_id, provider_account_id, credential, provider_metadata, created_at, updated_at, provider_tokens`
 
 type accountRepo struct{ db dbConn }
 
 func scanAccount(s rowScanner) (*domain.Account, error) {
 	var a domain.Account
-	var providerAccountID, credential sql.NullString
-	if err := s.Scan(&a.ID, &a.UserID, &a.ProviderID, &providerAccountID, &credential, &a.ProviderMetadata, &a.CreatedAt, &a.UpdatedAt); err != nil {
+	var providerAccountID, credential, providerTokens sql.NullString
+	if err := s.Scan(&a.ID, &a.UserID, &a.ProviderID, &providerAccountID, &credential, &a.ProviderMetadata, &a.CreatedAt, &a.UpdatedAt, &providerTokens); err != nil {
 		return nil, err
 	}
 	a.ProviderAccountID = ptrString(providerAccountID)
 	a.Credential = ptrString(credential)
+	a.ProviderTokens = ptrString(providerTokens)
 	a.ProviderMetadata = defaultJSONObj(a.ProviderMetadata)
 	a.CreatedAt = a.CreatedAt.UTC()
 	a.UpdatedAt = a.UpdatedAt.UTC()
@@ -930,7 +1251,7 @@ func (r *accountRepo) FindByProviderAccount(ctx context.Context, providerID, pro
 	return a, nil
 }
 func (r *accountRepo) FindAllByUser(ctx context.Context, userID string) ([]domain.Account, error) {
-	rows, err := r.db.QueryContext(ctx, "SELECT "+accountColumns+" FROM auth_accounts WHERE user_id = ? ORDER BY created_at ASC", userID)
+	rows, err := r.db.QueryContext(ctx, "SELECT "+accountColumns+" FROM auth_accounts WHERE user_id = ? ORDER BY created_at ASC, id ASC", userID)
 	if err != nil {
 		return nil, dbErr(err)
 	}
@@ -951,14 +1272,21 @@ func (r *accountRepo) CountByUser(ctx context.Context, userID string) (uint64, e
 	return n, dbErr(err)
 }
 func (r *accountRepo) Insert(ctx context.Context, a *domain.Account) error {
-	_, err := r.db.ExecContext(ctx, `INSERT INTO auth_accounts (id, user_id, provider_id, provider_account_id, credential, provider_metadata, created_at, updated_at) VALUES (?, ?, ?, ?, ?, ?, ?, ?)`, a.ID, a.UserID, a.ProviderID, nullString(a.ProviderAccountID), nullString(a.Credential), defaultJSONObj(a.ProviderMetadata), a.CreatedAt.UTC(), a.UpdatedAt.UTC())
+	_, err := r.db.ExecContext(ctx, `INSERT INTO auth_accounts (id, user_id, provider_id, provider_account_id, credential, provider_metadata, created_at, updated_at, provider_tokens) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)`, a.ID, a.UserID, a.ProviderID, nullString(a.ProviderAccountID), nullString(a.Credential), defaultJSONObj(a.ProviderMetadata), a.CreatedAt.UTC(), a.UpdatedAt.UTC(), nullString(a.ProviderTokens))
 	if err != nil {
+		if isDuplicate(err) {
+			// Which unique key fired tells the two "already linked" cases apart.
+			if strings.Contains(err.Error(), "uq_auth_accounts_provider_account") {
+				return apperror.ProviderAccountInUse()
+			}
+			return apperror.AccountAlreadyLinked()
+		}
 		return dbErr(err)
 	}
 	return nil
 }
 func (r *accountRepo) Update(ctx context.Context, a *domain.Account) error {
-	_, err := r.db.ExecContext(ctx, `UPDATE auth_accounts SET user_id = ?, provider_id = ?, provider_account_id = ?, credential = ?, provider_metadata = ?, updated_at = ? WHERE id = ?`, a.UserID, a.ProviderID, nullString(a.ProviderAccountID), nullString(a.Credential), defaultJSONObj(a.ProviderMetadata), a.UpdatedAt.UTC(), a.ID)
+	_, err := r.db.ExecContext(ctx, `UPDATE auth_accounts SET user_id = ?, provider_id = ?, provider_account_id = ?, credential = ?, provider_metadata = ?, updated_at = ?, provider_tokens = ? WHERE id = ?`, a.UserID, a.ProviderID, nullString(a.ProviderAccountID), nullString(a.Credential), defaultJSONObj(a.ProviderMetadata), a.UpdatedAt.UTC(), nullString(a.ProviderTokens), a.ID)
 	return dbErr(err)
 }
 func (r *accountRepo) DeleteByID(ctx context.Context, id string) error {
@@ -970,16 +1298,18 @@ func (r *accountRepo) DeleteAllByUser(ctx context.Context, userID string) error
 	return dbErr(err)
 }
 
-const appProviderColumns = `id, app_id, provider_id, config, is_active, created_at`
+const appProviderColumns = `id, app_id, provider_id, config, is_active, created_at, display_name, icon_url, sort_order, created_by, email_domains`
 
 type appProviderRepo struct{ db dbConn }
 
 func scanAppProvider(s rowScanner) (*domain.AppProvider, error) {
 	var p domain.AppProvider
-	if err := s.Scan(&p.ID, &p.AppID, &p.ProviderID, &p.Config, &p.IsActive, &p.CreatedAt); err != nil {
+	var emailDomains sql.NullString
+	if err := s.Scan(&p.ID, &p.AppID, &p.ProviderID, &p.Config, &p.IsActive, &p.CreatedAt, &p.DisplayName, &p.IconURL, &p.SortOrder, &p.CreatedBy, &emailDomains); err != nil {
 		return nil, err
 	}
 	p.Config = defaultJSONObj(p.Config)
+	p.EmailDomains = defaultJSONArr(emailDomains.String)
 	p.CreatedAt = p.CreatedAt.UTC()
 	return &p, nil
 }
@@ -1010,7 +1340,7 @@ func (r *appProviderRepo) FindAllByApp(ctx context.Context, appID string) ([]dom
 	return out, dbErr(rows.Err())
 }
 func (r *appProviderRepo) Insert(ctx context.Context, ap *domain.AppProvider) error {
-	_, err := r.db.ExecContext(ctx, `INSERT INTO auth_app_providers (id, app_id, provider_id, config, is_active, created_at) VALUES (?, ?, ?, ?, ?, ?)`, ap.ID, ap.AppID, ap.ProviderID, defaultJSONObj(ap.Config), ap.IsActive, ap.CreatedAt.UTC())
+	_, err := r.db.ExecContext(ctx, `INSERT INTO auth_app_providers (id, app_id, provider_id, config, is_active, created_at, display_name, icon_url, sort_order, created_by, email_domains) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)`, ap.ID, ap.AppID, ap.ProviderID, defaultJSONObj(ap.Config), ap.IsActive, ap.CreatedAt.UTC(), ap.DisplayName, ap.IconURL, ap.SortOrder, ap.CreatedBy, defaultJSONArr(ap.EmailDomains))
 	return dbErr(err)
 }
 func (r *appProviderRepo) DeleteByID(ctx context.Context, id string) error {
@@ -1058,14 +1388,14 @@ func (r *authCodeRepo) DeleteAllByUser(ctx context.Context, userID string) error
 	return dbErr(err)
 }
 
-const refreshTokenColumns = `id, user_id, app_id, token_hash, scopes, device_id, expires_at, revoked, created_at`
+const refreshTokenColumns = `id, user_id, app_id, token_hash, scopes, device_id, expires_at, expiry_days, revoked, created_at`
 
 type refreshTokenRepo struct{ db dbConn }
 
 func scanRefreshToken(s rowScanner) (*domain.RefreshToken, error) {
 	var t domain.RefreshToken
 	var device sql.NullString
-	if err := s.Scan(&t.ID, &t.UserID, &t.AppID, &t.TokenHash, &t.Scopes, &device, &t.ExpiresAt, &t.Revoked, &t.CreatedAt); err != nil {
+	if err := s.Scan(&t.ID, &t.UserID, &t.AppID, &t.TokenHash, &t.Scopes, &device, &t.ExpiresAt, &t.ExpiryDays, &t.Revoked, &t.CreatedAt); err != nil {
 		return nil, err
 	}
 	t.DeviceID = ptrString(device)
@@ -1085,18 +1415,109 @@ func (r *refreshTokenRepo) FindByTokenHash(ctx context.Context, hash string) (*d
 	return t, nil
 }
 func (r *refreshTokenRepo) Insert(ctx context.Context, t *domain.RefreshToken) error {
-	_, err := r.db.ExecContext(ctx, `INSERT INTO auth_refresh_tokens (id, user_id, app_id, token_hash, scopes, device_id, expires_at, revoked, created_at) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)`, t.ID, t.UserID, t.AppID, t.TokenHash, defaultJSONArr(t.Scopes), nullString(t.DeviceID), t.ExpiresAt.UTC(), t.Revoked, t.CreatedAt.UTC())
+	_, err := r.db.ExecContext(ctx, `INSERT INTO auth_refresh_tokens (id, user_id, app_id, token_hash, scopes, device_id, expires_at, expiry_days, revoked, created_at) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)`, t.ID, t.UserID, t.AppID, t.TokenHash, defaultJSONArr(t.Scopes), nullString(t.DeviceID), t.ExpiresAt.UTC(), t.ExpiryDays, t.Revoked, t.CreatedAt.UTC())
 	return dbErr(err)
 }
 func (r *refreshTokenRepo) Revoke(ctx context.Context, id string) error {
 	_, err := r.db.ExecContext(ctx, "UPDATE auth_refresh_tokens SET revoked = TRUE WHERE id = ?", id)
 	return dbErr(err)
 }
+func (r *refreshTokenRepo) RevokeIfActive(ctx context.Context, id string) (bool, error) {
+	res, err := r.db.ExecContext(ctx, "UPDATE auth_refresh_tokens SET revoked = TRUE WHERE id = ? AND revoked = FALSE", id)
+	if err != nil {
+		return false, dbErr(err)
+	}
+	n, err := res.RowsAffected()
+	return n == 1, dbErr(err)
+}
+func (r *refreshTokenRepo) Extend(ctx context.Context, id string, expiresAt time.Time) error {
+	_, err := r.db.ExecContext(ctx, "UPDATE auth_refresh_tokens SET expires_at = ? WHERE id = ? AND revoked = FALSE", expiresAt.UTC(), id)
+	return dbErr(err)
+}
+func (r *refreshTokenRepo) RevokeIssuedBefore(ctx context.Context, before time.Time, appID string) (int64, error) {
+	query := "DELETE FROM auth_refresh_tokens WHERE revoked = FALSE AND created_at < ?"
+	args := []any{before.UTC()}
+	if appID != "" {
+		query += " AND app_id = ?"
+		args = append(args, appID)
+	}
+	res, err := r.db.ExecContext(ctx, query, args...)
+	if err != nil {
+		return 0, dbErr(err)
+	}
+	n, err := res.RowsAffected()
+	return n, dbErr(err)
+}
+func (r *refreshTokenRepo) UsageByApp(ctx context.Context, appID string, now, since time.Time) (repository.AppUsage, error) {
+	var u repository.AppUsage
+	err := r.db.QueryRowContext(ctx, `SELECT COUNT(DISTINCT t.user_id),
+		COALESCE(SUM(t.revoked = FALSE AND t.expires_at > ?), 0),
+		COUNT(DISTINCT CASE WHEN usr.created_at >= ? THEN usr.id END)
+		FROM auth_refresh_tokens t LEFT JOIN auth_users usr ON usr.id = t.user_id
+		WHERE t.app_id = ?`, now.UTC(), since.UTC(), appID).Scan(&u.Users, &u.ActiveTokens, &u.NewUsers)
+	if err != nil {
+		return repository.AppUsage{}, dbErr(err)
+	}
+	return u, nil
+}
+func (r *refreshTokenRepo) ListActiveByUser(ctx context.Context, userID string, now time.Time) ([]domain.RefreshToken, error) {
+	rows, err := r.db.QueryContext(ctx, "SELECT "+refreshTokenColumns+" FROM auth_refresh_tokens WHERE user_id = ? AND revoked = FALSE AND expires_at > ? ORDER BY created_at DESC", userID, now.UTC())
+	if err != nil {
+		return nil, dbErr(err)
+	}
+	defer rows.Close()
+	var out []domain.RefreshToken
+	for rows.Next() {
+		t, err := scanRefreshToken(rows)
+		if err != nil {
+			return nil, dbErr(err)
+		}
+		out = append(out, *t)
+	}
+	return out, dbErr(rows.Err())
+}
+func (r *refreshTokenRepo) DeleteByUserAndApp(ctx context.Context, userID, appID string) (int64, error) {
+	res, err := r.db.ExecContext(ctx, "DELETE FROM auth_refresh_tokens WHERE user_id = ? AND app_id = ? AND revoked = FALSE", userID, appID)
+	if err != nil {
+		return 0, dbErr(err)
+	}
+	n, err := res.RowsAffected()
+	return n, dbErr(err)
+}
 func (r *refreshTokenRepo) DeleteAllByUser(ctx context.Context, userID string) error {
 	_, err := r.db.ExecContext(ctx, "DELETE FROM auth_refresh_tokens WHERE user_id = ?", userID)
 	return dbErr(err)
 }
 
+type accessTokenRepo struct{ db dbConn }
+
+func (r *accessTokenRepo) FindByTokenHash(ctx context.Context, hash string) (*domain.AccessToken, error) {
+	var t domain.AccessToken
+	err := r.db.QueryRowContext(ctx, "SELECT token_hash, subject, claims, expires_at, created_at FROM auth_access_tokens WHERE token_hash = ?", hash).
+		Scan(&t.TokenHash, &t.Subject, &t.Claims, &t.ExpiresAt, &t.CreatedAt)
+	if errors.Is(err, sql.ErrNoRows) {
+		return nil, nil
+	}
+	if err != nil {
+		return nil, dbErr(err)
+	}
+	t.ExpiresAt = t.ExpiresAt.UTC()
+	t.CreatedAt = t.CreatedAt.UTC()
+	return &t, nil
+}
+func (r *accessTokenRepo) Insert(ctx context.Context, t *domain.AccessToken) error {
+	_, err := r.db.ExecContext(ctx, `INSERT INTO auth_access_tokens (token_hash, subject, claims, expires_at, created_at) VALUES (?, ?, ?, ?, ?)`, t.TokenHash, t.Subject, t.Claims, t.ExpiresAt.UTC(), t.CreatedAt.UTC())
+	return dbErr(err)
+}
+func (r *accessTokenRepo) DeleteByTokenHash(ctx context.Context, hash string) error {
+	_, err := r.db.ExecContext(ctx, "DELETE FROM auth_access_tokens WHERE token_hash = ?", hash)
+	return dbErr(err)
+}
+func (r *accessTokenRepo) DeleteAllByUser(ctx context.Context, userID string) error {
+	_, err := r.db.ExecContext(ctx, "DELETE FROM auth_access_tokens WHERE subject = ?", userID)
+	return dbErr(err)
+}
+
 const inviteCodeColumns = `id, code, created_by, created_at, used_at, used_by, is_revoked, kind, grants_membership, grants_membership_days, grants_user_type`
 
 type inviteCodeRepo struct{ db dbConn }
@@ -1393,6 +1814,42 @@ func (r *teamMembershipRepo) DeleteAllByUser(ctx context.Context, userID string)
 	return dbErr(err)
 }
 
+// auditEventRepo is not part of dataTables: a snapshot import leaves the
+// audit history alone.
+type auditEventRepo struct{ db dbConn }
+
+func (r *auditEventRepo) Insert(ctx context.Context, e *domain.AuditEvent) error {
+	_, err := r.db.ExecContext(ctx, `INSERT INTO auth_audit_events (id, type, user_id, target_id, app_id, ip, details, created_at) VALUES (?, ?, ?, ?, ?, ?, ?, ?)`, e.ID, e.Type, e.UserID, e.TargetID, e.AppID, e.IP, defaultJSONObj(e.Details), e.CreatedAt.UTC())
+	return dbErr(err)
+}
+func (r *auditEventRepo) ListByUser(ctx context.Context, userID, eventType string, offset, limit uint64) ([]domain.AuditEvent, uint64, error) {
+	where := " WHERE (user_id = ? OR target_id = ?)"
+	args := []any{userID, userID}
+	if eventType != "" {
+		where += " AND type = ?"
+		args = append(args, eventType)
+	}
+	var total uint64
+	if err := r.db.QueryRowContext(ctx, "SELECT COUNT(*) FROM auth_audit_events"+where, args...).Scan(&total); err != nil {
+		return nil, 0, dbErr(err)
+	}
+	rows, err := r.db.QueryContext(ctx, "SELECT id, type, user_id, target_id, app_id, ip, details, created_at FROM auth_audit_events"+where+" ORDER BY created_at DESC, id DESC LIMIT ? OFFSET ?", append(args, limit, offset)...)
+	if err != nil {
+		return nil, 0, dbErr(err)
+	}
+	defer rows.Close()
+	out := make([]domain.AuditEvent, 0)
+	for rows.Next() {
+		var e domain.AuditEvent
+		if err := rows.Scan(&e.ID, &e.Type, &e.UserID, &e.TargetID, &e.AppID, &e.IP, &e.Details, &e.CreatedAt); err != nil {
+			return nil, 0, dbErr(err)
+		}
+		e.CreatedAt = e.CreatedAt.UTC()
+		out = append(out, e)
+	}
+	return out, total, dbErr(rows.Err())
+}
+
 // ReplaceWithSnapshot clears existing rows and imports the snapshot in one
 // transaction. If any row fails to import, the target data is left unchanged.
 func (r *Repository) ReplaceWithSnapshot(ctx context.Context, data snapshot.Data) error {
@@ -56,11 +56,20 @@ func ParseUserListSort(sortBy, sortOrder string) UserListSort {
 type UserRepository interface {
 	FindByID(ctx context.Context, id string) (*domain.User, error)
 	FindByEmail(ctx context.Context, email string) (*domain.User, error)
+	// FindByUsername matches the username regardless of case.
+	FindByUsername(ctx context.Context, username string) (*domain.User, error)
 	Insert(ctx context.Context, u *domain.User) error
+	// InsertWithAccount inserts the user together with their first login
+	// method; if either insert fails, neither is kept. A taken email fails
+	// with a user-already-exists error.
+	InsertWithAccount(ctx context.Context, u *domain.User, a *domain.Account) error
 	Update(ctx context.Context, u *domain.User) error
 	DeleteByID(ctx context.Context, id string) error
 	CountAll(ctx context.Context) (uint64, error)
 	CountSince(ctx context.Context, since time.Time) (uint64, error)
+	// CountCreatedByDay counts users created at or after since, keyed by UTC
+	// day ("2006-01-02"). Days without registrations are absent.
+	CountCreatedByDay(ctx context.Context, since time.Time) (map[string]uint64, error)
 	// ListPaginated returns a page of users. search is a case-insensitive
 	// substring match on email/name; idSearch is a case-insensitive substring
 	// match on the user id (UUID). Empty filters are ignored.
@@ -68,6 +77,13 @@ type UserRepository interface {
 	// RecordLogin appends a login record (timestamp + IP), keeping at most the
 	// 3 most recent entries, and updates LastLoginAt.
 	RecordLogin(ctx context.Context, userID, ip string) error
+	// RecordFailedLogin counts a wrong password in one atomic step. When the
+	// count reaches max it locks the user until lockUntil and starts the count
+	// again from zero. It returns the count and lock as stored afterwards.
+	RecordFailedLogin(ctx context.Context, userID string, max int64, lockUntil time.Time) (int, *time.Time, error)
+	// ResetFailedLogins clears the failed-login count and any lock, leaving
+	// the rest of the user untouched.
+	ResetFailedLogins(ctx context.Context, userID string) error
 }
 
 // ApplicationRepository persists OAuth2 applications.
@@ -77,15 +93,24 @@ type ApplicationRepository interface {
 	FindByName(ctx context.Context, name string) (*domain.Application, error)
 	FindAll(ctx context.Context) ([]domain.Application, error)
 	Insert(ctx context.Context, a *domain.Application) error
+	// InsertWithProviders inserts the app together with its provider configs;
+	// if any insert fails, nothing is left behind.
+	InsertWithProviders(ctx context.Context, a *domain.Application, providers []domain.AppProvider) error
 	Update(ctx context.Context, a *domain.Application) error
 	CountAll(ctx context.Context) (uint64, error)
 	CountActive(ctx context.Context) (uint64, error)
+	// RecordLogin counts one sign-in to the app on at's UTC day.
+	RecordLogin(ctx context.Context, appID string, at time.Time) error
+	// CountLoginsByDay returns the app's sign-ins on since's UTC day and later,
+	// keyed by UTC day ("2006-01-02"). Days without logins are absent.
+	CountLoginsByDay(ctx context.Context, appID string, since time.Time) (map[string]uint64, error)
 }
 
 // AccountRepository persists user-provider account links.
 type AccountRepository interface {
 	FindByUserAndProvider(ctx context.Context, userID, providerID string) (*domain.Account, error)
 	FindByProviderAccount(ctx context.Context, providerID, providerAccountID string) (*domain.Account, error)
+	// FindAllByUser returns the user's accounts oldest first (ties by id).
 	FindAllByUser(ctx context.Context, userID string) ([]domain.Account, error)
 	CountByUser(ctx context.Context, userID string) (uint64, error)
 	Insert(ctx context.Context, a *domain.Account) error
@@ -110,11 +135,50 @@ type AuthCodeRepository interface {
 	DeleteAllByUser(ctx context.Context, userID string) error
 }
 
+// AppUsage summarizes one application's refresh tokens. A user counts towards
+// an app once they hold (or held, until cleanup) a refresh token issued to it.
+type AppUsage struct {
+	Users        uint64 // distinct users with a stored token for the app
+	ActiveTokens uint64 // tokens neither revoked nor expired
+	NewUsers     uint64 // of Users, those created at or after the cutoff
+}
+
 // RefreshTokenRepository persists refresh tokens.
 type RefreshTokenRepository interface {
 	FindByTokenHash(ctx context.Context, hash string) (*domain.RefreshToken, error)
 	Insert(ctx context.Context, t *domain.RefreshToken) error
 	Revoke(ctx context.Context, id string) error
+	// RevokeIfActive revokes the token only if it isn't revoked already and
+	// reports whether this call did it, so two concurrent rotations of one
+	// token can't both succeed.
+	RevokeIfActive(ctx context.Context, id string) (bool, error)
+	// Extend moves a live token's expiry, for apps that keep one refresh
+	// token instead of rotating it.
+	Extend(ctx context.Context, id string, expiresAt time.Time) error
+	// RevokeIssuedBefore deletes every live token created before the cutoff,
+	// limited to one app unless appID is empty, and returns how many it removed.
+	// Deleting (rather than flagging revoked) keeps a later use from tripping
+	// refresh-token reuse detection.
+	RevokeIssuedBefore(ctx context.Context, before time.Time, appID string) (int64, error)
+	// UsageByApp aggregates the app's tokens: tokens still valid at now count
+	// as active, and users created at or after since count as new.
+	UsageByApp(ctx context.Context, appID string, now, since time.Time) (AppUsage, error)
+	// ListActiveByUser returns the user's tokens still valid at now, newest
+	// first.
+	ListActiveByUser(ctx context.Context, userID string, now time.Time) ([]domain.RefreshToken, error)
+	// DeleteByUserAndApp deletes the user's live tokens for one app and
+	// returns how many it removed; like RevokeIssuedBefore it deletes, so the
+	// app's next refresh is not taken for reuse.
+	DeleteByUserAndApp(ctx context.Context, userID, appID string) (int64, error)
+	DeleteAllByUser(ctx context.Context, userID string) error
+}
+
+// AccessTokenRepository persists opaque access tokens by hash.
+type AccessTokenRepository interface {
+	FindByTokenHash(ctx context.Context, hash string) (*domain.AccessToken, error)
+	Insert(ctx context.Context, t *domain.AccessToken) error
+	DeleteByTokenHash(ctx context.Context, hash string) error
+	// DeleteAllByUser removes every token whose subject is userID.
 	DeleteAllByUser(ctx context.Context, userID string) error
 }
 
@@ -151,6 +215,14 @@ type TeamMembershipRepository interface {
 	DeleteAllByUser(ctx context.Context, userID string) error
 }
 
+// AuditEventRepository persists audit events.
+type AuditEventRepository interface {
+	Insert(ctx context.Context, e *domain.AuditEvent) error
+	// ListByUser returns the events with userID as user or target, newest
+	// first, limited to eventType unless it is empty, and the total count.
+	ListByUser(ctx context.Context, userID, eventType string, offset, limit uint64) ([]domain.AuditEvent, uint64, error)
+}
+
 // Repository is the composite store handed to handlers.
 type Repository interface {
 	Users() UserRepository
@@ -159,7 +231,9 @@ type Repository interface {
 	AppProviders() AppProviderRepository
 	AuthCodes() AuthCodeRepository
 	RefreshTokens() RefreshTokenRepository
+	AccessTokens() AccessTokenRepository
 	InviteCodes() InviteCodeRepository
 	Teams() TeamRepository
 	TeamMemberships() TeamMembershipRepository
+	AuditEvents() AuditEventRepository
 }
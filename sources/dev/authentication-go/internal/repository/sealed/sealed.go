@@ -0,0 +1,269 @@
+// Package sealed wraps a repository.Repository so third-party secrets are
+// encrypted at rest with auth.SealSecret. Secret-looking fields of an app
+// provider's config (e.g. "secret", "client_secret") are sealed individually,
+// leaving the rest of the config readable; account provider metadata is
+// sealed whole. Reads open sealed values transparently, and plaintext rows
+// written before a DATA_ENCRYPTION_KEY existed keep working. Without a key,
+// writes stay plaintext.
+package sealed
+
+import (
+	"context"
+	"encoding/json"
+	"strings"
+
+	"github.com/zhaochy1990/auth-service/internal/apperror"
+	"github.com/zhaochy1990/auth-service/internal/auth"
+	"github.com/zhaochy1990/auth-service/internal/domain"
+	"github.com/zhaochy1990/auth-service/internal/repository"
+)
+
+// Repository seals app-provider configs and account metadata; every other
+// store passes through to the wrapped repository.
+type Repository struct {
+	repository.Repository
+}
+
+// Wrap returns inner with sensitive columns sealed on write and opened on read.
+func Wrap(inner repository.Repository) *Repository {
+	return &Repository{Repository: inner}
+}
+
+func (r *Repository) Users() repository.UserRepository {
+	return userRepo{r.Repository.Users()}
+}
+
+func (r *Repository) Applications() repository.ApplicationRepository {
+	return appRepo{r.Repository.Applications()}
+}
+
+func (r *Repository) AppProviders() repository.AppProviderRepository {
+	return appProviderRepo{r.Repository.AppProviders()}
+}
+
+func (r *Repository) Accounts() repository.AccountRepository {
+	return accountRepo{r.Repository.Accounts()}
+}
+
+// secretConfigKey reports whether a provider config field holds a secret.
+func secretConfigKey(key string) bool {
+	k := strings.ToLower(key)
+	for _, marker := range []string{"secret", "password", "private_key", "token"} {
+		if strings.Contains(k, marker) {
+			return true
+		}
+	}
+	return false
+}
+
+// sealConfig seals the secret string fields of a JSON object config.
+func sealConfig(cfg string) (string, error) {
+	if !auth.DataEncryptionEnabled() {
+		return cfg, nil
+	}
+	return mapConfigStrings(cfg, func(key, value string) (string, bool, error) {
+		if !secretConfigKey(key) || auth.IsSealed(value) {
+			return "", false, nil
+		}
+		sealed, err := auth.SealSecret([]byte(value))
+		return sealed, true, err
+	})
+}
+
+// openConfig opens every sealed string field of a JSON object config.
+func openConfig(cfg string) (string, error) {
+	return mapConfigStrings(cfg, func(_, value string) (string, bool, error) {
+		if !auth.IsSealed(value) {
+			return "", false, nil
+		}
+		plain, err := auth.OpenSecret(value)
+		return string(plain), true, err
+	})
+}
+
+// mapConfigStrings rewrites the top-level string fields of a JSON object for
+// which fn reports a change. Anything that isn't an object passes unchanged.
+func mapConfigStrings(cfg string, fn func(key, value string) (string, bool, error)) (string, error) {
+	var fields map[string]json.RawMessage
+	if err := json.Unmarshal([]byte(cfg), &fields); err != nil {
+		return cfg, nil
+	}
+	changed := false
+	for k, raw := range fields {
+		var value string
+		if json.Unmarshal(raw, &value) != nil {
+			continue
+		}
+		out, ok, err := fn(k, value)
+		if err != nil {
+			return "", err
+		}
+		if !ok {
+			continue
+		}
+		encoded, err := json.Marshal(out)
+		if err != nil {
+			return "", apperror.Internal()
+		}
+		fields[k] = encoded
+		changed = true
+	}
+	if !changed {
+		return cfg, nil
+	}
+	b, err := json.Marshal(fields)
+	if err != nil {
+		return "", apperror.Internal()
+	}
+	return string(b), nil
+}
+
+func sealMetadata(meta string) (string, error) {
+	if !auth.DataEncryptionEnabled() || auth.IsSealed(meta) {
+		return meta, nil
+	}
+	return auth.SealSecret([]byte(meta))
+}
+
+func openMetadata(meta string) (string, error) {
+	if !auth.IsSealed(meta) {
+		return meta, nil
+	}
+	plain, err := auth.OpenSecret(meta)
+	if err != nil {
+		return "", err
+	}
+	return string(plain), nil
+}
+
+// ─── User ────────────────────────────────────────────────────────────────────
+
+type userRepo struct {
+	repository.UserRepository
+}
+
+func (r userRepo) InsertWithAccount(ctx context.Context, u *domain.User, a *domain.Account) error {
+	meta, err := sealMetadata(a.ProviderMetadata)
+	if err != nil {
+		return err
+	}
+	stored := *a
+	stored.ProviderMetadata = meta
+	return r.UserRepository.InsertWithAccount(ctx, u, &stored)
+}
+
+// ─── Application ─────────────────────────────────────────────────────────────
+
+type appRepo struct {
+	repository.ApplicationRepository
+}
+
+func (r appRepo) InsertWithProviders(ctx context.Context, a *domain.Application, providers []domain.AppProvider) error {
+	stored := make([]domain.AppProvider, len(providers))
+	for i := range providers {
+		cfg, err := sealConfig(providers[i].Config)
+		if err != nil {
+			return err
+		}
+		stored[i] = providers[i]
+		stored[i].Config = cfg
+	}
+	return r.ApplicationRepository.InsertWithProviders(ctx, a, stored)
+}
+
+// ─── AppProvider ─────────────────────────────────────────────────────────────
+
+type appProviderRepo struct {
+	repository.AppProviderRepository
+}
+
+func (r appProviderRepo) FindByAppAndProvider(ctx context.Context, appID, providerID string) (*domain.AppProvider, error) {
+	p, err := r.AppProviderRepository.FindByAppAndProvider(ctx, appID, providerID)
+	if err != nil || p == nil {
+		return p, err
+	}
+	if p.Config, err = openConfig(p.Config); err != nil {
+		return nil, err
+	}
+	return p, nil
+}
+
+func (r appProviderRepo) FindAllByApp(ctx context.Context, appID string) ([]domain.AppProvider, error) {
+	ps, err := r.AppProviderRepository.FindAllByApp(ctx, appID)
+	if err != nil {
+		return nil, err
+	}
+	for i := range ps {
+		if ps[i].Config, err = openConfig(ps[i].Config); err != nil {
+			return nil, err
+		}
+	}
+	return ps, nil
+}
+
+func (r appProviderRepo) Insert(ctx context.Context, ap *domain.AppProvider) error {
+	cfg, err := sealConfig(ap.Config)
+	if err != nil {
+		return err
+	}
+	stored := *ap
+	stored.Config = cfg
+	return r.AppProviderRepository.Insert(ctx, &stored)
+}
+
+// ─── Account ─────────────────────────────────────────────────────────────────
+
+type accountRepo struct {
+	repository.AccountRepository
+}
+
+func openAccount(a *domain.Account, err error) (*domain.Account, error) {
+	if err != nil || a == nil {
+		return a, err
+	}
+	if a.ProviderMetadata, err = openMetadata(a.ProviderMetadata); err != nil {
+		return nil, err
+	}
+	return a, nil
+}
+
+func (r accountRepo) FindByUserAndProvider(ctx context.Context, userID, providerID string) (*domain.Account, error) {
+	return openAccount(r.AccountRepository.FindByUserAndProvider(ctx, userID, providerID))
+}
+
+func (r accountRepo) FindByProviderAccount(ctx context.Context, providerID, providerAccountID string) (*domain.Account, error) {
+	return openAccount(r.AccountRepository.FindByProviderAccount(ctx, providerID, providerAccountID))
+}
+
+func (r accountRepo) FindAllByUser(ctx context.Context, userID string) ([]domain.Account, error) {
+	as, err := r.AccountRepository.FindAllByUser(ctx, userID)
+	if err != nil {
+		return nil, err
+	}
+	for i := range as {
+		if as[i].ProviderMetadata, err = openMetadata(as[i].ProviderMetadata); err != nil {
+			return nil, err
+		}
+	}
+	return as, nil
+}
+
+func (r accountRepo) Insert(ctx context.Context, a *domain.Account) error {
+	meta, err := sealMetadata(a.ProviderMetadata)
+	if err != nil {
+		return err
+	}
+	stored := *a
+	stored.ProviderMetadata = meta
+	return r.AccountRepository.Insert(ctx, &stored)
+}
+
+func (r accountRepo) Update(ctx context.Context, a *domain.Account) error {
+	meta, err := sealMetadata(a.ProviderMetadata)
+	if err != nil {
+		return err
+	}
+	stored := *a
+	stored.ProviderMetadata = meta
+	return r.AccountRepository.Update(ctx, &stored)
+}
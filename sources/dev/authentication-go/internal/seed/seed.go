@@ -1,6 +1,8 @@
 // Package seed bootstraps the Admin Dashboard application and an admin user. It
 // is idempotent: re-running promotes an existing user or reports already_admin;
-// the client secret is only returned on first creation.
+// the client secret is only returned on first creation. With Options.CheckOnly
+// it computes the same outcome without writing anything. The app name, redirect
+// URIs, scopes and role default to the local Admin Dashboard setup.
 package seed
 
 import (
@@ -16,55 +18,86 @@ import (
 	"github.com/zhaochy1990/auth-service/internal/repository"
 )
 
-// Result describes the outcome of a bootstrap.
+// DefaultAppName is used when Options.AppName is empty.
+const DefaultAppName = "Admin Dashboard"
+
+// Defaults for a newly created application.
+var (
+	DefaultRedirectURIs  = []string{"http://localhost:5173"}
+	DefaultAllowedScopes = []string{"admin"}
+)
+
+// Options tunes a bootstrap.
+type Options struct {
+	// CheckOnly reports what would happen without inserting or updating rows.
+	CheckOnly bool
+	// AppName names the bootstrapped application; an existing app with this
+	// name is reused as-is.
+	AppName string
+	// RedirectURIs and AllowedScopes configure a newly created application.
+	RedirectURIs  []string
+	AllowedScopes []string
+	// Role is given to the created or promoted user (default "admin").
+	Role string
+}
+
+func (o Options) withDefaults() Options {
+	if o.AppName == "" {
+		o.AppName = DefaultAppName
+	}
+	if len(o.RedirectURIs) == 0 {
+		o.RedirectURIs = DefaultRedirectURIs
+	}
+	if len(o.AllowedScopes) == 0 {
+		o.AllowedScopes = DefaultAllowedScopes
+	}
+	if o.Role == "" {
+		o.Role = domain.RoleAdmin
+	}
+	return o
+}
+
+// Result describes the outcome of a bootstrap. Under CheckOnly the actions
+// describe what a real run would do.
 type Result struct {
-	AppClientID     string
+	AppClientID     string  // empty when CheckOnly and the app doesn't exist yet
 	AppClientSecret *string // only set when a new application is created
-	UserAction      string  // "created" | "promoted" | "already_admin"
+	AppAction       string  // "created" | "exists"
+	UserAction      string  // "created" | "promoted" | "already_admin" (relative to Options.Role)
+	CheckOnly       bool
 }
 
-// Bootstrap creates/finds the Admin Dashboard app and creates/promotes the
-// admin user. A password is required only when creating a new user.
-func Bootstrap(ctx context.Context, repo repository.Repository, adminEmail string, adminPassword *string) (*Result, error) {
-	existingApp, err := repo.Applications().FindByName(ctx, "Admin Dashboard")
+// Bootstrap creates/finds the named app and creates/promotes the admin user.
+// A password is required only when creating a new user.
+func Bootstrap(ctx context.Context, repo repository.Repository, adminEmail string, adminPassword *string, opts Options) (*Result, error) {
+	opts = opts.withDefaults()
+	existingApp, err := repo.Applications().FindByName(ctx, opts.AppName)
 	if err != nil {
 		return nil, err
 	}
 
-	var appClientID string
+	var appClientID, appAction string
 	var appClientSecret *string
 	if existingApp != nil {
 		appClientID = existingApp.ClientID
+		appAction = "exists"
+	} else if opts.CheckOnly {
+		appAction = "created"
 	} else {
-		clientID := auth.GenerateClientID()
-		secret := auth.RandomHex(32)
-		now := time.Now().UTC()
-		appID := uuid.NewString()
-		redirect, _ := json.Marshal([]string{"http://localhost:5173"})
-		scopes, _ := json.Marshal([]string{"admin"})
-		app := &domain.Application{
-			ID:               appID,
-			Name:             "Admin Dashboard",
-			ClientID:         clientID,
-			ClientSecretHash: auth.HashClientSecret(secret),
-			RedirectURIs:     string(redirect),
-			AllowedScopes:    string(scopes),
-			IsActive:         true,
-			CreatedAt:        now,
-			UpdatedAt:        now,
-		}
-		if err := repo.Applications().Insert(ctx, app); err != nil {
+		app, secret, err := CreateApplication(ctx, repo, opts.AppName, opts.RedirectURIs, opts.AllowedScopes)
+		if err != nil {
 			return nil, err
 		}
 		provider := &domain.AppProvider{
-			ID: uuid.NewString(), AppID: appID, ProviderID: "password",
-			Config: "{}", IsActive: true, CreatedAt: now,
+			ID: uuid.NewString(), AppID: app.ID, ProviderID: "password",
+			Config: "{}", IsActive: true, CreatedAt: app.CreatedAt,
 		}
 		if err := repo.AppProviders().Insert(ctx, provider); err != nil {
 			return nil, err
 		}
-		appClientID = clientID
+		appClientID = app.ClientID
 		appClientSecret = &secret
+		appAction = "created"
 	}
 
 	existingUser, err := repo.Users().FindByEmail(ctx, adminEmail)
@@ -74,10 +107,12 @@ func Bootstrap(ctx context.Context, repo repository.Repository, adminEmail strin
 
 	var userAction string
 	if existingUser != nil {
-		if existingUser.Role == "admin" {
+		if existingUser.Role == opts.Role {
 			userAction = "already_admin"
+		} else if opts.CheckOnly {
+			userAction = "promoted"
 		} else {
-			existingUser.Role = "admin"
+			existingUser.Role = opts.Role
 			existingUser.UpdatedAt = time.Now().UTC()
 			if err := repo.Users().Update(ctx, existingUser); err != nil {
 				return nil, err
@@ -88,6 +123,9 @@ func Bootstrap(ctx context.Context, repo repository.Repository, adminEmail strin
 		if adminPassword == nil {
 			return nil, apperror.BadRequest("Password is required when creating a new admin user. Usage: auth-service seed <email> <password>")
 		}
+		if opts.CheckOnly {
+			return &Result{AppClientID: appClientID, AppAction: appAction, UserAction: "created", CheckOnly: true}, nil
+		}
 		hash, err := auth.HashPassword(*adminPassword)
 		if err != nil {
 			return nil, err
@@ -101,7 +139,7 @@ func Bootstrap(ctx context.Context, repo repository.Repository, adminEmail strin
 			Email:            &email,
 			Name:             &name,
 			EmailVerified:    true,
-			Role:             "admin",
+			Role:             opts.Role,
 			UserType:         domain.UserTypeRegular,
 			IsActive:         true,
 			CustomAttributes: map[string]any{},
@@ -128,5 +166,42 @@ func Bootstrap(ctx context.Context, repo repository.Repository, adminEmail strin
 		userAction = "created"
 	}
 
-	return &Result{AppClientID: appClientID, AppClientSecret: appClientSecret, UserAction: userAction}, nil
+	return &Result{AppClientID: appClientID, AppClientSecret: appClientSecret, AppAction: appAction, UserAction: userAction, CheckOnly: opts.CheckOnly}, nil
+}
+
+// CreateApplication inserts an active application with fresh client
+// credentials, like POST /admin/applications, and returns it together with the
+// plaintext secret (only the hash is stored). No providers are attached.
+func CreateApplication(ctx context.Context, repo repository.Repository, name string, redirectURIs, allowedScopes []string) (*domain.Application, string, error) {
+	if name == "" {
+		return nil, "", apperror.BadRequest("Application name is required")
+	}
+	if redirectURIs == nil {
+		redirectURIs = []string{}
+	}
+	if allowedScopes == nil {
+		allowedScopes = []string{}
+	}
+	secret, secretHash := auth.GenerateClientSecret()
+	now := time.Now().UTC()
+	redirect, _ := json.Marshal(redirectURIs)
+	scopes, _ := json.Marshal(allowedScopes)
+	app := &domain.Application{
+		ID:                    uuid.NewString(),
+		Name:                  name,
+		ClientID:              auth.GenerateClientID(),
+		ClientSecretHash:      secretHash,
+		RedirectURIs:          string(redirect),
+		AllowedScopes:         string(scopes),
+		IsActive:              true,
+		AllowSelfRegistration: true,
+		RotateRefreshTokens:   true,
+		IssueRefreshTokens:    true,
+		CreatedAt:             now,
+		UpdatedAt:             now,
+	}
+	if err := repo.Applications().Insert(ctx, app); err != nil {
+		return nil, "", err
+	}
+	return app, secret, nil
 }
@@ -1,7 +1,9 @@
 package server_test
 
 import (
+	"bufio"
 	"bytes"
+	"compress/gzip"
 	"context"
 	"crypto/rand"
 	"crypto/rsa"
@@ -9,22 +11,31 @@ import (
 	"encoding/base64"
 	"encoding/json"
 	"encoding/pem"
+	"net"
 	"net/http"
 	"net/http/httptest"
 	"net/url"
 	"os"
 	"path/filepath"
+	"runtime"
+	"slices"
 	"strconv"
 	"strings"
+	"sync"
 	"testing"
 	"time"
 
 	"github.com/gin-gonic/gin"
+	"github.com/golang-jwt/jwt/v5"
 
+	"github.com/zhaochy1990/auth-service/internal/apperror"
 	"github.com/zhaochy1990/auth-service/internal/auth"
 	"github.com/zhaochy1990/auth-service/internal/config"
 	"github.com/zhaochy1990/auth-service/internal/domain"
+	"github.com/zhaochy1990/auth-service/internal/i18n"
+	"github.com/zhaochy1990/auth-service/internal/middleware"
 	mysqlrepo "github.com/zhaochy1990/auth-service/internal/repository/mysql"
+	"github.com/zhaochy1990/auth-service/internal/securitylog"
 	"github.com/zhaochy1990/auth-service/internal/seed"
 	"github.com/zhaochy1990/auth-service/internal/server"
 )
@@ -74,15 +85,18 @@ func newTestApp(t *testing.T) *testApp {
 	privateKeyPath, publicKeyPath := writeTestKeyPair(t)
 
 	cfg := &config.Config{
-		StorageBackend:            config.StorageBackendMySQL,
-		MySQLDSN:                  testMySQLDSN(),
-		JWTPrivateKeyPath:         privateKeyPath,
-		JWTPublicKeyPath:          publicKeyPath,
-		JWTIssuer:                 "auth-service",
-		JWTAccessTokenExpirySecs:  3600,
-		JWTRefreshTokenExpiryDays: 30,
-		CORSAllowedOrigins:        "*",
-		EnableTestProviders:       true,
+		StorageBackend:                      config.StorageBackendMySQL,
+		MySQLDSN:                            testMySQLDSN(),
+		JWTPrivateKeyPath:                   privateKeyPath,
+		JWTPublicKeyPath:                    publicKeyPath,
+		JWTIssuer:                           "auth-service",
+		JWTAccessTokenExpirySecs:            3600,
+		JWTRefreshTokenExpiryDays:           30,
+		JWTRefreshTokenShortExpiryDays:      1,
+		JWTRefreshTokenRememberMeExpiryDays: 60,
+		CORSAllowedOrigins:                  "*",
+		EnableTestProviders:                 true,
+		Roles:                               config.DefaultRoles(),
 	}
 	jwtMgr, err := auth.NewJWTManager(cfg)
 	if err != nil {
@@ -90,7 +104,7 @@ func newTestApp(t *testing.T) *testApp {
 	}
 
 	pw := "AdminPass1!"
-	res, err := seed.Bootstrap(ctx, repo, "test-admin@internal", &pw)
+	res, err := seed.Bootstrap(ctx, repo, "test-admin@internal", &pw, seed.Options{})
 	if err != nil {
 		t.Fatalf("bootstrap: %v", err)
 	}
@@ -98,7 +112,7 @@ func newTestApp(t *testing.T) *testApp {
 	if err != nil || adminUser == nil {
 		t.Fatalf("find admin: %v", err)
 	}
-	adminToken, err := jwtMgr.IssueAccessToken(adminUser.ID, res.AppClientID, []string{"admin"}, "admin", domain.MembershipRegular, domain.UserTypeRegular, nil)
+	adminToken, err := jwtMgr.IssueAccessToken(ctx, adminUser.ID, res.AppClientID, []string{"admin"}, "admin", domain.MembershipRegular, domain.UserTypeRegular, nil, auth.TokenExtras{})
 	if err != nil {
 		t.Fatalf("issue admin token: %v", err)
 	}
@@ -174,10 +188,42 @@ func TestHealth(t *testing.T) {
 	ta := newTestApp(t)
 	w := ta.do(http.MethodGet, "/health", nil, nil)
 	mustStatus(t, w, http.StatusOK)
-	var body map[string]any
+	var body struct {
+		Status  string `json:"status"`
+		Version string `json:"version"`
+		Build   struct {
+			GoVersion string `json:"go_version"`
+		} `json:"build"`
+	}
+	decode(t, w, &body)
+	if body.Status != "ok" || body.Version == "" {
+		t.Fatalf("health = %+v", body)
+	}
+	if body.Build.GoVersion != runtime.Version() {
+		t.Fatalf("build.go_version = %q, want %q", body.Build.GoVersion, runtime.Version())
+	}
+}
+
+func TestKeysPEM(t *testing.T) {
+	ta := newTestApp(t)
+	w := ta.do(http.MethodGet, "/oauth/keys/pem", nil, nil)
+	mustStatus(t, w, http.StatusOK)
+	var body struct {
+		Issuer    string `json:"issuer"`
+		Algorithm string `json:"algorithm"`
+		Kid       string `json:"kid"`
+		PEM       string `json:"pem"`
+	}
 	decode(t, w, &body)
-	if body["status"] != "ok" {
-		t.Fatalf("status field = %v", body["status"])
+	if body.Issuer != "auth-service" || body.Algorithm != "RS256" || body.Kid == "" {
+		t.Fatalf("unexpected key info: %+v", body)
+	}
+	block, _ := pem.Decode([]byte(body.PEM))
+	if block == nil || block.Type != "PUBLIC KEY" {
+		t.Fatalf("pem did not decode: %q", body.PEM)
+	}
+	if _, err := x509.ParsePKIXPublicKey(block.Bytes); err != nil {
+		t.Fatalf("parse public key: %v", err)
 	}
 }
 
@@ -215,8 +261,12 @@ func TestRegisterLoginRefreshLogout(t *testing.T) {
 	var loginResp struct {
 		AccessToken  string `json:"access_token"`
 		RefreshToken string `json:"refresh_token"`
+		Scope        string `json:"scope"`
 	}
 	decode(t, login, &loginResp)
+	if loginResp.Scope != "admin" {
+		t.Fatalf("login scope = %q, want the app's granted scopes", loginResp.Scope)
+	}
 
 	bad := ta.do(http.MethodPost, "/api/auth/login", map[string]any{
 		"email": "u1@example.com", "password": "WrongPass1!",
@@ -230,8 +280,12 @@ func TestRegisterLoginRefreshLogout(t *testing.T) {
 	var refreshResp struct {
 		AccessToken  string `json:"access_token"`
 		RefreshToken string `json:"refresh_token"`
+		Scope        string `json:"scope"`
 	}
 	decode(t, refresh, &refreshResp)
+	if refreshResp.Scope != "admin" {
+		t.Fatalf("refresh scope = %q", refreshResp.Scope)
+	}
 	if refreshResp.RefreshToken == loginResp.RefreshToken {
 		t.Fatal("refresh token was not rotated")
 	}
@@ -247,6 +301,155 @@ func TestRegisterLoginRefreshLogout(t *testing.T) {
 	mustStatus(t, logout, http.StatusOK)
 }
 
+func TestLoginRememberMeSelectsRefreshTTL(t *testing.T) {
+	ta := newTestApp(t)
+	ctx := context.Background()
+	ta.registerUser(t, "remember@example.com")
+
+	// lifetime checks the refresh_token_expires_in a response reports against
+	// the stored token and returns the token with its lifetime.
+	lifetime := func(w *httptest.ResponseRecorder) (string, time.Duration) {
+		t.Helper()
+		mustStatus(t, w, http.StatusOK)
+		var resp struct {
+			RefreshToken          string `json:"refresh_token"`
+			RefreshTokenExpiresIn int64  `json:"refresh_token_expires_in"`
+		}
+		decode(t, w, &resp)
+		stored, err := ta.repo.RefreshTokens().FindByTokenHash(ctx, auth.HashToken(resp.RefreshToken))
+		if err != nil || stored == nil {
+			t.Fatalf("find refresh token: %v", err)
+		}
+		d := stored.ExpiresAt.Sub(stored.CreatedAt).Round(time.Hour)
+		if reported := time.Duration(resp.RefreshTokenExpiresIn) * time.Second; reported != d {
+			t.Fatalf("refresh_token_expires_in = %v, stored lifetime %v", reported, d)
+		}
+		return resp.RefreshToken, d
+	}
+	refreshLifetime := func(rememberMe any) time.Duration {
+		t.Helper()
+		body := map[string]any{"email": "remember@example.com", "password": "Password1!"}
+		if rememberMe != nil {
+			body["remember_me"] = rememberMe
+		}
+		_, d := lifetime(ta.do(http.MethodPost, "/api/auth/login", body, ta.clientHeaders()))
+		return d
+	}
+
+	if got := refreshLifetime(nil); got != 30*24*time.Hour {
+		t.Fatalf("default lifetime = %v, want 30d", got)
+	}
+	if got := refreshLifetime(false); got != 24*time.Hour {
+		t.Fatalf("short lifetime = %v, want 1d", got)
+	}
+	if got := refreshLifetime(true); got != 60*24*time.Hour {
+		t.Fatalf("remember-me lifetime = %v, want 60d", got)
+	}
+
+	// Rotation keeps the lifetime granted at login, longer or shorter than
+	// the default, across every replacement.
+	for _, tc := range []struct {
+		rememberMe bool
+		want       time.Duration
+	}{{true, 60 * 24 * time.Hour}, {false, 24 * time.Hour}} {
+		token, _ := lifetime(ta.do(http.MethodPost, "/api/auth/login", map[string]any{
+			"email": "remember@example.com", "password": "Password1!", "remember_me": tc.rememberMe,
+		}, ta.clientHeaders()))
+		for i := 0; i < 2; i++ {
+			var got time.Duration
+			token, got = lifetime(ta.do(http.MethodPost, "/api/auth/refresh", map[string]any{
+				"refresh_token": token,
+			}, ta.clientHeaders()))
+			if got != tc.want {
+				t.Fatalf("remember_me=%v: lifetime after refresh %d = %v, want %v", tc.rememberMe, i+1, got, tc.want)
+			}
+		}
+	}
+}
+
+func TestRefreshReuseForcesPasswordChange(t *testing.T) {
+	ta := newTestApp(t)
+	ta.cfg.RefreshReuseForcePasswordChange = true
+
+	reg := ta.do(http.MethodPost, "/api/auth/register", map[string]any{
+		"email": "reuse@example.com", "password": "Password1!",
+	}, ta.clientHeaders())
+	mustStatus(t, reg, http.StatusCreated)
+	var regResp struct {
+		UserID       string `json:"user_id"`
+		AccessToken  string `json:"access_token"`
+		RefreshToken string `json:"refresh_token"`
+	}
+	decode(t, reg, &regResp)
+
+	rotated := ta.do(http.MethodPost, "/api/auth/refresh", map[string]any{
+		"refresh_token": regResp.RefreshToken,
+	}, ta.clientHeaders())
+	mustStatus(t, rotated, http.StatusOK)
+	var rotatedResp struct {
+		RefreshToken string `json:"refresh_token"`
+	}
+	decode(t, rotated, &rotatedResp)
+
+	reuse := ta.do(http.MethodPost, "/api/auth/refresh", map[string]any{
+		"refresh_token": regResp.RefreshToken,
+	}, ta.clientHeaders())
+	mustStatus(t, reuse, http.StatusUnauthorized)
+
+	// Reuse revokes every session, including the legitimately rotated one.
+	after := ta.do(http.MethodPost, "/api/auth/refresh", map[string]any{
+		"refresh_token": rotatedResp.RefreshToken,
+	}, ta.clientHeaders())
+	mustStatus(t, after, http.StatusUnauthorized)
+
+	me := ta.do(http.MethodGet, "/api/users/me", nil, ta.bearer(regResp.AccessToken))
+	mustStatus(t, me, http.StatusOK)
+	var prof struct {
+		PasswordChangeRequired bool `json:"password_change_required"`
+	}
+	decode(t, me, &prof)
+	if !prof.PasswordChangeRequired {
+		t.Fatal("expected password_change_required after reuse")
+	}
+
+	// No new tokens until the password is reset, by any login path.
+	login := func(password string) *httptest.ResponseRecorder {
+		return ta.do(http.MethodPost, "/api/auth/login", map[string]any{
+			"email": "reuse@example.com", "password": password,
+		}, ta.clientHeaders())
+	}
+	blocked := login("Password1!")
+	mustStatus(t, blocked, http.StatusForbidden)
+	var body struct {
+		Error string `json:"error"`
+	}
+	decode(t, blocked, &body)
+	if body.Error != "password_change_required" {
+		t.Fatalf("login error = %q, want password_change_required", body.Error)
+	}
+	grant := ta.do(http.MethodPost, "/oauth/token", map[string]any{
+		"grant_type": "password", "username": "reuse@example.com", "password": "Password1!",
+	}, map[string]string{"Authorization": basicAuth(ta.clientID, ta.clientSecret)})
+	mustStatus(t, grant, http.StatusForbidden)
+	// A wrong password still answers invalid_credentials.
+	mustStatus(t, login("Wrong1!pass"), http.StatusUnauthorized)
+
+	reset := ta.do(http.MethodPost, "/admin/users/"+regResp.UserID+"/reset-password", map[string]any{
+		"password": "Password2!",
+	}, ta.bearer(ta.adminToken))
+	mustStatus(t, reset, http.StatusOK)
+	get := ta.do(http.MethodGet, "/admin/users/"+regResp.UserID, nil, ta.bearer(ta.adminToken))
+	mustStatus(t, get, http.StatusOK)
+	var user struct {
+		PasswordChangeRequired bool `json:"password_change_required"`
+	}
+	decode(t, get, &user)
+	if user.PasswordChangeRequired {
+		t.Fatal("expected password reset to clear password_change_required")
+	}
+	mustStatus(t, login("Password2!"), http.StatusOK)
+}
+
 func TestMissingClientID(t *testing.T) {
 	ta := newTestApp(t)
 	w := ta.do(http.MethodPost, "/api/auth/login", map[string]any{
@@ -354,6 +557,39 @@ func TestAdminApplications(t *testing.T) {
 	}
 }
 
+func TestAdminListsHonorIfNoneMatch(t *testing.T) {
+	ta := newTestApp(t)
+
+	for _, path := range []string{"/admin/applications", "/admin/users"} {
+		first := ta.do(http.MethodGet, path, nil, ta.bearer(ta.adminToken))
+		mustStatus(t, first, http.StatusOK)
+		etag := first.Header().Get("ETag")
+		if !strings.HasPrefix(etag, `W/"`) {
+			t.Fatalf("%s: missing weak etag, got %q", path, etag)
+		}
+
+		headers := ta.bearer(ta.adminToken)
+		headers["If-None-Match"] = etag
+		cached := ta.do(http.MethodGet, path, nil, headers)
+		mustStatus(t, cached, http.StatusNotModified)
+		if cached.Body.Len() != 0 {
+			t.Fatalf("%s: 304 carried a body: %s", path, cached.Body.String())
+		}
+	}
+
+	before := ta.do(http.MethodGet, "/admin/users", nil, ta.bearer(ta.adminToken))
+	mustStatus(t, before, http.StatusOK)
+	create := ta.do(http.MethodPost, "/admin/users", map[string]any{
+		"email": "etag@example.com", "password": "Password1!",
+	}, ta.bearer(ta.adminToken))
+	mustStatus(t, create, http.StatusOK)
+
+	headers := ta.bearer(ta.adminToken)
+	headers["If-None-Match"] = before.Header().Get("ETag")
+	stale := ta.do(http.MethodGet, "/admin/users", nil, headers)
+	mustStatus(t, stale, http.StatusOK)
+}
+
 func TestAdminUsersCRUD(t *testing.T) {
 	ta := newTestApp(t)
 
@@ -722,7 +958,7 @@ func TestInviteCodeGrantsTestingUserType(t *testing.T) {
 	}
 	decode(t, ok, &reg)
 
-	claims, err := ta.jwt.VerifyAccessToken(reg.AccessToken)
+	claims, err := ta.jwt.VerifyAccessToken(context.Background(), reg.AccessToken)
 	if err != nil {
 		t.Fatalf("verify access token: %v", err)
 	}
@@ -823,21 +1059,27 @@ func TestOAuth2PasswordGrantAndIntrospect(t *testing.T) {
 	intr := ta.do(http.MethodPost, "/oauth/introspect", map[string]any{"token": tr.AccessToken}, map[string]string{"Authorization": basic})
 	mustStatus(t, intr, http.StatusOK)
 	var ir struct {
-		Active bool `json:"active"`
+		Active    bool   `json:"active"`
+		ClientID  string `json:"client_id"`
+		Username  string `json:"username"`
+		TokenType string `json:"token_type"`
+		Iat       int64  `json:"iat"`
+		Nbf       int64  `json:"nbf"`
 	}
 	decode(t, intr, &ir)
 	if !ir.Active {
 		t.Fatal("expected active token")
 	}
+	if ir.ClientID != ta.clientID || ir.Username != "oauthuser@example.com" || ir.TokenType != "Bearer" || ir.Iat == 0 || ir.Nbf != ir.Iat {
+		t.Fatalf("introspection = %+v", ir)
+	}
 
 	intr2 := ta.do(http.MethodPost, "/oauth/introspect", map[string]any{"token": "garbage"}, map[string]string{"Authorization": basic})
 	mustStatus(t, intr2, http.StatusOK)
-	var ir2 struct {
-		Active bool `json:"active"`
-	}
+	var ir2 map[string]any
 	decode(t, intr2, &ir2)
-	if ir2.Active {
-		t.Fatal("expected inactive token")
+	if ir2["active"] != false || len(ir2) != 1 {
+		t.Fatalf("inactive introspection = %v, want only active: false", ir2)
 	}
 
 	badBasic := basicAuth(ta.clientID, "wrongsecret")
@@ -847,6 +1089,57 @@ func TestOAuth2PasswordGrantAndIntrospect(t *testing.T) {
 	mustStatus(t, badTok, http.StatusUnauthorized)
 }
 
+func TestAuthorizationCodeGrantRejectsDeactivatedApp(t *testing.T) {
+	ta := newTestApp(t)
+	if ta.clientSecret == "" {
+		t.Skip("client secret not available")
+	}
+	ctx := context.Background()
+
+	create := ta.do(http.MethodPost, "/admin/users", map[string]any{
+		"email": "codeuser@example.com", "password": "Password1!", "role": "user",
+	}, ta.bearer(ta.adminToken))
+	mustStatus(t, create, http.StatusOK)
+	var u struct {
+		ID string `json:"id"`
+	}
+	decode(t, create, &u)
+
+	app, err := ta.repo.Applications().FindByClientID(ctx, ta.clientID)
+	if err != nil || app == nil {
+		t.Fatalf("find app: %v", err)
+	}
+	code := auth.GenerateAuthCode()
+	redirect := "https://app.example.com/cb"
+	if err := auth.StoreAuthCode(ctx, ta.repo, code, app.ID, u.ID, redirect, []string{"openid"}, nil, nil); err != nil {
+		t.Fatalf("store auth code: %v", err)
+	}
+
+	app.IsActive = false
+	if err := ta.repo.Applications().Update(ctx, app); err != nil {
+		t.Fatalf("deactivate app: %v", err)
+	}
+
+	basic := map[string]string{"Authorization": basicAuth(ta.clientID, ta.clientSecret)}
+	tok := ta.do(http.MethodPost, "/oauth/token", map[string]any{
+		"grant_type": "authorization_code", "code": code, "redirect_uri": redirect,
+	}, basic)
+	mustStatus(t, tok, http.StatusForbidden)
+	var body map[string]any
+	decode(t, tok, &body)
+	if body["error"] != "application_not_active" {
+		t.Fatalf("error = %v", body["error"])
+	}
+
+	stored, err := ta.repo.AuthCodes().FindByCode(ctx, code)
+	if err != nil || stored == nil {
+		t.Fatalf("find auth code: %v", err)
+	}
+	if stored.Used {
+		t.Fatal("pending code was consumed by a rejected exchange")
+	}
+}
+
 func TestProviderLoginTestProvider(t *testing.T) {
 	ta := newTestApp(t)
 	ctx := context.Background()
@@ -878,6 +1171,3402 @@ func TestProviderLoginTestProvider(t *testing.T) {
 	mustStatus(t, login2, http.StatusOK)
 }
 
+func TestListAccountsOrderedWithIDsAndPagination(t *testing.T) {
+	ta := newTestApp(t)
+	ctx := context.Background()
+
+	app, err := ta.repo.Applications().FindByClientID(ctx, ta.clientID)
+	if err != nil || app == nil {
+		t.Fatalf("find app: %v", err)
+	}
+	add := ta.do(http.MethodPost, "/admin/applications/"+app.ID+"/providers", map[string]any{
+		"provider_id": "test", "config": map[string]any{},
+	}, ta.bearer(ta.adminToken))
+	mustStatus(t, add, http.StatusOK)
+
+	tok := ta.registerUser(t, "accounts@example.com")
+	link := ta.do(http.MethodPost, "/api/users/me/accounts/test/link", map[string]any{
+		"credential": map[string]any{"account_id": "acct-linked"},
+	}, ta.bearer(tok))
+	mustStatus(t, link, http.StatusOK)
+
+	type account struct {
+		ID         string `json:"id"`
+		ProviderID string `json:"provider_id"`
+	}
+	list := ta.do(http.MethodGet, "/api/users/me/accounts", nil, ta.bearer(tok))
+	mustStatus(t, list, http.StatusOK)
+	var all []account
+	decode(t, list, &all)
+	if len(all) != 2 || all[0].ProviderID != "password" || all[1].ProviderID != "test" {
+		t.Fatalf("unexpected account order: %+v", all)
+	}
+	if all[0].ID == "" || all[1].ID == "" {
+		t.Fatalf("accounts missing ids: %+v", all)
+	}
+	if got := list.Header().Get("X-Total-Count"); got != "2" {
+		t.Fatalf("X-Total-Count = %q, want 2", got)
+	}
+
+	page := ta.do(http.MethodGet, "/api/users/me/accounts?page=2&per_page=1", nil, ta.bearer(tok))
+	mustStatus(t, page, http.StatusOK)
+	var second []account
+	decode(t, page, &second)
+	if len(second) != 1 || second[0].ID != all[1].ID {
+		t.Fatalf("unexpected second page: %+v", second)
+	}
+}
+
+func TestSupportRoleHasReadOnlyAdminAccess(t *testing.T) {
+	ta := newTestApp(t)
+
+	bad := ta.do(http.MethodPost, "/admin/users", map[string]any{
+		"email": "bogus@example.com", "password": "Password1!", "role": "superuser",
+	}, ta.bearer(ta.adminToken))
+	mustStatus(t, bad, http.StatusBadRequest)
+
+	created := ta.do(http.MethodPost, "/admin/users", map[string]any{
+		"email": "support@example.com", "password": "Password1!", "role": "support",
+	}, ta.bearer(ta.adminToken))
+	mustStatus(t, created, http.StatusOK)
+
+	login := ta.do(http.MethodPost, "/api/auth/login", map[string]any{
+		"email": "support@example.com", "password": "Password1!",
+	}, ta.clientHeaders())
+	mustStatus(t, login, http.StatusOK)
+	var tokens struct {
+		AccessToken string `json:"access_token"`
+	}
+	decode(t, login, &tokens)
+
+	mustStatus(t, ta.do(http.MethodGet, "/admin/users", nil, ta.bearer(tokens.AccessToken)), http.StatusOK)
+	mustStatus(t, ta.do(http.MethodGet, "/admin/stats", nil, ta.bearer(tokens.AccessToken)), http.StatusOK)
+	mustStatus(t, ta.do(http.MethodPost, "/admin/users", map[string]any{
+		"email": "other@example.com", "password": "Password1!",
+	}, ta.bearer(tokens.AccessToken)), http.StatusForbidden)
+
+	user := ta.registerUser(t, "plain@example.com")
+	mustStatus(t, ta.do(http.MethodGet, "/admin/stats", nil, ta.bearer(user)), http.StatusForbidden)
+}
+
+func TestRolePermissionGrants(t *testing.T) {
+	ta := newTestApp(t)
+	ta.cfg.Roles.Allowed = append(ta.cfg.Roles.Allowed, "helpdesk")
+	ta.cfg.Roles.Permissions = map[string][]string{"helpdesk": {domain.PermUsersRead, domain.PermUsersWrite}}
+	ta.engine = server.NewRouter(ta.repo, ta.jwt, ta.cfg)
+
+	created := ta.do(http.MethodPost, "/admin/users", map[string]any{
+		"email": "helpdesk@example.com", "password": "Password1!", "role": "helpdesk",
+	}, ta.bearer(ta.adminToken))
+	mustStatus(t, created, http.StatusOK)
+	var u struct {
+		ID string `json:"id"`
+	}
+	decode(t, created, &u)
+	tok, err := ta.jwt.IssueAccessToken(context.Background(), u.ID, ta.clientID, nil, "helpdesk", domain.MembershipRegular, domain.UserTypeRegular, nil, auth.TokenExtras{})
+	if err != nil {
+		t.Fatalf("issue token: %v", err)
+	}
+
+	mustStatus(t, ta.do(http.MethodGet, "/admin/users/"+u.ID, nil, ta.bearer(tok)), http.StatusOK)
+	mustStatus(t, ta.do(http.MethodPatch, "/admin/users/"+u.ID, map[string]any{"name": "Help"}, ta.bearer(tok)), http.StatusOK)
+	mustStatus(t, ta.do(http.MethodGet, "/admin/stats", nil, ta.bearer(tok)), http.StatusForbidden)
+	mustStatus(t, ta.do(http.MethodGet, "/admin/applications", nil, ta.bearer(tok)), http.StatusForbidden)
+
+	// users:write does not reach roles stronger than the caller's own.
+	mustStatus(t, ta.do(http.MethodPatch, "/admin/users/"+u.ID, map[string]any{"role": domain.RoleAdmin}, ta.bearer(tok)), http.StatusForbidden)
+	mustStatus(t, ta.do(http.MethodPost, "/admin/users", map[string]any{
+		"email": "escalate@example.com", "password": "Password1!", "role": domain.RoleAdmin,
+	}, ta.bearer(tok)), http.StatusForbidden)
+	mustStatus(t, ta.do(http.MethodPost, "/admin/users", map[string]any{
+		"email": "peer@example.com", "password": "Password1!", "role": "helpdesk",
+	}, ta.bearer(tok)), http.StatusOK)
+	admin := ta.do(http.MethodPost, "/admin/users", map[string]any{
+		"email": "second-admin@example.com", "password": "Password1!", "role": domain.RoleAdmin,
+	}, ta.bearer(ta.adminToken))
+	mustStatus(t, admin, http.StatusOK)
+	var a struct {
+		ID string `json:"id"`
+	}
+	decode(t, admin, &a)
+	mustStatus(t, ta.do(http.MethodPatch, "/admin/users/"+a.ID, map[string]any{"role": domain.RoleUser}, ta.bearer(tok)), http.StatusForbidden)
+
+	// Nor does it reach any other change to a stronger user: a takeover
+	// through a password reset, disabling, unlinking or deleting the admin.
+	for _, tc := range []struct {
+		method, path string
+		body         map[string]any
+	}{
+		{http.MethodPatch, "/admin/users/" + a.ID, map[string]any{"is_active": false}},
+		{http.MethodPost, "/admin/users/" + a.ID + "/reset-password", map[string]any{"password": "Password2!"}},
+		{http.MethodPost, "/admin/users/" + a.ID + "/unlock", nil},
+		{http.MethodDelete, "/admin/users/" + a.ID + "/accounts/password", nil},
+		{http.MethodDelete, "/admin/users/" + a.ID, nil},
+	} {
+		w := ta.do(tc.method, tc.path, tc.body, ta.bearer(tok))
+		if w.Code != http.StatusForbidden {
+			t.Fatalf("%s %s = %d, want 403: %s", tc.method, tc.path, w.Code, w.Body.String())
+		}
+	}
+	mustStatus(t, ta.do(http.MethodPost, "/api/auth/login", map[string]any{
+		"email": "second-admin@example.com", "password": "Password1!",
+	}, ta.clientHeaders()), http.StatusOK)
+	mustStatus(t, ta.do(http.MethodPost, "/admin/users/"+u.ID+"/reset-password", map[string]any{
+		"password": "Password2!",
+	}, ta.bearer(tok)), http.StatusOK)
+}
+
+func TestTimestampsAreRFC3339(t *testing.T) {
+	ta := newTestApp(t)
+	tok := ta.registerUser(t, "rfc3339@example.com")
+
+	me := ta.do(http.MethodGet, "/api/users/me", nil, ta.bearer(tok))
+	mustStatus(t, me, http.StatusOK)
+	var prof struct {
+		CreatedAt string `json:"created_at"`
+	}
+	decode(t, me, &prof)
+	if _, err := time.Parse(time.RFC3339Nano, prof.CreatedAt); err != nil || !strings.HasSuffix(prof.CreatedAt, "Z") {
+		t.Fatalf("created_at %q is not RFC 3339 UTC: %v", prof.CreatedAt, err)
+	}
+}
+
+func TestListClientProvidersHidesConfig(t *testing.T) {
+	ta := newTestApp(t)
+	ctx := context.Background()
+
+	app, err := ta.repo.Applications().FindByClientID(ctx, ta.clientID)
+	if err != nil || app == nil {
+		t.Fatalf("find app: %v", err)
+	}
+	add := ta.do(http.MethodPost, "/admin/applications/"+app.ID+"/providers", map[string]any{
+		"provider_id": "wechat", "config": map[string]any{"appid": "wx1", "secret": "top-secret"},
+		"display_name": "WeChat", "icon_url": "https://example.com/wechat.svg", "sort_order": -1,
+	}, ta.bearer(ta.adminToken))
+	mustStatus(t, add, http.StatusOK)
+
+	mustStatus(t, ta.do(http.MethodGet, "/api/auth/providers", nil, nil), http.StatusBadRequest)
+
+	w := ta.do(http.MethodGet, "/api/auth/providers", nil, ta.clientHeaders())
+	mustStatus(t, w, http.StatusOK)
+	if strings.Contains(w.Body.String(), "top-secret") {
+		t.Fatalf("provider config leaked: %s", w.Body.String())
+	}
+	var list []map[string]any
+	decode(t, w, &list)
+	ids := map[any]bool{}
+	for _, p := range list {
+		ids[p["provider_id"]] = true
+		if _, ok := p["config"]; ok {
+			t.Fatalf("provider config exposed: %+v", p)
+		}
+	}
+	if len(list) != 2 || !ids["password"] || !ids["wechat"] {
+		t.Fatalf("unexpected providers: %+v", list)
+	}
+	if list[0]["provider_id"] != "wechat" || list[0]["display_name"] != "WeChat" || list[0]["icon_url"] != "https://example.com/wechat.svg" {
+		t.Fatalf("display metadata/order not applied: %+v", list)
+	}
+}
+
+func TestProviderTokensStoredEncrypted(t *testing.T) {
+	ta := newTestApp(t)
+	ctx := context.Background()
+	ta.cfg.DataKeys = config.DataKeys{Version: 1, Keys: map[int][]byte{1: make([]byte, 32)}}
+	ta.engine = server.NewRouter(ta.repo, ta.jwt, ta.cfg)
+
+	app, err := ta.repo.Applications().FindByClientID(ctx, ta.clientID)
+	if err != nil || app == nil {
+		t.Fatalf("find app: %v", err)
+	}
+	add := ta.do(http.MethodPost, "/admin/applications/"+app.ID+"/providers", map[string]any{
+		"provider_id": "test", "config": map[string]any{},
+	}, ta.bearer(ta.adminToken))
+	mustStatus(t, add, http.StatusOK)
+
+	login := ta.do(http.MethodPost, "/api/auth/provider/test/login", map[string]any{
+		"credential": map[string]any{"account_id": "acct-tokens", "refresh_token": "upstream-refresh"},
+	}, ta.clientHeaders())
+	mustStatus(t, login, http.StatusOK)
+	var tokens struct {
+		AccessToken string `json:"access_token"`
+	}
+	decode(t, login, &tokens)
+	claims, err := ta.jwt.VerifyAccessToken(context.Background(), tokens.AccessToken)
+	if err != nil {
+		t.Fatalf("verify: %v", err)
+	}
+
+	account, err := ta.repo.Accounts().FindByUserAndProvider(ctx, claims.Sub, "test")
+	if err != nil || account == nil || account.ProviderTokens == nil {
+		t.Fatalf("provider tokens not stored: %+v, %v", account, err)
+	}
+	if strings.Contains(*account.ProviderTokens, "upstream-refresh") {
+		t.Fatal("provider tokens stored in plaintext")
+	}
+	got, err := auth.ProviderTokens(ctx, ta.repo, claims.Sub, "test")
+	if err != nil || got == nil || got.RefreshToken != "upstream-refresh" {
+		t.Fatalf("ProviderTokens = %+v, %v", got, err)
+	}
+
+	list := ta.do(http.MethodGet, "/api/users/me/accounts", nil, ta.bearer(tokens.AccessToken))
+	mustStatus(t, list, http.StatusOK)
+	if strings.Contains(list.Body.String(), "upstream-refresh") || strings.Contains(list.Body.String(), "v1:") {
+		t.Fatalf("provider tokens exposed: %s", list.Body.String())
+	}
+}
+
+func TestProviderConfigSecretsSealedAtRest(t *testing.T) {
+	ta := newTestApp(t)
+	ctx := context.Background()
+	ta.cfg.DataKeys = config.DataKeys{Version: 1, Keys: map[int][]byte{1: make([]byte, 32)}}
+	ta.engine = server.NewRouter(ta.repo, ta.jwt, ta.cfg)
+
+	app, err := ta.repo.Applications().FindByClientID(ctx, ta.clientID)
+	if err != nil || app == nil {
+		t.Fatalf("find app: %v", err)
+	}
+	add := ta.do(http.MethodPost, "/admin/applications/"+app.ID+"/providers", map[string]any{
+		"provider_id": "wechat", "config": map[string]any{"appid": "wx1", "secret": "top-secret"},
+	}, ta.bearer(ta.adminToken))
+	mustStatus(t, add, http.StatusOK)
+
+	stored, err := ta.repo.AppProviders().FindByAppAndProvider(ctx, app.ID, "wechat")
+	if err != nil || stored == nil {
+		t.Fatalf("find provider: %v", err)
+	}
+	if strings.Contains(stored.Config, "top-secret") || !strings.Contains(stored.Config, `"appid":"wx1"`) {
+		t.Fatalf("config not selectively sealed: %s", stored.Config)
+	}
+
+	list := ta.do(http.MethodGet, "/admin/applications/"+app.ID+"/providers", nil, ta.bearer(ta.adminToken))
+	mustStatus(t, list, http.StatusOK)
+	if !strings.Contains(list.Body.String(), "top-secret") {
+		t.Fatalf("config not opened on read: %s", list.Body.String())
+	}
+}
+
+func TestSeedCheckOnlyWritesNothing(t *testing.T) {
+	ta := newTestApp(t)
+	ctx := context.Background()
+
+	pw := "Password1!"
+	res, err := seed.Bootstrap(ctx, ta.repo, "new-admin@example.com", &pw, seed.Options{CheckOnly: true})
+	if err != nil {
+		t.Fatalf("check: %v", err)
+	}
+	if !res.CheckOnly || res.AppAction != "exists" || res.UserAction != "created" || res.AppClientSecret != nil {
+		t.Fatalf("unexpected check result: %+v", res)
+	}
+	if u, err := ta.repo.Users().FindByEmail(ctx, "new-admin@example.com"); err != nil || u != nil {
+		t.Fatalf("check-only created a user: %+v, %v", u, err)
+	}
+
+	ta.registerUser(t, "to-promote@example.com")
+	res, err = seed.Bootstrap(ctx, ta.repo, "to-promote@example.com", nil, seed.Options{CheckOnly: true})
+	if err != nil || res.UserAction != "promoted" {
+		t.Fatalf("check promote = %+v, %v", res, err)
+	}
+	if u, _ := ta.repo.Users().FindByEmail(ctx, "to-promote@example.com"); u == nil || u.Role != domain.RoleUser {
+		t.Fatalf("check-only changed the role: %+v", u)
+	}
+}
+
+func TestSeedCustomAppAndRole(t *testing.T) {
+	ta := newTestApp(t)
+	ctx := context.Background()
+
+	pw := "Password1!"
+	res, err := seed.Bootstrap(ctx, ta.repo, "ops@example.com", &pw, seed.Options{
+		AppName:       "Ops Console",
+		RedirectURIs:  []string{"https://ops.example.com/callback"},
+		AllowedScopes: []string{"openid", "admin"},
+		Role:          domain.RoleSupport,
+	})
+	if err != nil {
+		t.Fatalf("bootstrap: %v", err)
+	}
+	if res.AppAction != "created" || res.UserAction != "created" {
+		t.Fatalf("unexpected result: %+v", res)
+	}
+	app, err := ta.repo.Applications().FindByName(ctx, "Ops Console")
+	if err != nil || app == nil {
+		t.Fatalf("app not created: %v", err)
+	}
+	if app.RedirectURIs != `["https://ops.example.com/callback"]` || app.AllowedScopes != `["openid","admin"]` {
+		t.Fatalf("app config = %s %s", app.RedirectURIs, app.AllowedScopes)
+	}
+	user, _ := ta.repo.Users().FindByEmail(ctx, "ops@example.com")
+	if user == nil || user.Role != domain.RoleSupport {
+		t.Fatalf("user = %+v", user)
+	}
+}
+
+func TestSeedCreateApplicationIssuesUsableCredentials(t *testing.T) {
+	ta := newTestApp(t)
+	ctx := context.Background()
+
+	app, secret, err := seed.CreateApplication(ctx, ta.repo, "CI App", []string{"https://ci.example.com/cb"}, []string{"read"})
+	if err != nil {
+		t.Fatalf("create app: %v", err)
+	}
+	if secret == "" || app.ClientSecretHash == secret {
+		t.Fatalf("secret must be returned in plaintext and stored hashed")
+	}
+	w := ta.do(http.MethodPost, "/oauth/token", map[string]any{"grant_type": "client_credentials"}, map[string]string{
+		"Authorization": basicAuth(app.ClientID, secret),
+	})
+	mustStatus(t, w, http.StatusOK)
+}
+
+func TestSeededAppSecretUsesFastHash(t *testing.T) {
+	ta := newTestApp(t)
+	app, err := ta.repo.Applications().FindByClientID(context.Background(), ta.clientID)
+	if err != nil || app == nil {
+		t.Fatalf("seeded app: %v", err)
+	}
+	if !strings.HasPrefix(app.ClientSecretHash, "sha256") {
+		t.Fatalf("seeded app secret hash = %q, want the sha256 scheme used by the admin API", app.ClientSecretHash)
+	}
+}
+
+func TestOpaqueAccessTokens(t *testing.T) {
+	ta := newTestApp(t)
+	ta.cfg.AccessTokenFormat = config.AccessTokenFormatOpaque
+	ta.engine = server.NewRouter(ta.repo, ta.jwt, ta.cfg)
+
+	token := ta.registerUser(t, "opaque@example.com")
+	if !strings.HasPrefix(token, "at_") || strings.Count(token, ".") != 0 {
+		t.Fatalf("expected an opaque token, got %q", token)
+	}
+	stored, err := ta.repo.AccessTokens().FindByTokenHash(context.Background(), auth.HashToken(token))
+	if err != nil || stored == nil {
+		t.Fatalf("opaque token not stored by hash: %v", err)
+	}
+	mustStatus(t, ta.do(http.MethodGet, "/api/users/me", nil, ta.bearer(token)), http.StatusOK)
+
+	basic := map[string]string{"Authorization": basicAuth(ta.clientID, ta.clientSecret)}
+	intr := ta.do(http.MethodPost, "/oauth/introspect", map[string]any{"token": token}, basic)
+	mustStatus(t, intr, http.StatusOK)
+	var ir struct {
+		Active bool   `json:"active"`
+		Sub    string `json:"sub"`
+	}
+	decode(t, intr, &ir)
+	if !ir.Active || ir.Sub != stored.Subject {
+		t.Fatalf("introspect = %+v", ir)
+	}
+
+	mustStatus(t, ta.do(http.MethodPost, "/oauth/revoke", map[string]any{"token": token}, basic), http.StatusOK)
+	mustStatus(t, ta.do(http.MethodGet, "/api/users/me", nil, ta.bearer(token)), http.StatusUnauthorized)
+	intr = ta.do(http.MethodPost, "/oauth/introspect", map[string]any{"token": token}, basic)
+	decode(t, intr, &ir)
+	if ir.Active {
+		t.Fatal("revoked opaque token is still active")
+	}
+
+	// JWTs issued before the switch keep working until they expire.
+	mustStatus(t, ta.do(http.MethodGet, "/admin/stats", nil, ta.bearer(ta.adminToken)), http.StatusOK)
+}
+
+func TestRevokeRefreshTokensBefore(t *testing.T) {
+	ta := newTestApp(t)
+	ta.registerUser(t, "cutoff@example.com")
+	login := ta.do(http.MethodPost, "/api/auth/login", map[string]any{
+		"email": "cutoff@example.com", "password": "Password1!",
+	}, ta.clientHeaders())
+	mustStatus(t, login, http.StatusOK)
+	var lr struct {
+		RefreshToken string `json:"refresh_token"`
+	}
+	decode(t, login, &lr)
+
+	past := ta.do(http.MethodPost, "/admin/tokens/revoke-before", map[string]any{
+		"before": "2000-01-01T00:00:00Z",
+	}, ta.bearer(ta.adminToken))
+	mustStatus(t, past, http.StatusOK)
+	var pr struct {
+		Revoked int64 `json:"revoked"`
+	}
+	decode(t, past, &pr)
+	if pr.Revoked != 0 {
+		t.Fatalf("cutoff in the past revoked %d tokens", pr.Revoked)
+	}
+
+	time.Sleep(10 * time.Millisecond)
+	now := ta.do(http.MethodPost, "/admin/tokens/revoke-before", map[string]any{}, ta.bearer(ta.adminToken))
+	mustStatus(t, now, http.StatusOK)
+	decode(t, now, &pr)
+	if pr.Revoked < 2 {
+		t.Fatalf("revoked = %d, want the register and login tokens", pr.Revoked)
+	}
+	refresh := ta.do(http.MethodPost, "/api/auth/refresh", map[string]any{"refresh_token": lr.RefreshToken}, ta.clientHeaders())
+	mustStatus(t, refresh, http.StatusUnauthorized)
+
+	bad := ta.do(http.MethodPost, "/admin/tokens/revoke-before", map[string]any{"before": "yesterday"}, ta.bearer(ta.adminToken))
+	mustStatus(t, bad, http.StatusBadRequest)
+}
+
+func TestRequestTimeoutReturns504(t *testing.T) {
+	ta := newTestApp(t)
+	ta.cfg.RequestTimeoutSecs = 1
+	ta.engine = server.NewRouter(ta.repo, ta.jwt, ta.cfg)
+	// Registered after NewRouter, so it runs behind the global middleware.
+	ta.engine.GET("/slow", func(c *gin.Context) {
+		<-c.Request.Context().Done()
+		middleware.RespondError(c, c.Request.Context().Err())
+	})
+
+	w := ta.do(http.MethodGet, "/slow", nil, nil)
+	mustStatus(t, w, http.StatusGatewayTimeout)
+	var body struct {
+		Error string `json:"error"`
+	}
+	decode(t, w, &body)
+	if body.Error != "request_timeout" {
+		t.Fatalf("error = %q", body.Error)
+	}
+	mustStatus(t, ta.do(http.MethodGet, "/health", nil, nil), http.StatusOK)
+}
+
+func TestLoginLockoutReportsAttemptsRemaining(t *testing.T) {
+	ta := newTestApp(t)
+	ta.cfg.LoginMaxFailedAttempts = 3
+	ta.cfg.LoginLockoutSecs = 60
+	ta.engine = server.NewRouter(ta.repo, ta.jwt, ta.cfg)
+	ta.registerUser(t, "lockout@example.com")
+
+	login := func(email, password string) *httptest.ResponseRecorder {
+		return ta.do(http.MethodPost, "/api/auth/login", map[string]any{"email": email, "password": password}, ta.clientHeaders())
+	}
+
+	// An unknown email counts down and locks like a real account.
+	for _, want := range []string{"2", "1", "0"} {
+		w := login("nobody@example.com", "Wrong1!xx")
+		mustStatus(t, w, http.StatusUnauthorized)
+		if got := w.Header().Get("X-Login-Attempts-Remaining"); got != want {
+			t.Fatalf("unknown email remaining = %q, want %q", got, want)
+		}
+	}
+	mustStatus(t, login("nobody@example.com", "Wrong1!xx"), http.StatusTooManyRequests)
+
+	for _, want := range []string{"2", "1", "0"} {
+		w := login("lockout@example.com", "Wrong1!xx")
+		mustStatus(t, w, http.StatusUnauthorized)
+		if got := w.Header().Get("X-Login-Attempts-Remaining"); got != want {
+			t.Fatalf("remaining = %q, want %q", got, want)
+		}
+	}
+	locked := login("lockout@example.com", "Password1!")
+	mustStatus(t, locked, http.StatusTooManyRequests)
+
+	// An admin unlock lets the right password in and resets the count.
+	user, err := ta.repo.Users().FindByEmail(context.Background(), "lockout@example.com")
+	if err != nil || user == nil || user.LockedUntil == nil {
+		t.Fatalf("expected a locked user, got %+v, %v", user, err)
+	}
+	unlock := ta.do(http.MethodPost, "/admin/users/"+user.ID+"/unlock", nil, ta.bearer(ta.adminToken))
+	mustStatus(t, unlock, http.StatusOK)
+	var unlocked struct {
+		LockedUntil *string `json:"locked_until"`
+	}
+	decode(t, unlock, &unlocked)
+	if unlocked.LockedUntil != nil {
+		t.Fatalf("locked_until = %v after unlock", *unlocked.LockedUntil)
+	}
+	mustStatus(t, login("lockout@example.com", "Password1!"), http.StatusOK)
+	w := login("lockout@example.com", "Wrong1!xx")
+	if got := w.Header().Get("X-Login-Attempts-Remaining"); got != "2" {
+		t.Fatalf("remaining after success = %q, want 2", got)
+	}
+}
+
+func TestPasswordGrantLockout(t *testing.T) {
+	ta := newTestApp(t)
+	if ta.clientSecret == "" {
+		t.Skip("client secret not available")
+	}
+	ta.cfg.LoginMaxFailedAttempts = 2
+	ta.cfg.LoginLockoutSecs = 60
+	ta.engine = server.NewRouter(ta.repo, ta.jwt, ta.cfg)
+	ta.registerUser(t, "grant-lockout@example.com")
+
+	basic := map[string]string{"Authorization": basicAuth(ta.clientID, ta.clientSecret)}
+	grant := func(password string) *httptest.ResponseRecorder {
+		return ta.do(http.MethodPost, "/oauth/token", map[string]any{
+			"grant_type": "password", "username": "grant-lockout@example.com", "password": password,
+		}, basic)
+	}
+	for _, want := range []string{"1", "0"} {
+		w := grant("Wrong1!xx")
+		mustStatus(t, w, http.StatusUnauthorized)
+		if got := w.Header().Get("X-Login-Attempts-Remaining"); got != want {
+			t.Fatalf("remaining = %q, want %q", got, want)
+		}
+	}
+	// The lock set through the grant holds for /api/auth/login too.
+	mustStatus(t, grant("Password1!"), http.StatusTooManyRequests)
+	mustStatus(t, ta.do(http.MethodPost, "/api/auth/login", map[string]any{
+		"email": "grant-lockout@example.com", "password": "Password1!",
+	}, ta.clientHeaders()), http.StatusTooManyRequests)
+}
+
+func TestLoginPairThrottle(t *testing.T) {
+	ta := newTestApp(t)
+	ta.cfg.LoginMaxFailedAttempts, ta.cfg.LoginLockoutSecs = 10, 60
+	ta.cfg.LoginPairMaxFailures, ta.cfg.LoginPairFailureWindowSecs, ta.cfg.LoginPairBlockSecs = 3, 60, 600
+	ta.cfg.TrustedProxies = []string{"192.0.2.1"}
+	ta.engine = server.NewRouter(ta.repo, ta.jwt, ta.cfg)
+	ta.registerUser(t, "victim@example.com")
+
+	login := func(ip, email, password string) *httptest.ResponseRecorder {
+		h := ta.clientHeaders()
+		h["X-Forwarded-For"] = ip
+		return ta.do(http.MethodPost, "/api/auth/login", map[string]any{"email": email, "password": password}, h)
+	}
+	for range 3 {
+		mustStatus(t, login("203.0.113.5", "victim@example.com", "Wrong1!xx"), http.StatusUnauthorized)
+	}
+	// The pair is refused even with the right password, whatever the case.
+	w := login("203.0.113.5", "Victim@Example.com", "Password1!")
+	mustStatus(t, w, http.StatusTooManyRequests)
+	if w.Header().Get("Retry-After") == "" {
+		t.Fatal("missing Retry-After")
+	}
+	var body struct {
+		Error string `json:"error"`
+	}
+	decode(t, w, &body)
+	if body.Error != "login_throttled" {
+		t.Fatalf("error = %q", body.Error)
+	}
+	// Other emails from that address are separate pairs.
+	mustStatus(t, login("203.0.113.5", "other@example.com", "Wrong1!xx"), http.StatusUnauthorized)
+
+	// The account itself is not locked: only the attempts before the block
+	// counted, and its owner signs in from elsewhere.
+	user, err := ta.repo.Users().FindByEmail(context.Background(), "victim@example.com")
+	if err != nil || user == nil || user.LockedUntil != nil || user.FailedLoginAttempts != 3 {
+		t.Fatalf("user = %+v, %v", user, err)
+	}
+	mustStatus(t, login("198.51.100.7", "victim@example.com", "Password1!"), http.StatusOK)
+
+	// A successful login clears the pair's count.
+	for range 2 {
+		mustStatus(t, login("198.51.100.8", "victim@example.com", "Wrong1!xx"), http.StatusUnauthorized)
+	}
+	mustStatus(t, login("198.51.100.8", "victim@example.com", "Password1!"), http.StatusOK)
+	for range 2 {
+		mustStatus(t, login("198.51.100.8", "victim@example.com", "Wrong1!xx"), http.StatusUnauthorized)
+	}
+	mustStatus(t, login("198.51.100.8", "victim@example.com", "Password1!"), http.StatusOK)
+
+	// Without a trusted proxy in front, a rotated X-Forwarded-For does not
+	// make a fresh pair.
+	ta.cfg.TrustedProxies = nil
+	ta.engine = server.NewRouter(ta.repo, ta.jwt, ta.cfg)
+	for i := range 3 {
+		mustStatus(t, login("203.0.113."+strconv.Itoa(20+i), "victim@example.com", "Wrong1!xx"), http.StatusUnauthorized)
+	}
+	mustStatus(t, login("203.0.113.30", "victim@example.com", "Password1!"), http.StatusTooManyRequests)
+}
+
+func TestSessionCheck(t *testing.T) {
+	ta := newTestApp(t)
+	token := ta.registerUser(t, "session@example.com")
+
+	w := ta.do(http.MethodGet, "/api/auth/session", nil, ta.bearer(token))
+	mustStatus(t, w, http.StatusOK)
+	var resp struct {
+		Active    bool     `json:"active"`
+		UserID    string   `json:"user_id"`
+		ExpiresAt string   `json:"expires_at"`
+		Scopes    []string `json:"scopes"`
+	}
+	decode(t, w, &resp)
+	exp, err := time.Parse(time.RFC3339, resp.ExpiresAt)
+	if !resp.Active || resp.UserID == "" || err != nil || !exp.After(time.Now()) {
+		t.Fatalf("unexpected session: %+v (%v)", resp, err)
+	}
+	if len(resp.Scopes) != 1 || resp.Scopes[0] != "admin" {
+		t.Fatalf("scopes = %v", resp.Scopes)
+	}
+
+	mustStatus(t, ta.do(http.MethodGet, "/api/auth/session", nil, nil), http.StatusUnauthorized)
+
+	// Disabling the user ends the session even though the token is unexpired.
+	user, err := ta.repo.Users().FindByID(context.Background(), resp.UserID)
+	if err != nil || user == nil {
+		t.Fatalf("find user: %v", err)
+	}
+	user.IsActive = false
+	if err := ta.repo.Users().Update(context.Background(), user); err != nil {
+		t.Fatal(err)
+	}
+	mustStatus(t, ta.do(http.MethodGet, "/api/auth/session", nil, ta.bearer(token)), http.StatusForbidden)
+}
+
+func TestRegisterRequiresCaptcha(t *testing.T) {
+	ta := newTestApp(t)
+	ta.cfg.CaptchaEnabled = true
+	ta.cfg.CaptchaProvider = "turnstile"
+	ta.cfg.CaptchaSecret = "secret"
+	ta.engine = server.NewRouter(ta.repo, ta.jwt, ta.cfg)
+
+	w := ta.do(http.MethodPost, "/api/auth/register", map[string]any{
+		"email": "bot@example.com", "password": "Password1!",
+	}, ta.clientHeaders())
+	mustStatus(t, w, http.StatusBadRequest)
+	if u, _ := ta.repo.Users().FindByEmail(context.Background(), "bot@example.com"); u != nil {
+		t.Fatal("user created without a captcha token")
+	}
+}
+
+func TestApplicationCustomClaims(t *testing.T) {
+	ta := newTestApp(t)
+	ctx := context.Background()
+
+	create := ta.do(http.MethodPost, "/admin/applications", map[string]any{
+		"name":          "tenant-app",
+		"custom_claims": map[string]any{"tenant_id": "t-1", "sub": "spoofed"},
+	}, ta.bearer(ta.adminToken))
+	mustStatus(t, create, http.StatusOK)
+	var app struct {
+		ID           string         `json:"id"`
+		ClientID     string         `json:"client_id"`
+		ClientSecret string         `json:"client_secret"`
+		CustomClaims map[string]any `json:"custom_claims"`
+	}
+	decode(t, create, &app)
+	if app.CustomClaims["tenant_id"] != "t-1" {
+		t.Fatalf("custom_claims = %v", app.CustomClaims)
+	}
+
+	ta.registerUser(t, "tenant@example.com")
+	pw := ta.do(http.MethodPost, "/oauth/token", map[string]any{
+		"grant_type": "password", "username": "tenant@example.com", "password": "Password1!",
+	}, map[string]string{"Authorization": basicAuth(app.ClientID, app.ClientSecret)})
+	mustStatus(t, pw, http.StatusOK)
+	var tr struct {
+		AccessToken string `json:"access_token"`
+	}
+	decode(t, pw, &tr)
+	claims, err := ta.jwt.VerifyAccessToken(ctx, tr.AccessToken)
+	if err != nil {
+		t.Fatal(err)
+	}
+	// Custom claims are namespaced under "ext" and cannot replace sub.
+	if claims.Ext["tenant_id"] != "t-1" || claims.Sub == "spoofed" {
+		t.Fatalf("unexpected claims: %+v", claims)
+	}
+
+	cc := ta.do(http.MethodPost, "/oauth/token", map[string]any{"grant_type": "client_credentials"}, map[string]string{
+		"Authorization": basicAuth(app.ClientID, app.ClientSecret),
+	})
+	mustStatus(t, cc, http.StatusOK)
+	decode(t, cc, &tr)
+	appClaims, err := ta.jwt.VerifyAppToken(ctx, tr.AccessToken)
+	if err != nil || appClaims.Ext["tenant_id"] != "t-1" || appClaims.Sub != app.ID {
+		t.Fatalf("app token claims = %+v, %v", appClaims, err)
+	}
+
+	// An empty object clears them; oversized claims are rejected.
+	cleared := ta.do(http.MethodPatch, "/admin/applications/"+app.ID, map[string]any{"custom_claims": map[string]any{}}, ta.bearer(ta.adminToken))
+	mustStatus(t, cleared, http.StatusOK)
+	if stored, _ := ta.repo.Applications().FindByID(ctx, app.ID); stored == nil || stored.CustomClaims != "" {
+		t.Fatalf("custom claims not cleared: %+v", stored)
+	}
+	big := ta.do(http.MethodPatch, "/admin/applications/"+app.ID, map[string]any{
+		"custom_claims": map[string]any{"blob": strings.Repeat("x", 4096)},
+	}, ta.bearer(ta.adminToken))
+	mustStatus(t, big, http.StatusBadRequest)
+}
+
+func TestCustomAttributesOwnershipLimitAndClaims(t *testing.T) {
+	ta := newTestApp(t)
+	ta.cfg.CustomAttributesAdminOnly = []string{"external_id", "tier"}
+	ta.cfg.CustomAttributesTokenClaims = []string{"external_id", "tier"}
+	ta.engine = server.NewRouter(ta.repo, ta.jwt, ta.cfg)
+	token := ta.registerUser(t, "attrs@example.com")
+
+	patchMe := func(attrs map[string]any) *httptest.ResponseRecorder {
+		return ta.do(http.MethodPatch, "/api/users/me", map[string]any{"custom_attributes": attrs}, ta.bearer(token))
+	}
+	mustStatus(t, patchMe(map[string]any{"external_id": "x-1"}), http.StatusBadRequest)
+	mustStatus(t, patchMe(map[string]any{"bio": strings.Repeat("x", 9000)}), http.StatusBadRequest)
+	mustStatus(t, patchMe(map[string]any{"locale": "en", "theme": "dark"}), http.StatusOK)
+
+	user, err := ta.repo.Users().FindByEmail(context.Background(), "attrs@example.com")
+	if err != nil || user == nil {
+		t.Fatalf("find user: %v", err)
+	}
+	mustStatus(t, patchMe(map[string]any{"tier": "gold"}), http.StatusBadRequest)
+	admin := ta.do(http.MethodPatch, "/admin/users/"+user.ID, map[string]any{
+		"custom_attributes": map[string]any{"external_id": "x-1", "tier": "gold"},
+	}, ta.bearer(ta.adminToken))
+	mustStatus(t, admin, http.StatusOK)
+
+	login := ta.do(http.MethodPost, "/api/auth/login", map[string]any{"email": "attrs@example.com", "password": "Password1!"}, ta.clientHeaders())
+	mustStatus(t, login, http.StatusOK)
+	var lr struct {
+		AccessToken string `json:"access_token"`
+	}
+	decode(t, login, &lr)
+	claims, err := ta.jwt.VerifyAccessToken(context.Background(), lr.AccessToken)
+	if err != nil {
+		t.Fatal(err)
+	}
+	// Only the configured keys reach the token; user-set ones never do.
+	want := map[string]any{"external_id": "x-1", "tier": "gold"}
+	if len(claims.Attributes) != len(want) || claims.Attributes["external_id"] != "x-1" || claims.Attributes["tier"] != "gold" {
+		t.Fatalf("attributes claim = %v, want %v", claims.Attributes, want)
+	}
+}
+
+func TestGzipCompression(t *testing.T) {
+	ta := newTestApp(t)
+	for i := 0; i < 10; i++ {
+		ta.registerUser(t, "gzip"+strconv.Itoa(i)+"@example.com")
+	}
+	headers := ta.bearer(ta.adminToken)
+	headers["Accept-Encoding"] = "gzip, br"
+
+	w := ta.do(http.MethodGet, "/admin/users?per_page=50", nil, headers)
+	mustStatus(t, w, http.StatusOK)
+	if w.Header().Get("Content-Encoding") != "gzip" {
+		t.Fatalf("large response not compressed: %v", w.Header())
+	}
+	zr, err := gzip.NewReader(w.Body)
+	if err != nil {
+		t.Fatal(err)
+	}
+	var page struct {
+		Users []json.RawMessage `json:"users"`
+	}
+	if err := json.NewDecoder(zr).Decode(&page); err != nil || len(page.Users) < 10 {
+		t.Fatalf("decode gzip body: %d users, %v", len(page.Users), err)
+	}
+
+	small := ta.do(http.MethodGet, "/health", nil, map[string]string{"Accept-Encoding": "gzip"})
+	mustStatus(t, small, http.StatusOK)
+	if small.Header().Get("Content-Encoding") != "" {
+		t.Fatal("tiny response should not be compressed")
+	}
+	plain := ta.do(http.MethodGet, "/admin/users?per_page=50", nil, ta.bearer(ta.adminToken))
+	if plain.Header().Get("Content-Encoding") != "" {
+		t.Fatal("compressed without Accept-Encoding")
+	}
+}
+
+func TestSecurityHeaders(t *testing.T) {
+	ta := newTestApp(t)
+	health := ta.do(http.MethodGet, "/health", nil, nil)
+	if health.Header().Get("X-Content-Type-Options") != "nosniff" || health.Header().Get("Strict-Transport-Security") != "" {
+		t.Fatalf("unexpected default headers: %v", health.Header())
+	}
+	if health.Header().Get("Cache-Control") == "no-store" {
+		t.Fatal("no-store should be limited to token endpoints")
+	}
+
+	tok := ta.do(http.MethodPost, "/oauth/token", map[string]any{"grant_type": "client_credentials"}, map[string]string{
+		"Authorization": basicAuth(ta.clientID, ta.clientSecret),
+	})
+	mustStatus(t, tok, http.StatusOK)
+	if tok.Header().Get("Cache-Control") != "no-store" {
+		t.Fatalf("token response Cache-Control = %q", tok.Header().Get("Cache-Control"))
+	}
+	login := ta.do(http.MethodPost, "/api/auth/login", map[string]any{"email": "x@example.com", "password": "nope"}, ta.clientHeaders())
+	if login.Header().Get("Cache-Control") != "no-store" {
+		t.Fatalf("login response Cache-Control = %q", login.Header().Get("Cache-Control"))
+	}
+
+	ta.cfg.HSTSMaxAgeSecs = 31536000
+	ta.engine = server.NewRouter(ta.repo, ta.jwt, ta.cfg)
+	health = ta.do(http.MethodGet, "/health", nil, nil)
+	if got := health.Header().Get("Strict-Transport-Security"); got != "max-age=31536000; includeSubDomains" {
+		t.Fatalf("HSTS = %q", got)
+	}
+}
+
+func TestSelfRegistrationToggle(t *testing.T) {
+	ta := newTestApp(t)
+	create := ta.do(http.MethodPost, "/admin/applications", map[string]any{
+		"name": "closed-app", "allow_self_registration": false,
+	}, ta.bearer(ta.adminToken))
+	mustStatus(t, create, http.StatusOK)
+	var app struct {
+		ID                    string `json:"id"`
+		ClientID              string `json:"client_id"`
+		AllowSelfRegistration bool   `json:"allow_self_registration"`
+	}
+	decode(t, create, &app)
+	if app.AllowSelfRegistration {
+		t.Fatal("allow_self_registration should be false")
+	}
+
+	body := map[string]any{"email": "closed@example.com", "password": "Password1!"}
+	closed := map[string]string{"X-Client-Id": app.ClientID}
+	mustStatus(t, ta.do(http.MethodPost, "/api/auth/register", body, closed), http.StatusForbidden)
+	mustStatus(t, ta.do(http.MethodPost, "/admin/users", body, ta.bearer(ta.adminToken)), http.StatusOK)
+
+	// The default app stays open, and re-enabling the flag reopens the closed one.
+	ta.registerUser(t, "open@example.com")
+	patch := ta.do(http.MethodPatch, "/admin/applications/"+app.ID, map[string]any{"allow_self_registration": true}, ta.bearer(ta.adminToken))
+	mustStatus(t, patch, http.StatusOK)
+	body["email"] = "reopened@example.com"
+	mustStatus(t, ta.do(http.MethodPost, "/api/auth/register", body, closed), http.StatusCreated)
+}
+
+func TestAllowedEmailDomains(t *testing.T) {
+	ta := newTestApp(t)
+	create := ta.do(http.MethodPost, "/admin/applications", map[string]any{
+		"name": "corp-app", "allowed_email_domains": []string{"@Corp.Example"},
+	}, ta.bearer(ta.adminToken))
+	mustStatus(t, create, http.StatusOK)
+	var app struct {
+		ClientID            string   `json:"client_id"`
+		AllowedEmailDomains []string `json:"allowed_email_domains"`
+	}
+	decode(t, create, &app)
+	if len(app.AllowedEmailDomains) != 1 || app.AllowedEmailDomains[0] != "corp.example" {
+		t.Fatalf("allowed_email_domains = %v", app.AllowedEmailDomains)
+	}
+
+	corp := map[string]string{"X-Client-Id": app.ClientID}
+	outside := ta.do(http.MethodPost, "/api/auth/register", map[string]any{"email": "eve@other.example", "password": "Password1!"}, corp)
+	mustStatus(t, outside, http.StatusBadRequest)
+	inside := ta.do(http.MethodPost, "/api/auth/register", map[string]any{"email": "bob@CORP.example", "password": "Password1!"}, corp)
+	mustStatus(t, inside, http.StatusCreated)
+
+	// Apps without a list accept any domain.
+	ta.registerUser(t, "eve@other.example")
+}
+
+func TestIdempotencyKeyReplaysResponse(t *testing.T) {
+	ta := newTestApp(t)
+	ta.cfg.IdempotencyTTLSecs = 600
+	ta.engine = server.NewRouter(ta.repo, ta.jwt, ta.cfg)
+
+	headers := ta.clientHeaders()
+	headers["Idempotency-Key"] = "register-1"
+	body := map[string]any{"email": "retry@example.com", "password": "Password1!"}
+	first := ta.do(http.MethodPost, "/api/auth/register", body, headers)
+	mustStatus(t, first, http.StatusCreated)
+	retry := ta.do(http.MethodPost, "/api/auth/register", body, headers)
+	mustStatus(t, retry, http.StatusCreated)
+	if retry.Body.String() != first.Body.String() || retry.Header().Get("Idempotent-Replayed") != "true" {
+		t.Fatalf("retry was not replayed: %s", retry.Body.String())
+	}
+	body["email"] = "other@example.com"
+	mustStatus(t, ta.do(http.MethodPost, "/api/auth/register", body, headers), http.StatusUnprocessableEntity)
+
+	// Keys are scoped per client and per endpoint; a fresh key mints new tokens.
+	tokenHeaders := map[string]string{"Authorization": basicAuth(ta.clientID, ta.clientSecret), "Idempotency-Key": "token-1"}
+	grant := map[string]any{"grant_type": "password", "username": "retry@example.com", "password": "Password1!"}
+	a := ta.do(http.MethodPost, "/oauth/token", grant, tokenHeaders)
+	mustStatus(t, a, http.StatusOK)
+	b := ta.do(http.MethodPost, "/oauth/token", grant, tokenHeaders)
+	if b.Body.String() != a.Body.String() {
+		t.Fatal("token retry minted a second set of tokens")
+	}
+	tokenHeaders["Idempotency-Key"] = "token-2"
+	fresh := ta.do(http.MethodPost, "/oauth/token", grant, tokenHeaders)
+	mustStatus(t, fresh, http.StatusOK)
+	if fresh.Body.String() == a.Body.String() {
+		t.Fatal("a new key should mint new tokens")
+	}
+}
+
+// Compress holds back small bodies, so a gzip-accepting client must still
+// have its response stored and replayed.
+func TestIdempotencyKeyReplaysGzipResponse(t *testing.T) {
+	ta := newTestApp(t)
+	ta.cfg.IdempotencyTTLSecs = 600
+	ta.engine = server.NewRouter(ta.repo, ta.jwt, ta.cfg)
+
+	headers := ta.clientHeaders()
+	headers["Idempotency-Key"] = "register-gzip"
+	headers["Accept-Encoding"] = "gzip"
+	body := map[string]any{"email": "retry-gzip@example.com", "password": "Password1!"}
+	mustStatus(t, ta.do(http.MethodPost, "/api/auth/register", body, headers), http.StatusCreated)
+	retry := ta.do(http.MethodPost, "/api/auth/register", body, headers)
+	mustStatus(t, retry, http.StatusCreated)
+	if retry.Header().Get("Idempotent-Replayed") != "true" {
+		t.Fatalf("gzip retry was not replayed: %v", retry.Header())
+	}
+
+	tokenHeaders := map[string]string{
+		"Authorization": basicAuth(ta.clientID, ta.clientSecret), "Idempotency-Key": "token-gzip", "Accept-Encoding": "gzip",
+	}
+	grant := map[string]any{"grant_type": "password", "username": "retry-gzip@example.com", "password": "Password1!"}
+	mustStatus(t, ta.do(http.MethodPost, "/oauth/token", grant, tokenHeaders), http.StatusOK)
+	again := ta.do(http.MethodPost, "/oauth/token", grant, tokenHeaders)
+	mustStatus(t, again, http.StatusOK)
+	if again.Header().Get("Idempotent-Replayed") != "true" {
+		t.Fatal("gzip token retry minted a second set of tokens")
+	}
+}
+
+func TestLinkAccountRequiresRecentAuth(t *testing.T) {
+	ta := newTestApp(t)
+	ta.cfg.LinkReauthMaxAgeSecs = 300
+	ta.engine = server.NewRouter(ta.repo, ta.jwt, ta.cfg)
+	ctx := context.Background()
+
+	app, err := ta.repo.Applications().FindByClientID(ctx, ta.clientID)
+	if err != nil || app == nil {
+		t.Fatalf("find app: %v", err)
+	}
+	add := ta.do(http.MethodPost, "/admin/applications/"+app.ID+"/providers", map[string]any{
+		"provider_id": "test", "config": map[string]any{},
+	}, ta.bearer(ta.adminToken))
+	mustStatus(t, add, http.StatusOK)
+
+	reg := ta.do(http.MethodPost, "/api/auth/register", map[string]any{
+		"email": "relink@example.com", "password": "Password1!",
+	}, ta.clientHeaders())
+	mustStatus(t, reg, http.StatusCreated)
+	var regResp struct {
+		AccessToken  string `json:"access_token"`
+		RefreshToken string `json:"refresh_token"`
+	}
+	decode(t, reg, &regResp)
+
+	// A refreshed token has no auth_time, so it cannot link.
+	refresh := ta.do(http.MethodPost, "/api/auth/refresh", map[string]any{
+		"refresh_token": regResp.RefreshToken,
+	}, ta.clientHeaders())
+	mustStatus(t, refresh, http.StatusOK)
+	var refreshed struct {
+		AccessToken string `json:"access_token"`
+	}
+	decode(t, refresh, &refreshed)
+	credential := map[string]any{"credential": map[string]any{"account_id": "acct-relink"}}
+	stale := ta.do(http.MethodPost, "/api/users/me/accounts/test/link", credential, ta.bearer(refreshed.AccessToken))
+	mustStatus(t, stale, http.StatusForbidden)
+	var body map[string]any
+	decode(t, stale, &body)
+	if body["error"] != "reauthentication_required" {
+		t.Fatalf("error = %v, want reauthentication_required", body["error"])
+	}
+
+	fresh := ta.do(http.MethodPost, "/api/users/me/accounts/test/link", credential, ta.bearer(regResp.AccessToken))
+	mustStatus(t, fresh, http.StatusOK)
+}
+
+func TestStepUpRequiresRecentAuth(t *testing.T) {
+	ta := newTestApp(t)
+	ta.cfg.StepUpMaxAgeSecs = 300
+	ta.engine = server.NewRouter(ta.repo, ta.jwt, ta.cfg)
+
+	reg := ta.do(http.MethodPost, "/api/auth/register", map[string]any{
+		"email": "stepup@example.com", "password": "Password1!",
+	}, ta.clientHeaders())
+	mustStatus(t, reg, http.StatusCreated)
+	var regResp struct {
+		AccessToken  string `json:"access_token"`
+		RefreshToken string `json:"refresh_token"`
+	}
+	decode(t, reg, &regResp)
+	refresh := ta.do(http.MethodPost, "/api/auth/refresh", map[string]any{
+		"refresh_token": regResp.RefreshToken,
+	}, ta.clientHeaders())
+	mustStatus(t, refresh, http.StatusOK)
+	var refreshed struct {
+		AccessToken string `json:"access_token"`
+	}
+	decode(t, refresh, &refreshed)
+	stale := ta.bearer(refreshed.AccessToken)
+
+	for _, r := range []struct{ method, path string }{
+		{http.MethodPost, "/api/users/me/email"},
+		{http.MethodDelete, "/api/users/me/accounts/password"},
+		{http.MethodDelete, "/api/users/me"},
+	} {
+		w := ta.do(r.method, r.path, map[string]any{"email": "stepup2@example.com"}, stale)
+		mustStatus(t, w, http.StatusForbidden)
+		var body map[string]any
+		decode(t, w, &body)
+		if body["error"] != "reauthentication_required" {
+			t.Fatalf("%s %s: error = %v, want reauthentication_required", r.method, r.path, body["error"])
+		}
+	}
+	// Reading the profile needs no step-up.
+	mustStatus(t, ta.do(http.MethodGet, "/api/users/me", nil, stale), http.StatusOK)
+
+	mustStatus(t, ta.do(http.MethodDelete, "/api/users/me", nil, ta.bearer(regResp.AccessToken)), http.StatusNoContent)
+}
+
+func TestProblemJSONErrorFormat(t *testing.T) {
+	ta := newTestApp(t)
+	missing := ta.do(http.MethodGet, "/api/users/me", nil, nil)
+	mustStatus(t, missing, http.StatusUnauthorized)
+	if ct := missing.Header().Get("Content-Type"); !strings.HasPrefix(ct, "application/json") {
+		t.Fatalf("default Content-Type = %q", ct)
+	}
+
+	ta.cfg.ErrorFormat = config.ErrorFormatProblemJSON
+	ta.engine = server.NewRouter(ta.repo, ta.jwt, ta.cfg)
+	w := ta.do(http.MethodGet, "/api/users/me", nil, nil)
+	mustStatus(t, w, http.StatusUnauthorized)
+	if ct := w.Header().Get("Content-Type"); ct != "application/problem+json" {
+		t.Fatalf("Content-Type = %q, want application/problem+json", ct)
+	}
+	var problem struct {
+		Type   string `json:"type"`
+		Title  string `json:"title"`
+		Status int    `json:"status"`
+		Detail string `json:"detail"`
+	}
+	decode(t, w, &problem)
+	if problem.Type != "unauthorized" || problem.Title != "Unauthorized" || problem.Status != http.StatusUnauthorized || problem.Detail == "" {
+		t.Fatalf("problem = %+v", problem)
+	}
+}
+
+func TestTestProviderFixture(t *testing.T) {
+	ta := newTestApp(t)
+	ctx := context.Background()
+	app, err := ta.repo.Applications().FindByClientID(ctx, ta.clientID)
+	if err != nil || app == nil {
+		t.Fatalf("find app: %v", err)
+	}
+	configure := func(config map[string]any) {
+		t.Helper()
+		ta.do(http.MethodDelete, "/admin/applications/"+app.ID+"/providers/test", nil, ta.bearer(ta.adminToken))
+		add := ta.do(http.MethodPost, "/admin/applications/"+app.ID+"/providers", map[string]any{
+			"provider_id": "test", "config": config,
+		}, ta.bearer(ta.adminToken))
+		mustStatus(t, add, http.StatusOK)
+	}
+	login := func() *httptest.ResponseRecorder {
+		return ta.do(http.MethodPost, "/api/auth/provider/test/login", map[string]any{
+			"credential": map[string]any{"account_id": "from-credential"},
+		}, ta.clientHeaders())
+	}
+
+	configure(map[string]any{"user": map[string]any{"account_id": "canned-1", "email": "canned@example.com", "name": "Canned"}})
+	mustStatus(t, login(), http.StatusOK)
+	user, err := ta.repo.Users().FindByEmail(ctx, "canned@example.com")
+	if err != nil || user == nil || user.Name == nil || *user.Name != "Canned" {
+		t.Fatalf("canned identity not used: %+v, %v", user, err)
+	}
+
+	configure(map[string]any{"fail": "unavailable"})
+	mustStatus(t, login(), http.StatusBadGateway)
+	configure(map[string]any{"fail": "auth_failed"})
+	mustStatus(t, login(), http.StatusUnauthorized)
+}
+
+func TestLinkProviderAccountInUse(t *testing.T) {
+	ta := newTestApp(t)
+	ctx := context.Background()
+	app, err := ta.repo.Applications().FindByClientID(ctx, ta.clientID)
+	if err != nil || app == nil {
+		t.Fatalf("find app: %v", err)
+	}
+	add := ta.do(http.MethodPost, "/admin/applications/"+app.ID+"/providers", map[string]any{
+		"provider_id": "test", "config": map[string]any{},
+	}, ta.bearer(ta.adminToken))
+	mustStatus(t, add, http.StatusOK)
+
+	credential := map[string]any{"credential": map[string]any{"account_id": "shared"}}
+	alice := ta.registerUser(t, "alice-link@example.com")
+	bob := ta.registerUser(t, "bob-link@example.com")
+	mustStatus(t, ta.do(http.MethodPost, "/api/users/me/accounts/test/link", credential, ta.bearer(alice)), http.StatusOK)
+
+	errorType := func(w *httptest.ResponseRecorder) any {
+		t.Helper()
+		mustStatus(t, w, http.StatusConflict)
+		var body map[string]any
+		decode(t, w, &body)
+		return body["error"]
+	}
+	if got := errorType(ta.do(http.MethodPost, "/api/users/me/accounts/test/link", credential, ta.bearer(bob))); got != "provider_account_in_use" {
+		t.Fatalf("other user linking = %v, want provider_account_in_use", got)
+	}
+	if got := errorType(ta.do(http.MethodPost, "/api/users/me/accounts/test/link", credential, ta.bearer(alice))); got != "account_already_linked" {
+		t.Fatalf("relinking = %v, want account_already_linked", got)
+	}
+
+	// The repository reports the same conflict when the pre-check is raced.
+	shared := "shared"
+	err = ta.repo.Accounts().Insert(ctx, &domain.Account{
+		ID: "acct-race", UserID: "someone-else", ProviderID: "test", ProviderAccountID: &shared,
+		ProviderMetadata: "{}", CreatedAt: time.Now().UTC(), UpdatedAt: time.Now().UTC(),
+	})
+	if ae, ok := apperror.As(err); !ok || ae.Type != "provider_account_in_use" {
+		t.Fatalf("racing insert = %v, want provider_account_in_use", err)
+	}
+}
+
+func TestScopeRegistry(t *testing.T) {
+	ta := newTestApp(t)
+	ta.cfg.ScopeRegistry = map[string]config.ScopeInfo{
+		"profile": {DisplayName: "Profile", Description: "Read your name and avatar"},
+		"admin":   {DisplayName: "Administration", Description: "Manage the service"},
+	}
+	ta.engine = server.NewRouter(ta.repo, ta.jwt, ta.cfg)
+
+	list := ta.do(http.MethodGet, "/oauth/scopes", nil, nil)
+	mustStatus(t, list, http.StatusOK)
+	var scopes []struct {
+		Scope       string `json:"scope"`
+		DisplayName string `json:"display_name"`
+		Description string `json:"description"`
+	}
+	decode(t, list, &scopes)
+	if len(scopes) != 2 || scopes[0].Scope != "admin" || scopes[1].DisplayName != "Profile" {
+		t.Fatalf("scopes = %+v", scopes)
+	}
+
+	typo := map[string]any{"name": "typo-app", "allowed_scopes": []string{"profle"}}
+	mustStatus(t, ta.do(http.MethodPost, "/admin/applications", typo, ta.bearer(ta.adminToken)), http.StatusOK)
+	ta.cfg.ScopeRegistryStrict = true
+	ta.engine = server.NewRouter(ta.repo, ta.jwt, ta.cfg)
+	mustStatus(t, ta.do(http.MethodPost, "/admin/applications", typo, ta.bearer(ta.adminToken)), http.StatusBadRequest)
+	ok := map[string]any{"name": "ok-app", "allowed_scopes": []string{"profile"}}
+	mustStatus(t, ta.do(http.MethodPost, "/admin/applications", ok, ta.bearer(ta.adminToken)), http.StatusOK)
+}
+
+func TestEmailChangeConfirmation(t *testing.T) {
+	ta := newTestApp(t)
+	host, port, inbox := fakeSMTP(t)
+	ta.cfg.SMTPHost, ta.cfg.SMTPPort, ta.cfg.SMTPFrom = host, port, "auth@example.com"
+	ta.cfg.EmailConfirmURL, ta.cfg.EmailChangeTokenTTLSecs = "https://app.example.com/confirm-email", 3600
+	ta.engine = server.NewRouter(ta.repo, ta.jwt, ta.cfg)
+
+	token := ta.registerUser(t, "old-addr@example.com")
+	ta.registerUser(t, "taken-addr@example.com")
+	login := func(email string) *httptest.ResponseRecorder {
+		return ta.do(http.MethodPost, "/api/auth/login", map[string]any{
+			"email": email, "password": "Password1!",
+		}, ta.clientHeaders())
+	}
+
+	mustStatus(t, ta.do(http.MethodPost, "/api/users/me/email", map[string]any{"email": "taken-addr@example.com"}, ta.bearer(token)), http.StatusConflict)
+	mustStatus(t, ta.do(http.MethodPost, "/api/users/me/email", map[string]any{"email": "new-addr@example.com"}, ta.bearer(token)), http.StatusAccepted)
+
+	var msg string
+	select {
+	case msg = <-inbox:
+	case <-time.After(5 * time.Second):
+		t.Fatal("no confirmation email sent")
+	}
+	if !strings.Contains(msg, "To: new-addr@example.com") {
+		t.Fatalf("email sent to wrong recipient:\n%s", msg)
+	}
+	i := strings.Index(msg, "?token=")
+	if i < 0 {
+		t.Fatalf("no confirmation link in email:\n%s", msg)
+	}
+	confirm, err := url.QueryUnescape(strings.Fields(msg[i+len("?token="):])[0])
+	if err != nil {
+		t.Fatalf("unescape token: %v", err)
+	}
+
+	// The old address keeps working until the link is used.
+	session := login("old-addr@example.com")
+	mustStatus(t, session, http.StatusOK)
+	var tokens struct {
+		RefreshToken string `json:"refresh_token"`
+	}
+	decode(t, session, &tokens)
+	profile := ta.do(http.MethodGet, "/api/users/me", nil, ta.bearer(token))
+	mustStatus(t, profile, http.StatusOK)
+	var p struct {
+		Email        string  `json:"email"`
+		PendingEmail *string `json:"pending_email"`
+	}
+	decode(t, profile, &p)
+	if p.Email != "old-addr@example.com" || p.PendingEmail == nil || *p.PendingEmail != "new-addr@example.com" {
+		t.Fatalf("profile before confirm = %+v", p)
+	}
+
+	mustStatus(t, ta.do(http.MethodPost, "/api/auth/email/confirm", map[string]any{"token": confirm + "x"}, nil), http.StatusBadRequest)
+	mustStatus(t, ta.do(http.MethodPost, "/api/auth/email/confirm", map[string]any{"token": confirm}, nil), http.StatusOK)
+	// Sessions from before the change are gone.
+	mustStatus(t, ta.do(http.MethodPost, "/api/auth/refresh", map[string]any{
+		"refresh_token": tokens.RefreshToken,
+	}, ta.clientHeaders()), http.StatusUnauthorized)
+	mustStatus(t, login("new-addr@example.com"), http.StatusOK)
+	mustStatus(t, login("old-addr@example.com"), http.StatusUnauthorized)
+	// The token is single-use.
+	mustStatus(t, ta.do(http.MethodPost, "/api/auth/email/confirm", map[string]any{"token": confirm}, nil), http.StatusBadRequest)
+}
+
+// fakeSMTP accepts plain SMTP on a loopback port and delivers each message's
+// DATA section to the returned channel.
+func fakeSMTP(t *testing.T) (string, int, <-chan string) {
+	t.Helper()
+	ln, err := net.Listen("tcp", "127.0.0.1:0")
+	if err != nil {
+		t.Fatalf("listen: %v", err)
+	}
+	t.Cleanup(func() { ln.Close() })
+	inbox := make(chan string, 8)
+	go func() {
+		for {
+			conn, err := ln.Accept()
+			if err != nil {
+				return
+			}
+			go func(conn net.Conn) {
+				defer conn.Close()
+				r := bufio.NewReader(conn)
+				reply := func(s string) { _, _ = conn.Write([]byte(s + "\r\n")) }
+				reply("220 localhost")
+				for {
+					line, err := r.ReadString('\n')
+					if err != nil {
+						return
+					}
+					switch cmd := strings.ToUpper(strings.TrimSpace(line)); {
+					case strings.HasPrefix(cmd, "EHLO"), strings.HasPrefix(cmd, "HELO"):
+						reply("250 localhost")
+					case cmd == "DATA":
+						reply("354 go ahead")
+						var data strings.Builder
+						for {
+							l, err := r.ReadString('\n')
+							if err != nil {
+								return
+							}
+							if l == ".\r\n" {
+								break
+							}
+							data.WriteString(l)
+						}
+						inbox <- data.String()
+						reply("250 queued")
+					case cmd == "QUIT":
+						reply("221 bye")
+						return
+					default:
+						reply("250 ok")
+					}
+				}
+			}(conn)
+		}
+	}()
+	addr := ln.Addr().(*net.TCPAddr)
+	return addr.IP.String(), addr.Port, inbox
+}
+
+func TestLoginPairThrottlePerClientBehindProxy(t *testing.T) {
+	ta := newTestApp(t)
+	ta.cfg.LoginMaxFailedAttempts, ta.cfg.LoginLockoutSecs = 10, 60
+	ta.cfg.LoginPairMaxFailures, ta.cfg.LoginPairFailureWindowSecs, ta.cfg.LoginPairBlockSecs = 3, 60, 600
+	// httptest requests come from 192.0.2.1, here the proxy in front.
+	ta.cfg.TrustedProxies = []string{"192.0.2.1"}
+	ta.engine = server.NewRouter(ta.repo, ta.jwt, ta.cfg)
+	ta.registerUser(t, "shared-proxy@example.com")
+
+	login := func(ip, password string) *httptest.ResponseRecorder {
+		h := ta.clientHeaders()
+		h["X-Forwarded-For"] = ip
+		return ta.do(http.MethodPost, "/api/auth/login", map[string]any{"email": "shared-proxy@example.com", "password": password}, h)
+	}
+	// An attacker throttling the account from one address does not throttle
+	// its owner arriving through the same proxy from another.
+	for range 3 {
+		mustStatus(t, login("203.0.113.50", "Wrong1!xx"), http.StatusUnauthorized)
+	}
+	mustStatus(t, login("203.0.113.50", "Password1!"), http.StatusTooManyRequests)
+	mustStatus(t, login("198.51.100.50", "Password1!"), http.StatusOK)
+
+	// The second address has its own count and its own block.
+	for range 3 {
+		mustStatus(t, login("198.51.100.51", "Wrong1!xx"), http.StatusUnauthorized)
+	}
+	mustStatus(t, login("198.51.100.51", "Password1!"), http.StatusTooManyRequests)
+	mustStatus(t, login("198.51.100.50", "Password1!"), http.StatusOK)
+}
+
+func TestAdminAuthAbuseBlocksIP(t *testing.T) {
+	ta := newTestApp(t)
+	ta.cfg.AdminAuthMaxFailures, ta.cfg.AdminAuthFailureWindowSecs, ta.cfg.AdminAuthBlockSecs = 3, 60, 600
+	ta.cfg.TrustedProxies = []string{"192.0.2.1"}
+	ta.engine = server.NewRouter(ta.repo, ta.jwt, ta.cfg)
+
+	from := func(ip, token string) map[string]string {
+		return map[string]string{"Authorization": "Bearer " + token, "X-Forwarded-For": ip}
+	}
+	// A signed-in user without an admin role is refused, not counted.
+	user := ta.registerUser(t, "not-admin@example.com")
+	for i := 0; i < 3; i++ {
+		mustStatus(t, ta.do(http.MethodGet, "/admin/applications", nil, from("203.0.113.9", user)), http.StatusForbidden)
+	}
+	mustStatus(t, ta.do(http.MethodGet, "/admin/applications", nil, from("203.0.113.9", ta.adminToken)), http.StatusOK)
+
+	for i := 0; i < 3; i++ {
+		mustStatus(t, ta.do(http.MethodGet, "/admin/applications", nil, from("203.0.113.9", "not-a-token")), http.StatusUnauthorized)
+	}
+
+	// Even a valid admin token is refused from the blocked IP.
+	w := ta.do(http.MethodGet, "/admin/applications", nil, from("203.0.113.9", ta.adminToken))
+	mustStatus(t, w, http.StatusForbidden)
+	var body map[string]any
+	decode(t, w, &body)
+	if body["error"] != "ip_blocked" || w.Header().Get("Retry-After") == "" {
+		t.Fatalf("blocked response = %v (Retry-After %q)", body, w.Header().Get("Retry-After"))
+	}
+	mustStatus(t, ta.do(http.MethodGet, "/admin/users", nil, from("203.0.113.9", ta.adminToken)), http.StatusForbidden)
+	mustStatus(t, ta.do(http.MethodGet, "/admin/applications", nil, from("198.51.100.4", ta.adminToken)), http.StatusOK)
+
+	// From a peer that is not a trusted proxy, rotating X-Forwarded-For does
+	// not dodge the guard: every attempt counts against the peer.
+	ta.cfg.TrustedProxies = nil
+	ta.engine = server.NewRouter(ta.repo, ta.jwt, ta.cfg)
+	for i := 0; i < 3; i++ {
+		mustStatus(t, ta.do(http.MethodGet, "/admin/applications", nil, from("198.51.100."+strconv.Itoa(10+i), "not-a-token")), http.StatusUnauthorized)
+	}
+	mustStatus(t, ta.do(http.MethodGet, "/admin/applications", nil, from("198.51.100.20", ta.adminToken)), http.StatusForbidden)
+}
+
+func TestCreateApplicationWithProviders(t *testing.T) {
+	ta := newTestApp(t)
+	ctx := context.Background()
+
+	w := ta.do(http.MethodPost, "/admin/applications", map[string]any{
+		"name": "bulk-app",
+		"providers": []map[string]any{
+			{"provider_id": "test", "config": map[string]any{}},
+			{"provider_id": "wechat", "config": map[string]any{"appid": "wx1", "secret": "top-secret"}, "sort_order": 2},
+		},
+	}, ta.bearer(ta.adminToken))
+	mustStatus(t, w, http.StatusOK)
+	var created struct {
+		ID        string `json:"id"`
+		Providers []struct {
+			ProviderID string `json:"provider_id"`
+			SortOrder  int    `json:"sort_order"`
+		} `json:"providers"`
+	}
+	decode(t, w, &created)
+	if len(created.Providers) != 2 || created.Providers[1].ProviderID != "wechat" || created.Providers[1].SortOrder != 2 {
+		t.Fatalf("created providers = %+v", created.Providers)
+	}
+	stored, err := ta.repo.AppProviders().FindAllByApp(ctx, created.ID)
+	if err != nil || len(stored) != 2 {
+		t.Fatalf("stored providers = %+v, %v", stored, err)
+	}
+
+	// One bad provider fails the request and leaves no app behind.
+	bad := ta.do(http.MethodPost, "/admin/applications", map[string]any{
+		"name": "bulk-bad",
+		"providers": []map[string]any{
+			{"provider_id": "test", "config": map[string]any{}},
+			{"provider_id": "test", "config": map[string]any{"fail": "sometimes"}},
+		},
+	}, ta.bearer(ta.adminToken))
+	mustStatus(t, bad, http.StatusBadRequest)
+	mustStatus(t, ta.do(http.MethodPost, "/admin/applications", map[string]any{
+		"name": "bulk-bad", "providers": []map[string]any{{"provider_id": "nope"}},
+	}, ta.bearer(ta.adminToken)), http.StatusBadRequest)
+	if app, err := ta.repo.Applications().FindByName(ctx, "bulk-bad"); err != nil || app != nil {
+		t.Fatalf("rejected app was stored: %+v, %v", app, err)
+	}
+}
+
+func TestInsertUserWithAccountIsAtomic(t *testing.T) {
+	ta := newTestApp(t)
+	ctx := context.Background()
+	ta.registerUser(t, "atomic-first@example.com")
+
+	// The account collides with the first user's password login, so the new
+	// user must not be kept either.
+	now := time.Now().UTC()
+	email, taken := "atomic-second@example.com", "atomic-first@example.com"
+	user := &domain.User{
+		ID: "atomic-user", Email: &email, Role: "user", IsActive: true,
+		CustomAttributes: map[string]any{}, CreatedAt: now, UpdatedAt: now, Membership: domain.MembershipRegular,
+	}
+	err := ta.repo.Users().InsertWithAccount(ctx, user, &domain.Account{
+		ID: "atomic-account", UserID: user.ID, ProviderID: "password", ProviderAccountID: &taken,
+		ProviderMetadata: "{}", CreatedAt: now, UpdatedAt: now,
+	})
+	if ae, ok := apperror.As(err); !ok || ae.Type != "provider_account_in_use" {
+		t.Fatalf("insert = %v, want provider_account_in_use", err)
+	}
+	if u, err := ta.repo.Users().FindByID(ctx, user.ID); err != nil || u != nil {
+		t.Fatalf("orphaned user left behind: %+v, %v", u, err)
+	}
+
+	// Duplicate emails are caught by the unique key, also on the admin path.
+	w := ta.do(http.MethodPost, "/admin/users", map[string]any{
+		"email": "atomic-first@example.com", "password": "Password1!",
+	}, ta.bearer(ta.adminToken))
+	mustStatus(t, w, http.StatusConflict)
+}
+
+func TestStatsTimeSeries(t *testing.T) {
+	ta := newTestApp(t)
+	todayCount := func() uint64 {
+		t.Helper()
+		w := ta.do(http.MethodGet, "/admin/stats/timeseries?metric=registrations&days=7", nil, ta.bearer(ta.adminToken))
+		mustStatus(t, w, http.StatusOK)
+		var r struct {
+			Metric  string `json:"metric"`
+			Buckets []struct {
+				Date  string `json:"date"`
+				Count uint64 `json:"count"`
+			} `json:"buckets"`
+		}
+		decode(t, w, &r)
+		today := time.Now().UTC().Format("2006-01-02")
+		if r.Metric != "registrations" || len(r.Buckets) != 7 || r.Buckets[6].Date != today {
+			t.Fatalf("timeseries = %+v", r)
+		}
+		return r.Buckets[6].Count
+	}
+	n := todayCount()
+	ta.registerUser(t, "series-1@example.com")
+	ta.registerUser(t, "series-2@example.com")
+	if got := todayCount(); got != n+2 {
+		t.Fatalf("today's registrations = %d, want %d", got, n+2)
+	}
+
+	mustStatus(t, ta.do(http.MethodGet, "/admin/stats/timeseries?metric=logins", nil, ta.bearer(ta.adminToken)), http.StatusBadRequest)
+	mustStatus(t, ta.do(http.MethodGet, "/admin/stats/timeseries?days=0", nil, ta.bearer(ta.adminToken)), http.StatusBadRequest)
+}
+
+func TestAppDefaultScopes(t *testing.T) {
+	ta := newTestApp(t)
+	if ta.clientSecret == "" {
+		t.Skip("client secret not available")
+	}
+	app, err := ta.repo.Applications().FindByClientID(context.Background(), ta.clientID)
+	if err != nil || app == nil {
+		t.Fatalf("find app: %v", err)
+	}
+	patch := func(body map[string]any) *httptest.ResponseRecorder {
+		return ta.do(http.MethodPatch, "/admin/applications/"+app.ID, body, ta.bearer(ta.adminToken))
+	}
+	mustStatus(t, patch(map[string]any{"allowed_scopes": []string{"openid", "profile", "email"}, "default_scopes": []string{"admin"}}), http.StatusBadRequest)
+	mustStatus(t, patch(map[string]any{"allowed_scopes": []string{"openid", "profile", "email"}, "default_scopes": []string{"openid"}}), http.StatusOK)
+	// Narrowing allowed_scopes may not strand a default.
+	mustStatus(t, patch(map[string]any{"allowed_scopes": []string{"profile"}}), http.StatusBadRequest)
+
+	reg := ta.do(http.MethodPost, "/api/auth/register", map[string]any{
+		"email": "defaults@example.com", "password": "Password1!",
+	}, ta.clientHeaders())
+	mustStatus(t, reg, http.StatusCreated)
+	var r struct {
+		Scope string `json:"scope"`
+	}
+	decode(t, reg, &r)
+	if r.Scope != "openid" {
+		t.Fatalf("register scope = %q, want openid", r.Scope)
+	}
+
+	// An explicit request may still ask for more than the default.
+	tok := ta.do(http.MethodPost, "/oauth/token", map[string]any{
+		"grant_type": "password", "username": "defaults@example.com", "password": "Password1!", "scope": "openid email",
+	}, map[string]string{"Authorization": basicAuth(ta.clientID, ta.clientSecret)})
+	mustStatus(t, tok, http.StatusOK)
+	decode(t, tok, &r)
+	if r.Scope != "openid email" {
+		t.Fatalf("requested scope = %q, want openid email", r.Scope)
+	}
+}
+
+func TestEmailClaimOptIn(t *testing.T) {
+	ta := newTestApp(t)
+	if ta.clientSecret == "" {
+		t.Skip("client secret not available")
+	}
+	app, err := ta.repo.Applications().FindByClientID(context.Background(), ta.clientID)
+	if err != nil || app == nil {
+		t.Fatalf("find app: %v", err)
+	}
+	mustStatus(t, ta.do(http.MethodPatch, "/admin/applications/"+app.ID, map[string]any{
+		"allowed_scopes": []string{"openid", "profile", "email"},
+	}, ta.bearer(ta.adminToken)), http.StatusOK)
+	ta.registerUser(t, "claims@example.com")
+
+	emailClaim := func(scope string) *string {
+		t.Helper()
+		w := ta.do(http.MethodPost, "/oauth/token", map[string]any{
+			"grant_type": "password", "username": "claims@example.com", "password": "Password1!", "scope": scope,
+		}, map[string]string{"Authorization": basicAuth(ta.clientID, ta.clientSecret)})
+		mustStatus(t, w, http.StatusOK)
+		var r struct {
+			AccessToken string `json:"access_token"`
+		}
+		decode(t, w, &r)
+		claims, err := ta.jwt.VerifyAccessToken(context.Background(), r.AccessToken)
+		if err != nil {
+			t.Fatalf("verify: %v", err)
+		}
+		return claims.Email
+	}
+
+	// Off by default, even when the email scope is granted.
+	if got := emailClaim("openid email"); got != nil {
+		t.Fatalf("email claim = %q with include_email_claim off", *got)
+	}
+	mustStatus(t, ta.do(http.MethodPatch, "/admin/applications/"+app.ID, map[string]any{
+		"include_email_claim": true,
+	}, ta.bearer(ta.adminToken)), http.StatusOK)
+	if got := emailClaim("openid email"); got == nil || *got != "claims@example.com" {
+		t.Fatalf("email claim = %v, want claims@example.com", got)
+	}
+	// The scope still gates the claim.
+	if got := emailClaim("openid"); got != nil {
+		t.Fatalf("email claim = %q without the email scope", *got)
+	}
+}
+
+func TestTokenEndpointAuthMethods(t *testing.T) {
+	ta := newTestApp(t)
+	if ta.clientSecret == "" {
+		t.Skip("client secret not available")
+	}
+	ta.cfg.PublicBaseURL = "https://auth.example.com"
+	ta.engine = server.NewRouter(ta.repo, ta.jwt, ta.cfg)
+	// client_secret_post: credentials in the body, no Authorization header.
+	mustStatus(t, ta.do(http.MethodPost, "/oauth/token", map[string]any{
+		"grant_type": "client_credentials", "client_id": ta.clientID, "client_secret": ta.clientSecret,
+	}, nil), http.StatusOK)
+	mustStatus(t, ta.do(http.MethodPost, "/oauth/token", map[string]any{
+		"grant_type": "client_credentials", "client_id": ta.clientID, "client_secret": "wrong",
+	}, nil), http.StatusUnauthorized)
+
+	key, err := rsa.GenerateKey(rand.Reader, 2048)
+	if err != nil {
+		t.Fatal(err)
+	}
+	der, _ := x509.MarshalPKIXPublicKey(&key.PublicKey)
+	publicPEM := string(pem.EncodeToMemory(&pem.Block{Type: "PUBLIC KEY", Bytes: der}))
+	assertionFor := func(aud, jti string) string {
+		t.Helper()
+		s, err := jwt.NewWithClaims(jwt.SigningMethodRS256, jwt.RegisteredClaims{
+			Issuer: ta.clientID, Subject: ta.clientID, Audience: jwt.ClaimStrings{aud},
+			ID: jti, ExpiresAt: jwt.NewNumericDate(time.Now().Add(time.Minute)),
+		}).SignedString(key)
+		if err != nil {
+			t.Fatal(err)
+		}
+		return s
+	}
+	assertion := func(jti string) string {
+		return assertionFor("https://auth.example.com/oauth/token", jti)
+	}
+	keyAuth := func(a string) *httptest.ResponseRecorder {
+		return ta.do(http.MethodPost, "/oauth/token", map[string]any{
+			"grant_type": "client_credentials", "client_assertion_type": auth.ClientAssertionType, "client_assertion": a,
+		}, nil)
+	}
+	// No key registered yet.
+	mustStatus(t, keyAuth(assertion("jti-1")), http.StatusUnauthorized)
+
+	app, err := ta.repo.Applications().FindByClientID(context.Background(), ta.clientID)
+	if err != nil || app == nil {
+		t.Fatalf("find app: %v", err)
+	}
+	patch := func(body map[string]any) *httptest.ResponseRecorder {
+		return ta.do(http.MethodPatch, "/admin/applications/"+app.ID, body, ta.bearer(ta.adminToken))
+	}
+	mustStatus(t, patch(map[string]any{"client_public_key": "not a key"}), http.StatusBadRequest)
+	mustStatus(t, patch(map[string]any{"client_public_key": publicPEM}), http.StatusOK)
+
+	a := assertion("jti-2")
+	mustStatus(t, keyAuth(a), http.StatusOK)
+	// Each assertion is single-use.
+	mustStatus(t, keyAuth(a), http.StatusUnauthorized)
+	mustStatus(t, keyAuth(assertionFor(ta.jwt.KeyInfo().Issuer, "jti-3")), http.StatusOK)
+	// The Host header is the client's to pick, so URLs built from it don't
+	// count as the audience.
+	mustStatus(t, keyAuth(assertionFor("http://example.com/oauth/token", "jti-4")), http.StatusUnauthorized)
+
+	w := ta.do(http.MethodGet, "/.well-known/oauth-authorization-server", nil, nil)
+	mustStatus(t, w, http.StatusOK)
+	var meta struct {
+		TokenEndpoint string   `json:"token_endpoint"`
+		AuthMethods   []string `json:"token_endpoint_auth_methods_supported"`
+	}
+	decode(t, w, &meta)
+	if meta.TokenEndpoint != "https://auth.example.com/oauth/token" || len(meta.AuthMethods) != 3 {
+		t.Fatalf("metadata = %+v", meta)
+	}
+}
+
+func TestVerifyClient(t *testing.T) {
+	ta := newTestApp(t)
+	if ta.clientSecret == "" {
+		t.Skip("client secret not available")
+	}
+	w := ta.do(http.MethodPost, "/oauth/verify-client", nil, map[string]string{"Authorization": basicAuth(ta.clientID, ta.clientSecret)})
+	mustStatus(t, w, http.StatusOK)
+	var r struct {
+		Valid    bool   `json:"valid"`
+		AppID    string `json:"app_id"`
+		ClientID string `json:"client_id"`
+	}
+	decode(t, w, &r)
+	if !r.Valid || r.AppID == "" || r.ClientID != ta.clientID {
+		t.Fatalf("verify-client = %+v", r)
+	}
+	mustStatus(t, ta.do(http.MethodPost, "/oauth/verify-client", nil, map[string]string{"Authorization": basicAuth(ta.clientID, "wrong")}), http.StatusUnauthorized)
+}
+
+func TestLocalizedErrorMessages(t *testing.T) {
+	ta := newTestApp(t)
+	ta.cfg.Locales = i18n.Bundled()
+	ta.engine = server.NewRouter(ta.repo, ta.jwt, ta.cfg)
+	ta.registerUser(t, "locale@example.com")
+
+	withLanguage := func(lang string) map[string]string {
+		h := ta.clientHeaders()
+		h["Accept-Language"] = lang
+		return h
+	}
+	var r struct {
+		Error   string `json:"error"`
+		Message string `json:"message"`
+	}
+	w := ta.do(http.MethodPost, "/api/auth/login", map[string]any{
+		"email": "locale@example.com", "password": "Wrong-pass1",
+	}, withLanguage("fr-CA, fr;q=0.9, en;q=0.5"))
+	mustStatus(t, w, http.StatusUnauthorized)
+	decode(t, w, &r)
+	if r.Error != "invalid_credentials" || r.Message != "Identifiants invalides" || w.Header().Get("Content-Language") != "fr" {
+		t.Fatalf("fr login error = %+v (Content-Language %q)", r, w.Header().Get("Content-Language"))
+	}
+
+	w = ta.do(http.MethodPost, "/api/auth/register", map[string]any{
+		"email": "weak@example.com", "password": "password1!",
+	}, withLanguage("de"))
+	mustStatus(t, w, http.StatusBadRequest)
+	decode(t, w, &r)
+	if r.Error != "bad_request" || r.Message != "Das Passwort muss mindestens einen Großbuchstaben enthalten" {
+		t.Fatalf("de register error = %+v", r)
+	}
+
+	// English first wins over a lower-ranked bundled language.
+	w = ta.do(http.MethodPost, "/api/auth/register", map[string]any{
+		"email": "weak@example.com", "password": "password1!",
+	}, withLanguage("en-GB, es;q=0.8"))
+	mustStatus(t, w, http.StatusBadRequest)
+	decode(t, w, &r)
+	if r.Message != "Password must contain at least one uppercase letter" {
+		t.Fatalf("en register error = %+v", r)
+	}
+}
+
+func TestRequireHTTPS(t *testing.T) {
+	ta := newTestApp(t)
+	ta.cfg.RequireHTTPS = true
+	ta.engine = server.NewRouter(ta.repo, ta.jwt, ta.cfg)
+
+	w := ta.do(http.MethodGet, "/api/auth/providers", nil, ta.clientHeaders())
+	mustStatus(t, w, http.StatusForbidden)
+	var r struct {
+		Error string `json:"error"`
+	}
+	decode(t, w, &r)
+	if r.Error != "https_required" {
+		t.Fatalf("error = %q, want https_required", r.Error)
+	}
+	h := ta.clientHeaders()
+	h["X-Forwarded-Proto"] = "https"
+	mustStatus(t, ta.do(http.MethodGet, "/api/auth/providers", nil, h), http.StatusOK)
+	// Health probes usually skip the proxy.
+	mustStatus(t, ta.do(http.MethodGet, "/health", nil, nil), http.StatusOK)
+}
+
+func TestAdminSearch(t *testing.T) {
+	ta := newTestApp(t)
+	ctx := context.Background()
+	ta.registerUser(t, "findme@example.com")
+	user, err := ta.repo.Users().FindByEmail(ctx, "findme@example.com")
+	if err != nil || user == nil {
+		t.Fatalf("find user: %v", err)
+	}
+	openID := "wx-openid-search"
+	if err := ta.repo.Accounts().Insert(ctx, &domain.Account{
+		ID: "acct-search", UserID: user.ID, ProviderID: "wechat", ProviderAccountID: &openID,
+		ProviderMetadata: "{}", CreatedAt: time.Now().UTC(), UpdatedAt: time.Now().UTC(),
+	}); err != nil {
+		t.Fatal(err)
+	}
+
+	search := func(q string) []map[string]any {
+		t.Helper()
+		w := ta.do(http.MethodGet, "/admin/search?q="+url.QueryEscape(q), nil, ta.bearer(ta.adminToken))
+		mustStatus(t, w, http.StatusOK)
+		var body struct {
+			Results []map[string]any `json:"results"`
+		}
+		decode(t, w, &body)
+		return body.Results
+	}
+	if r := search("FINDME@"); len(r) != 1 || r[0]["type"] != "user" || r[0]["id"] != user.ID {
+		t.Fatalf("email search = %+v", r)
+	}
+	if r := search(user.ID[:13]); len(r) != 1 || r[0]["type"] != "user" {
+		t.Fatalf("id search = %+v", r)
+	}
+	if r := search(ta.clientID); len(r) != 1 || r[0]["type"] != "application" || r[0]["client_id"] != ta.clientID {
+		t.Fatalf("client_id search = %+v", r)
+	}
+	if r := search(openID); len(r) != 1 || r[0]["type"] != "account" || r[0]["user_id"] != user.ID {
+		t.Fatalf("account search = %+v", r)
+	}
+	mustStatus(t, ta.do(http.MethodGet, "/admin/search?q=", nil, ta.bearer(ta.adminToken)), http.StatusBadRequest)
+}
+
+func TestConcurrentRefreshRotatesOnce(t *testing.T) {
+	ta := newTestApp(t)
+	w := ta.do(http.MethodPost, "/api/auth/register", map[string]any{
+		"email": "race@example.com", "password": "Password1!",
+	}, ta.clientHeaders())
+	mustStatus(t, w, http.StatusCreated)
+	var reg struct {
+		RefreshToken string `json:"refresh_token"`
+	}
+	decode(t, w, &reg)
+
+	const attempts = 8
+	codes := make(chan int, attempts)
+	var wg sync.WaitGroup
+	for range attempts {
+		wg.Add(1)
+		go func() {
+			defer wg.Done()
+			codes <- ta.do(http.MethodPost, "/api/auth/refresh", map[string]any{
+				"refresh_token": reg.RefreshToken,
+			}, ta.clientHeaders()).Code
+		}()
+	}
+	wg.Wait()
+	close(codes)
+	ok := 0
+	for code := range codes {
+		switch code {
+		case http.StatusOK:
+			ok++
+		case http.StatusUnauthorized:
+		default:
+			t.Fatalf("unexpected status %d", code)
+		}
+	}
+	if ok != 1 {
+		t.Fatalf("%d concurrent refreshes succeeded, want exactly 1", ok)
+	}
+}
+
+func TestAppBranding(t *testing.T) {
+	ta := newTestApp(t)
+	app, err := ta.repo.Applications().FindByClientID(context.Background(), ta.clientID)
+	if err != nil || app == nil {
+		t.Fatalf("find app: %v", err)
+	}
+	patch := func(body map[string]any) *httptest.ResponseRecorder {
+		return ta.do(http.MethodPatch, "/admin/applications/"+app.ID, body, ta.bearer(ta.adminToken))
+	}
+	mustStatus(t, patch(map[string]any{"logo_url": "javascript:alert(1)"}), http.StatusBadRequest)
+	mustStatus(t, patch(map[string]any{
+		"logo_url": "https://cdn.example.com/logo.png", "privacy_policy_url": "https://example.com/privacy",
+	}), http.StatusOK)
+
+	var branding struct {
+		Name             string  `json:"name"`
+		LogoURL          *string `json:"logo_url"`
+		PrivacyPolicyURL *string `json:"privacy_policy_url"`
+		TermsURL         *string `json:"terms_url"`
+	}
+	w := ta.do(http.MethodGet, "/api/auth/app", nil, ta.clientHeaders())
+	mustStatus(t, w, http.StatusOK)
+	decode(t, w, &branding)
+	if branding.Name != app.Name || branding.LogoURL == nil || *branding.LogoURL != "https://cdn.example.com/logo.png" ||
+		branding.PrivacyPolicyURL == nil || branding.TermsURL != nil {
+		t.Fatalf("branding = %+v", branding)
+	}
+
+	// Omitted fields stay; "" clears.
+	mustStatus(t, patch(map[string]any{"logo_url": ""}), http.StatusOK)
+	w = ta.do(http.MethodGet, "/api/auth/app", nil, ta.clientHeaders())
+	mustStatus(t, w, http.StatusOK)
+	branding.LogoURL, branding.PrivacyPolicyURL = nil, nil
+	decode(t, w, &branding)
+	if branding.LogoURL != nil || branding.PrivacyPolicyURL == nil {
+		t.Fatalf("branding after clear = %+v", branding)
+	}
+}
+
+func TestRateLimitPerClientBehindProxy(t *testing.T) {
+	ta := newTestApp(t)
+	// httptest requests come from 192.0.2.1, here the proxy in front.
+	ta.cfg.TrustedProxies = []string{"192.0.2.1"}
+	ta.engine = server.NewRouter(ta.repo, ta.jwt, ta.cfg)
+
+	from := func(ip string) map[string]string {
+		h := ta.clientHeaders()
+		h["X-Forwarded-For"] = ip
+		return h
+	}
+	// The auth limiter allows 20 per minute per client address; using them
+	// up from one client leaves the other behind the same proxy untouched.
+	for range 20 {
+		mustStatus(t, ta.do(http.MethodGet, "/api/auth/providers", nil, from("203.0.113.40")), http.StatusOK)
+	}
+	mustStatus(t, ta.do(http.MethodGet, "/api/auth/providers", nil, from("203.0.113.40")), http.StatusTooManyRequests)
+	for range 20 {
+		mustStatus(t, ta.do(http.MethodGet, "/api/auth/providers", nil, from("203.0.113.41")), http.StatusOK)
+	}
+	mustStatus(t, ta.do(http.MethodGet, "/api/auth/providers", nil, from("203.0.113.41")), http.StatusTooManyRequests)
+}
+
+func TestRateLimitExemptions(t *testing.T) {
+	ta := newTestApp(t)
+	ta.cfg.RateLimitExemptCIDRs = []string{"203.0.113.0/24"}
+	ta.cfg.RateLimitTrustedClientIDs = []string{ta.clientID}
+	ta.cfg.RateLimitTrustedFactor = 2
+	// httptest requests come from 192.0.2.1, here the proxy in front.
+	ta.cfg.TrustedProxies = []string{"192.0.2.1"}
+	ta.engine = server.NewRouter(ta.repo, ta.jwt, ta.cfg)
+
+	from := func(ip string) map[string]string { return map[string]string{"X-Forwarded-For": ip} }
+	// The OAuth limiter allows 30 per minute per IP.
+	for i := range 40 {
+		mustStatus(t, ta.do(http.MethodGet, "/oauth/scopes", nil, from("203.0.113.7")), http.StatusOK)
+		want := http.StatusOK
+		if i >= 30 {
+			want = http.StatusTooManyRequests
+		}
+		mustStatus(t, ta.do(http.MethodGet, "/oauth/scopes", nil, from("198.51.100.1")), want)
+	}
+
+	// Naming a trusted client_id without its credentials earns nothing: the
+	// auth limiter's 20 still apply.
+	h := ta.clientHeaders()
+	h["X-Forwarded-For"] = "198.51.100.2"
+	for range 20 {
+		mustStatus(t, ta.do(http.MethodGet, "/api/auth/providers", nil, h), http.StatusOK)
+	}
+	mustStatus(t, ta.do(http.MethodGet, "/api/auth/providers", nil, h), http.StatusTooManyRequests)
+
+	// Authenticated as that client, it gets twice the OAuth limiter's 30.
+	if ta.clientSecret != "" {
+		basic := from("198.51.100.3")
+		basic["Authorization"] = basicAuth(ta.clientID, ta.clientSecret)
+		for range 60 {
+			mustStatus(t, ta.do(http.MethodPost, "/oauth/verify-client", nil, basic), http.StatusOK)
+		}
+		mustStatus(t, ta.do(http.MethodPost, "/oauth/verify-client", nil, basic), http.StatusTooManyRequests)
+	}
+
+	// From a peer that is not a trusted proxy, X-Forwarded-For is ignored and
+	// cannot claim an exempt address.
+	ta.cfg.TrustedProxies = nil
+	ta.engine = server.NewRouter(ta.repo, ta.jwt, ta.cfg)
+	for range 30 {
+		mustStatus(t, ta.do(http.MethodGet, "/oauth/scopes", nil, from("203.0.113.7")), http.StatusOK)
+	}
+	mustStatus(t, ta.do(http.MethodGet, "/oauth/scopes", nil, from("203.0.113.7")), http.StatusTooManyRequests)
+}
+
+func TestApplicationStats(t *testing.T) {
+	ta := newTestApp(t)
+	ctx := context.Background()
+	app, err := ta.repo.Applications().FindByClientID(ctx, ta.clientID)
+	if err != nil || app == nil {
+		t.Fatalf("find app: %v", err)
+	}
+	ta.registerUser(t, "stats-a@example.com")
+	ta.registerUser(t, "stats-b@example.com")
+	mustStatus(t, ta.do(http.MethodPost, "/api/auth/login", map[string]any{
+		"email": "stats-a@example.com", "password": "Password1!",
+	}, ta.clientHeaders()), http.StatusOK)
+
+	// A user registered long ago whose only token for the app is revoked.
+	old := time.Now().UTC().AddDate(0, -1, 0)
+	email := "stats-old@example.com"
+	if err := ta.repo.Users().Insert(ctx, &domain.User{
+		ID: "user-stats-old", Email: &email, Role: "user", UserType: domain.UserTypeRegular,
+		IsActive: true, Membership: domain.MembershipRegular, CreatedAt: old, UpdatedAt: old,
+	}); err != nil {
+		t.Fatal(err)
+	}
+	if err := ta.repo.RefreshTokens().Insert(ctx, &domain.RefreshToken{
+		ID: "rt-stats-old", UserID: "user-stats-old", AppID: app.ID, TokenHash: "stats-old-hash",
+		Scopes: "[]", ExpiresAt: time.Now().UTC().Add(time.Hour), Revoked: true, CreatedAt: old,
+	}); err != nil {
+		t.Fatal(err)
+	}
+
+	w := ta.do(http.MethodGet, "/admin/applications/"+app.ID+"/stats", nil, ta.bearer(ta.adminToken))
+	mustStatus(t, w, http.StatusOK)
+	var stats struct {
+		AppID string `json:"app_id"`
+		Users struct {
+			Total  uint64 `json:"total"`
+			Recent uint64 `json:"recent"`
+		} `json:"users"`
+		Logins struct {
+			Recent uint64 `json:"recent"`
+			Daily  []struct {
+				Date  string `json:"date"`
+				Count uint64 `json:"count"`
+			} `json:"daily"`
+		} `json:"logins"`
+		ActiveRefreshTokens uint64 `json:"active_refresh_tokens"`
+	}
+	decode(t, w, &stats)
+	if stats.AppID != app.ID || stats.Users.Total != 3 || stats.Users.Recent != 2 || stats.ActiveRefreshTokens != 3 {
+		t.Fatalf("stats = %+v", stats)
+	}
+	// Two registrations and one login, all today.
+	if n := len(stats.Logins.Daily); n != 7 || stats.Logins.Recent != 3 || stats.Logins.Daily[n-1].Count != 3 {
+		t.Fatalf("logins = %+v", stats.Logins)
+	}
+
+	mustStatus(t, ta.do(http.MethodGet, "/admin/applications/missing/stats", nil, ta.bearer(ta.adminToken)), http.StatusNotFound)
+	user := ta.registerUser(t, "stats-plain@example.com")
+	mustStatus(t, ta.do(http.MethodGet, "/admin/applications/"+app.ID+"/stats", nil, ta.bearer(user)), http.StatusForbidden)
+}
+
+func TestPasswordDenylist(t *testing.T) {
+	ta := newTestApp(t)
+	ta.cfg.PasswordDenylist = map[string]bool{"password": true}
+	ta.engine = server.NewRouter(ta.repo, ta.jwt, ta.cfg)
+
+	w := ta.do(http.MethodPost, "/api/auth/register", map[string]any{
+		"email": "common@example.com", "password": "Password1!",
+	}, ta.clientHeaders())
+	mustStatus(t, w, http.StatusBadRequest)
+	if !strings.Contains(w.Body.String(), "too common") {
+		t.Fatalf("body = %s", w.Body.String())
+	}
+	mustStatus(t, ta.do(http.MethodPost, "/admin/users", map[string]any{
+		"email": "common@example.com", "password": "P@ssw0rd123!",
+	}, ta.bearer(ta.adminToken)), http.StatusBadRequest)
+	mustStatus(t, ta.do(http.MethodPost, "/api/auth/register", map[string]any{
+		"email": "common@example.com", "password": "Tr1cky-Horse!",
+	}, ta.clientHeaders()), http.StatusCreated)
+}
+
+func TestNonRotatingRefreshTokens(t *testing.T) {
+	ta := newTestApp(t)
+	ctx := context.Background()
+	app, err := ta.repo.Applications().FindByClientID(ctx, ta.clientID)
+	if err != nil || app == nil {
+		t.Fatalf("find app: %v", err)
+	}
+	w := ta.do(http.MethodPatch, "/admin/applications/"+app.ID, map[string]any{"rotate_refresh_tokens": false}, ta.bearer(ta.adminToken))
+	mustStatus(t, w, http.StatusOK)
+	var updated struct {
+		RotateRefreshTokens bool `json:"rotate_refresh_tokens"`
+	}
+	decode(t, w, &updated)
+	if updated.RotateRefreshTokens {
+		t.Fatal("rotate_refresh_tokens still true after update")
+	}
+
+	reg := ta.do(http.MethodPost, "/api/auth/register", map[string]any{
+		"email": "fixed@example.com", "password": "Password1!",
+	}, ta.clientHeaders())
+	mustStatus(t, reg, http.StatusCreated)
+	var tokens struct {
+		RefreshToken string `json:"refresh_token"`
+	}
+	decode(t, reg, &tokens)
+	stored, err := ta.repo.RefreshTokens().FindByTokenHash(ctx, auth.HashToken(tokens.RefreshToken))
+	if err != nil || stored == nil {
+		t.Fatalf("find refresh token: %v", err)
+	}
+	firstExpiry := stored.ExpiresAt
+
+	for range 2 {
+		w := ta.do(http.MethodPost, "/api/auth/refresh", map[string]any{"refresh_token": tokens.RefreshToken}, ta.clientHeaders())
+		mustStatus(t, w, http.StatusOK)
+		var refreshed struct {
+			AccessToken  string `json:"access_token"`
+			RefreshToken string `json:"refresh_token"`
+		}
+		decode(t, w, &refreshed)
+		if refreshed.RefreshToken != tokens.RefreshToken || refreshed.AccessToken == "" {
+			t.Fatalf("refresh returned a different token: %+v", refreshed)
+		}
+	}
+	stored, err = ta.repo.RefreshTokens().FindByTokenHash(ctx, auth.HashToken(tokens.RefreshToken))
+	if err != nil || stored == nil || stored.Revoked {
+		t.Fatalf("token after refresh = %+v, %v", stored, err)
+	}
+	if stored.ExpiresAt.Before(firstExpiry) {
+		t.Fatalf("expiry moved backwards: %v -> %v", firstExpiry, stored.ExpiresAt)
+	}
+}
+
+func TestBootstrapInfo(t *testing.T) {
+	ta := newTestApp(t)
+	w := ta.do(http.MethodGet, "/api/bootstrap-info", nil, nil)
+	mustStatus(t, w, http.StatusOK)
+	var info map[string]any
+	decode(t, w, &info)
+	if info["client_id"] != ta.clientID || len(info) != 1 {
+		t.Fatalf("bootstrap info = %v, want only client_id %q", info, ta.clientID)
+	}
+
+	ta.cfg.BootstrapAppName = "No Such App"
+	ta.engine = server.NewRouter(ta.repo, ta.jwt, ta.cfg)
+	mustStatus(t, ta.do(http.MethodGet, "/api/bootstrap-info", nil, nil), http.StatusNotFound)
+}
+
+func TestUsernameLogin(t *testing.T) {
+	ta := newTestApp(t)
+	ctx := context.Background()
+	app, err := ta.repo.Applications().FindByClientID(ctx, ta.clientID)
+	if err != nil || app == nil {
+		t.Fatalf("find app: %v", err)
+	}
+	mustStatus(t, ta.do(http.MethodPatch, "/admin/applications/"+app.ID, map[string]any{"login_identifier": "phone"}, ta.bearer(ta.adminToken)), http.StatusBadRequest)
+	w := ta.do(http.MethodPatch, "/admin/applications/"+app.ID, map[string]any{"login_identifier": "username"}, ta.bearer(ta.adminToken))
+	mustStatus(t, w, http.StatusOK)
+	var updated struct {
+		LoginIdentifier string `json:"login_identifier"`
+	}
+	decode(t, w, &updated)
+	if updated.LoginIdentifier != "username" {
+		t.Fatalf("login_identifier = %q, want username", updated.LoginIdentifier)
+	}
+
+	mustStatus(t, ta.do(http.MethodPost, "/api/auth/register", map[string]any{
+		"email": "nouser@example.com", "password": "Password1!",
+	}, ta.clientHeaders()), http.StatusBadRequest)
+	reg := ta.do(http.MethodPost, "/api/auth/register", map[string]any{
+		"username": "jdoe", "password": "Password1!",
+	}, ta.clientHeaders())
+	mustStatus(t, reg, http.StatusCreated)
+	mustStatus(t, ta.do(http.MethodPost, "/api/auth/register", map[string]any{
+		"username": "jdoe", "password": "Password1!",
+	}, ta.clientHeaders()), http.StatusConflict)
+
+	login := ta.do(http.MethodPost, "/api/auth/login", map[string]any{
+		"username": "jdoe", "password": "Password1!",
+	}, ta.clientHeaders())
+	mustStatus(t, login, http.StatusOK)
+	var tokens struct {
+		AccessToken string `json:"access_token"`
+	}
+	decode(t, login, &tokens)
+	me := ta.do(http.MethodGet, "/api/users/me", nil, ta.bearer(tokens.AccessToken))
+	mustStatus(t, me, http.StatusOK)
+	var profile struct {
+		Email    *string `json:"email"`
+		Username *string `json:"username"`
+	}
+	decode(t, me, &profile)
+	if profile.Email != nil || profile.Username == nil || *profile.Username != "jdoe" {
+		t.Fatalf("profile = %+v, want username jdoe and no email", profile)
+	}
+
+	// An email no longer identifies anyone on this app.
+	mustStatus(t, ta.do(http.MethodPost, "/admin/users", map[string]any{
+		"email": "byemail@example.com", "password": "Password1!",
+	}, ta.bearer(ta.adminToken)), http.StatusOK)
+	mustStatus(t, ta.do(http.MethodPost, "/api/auth/login", map[string]any{
+		"email": "byemail@example.com", "password": "Password1!",
+	}, ta.clientHeaders()), http.StatusUnauthorized)
+
+	if ta.clientSecret != "" {
+		tok := ta.do(http.MethodPost, "/oauth/token", map[string]any{
+			"grant_type": "password", "username": "jdoe", "password": "Password1!",
+		}, map[string]string{"Authorization": basicAuth(ta.clientID, ta.clientSecret)})
+		mustStatus(t, tok, http.StatusOK)
+	}
+}
+
+func TestTokenEndpointAcceptsFormBody(t *testing.T) {
+	ta := newTestApp(t)
+	if ta.clientSecret == "" {
+		t.Skip("client secret not available")
+	}
+	mustStatus(t, ta.do(http.MethodPost, "/admin/users", map[string]any{
+		"email": "formuser@example.com", "password": "Password1!",
+	}, ta.bearer(ta.adminToken)), http.StatusOK)
+
+	// client_secret_post: the client credentials travel in the form too.
+	w := ta.postForm("/oauth/token", url.Values{
+		"grant_type": {"password"}, "username": {"formuser@example.com"}, "password": {"Password1!"},
+		"client_id": {ta.clientID}, "client_secret": {ta.clientSecret},
+	}, nil)
+	mustStatus(t, w, http.StatusOK)
+	var tokens struct {
+		AccessToken  string `json:"access_token"`
+		RefreshToken string `json:"refresh_token"`
+	}
+	decode(t, w, &tokens)
+	if tokens.AccessToken == "" || tokens.RefreshToken == "" {
+		t.Fatalf("form password grant = %+v", tokens)
+	}
+
+	w = ta.postForm("/oauth/token", url.Values{
+		"grant_type": {"refresh_token"}, "refresh_token": {tokens.RefreshToken},
+	}, map[string]string{"Authorization": basicAuth(ta.clientID, ta.clientSecret)})
+	mustStatus(t, w, http.StatusOK)
+
+	w = ta.postForm("/oauth/token", url.Values{
+		"grant_type": {"password"}, "username": {"formuser@example.com"}, "password": {"Password1!"},
+		"client_id": {ta.clientID}, "client_secret": {"wrong"},
+	}, nil)
+	mustStatus(t, w, http.StatusUnauthorized)
+}
+
+func TestRevokeAndIntrospectAcceptFormBody(t *testing.T) {
+	ta := newTestApp(t)
+	if ta.clientSecret == "" {
+		t.Skip("client secret not available")
+	}
+	mustStatus(t, ta.do(http.MethodPost, "/admin/users", map[string]any{
+		"email": "formintrospect@example.com", "password": "Password1!",
+	}, ta.bearer(ta.adminToken)), http.StatusOK)
+	basic := map[string]string{"Authorization": basicAuth(ta.clientID, ta.clientSecret)}
+	w := ta.postForm("/oauth/token", url.Values{
+		"grant_type": {"password"}, "username": {"formintrospect@example.com"}, "password": {"Password1!"},
+	}, basic)
+	mustStatus(t, w, http.StatusOK)
+	var tokens struct {
+		AccessToken  string `json:"access_token"`
+		RefreshToken string `json:"refresh_token"`
+	}
+	decode(t, w, &tokens)
+
+	w = ta.postForm("/oauth/introspect", url.Values{
+		"token": {tokens.AccessToken}, "token_type_hint": {"access_token"},
+	}, basic)
+	mustStatus(t, w, http.StatusOK)
+	var ir struct {
+		Active bool `json:"active"`
+	}
+	decode(t, w, &ir)
+	if !ir.Active {
+		t.Fatal("form introspection reported the access token inactive")
+	}
+
+	mustStatus(t, ta.postForm("/oauth/revoke", url.Values{
+		"token": {tokens.RefreshToken}, "token_type_hint": {"refresh_token"},
+	}, basic), http.StatusOK)
+	mustStatus(t, ta.postForm("/oauth/token", url.Values{
+		"grant_type": {"refresh_token"}, "refresh_token": {tokens.RefreshToken},
+	}, basic), http.StatusUnauthorized)
+}
+
+// postForm sends an application/x-www-form-urlencoded POST.
+func (ta *testApp) postForm(path string, form url.Values, headers map[string]string) *httptest.ResponseRecorder {
+	ta.t.Helper()
+	req := httptest.NewRequest(http.MethodPost, path, strings.NewReader(form.Encode()))
+	req.Header.Set("Content-Type", "application/x-www-form-urlencoded")
+	for k, v := range headers {
+		req.Header.Set(k, v)
+	}
+	w := httptest.NewRecorder()
+	ta.engine.ServeHTTP(w, req)
+	return w
+}
+
+func TestAccessTokenCookie(t *testing.T) {
+	ta := newTestApp(t)
+	ctx := context.Background()
+	reg := ta.do(http.MethodPost, "/api/auth/register", map[string]any{
+		"email": "cookie@example.com", "password": "Password1!",
+	}, ta.clientHeaders())
+	mustStatus(t, reg, http.StatusCreated)
+	if cookies := reg.Result().Cookies(); len(cookies) != 0 {
+		t.Fatalf("cookies set without opt-in: %v", cookies)
+	}
+
+	app, err := ta.repo.Applications().FindByClientID(ctx, ta.clientID)
+	if err != nil || app == nil {
+		t.Fatalf("find app: %v", err)
+	}
+	mustStatus(t, ta.do(http.MethodPatch, "/admin/applications/"+app.ID, map[string]any{"access_token_cookie": true}, ta.bearer(ta.adminToken)), http.StatusOK)
+	login := ta.do(http.MethodPost, "/api/auth/login", map[string]any{
+		"email": "cookie@example.com", "password": "Password1!",
+	}, ta.clientHeaders())
+	mustStatus(t, login, http.StatusOK)
+	var tokens struct {
+		AccessToken  string `json:"access_token"`
+		RefreshToken string `json:"refresh_token"`
+	}
+	decode(t, login, &tokens)
+	var cookie *http.Cookie
+	for _, ck := range login.Result().Cookies() {
+		if ck.Name == "access_token" {
+			cookie = ck
+		}
+	}
+	if cookie == nil || cookie.Value != tokens.AccessToken || !cookie.HttpOnly || !cookie.Secure || cookie.SameSite != http.SameSiteLaxMode {
+		t.Fatalf("access token cookie = %+v", cookie)
+	}
+
+	req := httptest.NewRequest(http.MethodGet, "/api/users/me", nil)
+	req.AddCookie(&http.Cookie{Name: "access_token", Value: tokens.AccessToken})
+	w := httptest.NewRecorder()
+	ta.engine.ServeHTTP(w, req)
+	mustStatus(t, w, http.StatusOK)
+
+	// An explicit Authorization header wins over the cookie.
+	req = httptest.NewRequest(http.MethodGet, "/api/users/me", nil)
+	req.AddCookie(&http.Cookie{Name: "access_token", Value: tokens.AccessToken})
+	req.Header.Set("Authorization", "Bearer garbage")
+	w = httptest.NewRecorder()
+	ta.engine.ServeHTTP(w, req)
+	mustStatus(t, w, http.StatusUnauthorized)
+
+	// A state-changing request on the cookie needs an allowed Origin.
+	ta.cfg.CORSAllowedOrigins = "https://app.example.com"
+	ta.engine = server.NewRouter(ta.repo, ta.jwt, ta.cfg)
+	patchMe := func(origin string) *httptest.ResponseRecorder {
+		req := httptest.NewRequest(http.MethodPatch, "/api/users/me", strings.NewReader(`{"name":"Cookie"}`))
+		req.Header.Set("Content-Type", "application/json")
+		req.AddCookie(&http.Cookie{Name: "access_token", Value: tokens.AccessToken})
+		if origin != "" {
+			req.Header.Set("Origin", origin)
+		}
+		w := httptest.NewRecorder()
+		ta.engine.ServeHTTP(w, req)
+		return w
+	}
+	mustStatus(t, patchMe(""), http.StatusForbidden)
+	mustStatus(t, patchMe("https://evil.example.com"), http.StatusForbidden)
+	mustStatus(t, patchMe("https://app.example.com"), http.StatusOK)
+	// The same PATCH with the token as a Bearer header needs no Origin.
+	mustStatus(t, ta.do(http.MethodPatch, "/api/users/me", map[string]any{"name": "Bearer"}, ta.bearer(tokens.AccessToken)), http.StatusOK)
+
+	// Once the app opts out, its tokens no longer authenticate as cookies.
+	mustStatus(t, ta.do(http.MethodPatch, "/admin/applications/"+app.ID, map[string]any{"access_token_cookie": false}, ta.bearer(ta.adminToken)), http.StatusOK)
+	req = httptest.NewRequest(http.MethodGet, "/api/users/me", nil)
+	req.AddCookie(&http.Cookie{Name: "access_token", Value: tokens.AccessToken})
+	w = httptest.NewRecorder()
+	ta.engine.ServeHTTP(w, req)
+	mustStatus(t, w, http.StatusUnauthorized)
+}
+
+func TestUserAuditTrail(t *testing.T) {
+	ta := newTestApp(t)
+	ta.cfg.LoginMaxFailedAttempts = 1
+	ta.cfg.LoginLockoutSecs = 60
+	ta.engine = server.NewRouter(ta.repo, ta.jwt, ta.cfg)
+	ta.registerUser(t, "audited@example.com")
+	user, err := ta.repo.Users().FindByEmail(context.Background(), "audited@example.com")
+	if err != nil || user == nil {
+		t.Fatalf("find user: %v", err)
+	}
+
+	mustStatus(t, ta.do(http.MethodPost, "/api/auth/login", map[string]any{
+		"email": "audited@example.com", "password": "Wrong1!xx",
+	}, ta.clientHeaders()), http.StatusUnauthorized)
+	mustStatus(t, ta.do(http.MethodPost, "/admin/users/"+user.ID+"/unlock", nil, ta.bearer(ta.adminToken)), http.StatusOK)
+
+	type auditList struct {
+		Events []struct {
+			Type     string `json:"type"`
+			UserID   string `json:"user_id"`
+			TargetID string `json:"target_id"`
+		} `json:"events"`
+		Total   uint64 `json:"total"`
+		PerPage uint64 `json:"per_page"`
+	}
+	w := ta.do(http.MethodGet, "/admin/users/"+user.ID+"/audit", nil, ta.bearer(ta.adminToken))
+	mustStatus(t, w, http.StatusOK)
+	var all auditList
+	decode(t, w, &all)
+	if all.Total != 2 || len(all.Events) != 2 {
+		t.Fatalf("audit trail = %+v, want 2 events", all)
+	}
+	// Newest first: the admin's unlock, targeting the user, then the lockout.
+	if e := all.Events[0]; e.Type != "account_unlocked" || e.UserID != ta.adminUserID || e.TargetID != user.ID {
+		t.Fatalf("first event = %+v", e)
+	}
+	if e := all.Events[1]; e.Type != "account_locked" || e.UserID != user.ID {
+		t.Fatalf("second event = %+v", e)
+	}
+
+	w = ta.do(http.MethodGet, "/admin/users/"+user.ID+"/audit?type=account_locked&per_page=1", nil, ta.bearer(ta.adminToken))
+	mustStatus(t, w, http.StatusOK)
+	var filtered auditList
+	decode(t, w, &filtered)
+	if filtered.Total != 1 || len(filtered.Events) != 1 || filtered.Events[0].Type != "account_locked" || filtered.PerPage != 1 {
+		t.Fatalf("filtered audit trail = %+v", filtered)
+	}
+
+	// The unlocking admin sees the event in their own trail too.
+	w = ta.do(http.MethodGet, "/admin/users/"+ta.adminUserID+"/audit?type=account_unlocked", nil, ta.bearer(ta.adminToken))
+	mustStatus(t, w, http.StatusOK)
+	var admin auditList
+	decode(t, w, &admin)
+	if admin.Total != 1 {
+		t.Fatalf("admin audit trail = %+v", admin)
+	}
+
+	// Every admin change to the user lands in the user's trail.
+	mustStatus(t, ta.do(http.MethodPatch, "/admin/users/"+user.ID, map[string]any{"name": "Audited"}, ta.bearer(ta.adminToken)), http.StatusOK)
+	w = ta.do(http.MethodGet, "/admin/users/"+user.ID+"/audit?type=user_updated", nil, ta.bearer(ta.adminToken))
+	mustStatus(t, w, http.StatusOK)
+	var updated auditList
+	decode(t, w, &updated)
+	if updated.Total != 1 || updated.Events[0].UserID != ta.adminUserID || updated.Events[0].TargetID != user.ID {
+		t.Fatalf("user_updated trail = %+v", updated)
+	}
+
+	// type must name a known event, so it cannot smuggle a filter.
+	injected := "/admin/users/" + user.ID + "/audit?type=" + url.QueryEscape("x' or user_id ne '")
+	mustStatus(t, ta.do(http.MethodGet, injected, nil, ta.bearer(ta.adminToken)), http.StatusBadRequest)
+}
+
+func TestDisabledUserLoginResponse(t *testing.T) {
+	ta := newTestApp(t)
+	create := ta.do(http.MethodPost, "/admin/users", map[string]any{
+		"email": "disabled@example.com", "password": "Password1!",
+	}, ta.bearer(ta.adminToken))
+	mustStatus(t, create, http.StatusOK)
+	var created struct {
+		ID string `json:"id"`
+	}
+	decode(t, create, &created)
+	mustStatus(t, ta.do(http.MethodPatch, "/admin/users/"+created.ID, map[string]any{"is_active": false}, ta.bearer(ta.adminToken)), http.StatusOK)
+
+	login := func() (int, string) {
+		w := ta.do(http.MethodPost, "/api/auth/login", map[string]any{
+			"email": "disabled@example.com", "password": "Password1!",
+		}, ta.clientHeaders())
+		var body struct {
+			Error string `json:"error"`
+		}
+		decode(t, w, &body)
+		return w.Code, body.Error
+	}
+	if code, errCode := login(); code != http.StatusUnauthorized || errCode != "invalid_credentials" {
+		t.Fatalf("disabled login = %d %q, want 401 invalid_credentials", code, errCode)
+	}
+
+	ta.cfg.RevealDisabledStatus = true
+	ta.engine = server.NewRouter(ta.repo, ta.jwt, ta.cfg)
+	if code, errCode := login(); code != http.StatusForbidden || errCode != "user_disabled" {
+		t.Fatalf("disabled login = %d %q, want 403 user_disabled", code, errCode)
+	}
+}
+
+func TestRefreshGrantNarrowsScopes(t *testing.T) {
+	ta := newTestApp(t)
+	if ta.clientSecret == "" {
+		t.Skip("client secret not available")
+	}
+	app, err := ta.repo.Applications().FindByClientID(context.Background(), ta.clientID)
+	if err != nil || app == nil {
+		t.Fatalf("find app: %v", err)
+	}
+	mustStatus(t, ta.do(http.MethodPatch, "/admin/applications/"+app.ID, map[string]any{
+		"allowed_scopes": []string{"openid", "profile", "email"},
+	}, ta.bearer(ta.adminToken)), http.StatusOK)
+	mustStatus(t, ta.do(http.MethodPost, "/admin/users", map[string]any{
+		"email": "narrow@example.com", "password": "Password1!", "role": "user",
+	}, ta.bearer(ta.adminToken)), http.StatusOK)
+
+	basic := map[string]string{"Authorization": basicAuth(ta.clientID, ta.clientSecret)}
+	type tokenResp struct {
+		RefreshToken string `json:"refresh_token"`
+		Scope        string `json:"scope"`
+	}
+	login := ta.do(http.MethodPost, "/oauth/token", map[string]any{
+		"grant_type": "password", "username": "narrow@example.com", "password": "Password1!", "scope": "openid profile",
+	}, basic)
+	mustStatus(t, login, http.StatusOK)
+	var lr tokenResp
+	decode(t, login, &lr)
+	if lr.Scope != "openid profile" {
+		t.Fatalf("login scope = %q", lr.Scope)
+	}
+
+	refresh := func(token, scope string) *httptest.ResponseRecorder {
+		body := map[string]any{"grant_type": "refresh_token", "refresh_token": token}
+		if scope != "" {
+			body["scope"] = scope
+		}
+		return ta.do(http.MethodPost, "/oauth/token", body, basic)
+	}
+	narrowed := refresh(lr.RefreshToken, "openid")
+	mustStatus(t, narrowed, http.StatusOK)
+	var nr tokenResp
+	decode(t, narrowed, &nr)
+	if nr.Scope != "openid" {
+		t.Fatalf("narrowed scope = %q, want openid", nr.Scope)
+	}
+
+	// The narrowed refresh token cannot win back "profile", and the failed
+	// attempt leaves it usable.
+	mustStatus(t, refresh(nr.RefreshToken, "openid profile"), http.StatusBadRequest)
+	again := refresh(nr.RefreshToken, "")
+	mustStatus(t, again, http.StatusOK)
+	var ar tokenResp
+	decode(t, again, &ar)
+	if ar.Scope != "openid" {
+		t.Fatalf("scope after refresh = %q, want openid", ar.Scope)
+	}
+}
+
+func TestNonRefreshableScopes(t *testing.T) {
+	ta := newTestApp(t)
+	if ta.clientSecret == "" {
+		t.Skip("client secret not available")
+	}
+	app, err := ta.repo.Applications().FindByClientID(context.Background(), ta.clientID)
+	if err != nil || app == nil {
+		t.Fatalf("find app: %v", err)
+	}
+	mustStatus(t, ta.do(http.MethodPatch, "/admin/applications/"+app.ID, map[string]any{
+		"allowed_scopes": []string{"openid", "profile", "email"},
+	}, ta.bearer(ta.adminToken)), http.StatusOK)
+	mustStatus(t, ta.do(http.MethodPost, "/admin/users", map[string]any{
+		"email": "sensitive@example.com", "password": "Password1!", "role": "user",
+	}, ta.bearer(ta.adminToken)), http.StatusOK)
+	ta.cfg.NonRefreshableScopes = []string{"email"}
+	ta.engine = server.NewRouter(ta.repo, ta.jwt, ta.cfg)
+
+	basic := map[string]string{"Authorization": basicAuth(ta.clientID, ta.clientSecret)}
+	type tokenResp struct {
+		RefreshToken string `json:"refresh_token"`
+		Scope        string `json:"scope"`
+	}
+	login := ta.do(http.MethodPost, "/oauth/token", map[string]any{
+		"grant_type": "password", "username": "sensitive@example.com", "password": "Password1!", "scope": "openid email",
+	}, basic)
+	mustStatus(t, login, http.StatusOK)
+	var lr tokenResp
+	decode(t, login, &lr)
+	if lr.Scope != "openid email" {
+		t.Fatalf("login scope = %q, want the full grant on the first access token", lr.Scope)
+	}
+
+	refresh := func(token, scope string) *httptest.ResponseRecorder {
+		body := map[string]any{"grant_type": "refresh_token", "refresh_token": token}
+		if scope != "" {
+			body["scope"] = scope
+		}
+		return ta.do(http.MethodPost, "/oauth/token", body, basic)
+	}
+	// Asking for the stripped scope back is a narrowing the token cannot
+	// satisfy.
+	mustStatus(t, refresh(lr.RefreshToken, "email"), http.StatusBadRequest)
+	w := refresh(lr.RefreshToken, "")
+	mustStatus(t, w, http.StatusOK)
+	var rr tokenResp
+	decode(t, w, &rr)
+	if rr.Scope != "openid" {
+		t.Fatalf("refreshed scope = %q, want openid", rr.Scope)
+	}
+}
+
+func TestAppWithoutRefreshTokens(t *testing.T) {
+	ta := newTestApp(t)
+	ctx := context.Background()
+	app, err := ta.repo.Applications().FindByClientID(ctx, ta.clientID)
+	if err != nil || app == nil {
+		t.Fatalf("find app: %v", err)
+	}
+	w := ta.do(http.MethodPatch, "/admin/applications/"+app.ID, map[string]any{"issue_refresh_tokens": false}, ta.bearer(ta.adminToken))
+	mustStatus(t, w, http.StatusOK)
+	var updated struct {
+		IssueRefreshTokens bool `json:"issue_refresh_tokens"`
+	}
+	decode(t, w, &updated)
+	if updated.IssueRefreshTokens {
+		t.Fatal("issue_refresh_tokens still true after update")
+	}
+
+	accessOnly := func(w *httptest.ResponseRecorder) {
+		t.Helper()
+		var body map[string]any
+		decode(t, w, &body)
+		if body["access_token"] == nil {
+			t.Fatalf("no access token: %v", body)
+		}
+		if _, ok := body["refresh_token"]; ok {
+			t.Fatalf("refresh_token issued: %v", body)
+		}
+		if _, ok := body["refresh_token_expires_in"]; ok {
+			t.Fatalf("refresh_token_expires_in present: %v", body)
+		}
+	}
+	reg := ta.do(http.MethodPost, "/api/auth/register", map[string]any{
+		"email": "noref@example.com", "password": "Password1!",
+	}, ta.clientHeaders())
+	mustStatus(t, reg, http.StatusCreated)
+	accessOnly(reg)
+	login := ta.do(http.MethodPost, "/api/auth/login", map[string]any{
+		"email": "noref@example.com", "password": "Password1!",
+	}, ta.clientHeaders())
+	mustStatus(t, login, http.StatusOK)
+	accessOnly(login)
+	if ta.clientSecret != "" {
+		grant := ta.do(http.MethodPost, "/oauth/token", map[string]any{
+			"grant_type": "password", "username": "noref@example.com", "password": "Password1!",
+		}, map[string]string{"Authorization": basicAuth(ta.clientID, ta.clientSecret)})
+		mustStatus(t, grant, http.StatusOK)
+		accessOnly(grant)
+	}
+
+	now := time.Now().UTC()
+	usage, err := ta.repo.RefreshTokens().UsageByApp(ctx, app.ID, now, now)
+	if err != nil {
+		t.Fatalf("usage: %v", err)
+	}
+	if usage.ActiveTokens != 0 {
+		t.Fatalf("stored %d refresh tokens, want none", usage.ActiveTokens)
+	}
+}
+
+func TestApplicationOwnership(t *testing.T) {
+	ta := newTestApp(t)
+	ctx := context.Background()
+
+	create := ta.do(http.MethodPost, "/admin/applications", map[string]any{"name": "owned-app"}, ta.bearer(ta.adminToken))
+	mustStatus(t, create, http.StatusOK)
+	var created struct {
+		ID        string  `json:"id"`
+		CreatedBy *string `json:"created_by"`
+	}
+	decode(t, create, &created)
+	if created.CreatedBy == nil || *created.CreatedBy != ta.adminUserID {
+		t.Fatalf("created_by = %v, want %s", created.CreatedBy, ta.adminUserID)
+	}
+
+	// The seeded app predates tracking: no creator, but a PATCH records the
+	// admin as its last editor.
+	seeded, err := ta.repo.Applications().FindByClientID(ctx, ta.clientID)
+	if err != nil || seeded == nil {
+		t.Fatalf("find app: %v", err)
+	}
+	patch := ta.do(http.MethodPatch, "/admin/applications/"+seeded.ID, map[string]any{"name": "renamed"}, ta.bearer(ta.adminToken))
+	mustStatus(t, patch, http.StatusOK)
+	var patched struct {
+		CreatedBy *string `json:"created_by"`
+		UpdatedBy *string `json:"updated_by"`
+	}
+	decode(t, patch, &patched)
+	if patched.CreatedBy != nil || patched.UpdatedBy == nil || *patched.UpdatedBy != ta.adminUserID {
+		t.Fatalf("seeded app created_by = %v, updated_by = %v", patched.CreatedBy, patched.UpdatedBy)
+	}
+
+	add := ta.do(http.MethodPost, "/admin/applications/"+created.ID+"/providers", map[string]any{
+		"provider_id": "test", "config": map[string]any{},
+	}, ta.bearer(ta.adminToken))
+	mustStatus(t, add, http.StatusOK)
+	var provider struct {
+		CreatedBy *string `json:"created_by"`
+	}
+	decode(t, add, &provider)
+	if provider.CreatedBy == nil || *provider.CreatedBy != ta.adminUserID {
+		t.Fatalf("provider created_by = %v", provider.CreatedBy)
+	}
+
+	list := ta.do(http.MethodGet, "/admin/applications", nil, ta.bearer(ta.adminToken))
+	mustStatus(t, list, http.StatusOK)
+	var apps []struct {
+		ID        string  `json:"id"`
+		CreatedBy *string `json:"created_by"`
+		UpdatedBy *string `json:"updated_by"`
+	}
+	decode(t, list, &apps)
+	for _, a := range apps {
+		if a.ID == created.ID && (a.CreatedBy == nil || a.UpdatedBy == nil || *a.UpdatedBy != ta.adminUserID) {
+			t.Fatalf("listed app created_by = %v, updated_by = %v", a.CreatedBy, a.UpdatedBy)
+		}
+	}
+}
+
+func TestAdminAPIKey(t *testing.T) {
+	ta := newTestApp(t)
+	key := map[string]string{"X-Admin-Key": "ci-admin-key"}
+
+	// Unset, the header is ignored and the request is unauthenticated.
+	mustStatus(t, ta.do(http.MethodGet, "/admin/stats", nil, key), http.StatusUnauthorized)
+
+	ta.cfg.AdminAPIKey = "ci-admin-key"
+	ta.engine = server.NewRouter(ta.repo, ta.jwt, ta.cfg)
+	mustStatus(t, ta.do(http.MethodGet, "/admin/stats", nil, key), http.StatusOK)
+	mustStatus(t, ta.do(http.MethodGet, "/admin/users", nil, key), http.StatusOK)
+	mustStatus(t, ta.do(http.MethodGet, "/admin/stats", nil, map[string]string{"X-Admin-Key": "wrong"}), http.StatusUnauthorized)
+
+	create := ta.do(http.MethodPost, "/admin/applications", map[string]any{"name": "ci-app"}, key)
+	mustStatus(t, create, http.StatusOK)
+	var app struct {
+		CreatedBy *string `json:"created_by"`
+	}
+	decode(t, create, &app)
+	if app.CreatedBy == nil || *app.CreatedBy != "system" {
+		t.Fatalf("created_by = %v, want system", app.CreatedBy)
+	}
+}
+
+func TestRotateSecretGracePeriod(t *testing.T) {
+	ta := newTestApp(t)
+	if ta.clientSecret == "" {
+		t.Skip("client secret not available")
+	}
+	app, err := ta.repo.Applications().FindByClientID(context.Background(), ta.clientID)
+	if err != nil || app == nil {
+		t.Fatalf("find app: %v", err)
+	}
+	verify := func(secret string) int {
+		return ta.do(http.MethodPost, "/oauth/verify-client", nil, map[string]string{"Authorization": basicAuth(ta.clientID, secret)}).Code
+	}
+	rotate := func(body any) (string, *string) {
+		t.Helper()
+		w := ta.do(http.MethodPost, "/admin/applications/"+app.ID+"/rotate-secret", body, ta.bearer(ta.adminToken))
+		mustStatus(t, w, http.StatusOK)
+		var r struct {
+			ClientSecret            string  `json:"client_secret"`
+			PreviousSecretExpiresAt *string `json:"previous_secret_expires_at"`
+		}
+		decode(t, w, &r)
+		return r.ClientSecret, r.PreviousSecretExpiresAt
+	}
+
+	mustStatus(t, ta.do(http.MethodPost, "/admin/applications/"+app.ID+"/rotate-secret", map[string]any{"grace_period_secs": -1}, ta.bearer(ta.adminToken)), http.StatusBadRequest)
+
+	second, expires := rotate(map[string]any{"grace_period_secs": 3600})
+	if expires == nil {
+		t.Fatal("no previous_secret_expires_at with a grace period")
+	}
+	if verify(ta.clientSecret) != http.StatusOK || verify(second) != http.StatusOK {
+		t.Fatal("old and new secret should both work during the grace period")
+	}
+
+	// Without a body the old secret stops at once, and the grace window of
+	// the first rotation is gone with it.
+	third, expires := rotate(nil)
+	if expires != nil {
+		t.Fatalf("previous_secret_expires_at = %s without a grace period", *expires)
+	}
+	if verify(third) != http.StatusOK {
+		t.Fatal("new secret rejected")
+	}
+	if verify(second) != http.StatusUnauthorized || verify(ta.clientSecret) != http.StatusUnauthorized {
+		t.Fatal("replaced secrets should be rejected")
+	}
+}
+
+func TestSecurityLog(t *testing.T) {
+	ta := newTestApp(t)
+	if ta.clientSecret == "" {
+		t.Skip("client secret not available")
+	}
+	var buf bytes.Buffer
+	securitylog.SetOutput(&buf)
+	t.Cleanup(func() { securitylog.SetOutput(nil) })
+
+	ta.registerUser(t, "siem@example.com")
+	mustStatus(t, ta.do(http.MethodPost, "/api/auth/login", map[string]any{
+		"email": "siem@example.com", "password": "wrong-password",
+	}, ta.clientHeaders()), http.StatusUnauthorized)
+	login := ta.do(http.MethodPost, "/api/auth/login", map[string]any{
+		"email": "siem@example.com", "password": "Password1!",
+	}, ta.clientHeaders())
+	mustStatus(t, login, http.StatusOK)
+	var tr struct {
+		AccessToken  string `json:"access_token"`
+		RefreshToken string `json:"refresh_token"`
+	}
+	decode(t, login, &tr)
+	mustStatus(t, ta.do(http.MethodPost, "/api/auth/logout", map[string]any{"refresh_token": tr.RefreshToken}, ta.bearer(tr.AccessToken)), http.StatusOK)
+	mustStatus(t, ta.do(http.MethodPost, "/api/auth/register", map[string]any{
+		"email": "siem@example.com", "password": "Password1!",
+	}, ta.clientHeaders()), http.StatusConflict)
+	mustStatus(t, ta.do(http.MethodPost, "/oauth/token", map[string]any{
+		"grant_type": "client_credentials",
+	}, map[string]string{"Authorization": basicAuth(ta.clientID, "wrong-secret")}), http.StatusUnauthorized)
+	mustStatus(t, ta.do(http.MethodPost, "/oauth/token", map[string]any{
+		"grant_type": "password", "username": "siem@example.com", "password": "wrong-password",
+	}, map[string]string{"Authorization": basicAuth(ta.clientID, ta.clientSecret)}), http.StatusUnauthorized)
+
+	type entry struct {
+		Schema   string `json:"schema"`
+		Event    string `json:"event"`
+		Outcome  string `json:"outcome"`
+		Reason   string `json:"reason"`
+		Method   string `json:"method"`
+		UserID   string `json:"user_id"`
+		ClientID string `json:"client_id"`
+	}
+	var got []entry
+	for _, l := range strings.Split(strings.TrimSpace(buf.String()), "\n") {
+		var e entry
+		if err := json.Unmarshal([]byte(l), &e); err != nil {
+			t.Fatalf("line %q: %v", l, err)
+		}
+		if e.Schema != securitylog.Schema || e.ClientID != ta.clientID {
+			t.Fatalf("entry = %+v", e)
+		}
+		got = append(got, e)
+	}
+	if len(got) != 8 {
+		t.Fatalf("got %d lines: %s", len(got), buf.String())
+	}
+	if e := got[0]; e.Event != "register" || e.Outcome != "success" || e.Method != "password" || e.UserID == "" {
+		t.Fatalf("register = %+v", e)
+	}
+	if e := got[1]; e.Event != "login" || e.Outcome != "failure" || e.Reason != "invalid_password" || e.UserID != got[0].UserID {
+		t.Fatalf("failed login = %+v", e)
+	}
+	if e := got[2]; e.Event != "login" || e.Outcome != "success" || e.Method != "password" || e.UserID != got[0].UserID {
+		t.Fatalf("login = %+v", e)
+	}
+	if e := got[3]; e.Event != "token_revoked" || e.Method != "logout" {
+		t.Fatalf("logout = %+v", e)
+	}
+	if e := got[4]; e.Event != "register" || e.Outcome != "failure" || e.Reason != "user_already_exists" {
+		t.Fatalf("failed register = %+v", e)
+	}
+	if e := got[5]; e.Event != "client_auth" || e.Outcome != "failure" || e.Method != "client_secret_basic" || e.Reason != "invalid_credentials" {
+		t.Fatalf("client auth = %+v", e)
+	}
+	if e := got[6]; e.Event != "login" || e.Outcome != "failure" || e.Reason != "invalid_password" {
+		t.Fatalf("password grant login = %+v", e)
+	}
+	if e := got[7]; e.Event != "token_issued" || e.Outcome != "failure" || e.Method != "password" || e.Reason != "invalid_credentials" {
+		t.Fatalf("password grant = %+v", e)
+	}
+}
+
+func TestIntrospectBatch(t *testing.T) {
+	ta := newTestApp(t)
+	if ta.clientSecret == "" {
+		t.Skip("client secret not available")
+	}
+	basic := map[string]string{"Authorization": basicAuth(ta.clientID, ta.clientSecret)}
+
+	w := ta.do(http.MethodPost, "/oauth/introspect/batch", map[string]any{
+		"tokens": []string{"garbage", ta.adminToken},
+	}, basic)
+	mustStatus(t, w, http.StatusOK)
+	var results []struct {
+		Active bool   `json:"active"`
+		Sub    string `json:"sub"`
+	}
+	decode(t, w, &results)
+	if len(results) != 2 || results[0].Active || !results[1].Active || results[1].Sub != ta.adminUserID {
+		t.Fatalf("batch introspection = %+v", results)
+	}
+
+	w = ta.postForm("/oauth/introspect/batch", url.Values{
+		"client_id": {ta.clientID}, "client_secret": {ta.clientSecret},
+		"tokens": {ta.adminToken, "garbage"},
+	}, nil)
+	mustStatus(t, w, http.StatusOK)
+	results = nil
+	decode(t, w, &results)
+	if len(results) != 2 || !results[0].Active || results[1].Active {
+		t.Fatalf("form batch introspection = %+v", results)
+	}
+
+	tokens := make([]string, 101)
+	for i := range tokens {
+		tokens[i] = "garbage"
+	}
+	w = ta.do(http.MethodPost, "/oauth/introspect/batch", map[string]any{"tokens": tokens}, basic)
+	mustStatus(t, w, http.StatusBadRequest)
+	w = ta.do(http.MethodPost, "/oauth/introspect/batch", map[string]any{"tokens": []string{}}, basic)
+	mustStatus(t, w, http.StatusBadRequest)
+
+	w = ta.do(http.MethodPost, "/oauth/introspect/batch", map[string]any{"tokens": []string{ta.adminToken}}, nil)
+	mustStatus(t, w, http.StatusUnauthorized)
+}
+
+func TestExportMe(t *testing.T) {
+	ta := newTestApp(t)
+	token := ta.registerUser(t, "export@example.com")
+
+	w := ta.do(http.MethodGet, "/api/users/me/export", nil, ta.bearer(token))
+	mustStatus(t, w, http.StatusOK)
+	if cd := w.Header().Get("Content-Disposition"); !strings.HasPrefix(cd, "attachment") {
+		t.Fatalf("Content-Disposition = %q", cd)
+	}
+	body := w.Body.String()
+	for _, secret := range []string{"password_hash", "token_hash", "credential", "Password1!"} {
+		if strings.Contains(body, secret) {
+			t.Fatalf("export leaks %q: %s", secret, body)
+		}
+	}
+	var export struct {
+		Profile struct {
+			Email string `json:"email"`
+		} `json:"profile"`
+		Accounts []struct {
+			ProviderID string `json:"provider_id"`
+		} `json:"accounts"`
+		Sessions []struct {
+			AppID     string `json:"app_id"`
+			ExpiresAt string `json:"expires_at"`
+		} `json:"sessions"`
+		AuditEvents []map[string]any `json:"audit_events"`
+	}
+	decode(t, w, &export)
+	if export.Profile.Email != "export@example.com" {
+		t.Fatalf("profile = %+v", export.Profile)
+	}
+	if len(export.Accounts) != 1 || export.Accounts[0].ProviderID != "password" {
+		t.Fatalf("accounts = %+v", export.Accounts)
+	}
+	if len(export.Sessions) != 1 || export.Sessions[0].AppID == "" || export.Sessions[0].ExpiresAt == "" {
+		t.Fatalf("sessions = %+v", export.Sessions)
+	}
+	if export.AuditEvents == nil {
+		t.Fatal("audit_events missing")
+	}
+
+	w = ta.do(http.MethodGet, "/api/users/me/export", nil, nil)
+	mustStatus(t, w, http.StatusUnauthorized)
+}
+
+func TestConsents(t *testing.T) {
+	ta := newTestApp(t)
+	app, err := ta.repo.Applications().FindByClientID(context.Background(), ta.clientID)
+	if err != nil || app == nil {
+		t.Fatalf("find app: %v", err)
+	}
+	w := ta.do(http.MethodPost, "/api/auth/register", map[string]any{
+		"email": "consent@example.com", "password": "Password1!",
+	}, ta.clientHeaders())
+	mustStatus(t, w, http.StatusCreated)
+	var reg struct {
+		AccessToken  string `json:"access_token"`
+		RefreshToken string `json:"refresh_token"`
+	}
+	decode(t, w, &reg)
+	mustStatus(t, ta.do(http.MethodPost, "/api/auth/login", map[string]any{
+		"email": "consent@example.com", "password": "Password1!",
+	}, ta.clientHeaders()), http.StatusOK)
+
+	type consent struct {
+		AppID     string   `json:"app_id"`
+		AppName   string   `json:"app_name"`
+		Scopes    []string `json:"scopes"`
+		GrantedAt string   `json:"granted_at"`
+		Sessions  int      `json:"sessions"`
+	}
+	list := func() []consent {
+		t.Helper()
+		w := ta.do(http.MethodGet, "/api/users/me/consents", nil, ta.bearer(reg.AccessToken))
+		mustStatus(t, w, http.StatusOK)
+		var out []consent
+		decode(t, w, &out)
+		return out
+	}
+	consents := list()
+	if len(consents) != 1 || consents[0].AppID != app.ID || consents[0].AppName != app.Name ||
+		consents[0].Sessions != 2 || consents[0].GrantedAt == "" || consents[0].Scopes == nil {
+		t.Fatalf("consents = %+v", consents)
+	}
+
+	// A quoted app_id must not widen the delete to other apps or users.
+	other := ta.registerUser(t, "consent-other@example.com")
+	injected := "/api/users/me/consents/" + url.PathEscape("x' or app_id ne '")
+	mustStatus(t, ta.do(http.MethodDelete, injected, nil, ta.bearer(other)), http.StatusBadRequest)
+	if consents := list(); len(consents) != 1 || consents[0].Sessions != 2 {
+		t.Fatalf("consents after injected revoke = %+v", consents)
+	}
+
+	mustStatus(t, ta.do(http.MethodDelete, "/api/users/me/consents/"+app.ID, nil, ta.bearer(reg.AccessToken)), http.StatusNoContent)
+	if consents := list(); len(consents) != 0 {
+		t.Fatalf("consents after revoke = %+v", consents)
+	}
+	mustStatus(t, ta.do(http.MethodPost, "/api/auth/refresh", map[string]any{
+		"refresh_token": reg.RefreshToken,
+	}, ta.clientHeaders()), http.StatusUnauthorized)
+	mustStatus(t, ta.do(http.MethodDelete, "/api/users/me/consents/"+app.ID, nil, ta.bearer(reg.AccessToken)), http.StatusNotFound)
+}
+
+func TestAppAudiences(t *testing.T) {
+	ta := newTestApp(t)
+	if ta.clientSecret == "" {
+		t.Skip("client secret not available")
+	}
+	basic := map[string]string{"Authorization": basicAuth(ta.clientID, ta.clientSecret)}
+	audOf := func(token string) any {
+		t.Helper()
+		w := ta.do(http.MethodPost, "/oauth/introspect", map[string]any{"token": token}, basic)
+		mustStatus(t, w, http.StatusOK)
+		var r struct {
+			Active   bool   `json:"active"`
+			Aud      any    `json:"aud"`
+			ClientID string `json:"client_id"`
+		}
+		decode(t, w, &r)
+		if !r.Active || r.ClientID != ta.clientID {
+			t.Fatalf("introspection = %+v", r)
+		}
+		return r.Aud
+	}
+
+	// Without extra audiences aud stays a plain string.
+	if aud := audOf(ta.registerUser(t, "aud1@example.com")); aud != ta.clientID {
+		t.Fatalf("aud = %v, want %q", aud, ta.clientID)
+	}
+
+	app, err := ta.repo.Applications().FindByClientID(context.Background(), ta.clientID)
+	if err != nil || app == nil {
+		t.Fatalf("find app: %v", err)
+	}
+	w := ta.do(http.MethodPatch, "/admin/applications/"+app.ID, map[string]any{
+		"audiences": []string{" https://api.example.com ", "https://api.example.com", "urn:billing"},
+	}, ta.bearer(ta.adminToken))
+	mustStatus(t, w, http.StatusOK)
+	var updated struct {
+		Audiences []string `json:"audiences"`
+	}
+	decode(t, w, &updated)
+	if !slices.Equal(updated.Audiences, []string{"https://api.example.com", "urn:billing"}) {
+		t.Fatalf("audiences = %v", updated.Audiences)
+	}
+
+	token := ta.registerUser(t, "aud2@example.com")
+	aud, ok := audOf(token).([]any)
+	if !ok || len(aud) != 3 || aud[0] != ta.clientID || aud[1] != "https://api.example.com" || aud[2] != "urn:billing" {
+		t.Fatalf("aud = %v", audOf(token))
+	}
+	// The client_id still identifies the caller on Bearer endpoints.
+	mustStatus(t, ta.do(http.MethodGet, "/api/users/me", nil, ta.bearer(token)), http.StatusOK)
+
+	w = ta.do(http.MethodPatch, "/admin/applications/"+app.ID, map[string]any{"audiences": []string{""}}, ta.bearer(ta.adminToken))
+	mustStatus(t, w, http.StatusBadRequest)
+}
+
+func TestAppIssuer(t *testing.T) {
+	ta := newTestApp(t)
+	ctx := context.Background()
+	app, err := ta.repo.Applications().FindByClientID(ctx, ta.clientID)
+	if err != nil || app == nil {
+		t.Fatalf("find app: %v", err)
+	}
+	patch := func(issuer string) *httptest.ResponseRecorder {
+		return ta.do(http.MethodPatch, "/admin/applications/"+app.ID, map[string]any{"issuer": issuer}, ta.bearer(ta.adminToken))
+	}
+	mustStatus(t, patch("http://id.brand.example.com"), http.StatusBadRequest)
+	mustStatus(t, patch("https://id.brand.example.com?tenant=1"), http.StatusBadRequest)
+	w := patch(" https://id.brand.example.com ")
+	mustStatus(t, w, http.StatusOK)
+	var updated struct {
+		Issuer *string `json:"issuer"`
+	}
+	decode(t, w, &updated)
+	if updated.Issuer == nil || *updated.Issuer != "https://id.brand.example.com" {
+		t.Fatalf("issuer = %v", updated.Issuer)
+	}
+
+	token := ta.registerUser(t, "brand@example.com")
+	claims, err := ta.jwt.VerifyAccessToken(ctx, token)
+	if err != nil || claims.Iss != "https://id.brand.example.com" {
+		t.Fatalf("claims = %+v, err = %v", claims, err)
+	}
+	mustStatus(t, ta.do(http.MethodGet, "/api/users/me", nil, ta.bearer(token)), http.StatusOK)
+
+	issuerOf := func(path string) string {
+		t.Helper()
+		w := ta.do(http.MethodGet, path, nil, nil)
+		mustStatus(t, w, http.StatusOK)
+		var m struct {
+			Issuer string `json:"issuer"`
+		}
+		decode(t, w, &m)
+		return m.Issuer
+	}
+	if got := issuerOf("/.well-known/oauth-authorization-server?client_id=" + ta.clientID); got != "https://id.brand.example.com" {
+		t.Fatalf("app metadata issuer = %q", got)
+	}
+	if got := issuerOf("/.well-known/oauth-authorization-server"); got != "auth-service" {
+		t.Fatalf("default metadata issuer = %q", got)
+	}
+	mustStatus(t, ta.do(http.MethodGet, "/.well-known/oauth-authorization-server?client_id=nope", nil, nil), http.StatusNotFound)
+
+	// Without the override the app no longer vouches for tokens under it.
+	mustStatus(t, patch(""), http.StatusOK)
+	mustStatus(t, ta.do(http.MethodGet, "/api/users/me", nil, ta.bearer(token)), http.StatusUnauthorized)
+}
+
+func TestTokenPreview(t *testing.T) {
+	ta := newTestApp(t)
+	ctx := context.Background()
+	app, err := ta.repo.Applications().FindByClientID(ctx, ta.clientID)
+	if err != nil || app == nil {
+		t.Fatalf("find app: %v", err)
+	}
+	w := ta.do(http.MethodPatch, "/admin/applications/"+app.ID, map[string]any{
+		"custom_claims": map[string]any{"tenant_id": "t-1"},
+	}, ta.bearer(ta.adminToken))
+	mustStatus(t, w, http.StatusOK)
+	allowed := auth.DecodeStringArray(app.AllowedScopes)
+	if len(allowed) == 0 {
+		t.Skip("test app has no allowed scopes")
+	}
+
+	type preview struct {
+		Claims struct {
+			Sub string         `json:"sub"`
+			Aud string         `json:"aud"`
+			Ext map[string]any `json:"ext"`
+		} `json:"claims"`
+		Scopes        []string `json:"scopes"`
+		DroppedScopes []string `json:"dropped_scopes"`
+		ExpiresIn     int64    `json:"expires_in"`
+		SampleUser    bool     `json:"sample_user"`
+	}
+	w = ta.do(http.MethodGet, "/admin/applications/"+app.ID+"/token-preview?scope="+url.QueryEscape(allowed[0]+" not-allowed"), nil, ta.bearer(ta.adminToken))
+	mustStatus(t, w, http.StatusOK)
+	var p preview
+	decode(t, w, &p)
+	if !p.SampleUser || p.Claims.Aud != ta.clientID || p.Claims.Ext["tenant_id"] != "t-1" || p.ExpiresIn != ta.jwt.AccessTokenExpirySecs() {
+		t.Fatalf("preview = %+v", p)
+	}
+	if !slices.Equal(p.Scopes, allowed[:1]) || !slices.Equal(p.DroppedScopes, []string{"not-allowed"}) {
+		t.Fatalf("scopes = %v, dropped = %v", p.Scopes, p.DroppedScopes)
+	}
+
+	w = ta.do(http.MethodGet, "/admin/applications/"+app.ID+"/token-preview?user_id="+ta.adminUserID, nil, ta.bearer(ta.adminToken))
+	mustStatus(t, w, http.StatusOK)
+	p = preview{}
+	decode(t, w, &p)
+	if p.SampleUser || p.Claims.Sub != ta.adminUserID {
+		t.Fatalf("user preview = %+v", p)
+	}
+
+	w = ta.do(http.MethodGet, "/admin/applications/"+app.ID+"/token-preview?user_id=missing", nil, ta.bearer(ta.adminToken))
+	mustStatus(t, w, http.StatusNotFound)
+	w = ta.do(http.MethodGet, "/admin/applications/missing/token-preview", nil, ta.bearer(ta.adminToken))
+	mustStatus(t, w, http.StatusNotFound)
+
+	// A real user's claims carry their profile, so applications:read alone
+	// only gets the sample user.
+	ta.cfg.Roles.Allowed = append(ta.cfg.Roles.Allowed, "app-viewer")
+	ta.cfg.Roles.Permissions = map[string][]string{"app-viewer": {domain.PermApplicationsRead}}
+	ta.engine = server.NewRouter(ta.repo, ta.jwt, ta.cfg)
+	w = ta.do(http.MethodPost, "/admin/users", map[string]any{
+		"email": "app-viewer@example.com", "password": "Password1!", "role": "app-viewer",
+	}, ta.bearer(ta.adminToken))
+	mustStatus(t, w, http.StatusOK)
+	var u struct {
+		ID string `json:"id"`
+	}
+	decode(t, w, &u)
+	viewer, err := ta.jwt.IssueAccessToken(ctx, u.ID, ta.clientID, nil, "app-viewer", domain.MembershipRegular, domain.UserTypeRegular, nil, auth.TokenExtras{})
+	if err != nil {
+		t.Fatalf("issue token: %v", err)
+	}
+	mustStatus(t, ta.do(http.MethodGet, "/admin/applications/"+app.ID+"/token-preview", nil, ta.bearer(viewer)), http.StatusOK)
+	w = ta.do(http.MethodGet, "/admin/applications/"+app.ID+"/token-preview?user_id="+ta.adminUserID, nil, ta.bearer(viewer))
+	mustStatus(t, w, http.StatusForbidden)
+}
+
+func TestPaginationParams(t *testing.T) {
+	ta := newTestApp(t)
+	token := ta.registerUser(t, "paging@example.com")
+
+	for _, q := range []string{"page=abc", "per_page=abc", "page=1.5", "per_page=20x"} {
+		mustStatus(t, ta.do(http.MethodGet, "/admin/users?"+q, nil, ta.bearer(ta.adminToken)), http.StatusBadRequest)
+		mustStatus(t, ta.do(http.MethodGet, "/admin/users/"+ta.adminUserID+"/audit?"+q, nil, ta.bearer(ta.adminToken)), http.StatusBadRequest)
+		mustStatus(t, ta.do(http.MethodGet, "/api/users/me/accounts?"+q, nil, ta.bearer(token)), http.StatusBadRequest)
+	}
+
+	type listPage struct {
+		Page    uint64 `json:"page"`
+		PerPage uint64 `json:"per_page"`
+	}
+	cases := []struct {
+		query         string
+		page, perPage uint64
+	}{
+		{"", 1, 20},
+		{"page=0&per_page=0", 1, 1},
+		{"page=-3&per_page=-5", 1, 1},
+		{"per_page=1000", 1, 100},
+	}
+	for _, tc := range cases {
+		w := ta.do(http.MethodGet, "/admin/users?"+tc.query, nil, ta.bearer(ta.adminToken))
+		mustStatus(t, w, http.StatusOK)
+		var got listPage
+		decode(t, w, &got)
+		if got.Page != tc.page || got.PerPage != tc.perPage {
+			t.Fatalf("%q: page=%d per_page=%d, want %d/%d", tc.query, got.Page, got.PerPage, tc.page, tc.perPage)
+		}
+	}
+
+	ta.cfg.MaxPageSize = 5
+	ta.engine = server.NewRouter(ta.repo, ta.jwt, ta.cfg)
+	w := ta.do(http.MethodGet, "/admin/users?per_page=50", nil, ta.bearer(ta.adminToken))
+	mustStatus(t, w, http.StatusOK)
+	var got listPage
+	decode(t, w, &got)
+	if got.PerPage != 5 {
+		t.Fatalf("per_page = %d, want MAX_PAGE_SIZE 5", got.PerPage)
+	}
+}
+
+func TestTokenHashKeyKeepsLegacyTokens(t *testing.T) {
+	ta := newTestApp(t)
+	ta.registerUser(t, "hashkey@example.com")
+	login := ta.do(http.MethodPost, "/api/auth/login", map[string]any{
+		"email": "hashkey@example.com", "password": "Password1!",
+	}, ta.clientHeaders())
+	mustStatus(t, login, http.StatusOK)
+	var lr struct {
+		RefreshToken string `json:"refresh_token"`
+	}
+	decode(t, login, &lr)
+
+	ta.cfg.TokenHashKey = "test-token-hash-key"
+	ta.engine = server.NewRouter(ta.repo, ta.jwt, ta.cfg)
+	refresh := ta.do(http.MethodPost, "/api/auth/refresh", map[string]any{
+		"refresh_token": lr.RefreshToken,
+	}, ta.clientHeaders())
+	mustStatus(t, refresh, http.StatusOK)
+	var rr struct {
+		RefreshToken string `json:"refresh_token"`
+	}
+	decode(t, refresh, &rr)
+
+	stored, err := ta.repo.RefreshTokens().FindByTokenHash(context.Background(), auth.HashToken(rr.RefreshToken))
+	if err != nil || stored == nil {
+		t.Fatalf("rotated token not stored under its keyed hash: %v", err)
+	}
+	if !strings.HasPrefix(stored.TokenHash, "hmac1:") {
+		t.Fatalf("stored hash = %q", stored.TokenHash)
+	}
+	mustStatus(t, ta.do(http.MethodPost, "/api/auth/refresh", map[string]any{
+		"refresh_token": rr.RefreshToken,
+	}, ta.clientHeaders()), http.StatusOK)
+}
+
+func TestDiscoverProviderByEmailDomain(t *testing.T) {
+	ta := newTestApp(t)
+	app, err := ta.repo.Applications().FindByClientID(context.Background(), ta.clientID)
+	if err != nil || app == nil {
+		t.Fatalf("find app: %v", err)
+	}
+	add := ta.do(http.MethodPost, "/admin/applications/"+app.ID+"/providers", map[string]any{
+		"provider_id": "test", "config": map[string]any{}, "display_name": "Corp SSO", "email_domains": []string{"@Corp.Example"},
+	}, ta.bearer(ta.adminToken))
+	mustStatus(t, add, http.StatusOK)
+	var added struct {
+		EmailDomains []string `json:"email_domains"`
+	}
+	decode(t, add, &added)
+	if len(added.EmailDomains) != 1 || added.EmailDomains[0] != "corp.example" {
+		t.Fatalf("email_domains = %v", added.EmailDomains)
+	}
+	mustStatus(t, ta.do(http.MethodPost, "/admin/applications/"+app.ID+"/providers", map[string]any{
+		"provider_id": "wechat", "config": map[string]any{"appid": "wx1", "secret": "top-secret"}, "email_domains": []string{"corp.example"},
+	}, ta.bearer(ta.adminToken)), http.StatusBadRequest)
+
+	type discovered struct {
+		Provider *struct {
+			ProviderID  string  `json:"provider_id"`
+			DisplayName *string `json:"display_name"`
+		} `json:"provider"`
+	}
+	w := ta.do(http.MethodGet, "/api/auth/discover?email="+url.QueryEscape("ann@CORP.example"), nil, ta.clientHeaders())
+	mustStatus(t, w, http.StatusOK)
+	var hit discovered
+	decode(t, w, &hit)
+	if hit.Provider == nil || hit.Provider.ProviderID != "test" || hit.Provider.DisplayName == nil || *hit.Provider.DisplayName != "Corp SSO" {
+		t.Fatalf("discover = %+v", hit.Provider)
+	}
+
+	w = ta.do(http.MethodGet, "/api/auth/discover?email="+url.QueryEscape("bob@example.com"), nil, ta.clientHeaders())
+	mustStatus(t, w, http.StatusOK)
+	var miss discovered
+	decode(t, w, &miss)
+	if miss.Provider != nil {
+		t.Fatalf("discover for an unmapped domain = %+v", miss.Provider)
+	}
+
+	mustStatus(t, ta.do(http.MethodGet, "/api/auth/discover", nil, ta.clientHeaders()), http.StatusBadRequest)
+	mustStatus(t, ta.do(http.MethodGet, "/api/auth/discover?email=ann@", nil, ta.clientHeaders()), http.StatusBadRequest)
+}
+
+func TestAdminRateLimits(t *testing.T) {
+	ta := newTestApp(t)
+	ta.cfg.TrustedProxies = []string{"192.0.2.1"}
+	ta.engine = server.NewRouter(ta.repo, ta.jwt, ta.cfg)
+	h := ta.clientHeaders()
+	h["X-Forwarded-For"] = "198.51.100.9"
+	// The auth limiter allows 20 per minute per IP.
+	for range 23 {
+		ta.do(http.MethodGet, "/api/auth/providers", nil, h)
+	}
+
+	w := ta.do(http.MethodGet, "/admin/rate-limits?top=5", nil, ta.bearer(ta.adminToken))
+	mustStatus(t, w, http.StatusOK)
+	var body struct {
+		Limiters map[string]struct {
+			Max             int               `json:"max"`
+			WindowSecs      int64             `json:"window_secs"`
+			ActiveKeys      int               `json:"active_keys"`
+			Rejected        uint64            `json:"rejected"`
+			RejectedByRoute map[string]uint64 `json:"rejected_by_route"`
+			TopKeys         []struct {
+				Key      string `json:"key"`
+				Requests int    `json:"requests"`
+				Rejected uint64 `json:"rejected"`
+			} `json:"top_keys"`
+		} `json:"limiters"`
+	}
+	decode(t, w, &body)
+	authStats, ok := body.Limiters["auth"]
+	if !ok || len(body.Limiters) != 4 {
+		t.Fatalf("limiters = %+v", body.Limiters)
+	}
+	if authStats.Max != 20 || authStats.WindowSecs != 60 || authStats.Rejected != 3 || authStats.ActiveKeys < 1 {
+		t.Fatalf("auth limiter = %+v", authStats)
+	}
+	if authStats.RejectedByRoute["/api/auth/providers"] != 3 {
+		t.Fatalf("rejected_by_route = %v", authStats.RejectedByRoute)
+	}
+	if len(authStats.TopKeys) != 1 || authStats.TopKeys[0].Key != "198.51.100.9" || authStats.TopKeys[0].Rejected != 3 || authStats.TopKeys[0].Requests != 20 {
+		t.Fatalf("top_keys = %+v", authStats.TopKeys)
+	}
+
+	mustStatus(t, ta.do(http.MethodGet, "/admin/rate-limits?top=0", nil, ta.bearer(ta.adminToken)), http.StatusBadRequest)
+}
+
+func TestRequireEmailFromProvider(t *testing.T) {
+	ta := newTestApp(t)
+	app, err := ta.repo.Applications().FindByClientID(context.Background(), ta.clientID)
+	if err != nil || app == nil {
+		t.Fatalf("find app: %v", err)
+	}
+	patch := ta.do(http.MethodPatch, "/admin/applications/"+app.ID, map[string]any{
+		"require_email_from_provider": true,
+	}, ta.bearer(ta.adminToken))
+	mustStatus(t, patch, http.StatusOK)
+	var patched struct {
+		RequireEmailFromProvider bool `json:"require_email_from_provider"`
+	}
+	decode(t, patch, &patched)
+	if !patched.RequireEmailFromProvider {
+		t.Fatal("require_email_from_provider not saved")
+	}
+	mustStatus(t, ta.do(http.MethodPost, "/admin/applications/"+app.ID+"/providers", map[string]any{
+		"provider_id": "test", "config": map[string]any{},
+	}, ta.bearer(ta.adminToken)), http.StatusOK)
+	ta.registerUser(t, "taken@example.com")
+
+	login := func(body map[string]any) *httptest.ResponseRecorder {
+		return ta.do(http.MethodPost, "/api/auth/provider/test/login", body, ta.clientHeaders())
+	}
+	credential := map[string]any{"account_id": "no-email-1", "name": "Wei"}
+	w := login(map[string]any{"credential": credential})
+	mustStatus(t, w, http.StatusUnprocessableEntity)
+	var missing struct {
+		Error   string `json:"error"`
+		Details struct {
+			ProviderID string `json:"provider_id"`
+			Name       string `json:"name"`
+		} `json:"details"`
+	}
+	decode(t, w, &missing)
+	if missing.Error != "email_required" || missing.Details.ProviderID != "test" || missing.Details.Name != "Wei" {
+		t.Fatalf("email_required body = %+v", missing)
+	}
+
+	mustStatus(t, login(map[string]any{"credential": credential, "email": "not an email"}), http.StatusBadRequest)
+	mustStatus(t, login(map[string]any{"credential": credential, "email": "taken@example.com"}), http.StatusConflict)
+	w = login(map[string]any{"credential": credential, "email": "wei@example.com"})
+	mustStatus(t, w, http.StatusOK)
+	var tr struct {
+		AccessToken string `json:"access_token"`
+	}
+	decode(t, w, &tr)
+	me := ta.do(http.MethodGet, "/api/users/me", nil, ta.bearer(tr.AccessToken))
+	mustStatus(t, me, http.StatusOK)
+	var profile struct {
+		Email         *string `json:"email"`
+		EmailVerified bool    `json:"email_verified"`
+	}
+	decode(t, me, &profile)
+	if profile.Email == nil || *profile.Email != "wei@example.com" || profile.EmailVerified {
+		t.Fatalf("profile = %+v", profile)
+	}
+
+	// The account exists now, so later logins need no email.
+	mustStatus(t, login(map[string]any{"credential": credential}), http.StatusOK)
+}
+
+func TestCompleteRegistration(t *testing.T) {
+	ta := newTestApp(t)
+	app, err := ta.repo.Applications().FindByClientID(context.Background(), ta.clientID)
+	if err != nil || app == nil {
+		t.Fatalf("find app: %v", err)
+	}
+	mustStatus(t, ta.do(http.MethodPatch, "/admin/applications/"+app.ID, map[string]any{
+		"require_email_from_provider": true,
+	}, ta.bearer(ta.adminToken)), http.StatusOK)
+	mustStatus(t, ta.do(http.MethodPost, "/admin/applications/"+app.ID+"/providers", map[string]any{
+		"provider_id": "test", "config": map[string]any{},
+	}, ta.bearer(ta.adminToken)), http.StatusOK)
+	userToken := ta.registerUser(t, "taken@example.com")
+
+	w := ta.do(http.MethodPost, "/api/auth/provider/test/login", map[string]any{
+		"credential": map[string]any{"account_id": "no-email-2", "name": "Li", "avatar_url": "https://img.example.com/li.png"},
+	}, ta.clientHeaders())
+	mustStatus(t, w, http.StatusUnprocessableEntity)
+	var missing struct {
+		Details struct {
+			RegistrationToken string `json:"registration_token"`
+			ExpiresIn         int64  `json:"expires_in"`
+		} `json:"details"`
+	}
+	decode(t, w, &missing)
+	regToken := missing.Details.RegistrationToken
+	if regToken == "" || missing.Details.ExpiresIn != 600 {
+		t.Fatalf("email_required details = %+v", missing.Details)
+	}
+
+	complete := func(token, email string) *httptest.ResponseRecorder {
+		return ta.do(http.MethodPost, "/api/auth/complete-registration", map[string]any{
+			"registration_token": token, "email": email,
+		}, ta.clientHeaders())
+	}
+	mustStatus(t, complete(regToken, ""), http.StatusBadRequest)
+	mustStatus(t, complete("bogus", "li@example.com"), http.StatusUnauthorized)
+	// An access token is not a registration token.
+	mustStatus(t, complete(userToken, "li@example.com"), http.StatusUnauthorized)
+	mustStatus(t, complete(regToken, "not an email"), http.StatusBadRequest)
+	mustStatus(t, complete(regToken, "taken@example.com"), http.StatusConflict)
+
+	w = complete(regToken, "li@example.com")
+	mustStatus(t, w, http.StatusOK)
+	var tr struct {
+		AccessToken  string `json:"access_token"`
+		RefreshToken string `json:"refresh_token"`
+	}
+	decode(t, w, &tr)
+	if tr.RefreshToken == "" {
+		t.Fatal("no refresh token issued")
+	}
+	me := ta.do(http.MethodGet, "/api/users/me", nil, ta.bearer(tr.AccessToken))
+	mustStatus(t, me, http.StatusOK)
+	var profile struct {
+		Email     *string `json:"email"`
+		Name      *string `json:"name"`
+		AvatarURL *string `json:"avatar_url"`
+	}
+	decode(t, me, &profile)
+	if profile.Email == nil || *profile.Email != "li@example.com" || profile.Name == nil || *profile.Name != "Li" ||
+		profile.AvatarURL == nil || *profile.AvatarURL != "https://img.example.com/li.png" {
+		t.Fatalf("profile = %+v", profile)
+	}
+
+	// The provider account is linked now, so the token cannot create a
+	// second user.
+	mustStatus(t, complete(regToken, "li2@example.com"), http.StatusConflict)
+	mustStatus(t, ta.do(http.MethodPost, "/api/auth/provider/test/login", map[string]any{
+		"credential": map[string]any{"account_id": "no-email-2"},
+	}, ta.clientHeaders()), http.StatusOK)
+}
+
 func (ta *testApp) registerUser(t *testing.T, email string) string {
 	t.Helper()
 	w := ta.do(http.MethodPost, "/api/auth/register", map[string]any{
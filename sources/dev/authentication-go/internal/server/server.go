@@ -10,67 +10,151 @@ import (
 
 	"github.com/gin-gonic/gin"
 
+	"github.com/zhaochy1990/auth-service/internal/audit"
 	"github.com/zhaochy1990/auth-service/internal/auth"
+	"github.com/zhaochy1990/auth-service/internal/buildinfo"
 	"github.com/zhaochy1990/auth-service/internal/config"
+	"github.com/zhaochy1990/auth-service/internal/domain"
 	"github.com/zhaochy1990/auth-service/internal/handlers"
 	"github.com/zhaochy1990/auth-service/internal/middleware"
 	"github.com/zhaochy1990/auth-service/internal/repository"
+	"github.com/zhaochy1990/auth-service/internal/repository/sealed"
 )
 
 // NewRouter builds the fully wired Gin engine.
 func NewRouter(repo repository.Repository, jwt *auth.JWTManager, cfg *config.Config) *gin.Engine {
 	r := gin.New()
+	// Only TRUSTED_PROXIES may report the caller's address; gin otherwise
+	// trusts every peer. FromEnv validates the list, so a failure here means
+	// a hand-built config, and trusting none is the safe fallback.
+	if err := r.SetTrustedProxies(cfg.TrustedProxies); err != nil {
+		_ = r.SetTrustedProxies(nil)
+	}
 	r.Use(gin.Recovery())
+	r.Use(middleware.WarnUntrustedForwarding(audit.Warn))
+	if cfg.ErrorFormat == config.ErrorFormatProblemJSON {
+		r.Use(middleware.ProblemJSONErrors())
+	}
+	if len(cfg.Locales) > 0 {
+		r.Use(middleware.Localize(cfg.Locales))
+	}
 	r.Use(middleware.CORS(cfg.CORSAllowedOrigins))
+	r.Use(middleware.SecurityHeaders(cfg.HSTSMaxAgeSecs))
+	if cfg.RequireHTTPS {
+		r.Use(middleware.RequireHTTPS("/health"))
+	}
+	r.Use(middleware.Compress(1024))
+	providerTimeout := time.Duration(cfg.ProviderRequestTimeoutSecs) * time.Second
+	r.Use(middleware.Timeout(time.Duration(cfg.RequestTimeoutSecs)*time.Second, map[string]time.Duration{
+		"/api/auth/provider/:provider_id/login":    providerTimeout,
+		"/api/users/me/accounts/:provider_id/link": providerTimeout,
+	}))
 
+	// Provider secrets and metadata are sealed at rest (DATA_ENCRYPTION_KEY).
+	auth.Configure(cfg)
+	repo = sealed.Wrap(repo)
+	audit.SetStore(repo.AuditEvents())
+	if cfg.AccessTokenFormat == config.AccessTokenFormatOpaque {
+		jwt.UseOpaqueTokens(repo.AccessTokens())
+	}
+	jwt.UseAppIssuers(repo.Applications())
 	h := handlers.New(repo, jwt, cfg)
-	am := &middleware.Auth{Repo: repo, JWT: jwt}
+	am := &middleware.Auth{Repo: repo, JWT: jwt, Roles: cfg.Roles, AdminAPIKey: cfg.AdminAPIKey, CookieOrigins: cfg.CORSAllowedOrigins, PublicBaseURL: cfg.PublicBaseURL}
+	am.AdminGuard = middleware.NewAdminGuard(int(cfg.AdminAuthMaxFailures),
+		time.Duration(cfg.AdminAuthFailureWindowSecs)*time.Second, time.Duration(cfg.AdminAuthBlockSecs)*time.Second)
 
 	// Per-IP sliding-window rate limiters.
 	authLimiter := middleware.NewRateLimiter(20, 60*time.Second)  // brute-force protection
 	oauthLimiter := middleware.NewRateLimiter(30, 60*time.Second) // OAuth2
 	userLimiter := middleware.NewRateLimiter(60, 60*time.Second)  // shared by /api/users + /api/teams
 	adminLimiter := middleware.NewRateLimiter(60, 60*time.Second) // admin
+	exemptions := middleware.NewRateLimitExemptions(cfg.RateLimitExemptCIDRs, cfg.RateLimitTrustedClientIDs, int(cfg.RateLimitTrustedFactor))
+	if exemptions != nil {
+		exemptions.Authenticate = am.AuthenticatedClientID
+	}
+	for _, l := range []*middleware.RateLimiter{authLimiter, oauthLimiter, userLimiter, adminLimiter} {
+		l.Exemptions = exemptions
+	}
+	h.RateLimiters = map[string]*middleware.RateLimiter{
+		"auth": authLimiter, "oauth": oauthLimiter, "user": userLimiter, "admin": adminLimiter,
+	}
+	idempotency := middleware.NewIdempotency(time.Duration(cfg.IdempotencyTTLSecs) * time.Second)
 
 	r.GET("/health", func(c *gin.Context) {
 		version := os.Getenv("APP_VERSION")
 		if version == "" {
 			version = "dev"
 		}
-		c.JSON(http.StatusOK, gin.H{"status": "ok", "version": version})
+		c.JSON(http.StatusOK, gin.H{"status": "ok", "version": version, "build": buildinfo.Get()})
 	})
 
-	// OAuth2 endpoints (Basic-auth client).
+	// OAuth2 endpoints (client_secret_basic, client_secret_post or
+	// private_key_jwt client auth).
 	oauth := r.Group("/oauth")
-	oauth.Use(oauthLimiter.Middleware(), am.AuthenticatedApp())
+	oauth.Use(oauthLimiter.Middleware(), middleware.NoStore(), am.AuthenticatedApp())
 	{
-		oauth.POST("/token", h.Token)
+		oauth.POST("/token", idempotency.Middleware(), h.Token)
 		oauth.POST("/revoke", h.Revoke)
 		oauth.POST("/introspect", h.Introspect)
+		oauth.POST("/introspect/batch", h.IntrospectBatch)
+		oauth.POST("/verify-client", h.VerifyClient)
+	}
+
+	// Verification-key info (read-only; admin-only when configured).
+	keyHandlers := []gin.HandlerFunc{oauthLimiter.Middleware()}
+	if cfg.JWTKeysRequireAdmin {
+		keyHandlers = append(keyHandlers, am.AdminAuth())
 	}
+	r.GET("/oauth/keys/pem", append(keyHandlers, h.KeysPEM)...)
+
+	// Scope registry (public; feeds consent screens).
+	r.GET("/oauth/scopes", oauthLimiter.Middleware(), h.ListScopes)
 
-	// Auth endpoints (X-Client-Id, except logout which is Bearer).
+	// Frontend bootstrap: the dashboard app's client_id (public).
+	r.GET("/api/bootstrap-info", oauthLimiter.Middleware(), h.BootstrapInfo)
+
+	// Authorization server metadata (RFC 8414; public).
+	r.GET("/.well-known/oauth-authorization-server", oauthLimiter.Middleware(), h.Metadata)
+
+	// Auth endpoints (X-Client-Id, except logout and session, which are Bearer,
+	// and email confirmation, which carries its own token).
 	authGroup := r.Group("/api/auth")
-	authGroup.Use(authLimiter.Middleware())
+	authGroup.Use(authLimiter.Middleware(), middleware.NoStore())
 	{
-		authGroup.POST("/register", am.ClientApp(), h.Register)
+		authGroup.POST("/register", am.ClientApp(), idempotency.Middleware(), h.Register)
 		authGroup.POST("/login", am.ClientApp(), h.Login)
+		authGroup.GET("/providers", am.ClientApp(), h.ListClientProviders)
+		authGroup.GET("/discover", am.ClientApp(), h.Discover)
+		authGroup.GET("/app", am.ClientApp(), h.GetClientApp)
 		authGroup.POST("/provider/:provider_id/login", am.ClientApp(), h.ProviderLogin)
+		authGroup.POST("/complete-registration", am.ClientApp(), h.CompleteRegistration)
 		authGroup.POST("/refresh", am.ClientApp(), h.Refresh)
 		authGroup.POST("/logout", am.AuthenticatedUser(), h.Logout)
+		authGroup.GET("/session", am.AuthenticatedUser(), h.Session)
+		authGroup.POST("/email/confirm", h.ConfirmEmailChange)
 	}
 
-	// User endpoints (Bearer).
+	// User endpoints (Bearer). Sensitive account changes need a recent
+	// sign-in (STEP_UP_MAX_AGE_SECS); linking a provider has its own limit.
+	stepUp := middleware.RequireRecentAuth(cfg.StepUpMaxAgeSecs)
+	linkMaxAge := cfg.LinkReauthMaxAgeSecs
+	if linkMaxAge <= 0 {
+		linkMaxAge = cfg.StepUpMaxAgeSecs
+	}
 	users := r.Group("/api/users")
 	users.Use(userLimiter.Middleware(), am.AuthenticatedUser())
 	{
 		users.GET("/me", h.GetProfile)
 		users.PATCH("/me", h.UpdateProfile)
-		users.DELETE("/me", h.DeleteMe)
+		users.POST("/me/email", stepUp, h.RequestEmailChange)
+		users.DELETE("/me", stepUp, h.DeleteMe)
 		users.GET("/me/accounts", h.ListAccounts)
-		users.POST("/me/accounts/:provider_id/link", h.LinkAccount)
-		users.DELETE("/me/accounts/:provider_id", h.UnlinkAccount)
+		users.POST("/me/accounts/:provider_id/link", middleware.RequireRecentAuth(linkMaxAge), h.LinkAccount)
+		users.DELETE("/me/accounts/:provider_id", stepUp, h.UnlinkAccount)
+		users.GET("/me/consents", h.ListConsents)
+		users.DELETE("/me/consents/:app_id", h.RevokeConsent)
 		users.GET("/me/teams", h.ListMyTeams)
+		users.GET("/me/export", h.ExportMe)
 	}
 
 	// Team endpoints (Bearer; shares the user limiter instance).
@@ -92,34 +176,44 @@ func NewRouter(repo repository.Repository, jwt *auth.JWTManager, cfg *config.Con
 	adminRead := r.Group("/admin")
 	adminRead.Use(adminLimiter.Middleware(), am.AdminOrAppTokenAuth())
 	{
-		adminRead.GET("/users", h.ListUsers)
+		adminRead.GET("/users", am.RequirePermission(domain.PermUsersRead), h.ListUsers)
 	}
 
-	// Admin endpoints (Bearer with admin role).
+	// Admin endpoints (Bearer with a privileged role; each route names the
+	// permission it needs, and admin roles hold them all).
+	perm := am.RequirePermission
 	admin := r.Group("/admin")
 	admin.Use(adminLimiter.Middleware(), am.AdminAuth())
 	{
-		admin.POST("/applications", h.CreateApplication)
-		admin.GET("/applications", h.ListApplications)
-		admin.PATCH("/applications/:id", h.UpdateApplication)
-		admin.GET("/applications/:id/providers", h.ListProviders)
-		admin.POST("/applications/:id/providers", h.AddProvider)
-		admin.DELETE("/applications/:id/providers/:provider_id", h.RemoveProvider)
-		admin.POST("/applications/:id/rotate-secret", h.RotateSecret)
-		admin.POST("/users", h.CreateUser)
-		admin.GET("/users/:id", h.GetUser)
-		admin.PATCH("/users/:id", h.UpdateUser)
-		admin.DELETE("/users/:id", h.DeleteUser)
-		admin.GET("/users/:id/accounts", h.GetUserAccounts)
-		admin.DELETE("/users/:id/accounts/:provider_id", h.AdminUnlinkAccount)
-		admin.POST("/users/:id/reset-password", h.ResetUserPassword)
-		admin.GET("/stats", h.Stats)
-		admin.GET("/invite-codes", h.ListInviteCodes)
-		admin.POST("/invite-codes", h.CreateInviteCode)
-		admin.DELETE("/invite-codes/:code", h.RevokeInviteCode)
-		admin.POST("/teams", h.AdminCreateTeam)
-		admin.POST("/teams/:id/members", h.AdminAddTeamMember)
-		admin.DELETE("/teams/:id/members/:user_id", h.AdminRemoveTeamMember)
+		admin.POST("/applications", perm(domain.PermApplicationsWrite), h.CreateApplication)
+		admin.GET("/applications", perm(domain.PermApplicationsRead), h.ListApplications)
+		admin.PATCH("/applications/:id", perm(domain.PermApplicationsWrite), h.UpdateApplication)
+		admin.GET("/applications/:id/providers", perm(domain.PermApplicationsRead), h.ListProviders)
+		admin.POST("/applications/:id/providers", perm(domain.PermApplicationsWrite), h.AddProvider)
+		admin.DELETE("/applications/:id/providers/:provider_id", perm(domain.PermApplicationsWrite), h.RemoveProvider)
+		admin.POST("/applications/:id/rotate-secret", perm(domain.PermApplicationsWrite), h.RotateSecret)
+		admin.GET("/applications/:id/token-preview", perm(domain.PermApplicationsRead), h.TokenPreview)
+		admin.GET("/applications/:id/stats", perm(domain.PermStatsRead), h.ApplicationStats)
+		admin.POST("/users", perm(domain.PermUsersWrite), h.CreateUser)
+		admin.GET("/users/:id", perm(domain.PermUsersRead), h.GetUser)
+		admin.PATCH("/users/:id", perm(domain.PermUsersWrite), h.UpdateUser)
+		admin.DELETE("/users/:id", perm(domain.PermUsersWrite), h.DeleteUser)
+		admin.GET("/users/:id/accounts", perm(domain.PermUsersRead), h.GetUserAccounts)
+		admin.DELETE("/users/:id/accounts/:provider_id", perm(domain.PermUsersWrite), h.AdminUnlinkAccount)
+		admin.POST("/users/:id/reset-password", perm(domain.PermUsersWrite), h.ResetUserPassword)
+		admin.POST("/users/:id/unlock", perm(domain.PermUsersWrite), h.UnlockUser)
+		admin.GET("/users/:id/audit", perm(domain.PermUsersRead), h.GetUserAudit)
+		admin.GET("/search", perm(domain.PermUsersRead), h.Search)
+		admin.GET("/stats", perm(domain.PermStatsRead), h.Stats)
+		admin.GET("/stats/timeseries", perm(domain.PermStatsRead), h.StatsTimeSeries)
+		admin.GET("/rate-limits", perm(domain.PermStatsRead), h.RateLimits)
+		admin.GET("/invite-codes", perm(domain.PermInviteCodesRead), h.ListInviteCodes)
+		admin.POST("/invite-codes", perm(domain.PermInviteCodesWrite), h.CreateInviteCode)
+		admin.DELETE("/invite-codes/:code", perm(domain.PermInviteCodesWrite), h.RevokeInviteCode)
+		admin.POST("/teams", perm(domain.PermTeamsWrite), h.AdminCreateTeam)
+		admin.POST("/teams/:id/members", perm(domain.PermTeamsWrite), h.AdminAddTeamMember)
+		admin.DELETE("/teams/:id/members/:user_id", perm(domain.PermTeamsWrite), h.AdminRemoveTeamMember)
+		admin.POST("/tokens/revoke-before", perm(domain.PermTokensWrite), h.RevokeTokensBefore)
 	}
 
 	return r